@@ -0,0 +1,31 @@
+//! Decrypts a token given on the command line, with the key and token
+//! arguments parsed by the `clap` value parsers.
+//!
+//! Usage: `clap_key --key <base64|@file|env:VAR> <token>`
+
+use std::sync::Arc;
+
+use clap::Parser;
+use simple_aes256_gcm::clap_parsers::{KeyValueParser, TokenValueParser};
+use simple_aes256_gcm::{decrypt_bytes, EncryptedAndIv, Key};
+
+#[derive(Parser)]
+struct Args {
+    /// The base64 key itself, @ and a file holding it, or env: and a variable holding it
+    #[arg(long, value_parser = KeyValueParser)]
+    key: Arc<Key>,
+    /// A combined token, as printed by `encrypt_to_token`
+    #[arg(value_parser = TokenValueParser)]
+    token: Arc<EncryptedAndIv>
+}
+
+fn main() {
+    let args = Args::parse();
+    match decrypt_bytes(&args.key, &args.token) {
+        Ok(plaintext) => println!("{}", String::from_utf8_lossy(&plaintext)),
+        Err(e) => {
+            eprintln!("clap_key: {}", e);
+            std::process::exit(1)
+        }
+    }
+}
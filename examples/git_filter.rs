@@ -0,0 +1,36 @@
+//! A git clean/smudge filter, see src/git_filter.rs for the setup.
+//!
+//! Usage: `git_filter clean|smudge`, content on stdin, result on stdout,
+//! base64 key in `$SAES_GIT_KEY`.
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::process::exit;
+
+use simple_aes256_gcm::git_filter::{clean, smudge};
+use simple_aes256_gcm::Key;
+
+fn fail(message: &str) -> ! {
+    eprintln!("git_filter: {}", message);
+    exit(1)
+}
+
+fn main() {
+    let mode = std::env::args().nth(1).unwrap_or_default();
+    let key = match std::env::var("SAES_GIT_KEY").ok().and_then(|value| Key::try_from(value.trim()).ok()) {
+        Some(key) => key,
+        None => fail("set SAES_GIT_KEY to a base64, 32-byte key")
+    };
+    let mut content = Vec::new();
+    if let Err(e) = io::stdin().read_to_end(&mut content) {
+        fail(&e.to_string());
+    }
+    let output = match mode.as_str() {
+        "clean" => clean(&key, &content).unwrap_or_else(|e| fail(&e.to_string())),
+        "smudge" => smudge(&key, &content).unwrap_or_else(|e| fail(&e.to_string())),
+        _ => fail("usage: git_filter clean|smudge")
+    };
+    if let Err(e) = io::stdout().write_all(&output) {
+        fail(&e.to_string());
+    }
+}
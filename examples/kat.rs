@@ -0,0 +1,7 @@
+use simple_aes256_gcm::kat::generate_known_answer_tests;
+
+fn main() {
+    // Seed 0 is the suite checked in as tests/golden/kat_suite.json
+    let suite = generate_known_answer_tests(0);
+    println!("{}", serde_json::to_string_pretty(&suite).unwrap());
+}
@@ -0,0 +1,18 @@
+use std::convert::TryFrom;
+
+use simple_aes256_gcm::{Decrypted, Key};
+
+fn main() {
+    // Use a base-64 encoded, 32-byte &str or String
+    let key = Key::try_from("MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=").unwrap();
+
+    let encrypted_value_and_iv = key.encrypt(&Decrypted::from("Lorem ipsum dolor sit amet.")).unwrap();
+    println!("IV: {}\n", encrypted_value_and_iv.iv());
+    println!("ENCRYPTED: {}\n", encrypted_value_and_iv.encrypted());
+    println!("DECRYPTED: {}\n", key.decrypt(&encrypted_value_and_iv).unwrap());
+
+    // Or everything in one string
+    let token = key.encrypt_to_token(b"Lorem ipsum dolor sit amet.").unwrap();
+    println!("TOKEN: {}\n", token);
+    println!("DECRYPTED: {}\n", String::from_utf8(key.decrypt_token(&token).unwrap()).unwrap());
+}
@@ -1,4 +1,3 @@
-use simple_aes256_gcm;
 use std::convert::TryFrom;
 
 fn main() {
@@ -15,9 +14,9 @@ fn main() {
     println!("PLAIN TEXT: {}\n", lorem_ipsum);
 
     // Iv is a randomly generated, 12-byte value, displaying as base64 (because it's not utf-8 otherwise)
-    println!("IV: {}\n", encrypted_value_and_iv.iv);
+    println!("IV: {}\n", encrypted_value_and_iv.iv());
     // Encrypted is displaying as base64 (because it's not utf-8 otherwise)
-    println!("ENCRYPTED: {}\n", encrypted_value_and_iv.encrypted);
+    println!("ENCRYPTED: {}\n", encrypted_value_and_iv.encrypted());
     let plaintext = simple_aes256_gcm::decrypt(&key, encrypted_value_and_iv).unwrap();
     println!("DECRYPTED: {}\n", plaintext);
 }
@@ -0,0 +1,134 @@
+#ifndef SIMPLE_AES256_GCM_H
+#define SIMPLE_AES256_GCM_H
+
+/* Generated with cbindgen from src/ffi.rs, do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Length of a raw key, in bytes.
+ */
+#define SAES_KEY_LEN 32
+
+/**
+ * Length of a raw iv, in bytes.
+ */
+#define SAES_IV_LEN 12
+
+/**
+ * The operation succeeded.
+ */
+#define SAES_OK 0
+
+/**
+ * A required pointer argument was null.
+ */
+#define SAES_ERR_NULL_POINTER 1
+
+/**
+ * The key is not valid base64 (`InvalidKeyError::InvalidKeyBase64Error`).
+ */
+#define SAES_ERR_INVALID_KEY_BASE64 2
+
+/**
+ * The key does not decode to 32 bytes (`InvalidKeyError::InvalidKeySizeError`).
+ */
+#define SAES_ERR_INVALID_KEY_SIZE 3
+
+/**
+ * An input string or plaintext is not valid UTF-8.
+ */
+#define SAES_ERR_INVALID_INPUT_UTF8 4
+
+/**
+ * Encryption failed (`EncryptionError::GenericEncryptionError`).
+ */
+#define SAES_ERR_ENCRYPTION 5
+
+/**
+ * Decryption failed: wrong key, wrong iv or tampered data
+ * (`DecryptionError::GenericDecryptionError`).
+ */
+#define SAES_ERR_DECRYPTION 6
+
+/**
+ * The decrypted value is not valid UTF-8
+ * (`DecryptionError::InvalidUTF8DecryptionError`).
+ */
+#define SAES_ERR_DECRYPTION_INVALID_UTF8 7
+
+/**
+ * The key doesn't match the value's key check value
+ * (`DecryptionError::ProbableWrongKey`).
+ */
+#define SAES_ERR_PROBABLE_WRONG_KEY 8
+
+/**
+ * A Rust panic was caught before it could unwind into the caller.
+ */
+#define SAES_ERR_PANIC 99
+
+/**
+ * Decodes a NUL-terminated base64 key into the 32 bytes pointed to by `out_key`.
+ *
+ * # Safety
+ *
+ * `base64_key` must be a valid NUL-terminated string and `out_key` must point
+ * to at least `SAES_KEY_LEN` writable bytes.
+ */
+int32_t saes_key_from_base64(const char *base64_key, uint8_t *out_key);
+
+/**
+ * Encrypts `plaintext_len` bytes of UTF-8 `plaintext` with a freshly generated iv.
+ *
+ * On success the iv is written to `out_iv` and the ciphertext is returned in
+ * a newly allocated buffer through `out_buf` / `out_len`.
+ *
+ * # Safety
+ *
+ * `key` must point to `SAES_KEY_LEN` readable bytes, `plaintext` to
+ * `plaintext_len` readable bytes (it may be null when `plaintext_len` is 0),
+ * `out_iv` to `SAES_IV_LEN` writable bytes, and `out_buf` / `out_len` must be
+ * valid for writes.
+ */
+int32_t saes_encrypt(const uint8_t *key,
+                     const uint8_t *plaintext,
+                     size_t plaintext_len,
+                     uint8_t *out_iv,
+                     uint8_t **out_buf,
+                     size_t *out_len);
+
+/**
+ * Decrypts `ciphertext_len` bytes of `ciphertext` produced with `iv`.
+ *
+ * On success the UTF-8 plaintext (not NUL-terminated) is returned in a newly
+ * allocated buffer through `out_buf` / `out_len`.
+ *
+ * # Safety
+ *
+ * `key` must point to `SAES_KEY_LEN` readable bytes, `iv` to `SAES_IV_LEN`
+ * readable bytes, `ciphertext` to `ciphertext_len` readable bytes, and
+ * `out_buf` / `out_len` must be valid for writes.
+ */
+int32_t saes_decrypt(const uint8_t *key,
+                     const uint8_t *iv,
+                     const uint8_t *ciphertext,
+                     size_t ciphertext_len,
+                     uint8_t **out_buf,
+                     size_t *out_len);
+
+/**
+ * Releases a buffer returned by `saes_encrypt` or `saes_decrypt`.
+ *
+ * # Safety
+ *
+ * `buf` / `len` must be exactly a pair previously returned by this library,
+ * and must not be used after this call. A null `buf` is ignored.
+ */
+void saes_free_buf(uint8_t *buf, size_t len);
+
+#endif  /* SIMPLE_AES256_GCM_H */
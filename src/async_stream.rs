@@ -0,0 +1,398 @@
+//! The stream format of `stream`, over `futures::io::AsyncRead` and
+//! `AsyncWrite`, for any executor.
+//!
+//! `AsyncStreamEncryptor` and `AsyncStreamDecryptor` write and read the
+//! same bytes as `StreamEncryptor` and `StreamDecryptor`, chunk for chunk,
+//! and report truncation the same way. With the `tokio` feature, `tokio_compat`
+//! wraps them for `tokio::io` types through `tokio_util::compat`.
+
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+#[cfg(feature = "rng")]
+use futures_io::AsyncWrite;
+use futures_io::AsyncRead;
+
+use crate::instrument::StreamInstrument;
+use crate::stream::{ChunkCipher, StreamError, CHUNK_SIZE, HEADER_LEN};
+use crate::{Key, TAG_LEN};
+
+/// Writes an encrypted stream. `poll_close` (`close().await`) writes the
+/// final chunk: without it, the stream reads as truncated. Writes after
+/// closing fail.
+#[cfg(feature = "rng")]
+pub struct AsyncStreamEncryptor<W: AsyncWrite + Unpin> {
+    chunks: ChunkCipher,
+    /// Plaintext not sealed yet, at most `CHUNK_SIZE` bytes between writes.
+    buffer: Vec<u8>,
+    /// Sealed bytes not written yet, from `position`.
+    pending: Vec<u8>,
+    position: usize,
+    finished: bool,
+    writer: W,
+    instrument: StreamInstrument
+}
+
+#[cfg(feature = "rng")]
+impl<W: AsyncWrite + Unpin> AsyncStreamEncryptor<W> {
+    /// Queues the header, with a fresh salt and nonce prefix; it is written
+    /// with the first chunk, or on flush.
+    pub fn new(key: &Key, writer: W) -> AsyncStreamEncryptor<W> {
+        let chunks = ChunkCipher::generate(key);
+        let pending = chunks.header().to_vec();
+        AsyncStreamEncryptor {
+            chunks,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            pending,
+            position: 0,
+            finished: false,
+            writer,
+            instrument: StreamInstrument::new("encrypt", key)
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn seal(&mut self, len: usize, last: bool) -> io::Result<()> {
+        let ciphertext = self.chunks.seal(&self.buffer[..len], last)?;
+        self.pending.extend_from_slice(&ciphertext);
+        self.buffer.drain(..len);
+        self.instrument.chunk();
+        Ok(())
+    }
+
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.position < self.pending.len() {
+            match Pin::new(&mut self.writer).poll_write(cx, &self.pending[self.position..]) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero))),
+                Poll::Ready(Ok(written)) => self.position += written,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending
+            }
+        }
+        self.pending.clear();
+        self.position = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "rng")]
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncStreamEncryptor<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.finished {
+            return Poll::Ready(Err(io::Error::other("stream already closed")));
+        }
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => {},
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending
+        }
+        // As in `StreamEncryptor`, a full chunk is only sealed once more
+        // data follows. Taking at most one chunk per call bounds the buffers.
+        let len = buf.len().min(CHUNK_SIZE + 1 - this.buffer.len());
+        this.buffer.extend_from_slice(&buf[..len]);
+        this.instrument.add(len);
+        if this.buffer.len() > CHUNK_SIZE {
+            this.seal(CHUNK_SIZE, false)?;
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.writer).poll_flush(cx),
+            other => other
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.finished {
+            let len = this.buffer.len();
+            this.seal(len, true)?;
+            this.finished = true;
+        }
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => {},
+            other => return other
+        }
+        let poll = match Pin::new(&mut this.writer).poll_flush(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.writer).poll_close(cx),
+            other => other
+        };
+        if let Poll::Ready(Ok(())) = poll {
+            this.instrument.encrypted();
+        }
+        poll
+    }
+}
+
+/// Reads an encrypted stream. As with `StreamDecryptor` through `io::Read`,
+/// errors wrap a `StreamError` and after one every read fails.
+pub struct AsyncStreamDecryptor<R: AsyncRead + Unpin> {
+    chunks: ChunkCipher,
+    /// Ciphertext read but not opened yet: a chunk and one byte of the
+    /// next, to tell whether it is the final one.
+    input: Vec<u8>,
+    exhausted: bool,
+    plaintext: Vec<u8>,
+    position: usize,
+    finished: bool,
+    failed: bool,
+    reader: R,
+    instrument: StreamInstrument
+}
+
+/// Reads into `buffer` until it holds `len` bytes or the reader is exhausted,
+/// which is `Ok(false)`.
+fn poll_read_up_to<R: AsyncRead + Unpin>(reader: &mut R, cx: &mut Context<'_>, buffer: &mut Vec<u8>, len: usize) -> Poll<io::Result<bool>> {
+    while buffer.len() < len {
+        let start = buffer.len();
+        buffer.resize(len, 0);
+        let poll = Pin::new(&mut *reader).poll_read(cx, &mut buffer[start..]);
+        match poll {
+            Poll::Ready(Ok(0)) => {
+                buffer.truncate(start);
+                return Poll::Ready(Ok(false));
+            },
+            Poll::Ready(Ok(read)) => buffer.truncate(start + read),
+            Poll::Ready(Err(e)) => {
+                buffer.truncate(start);
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Poll::Ready(Err(e));
+                }
+            },
+            Poll::Pending => {
+                buffer.truncate(start);
+                return Poll::Pending;
+            }
+        }
+    }
+    Poll::Ready(Ok(true))
+}
+
+impl<R: AsyncRead + Unpin> AsyncStreamDecryptor<R> {
+    /// Reads and checks the header.
+    pub async fn new(key: &Key, mut reader: R) -> Result<AsyncStreamDecryptor<R>, StreamError> {
+        let mut instrument = StreamInstrument::new("decrypt", key);
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        let chunks = match poll_fn(|cx| poll_read_up_to(&mut reader, cx, &mut header, HEADER_LEN)).await {
+            Ok(_) => ChunkCipher::from_header(key, &header),
+            Err(e) => Err(StreamError::from(e))
+        };
+        let chunks = match chunks {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                instrument.decrypted(Some(&e));
+                return Err(e);
+            }
+        };
+        Ok(AsyncStreamDecryptor {
+            chunks,
+            input: Vec::with_capacity(CHUNK_SIZE + TAG_LEN + 1),
+            exhausted: false,
+            plaintext: Vec::new(),
+            position: 0,
+            finished: false,
+            failed: false,
+            reader,
+            instrument
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn fail(&mut self, e: StreamError) -> io::Error {
+        self.instrument.decrypted(Some(&e));
+        self.failed = true;
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncStreamDecryptor<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.failed {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "stream decryption already failed")));
+        }
+        while this.position == this.plaintext.len() {
+            if this.finished {
+                return Poll::Ready(Ok(0));
+            }
+            if !this.exhausted {
+                match poll_read_up_to(&mut this.reader, cx, &mut this.input, CHUNK_SIZE + TAG_LEN + 1) {
+                    Poll::Ready(Ok(full)) => this.exhausted = !full,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending
+                }
+            }
+            let last = this.input.len() <= CHUNK_SIZE + TAG_LEN;
+            let len = this.input.len().min(CHUNK_SIZE + TAG_LEN);
+            match this.chunks.open(&this.input[..len], last) {
+                Ok(plaintext) => {
+                    this.input.drain(..len);
+                    this.instrument.add(len);
+                    this.instrument.chunk();
+                    if last {
+                        this.instrument.decrypted(None);
+                    }
+                    this.plaintext = plaintext;
+                    this.position = 0;
+                    this.finished = last;
+                },
+                Err(e) => return Poll::Ready(Err(this.fail(e)))
+            }
+        }
+        let len = buf.len().min(this.plaintext.len() - this.position);
+        buf[..len].copy_from_slice(&this.plaintext[this.position..this.position + len]);
+        this.position += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+/// The futures-io adapters over `tokio::io` types.
+#[cfg(feature = "tokio")]
+pub mod tokio_compat {
+    use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+    #[cfg(feature = "rng")]
+    use tokio_util::compat::{FuturesAsyncWriteCompatExt, TokioAsyncWriteCompatExt};
+
+    use super::*;
+
+    /// `AsyncStreamEncryptor` as a `tokio::io::AsyncWrite`; `shutdown`
+    /// writes the final chunk.
+    #[cfg(feature = "rng")]
+    pub fn encryptor<W: ::tokio::io::AsyncWrite + Unpin>(key: &Key, writer: W) -> Compat<AsyncStreamEncryptor<Compat<W>>> {
+        AsyncStreamEncryptor::new(key, writer.compat_write()).compat_write()
+    }
+
+    /// `AsyncStreamDecryptor` as a `tokio::io::AsyncRead`.
+    pub async fn decryptor<R: ::tokio::io::AsyncRead + Unpin>(key: &Key, reader: R) -> Result<Compat<AsyncStreamDecryptor<Compat<R>>>, StreamError> {
+        Ok(AsyncStreamDecryptor::new(key, reader.compat()).await?.compat())
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "rng")]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::stream::{StreamDecryptor, StreamEncryptor};
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use std::io::{Read, Write};
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    fn plaintext(len: usize) -> Vec<u8> {
+        (0..len).map(|index| (index % 251) as u8).collect()
+    }
+
+    async fn encrypt_async(plaintext: &[u8]) -> Vec<u8> {
+        let mut encryptor = AsyncStreamEncryptor::new(&Key { u8_array: KEY }, Vec::new());
+        for piece in plaintext.chunks(10_000) {
+            encryptor.write_all(piece).await.unwrap();
+        }
+        encryptor.close().await.unwrap();
+        encryptor.into_inner()
+    }
+
+    async fn decrypt_async(stream: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decryptor = AsyncStreamDecryptor::new(&Key { u8_array: KEY }, stream).await.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut plaintext = Vec::new();
+        decryptor.read_to_end(&mut plaintext).await?;
+        Ok(plaintext)
+    }
+
+    fn encrypt_sync(plaintext: &[u8]) -> Vec<u8> {
+        let mut encryptor = StreamEncryptor::new(&Key { u8_array: KEY }, Vec::new()).unwrap();
+        encryptor.write_all(plaintext).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn decrypt_sync(stream: &[u8]) -> Vec<u8> {
+        let mut plaintext = Vec::new();
+        StreamDecryptor::new(&Key { u8_array: KEY }, stream).unwrap().read_to_end(&mut plaintext).unwrap();
+        plaintext
+    }
+
+    fn stream_error(e: &io::Error) -> Option<&StreamError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<StreamError>())
+    }
+
+    #[test]
+    fn async_std_round_trips_in_the_sync_format() {
+        async_std::task::block_on(async {
+            for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE + 100].iter() {
+                let stream = encrypt_async(&plaintext(*len)).await;
+                assert_eq!(stream.len(), encrypt_sync(&plaintext(*len)).len());
+                assert_eq!(decrypt_sync(&stream), plaintext(*len));
+                assert_eq!(decrypt_async(&stream).await.unwrap(), plaintext(*len));
+                assert_eq!(decrypt_async(&encrypt_sync(&plaintext(*len))).await.unwrap(), plaintext(*len))
+            }
+        })
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn tokio_compat_round_trips_in_the_sync_format() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let data = plaintext(2 * CHUNK_SIZE + 7);
+        let mut encryptor = super::tokio_compat::encryptor(&Key { u8_array: KEY }, Vec::new());
+        encryptor.write_all(&data).await.unwrap();
+        encryptor.shutdown().await.unwrap();
+        let stream = encryptor.into_inner().into_inner().into_inner();
+        assert_eq!(decrypt_sync(&stream), data);
+
+        let sync_stream = encrypt_sync(&data);
+        let mut decryptor = super::tokio_compat::decryptor(&Key { u8_array: KEY }, sync_stream.as_slice()).await.unwrap();
+        let mut read = Vec::new();
+        decryptor.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, data)
+    }
+
+    #[test]
+    fn tampered_chunk_fails_and_keeps_failing() {
+        async_std::task::block_on(async {
+            let mut stream = encrypt_async(&plaintext(3 * CHUNK_SIZE + 100)).await;
+            stream[HEADER_LEN + CHUNK_SIZE + TAG_LEN + 10] ^= 1;
+            let mut decryptor = AsyncStreamDecryptor::new(&Key { u8_array: KEY }, stream.as_slice()).await.unwrap();
+            let mut read = Vec::new();
+            let e = decryptor.read_to_end(&mut read).await.err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            match stream_error(&e) {
+                Some(StreamError::ChunkAuthenticationError(1)) => assert!(true),
+                _ => assert!(false, "Should wrap ChunkAuthenticationError(1), got {}", e)
+            }
+            assert_eq!(read, plaintext(CHUNK_SIZE));
+            assert!(decryptor.read(&mut [0u8; 16]).await.is_err())
+        })
+    }
+
+    #[test]
+    fn cut_after_a_chunk_and_unclosed_encryptor_are_truncated() {
+        async_std::task::block_on(async {
+            let stream = encrypt_async(&plaintext(2 * CHUNK_SIZE + 100)).await;
+            let mut unclosed = AsyncStreamEncryptor::new(&Key { u8_array: KEY }, Vec::new());
+            unclosed.write_all(&plaintext(2 * CHUNK_SIZE)).await.unwrap();
+            unclosed.flush().await.unwrap();
+            for cut in [&stream[..HEADER_LEN + 2 * (CHUNK_SIZE + TAG_LEN)], &unclosed.into_inner()[..], &stream[..HEADER_LEN - 1], &stream[..0]].iter() {
+                match decrypt_async(cut).await {
+                    Ok(_) => assert!(false, "Should err Truncated"),
+                    Err(e) => match stream_error(&e) {
+                        Some(StreamError::Truncated) => assert!(true),
+                        _ => assert!(false, "Should wrap Truncated, got {}", e)
+                    }
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,342 @@
+//! Audit hooks for encryption and decryption events.
+//!
+//! Register an [`AuditSink`] with [`set_global_sink`] and every `encrypt*` /
+//! `decrypt*` call reports an [`AuditEvent`] to it, on success and on every
+//! failure path. Sinks only ever see non-secret facts (never key bytes nor
+//! plaintext), can't change the outcome of the operation, and a panicking
+//! sink is caught and ignored.
+//!
+//! A stream (`stream`, `async_stream` and `EncryptedTempFile` on top of
+//! them) reports one event with its total length, when encryption
+//! finishes or when decryption reaches the final chunk or fails. `dir` is
+//! audited file by file, and so is its manifest.
+//!
+//! The caller-supplied context (who is asking, for which record...) is set
+//! for the current thread with [`with_context`]:
+//!
+//! ```
+//! # use simple_aes256_gcm::{audit, Key, Iv, encrypt_bytes_with_iv};
+//! # let key = Key { u8_array: [0u8; 32] };
+//! audit::with_context("user=42", || {
+//!     encrypt_bytes_with_iv(&key, Iv { u8_array: [0u8; 12] }, b"secret")
+//! }).unwrap();
+//! ```
+
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, RwLock};
+
+use crate::Key;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditOperation {
+    Encrypt,
+    Decrypt
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditOutcome {
+    Success,
+    /// Failure class, one per error variant: `"generic"` for encryption;
+    /// for decryption `"authentication"` (`GenericDecryptionError`),
+    /// `"invalid_utf8"`, `"probable_wrong_key"`, `"rate_limited"`,
+    /// `"probable_context_mismatch"`, `"replayed"` and `"out_of_window"`;
+    /// for stream decryption `"truncated"`, `"authentication"`,
+    /// `"invalid_header"` and `"io"`.
+    ///
+    /// A rate-limited call decrypts nothing, so its `payload_len` is 0.
+    Failure(&'static str)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEvent {
+    pub operation: AuditOperation,
+    /// See `Key::fingerprint`.
+    pub key_fingerprint: String,
+    /// Plaintext length when encrypting, ciphertext length when decrypting.
+    pub payload_len: usize,
+    pub outcome: AuditOutcome,
+    /// Set with `with_context`, `None` outside of it.
+    pub context: Option<String>
+}
+
+pub trait AuditSink: Send + Sync {
+    fn on_encrypt(&self, event: &AuditEvent);
+    fn on_decrypt(&self, event: &AuditEvent);
+}
+
+static GLOBAL_SINK: RwLock<Option<Arc<dyn AuditSink>>> = RwLock::new(None);
+
+thread_local! {
+    static CONTEXT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Registers the sink receiving every event of the process, replacing any previous one.
+pub fn set_global_sink(sink: Arc<dyn AuditSink>) {
+    *GLOBAL_SINK.write().unwrap_or_else(|e| e.into_inner()) = Some(sink);
+}
+
+pub fn clear_global_sink() {
+    *GLOBAL_SINK.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Runs `f` with `context` attached to the events it triggers on this thread.
+pub fn with_context<T, F: FnOnce() -> T>(context: &str, f: F) -> T {
+    let previous = CONTEXT.with(|current| current.replace(Some(String::from(context))));
+    struct Restore(Option<String>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            CONTEXT.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+    let _restore = Restore(previous);
+    f()
+}
+
+pub(crate) fn record(operation: AuditOperation, key: &Key, payload_len: usize, failure: Option<&'static str>) {
+    if has_sink() {
+        record_fingerprint(operation, &key.fingerprint(), payload_len, failure)
+    }
+}
+
+fn has_sink() -> bool {
+    GLOBAL_SINK.read().unwrap_or_else(|e| e.into_inner()).is_some()
+}
+
+/// `record` for callers that computed the fingerprint up front (streams).
+pub(crate) fn record_fingerprint(operation: AuditOperation, key_fingerprint: &str, payload_len: usize, failure: Option<&'static str>) {
+    // Clone the sink out so the lock isn't held (or poisoned) while it runs.
+    let sink = match GLOBAL_SINK.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(sink) => Arc::clone(sink),
+        None => return
+    };
+    let event = AuditEvent {
+        operation,
+        key_fingerprint: String::from(key_fingerprint),
+        payload_len,
+        outcome: match failure {
+            Some(class) => AuditOutcome::Failure(class),
+            None => AuditOutcome::Success
+        },
+        context: CONTEXT.with(|current| current.borrow().clone())
+    };
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| match operation {
+        AuditOperation::Encrypt => sink.on_encrypt(&event),
+        AuditOperation::Decrypt => sink.on_decrypt(&event)
+    }));
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use std::sync::{Mutex, OnceLock};
+
+    // The sink is process-wide and tests run in parallel: install one
+    // recording sink once, and have each test filter on its own context.
+    struct RecordingSink {
+        events: Mutex<Vec<AuditEvent>>
+    }
+
+    impl AuditSink for RecordingSink {
+        fn on_encrypt(&self, event: &AuditEvent) {
+            assert_eq!(event.operation, AuditOperation::Encrypt);
+            self.events.lock().unwrap().push(event.clone());
+            if event.context.as_deref() == Some("panicking") {
+                panic!("Sink panics must not leak into crypto operations");
+            }
+        }
+        fn on_decrypt(&self, event: &AuditEvent) {
+            assert_eq!(event.operation, AuditOperation::Decrypt);
+            self.events.lock().unwrap().push(event.clone());
+            if event.context.as_deref() == Some("panicking") {
+                panic!("Sink panics must not leak into crypto operations");
+            }
+        }
+    }
+
+    fn sink() -> &'static Arc<RecordingSink> {
+        static SINK: OnceLock<Arc<RecordingSink>> = OnceLock::new();
+        SINK.get_or_init(|| {
+            let sink = Arc::new(RecordingSink { events: Mutex::new(Vec::new()) });
+            set_global_sink(sink.clone());
+            sink
+        })
+    }
+
+    fn events_for(context: &str) -> Vec<AuditEvent> {
+        sink().events.lock().unwrap().iter()
+            .filter(|event| event.context.as_deref() == Some(context))
+            .cloned()
+            .collect()
+    }
+
+    fn key() -> Key {
+        Key { u8_array: [3u8; 32] }
+    }
+
+    fn iv() -> Iv {
+        Iv { u8_array: [5u8; 12] }
+    }
+
+    #[test]
+    fn success_events_carry_context_and_fingerprint() {
+        sink();
+        let encrypted_and_iv = with_context("success", || {
+            encrypt_bytes_with_iv(&key(), iv(), b"This is a text.")
+        }).unwrap();
+        with_context("success", || decrypt_bytes(&key(), &encrypted_and_iv)).unwrap();
+        assert_eq!(events_for("success"), vec![
+            AuditEvent {
+                operation: AuditOperation::Encrypt,
+                key_fingerprint: key().fingerprint(),
+                payload_len: 15,
+                outcome: AuditOutcome::Success,
+                context: Some(String::from("success"))
+            },
+            AuditEvent {
+                operation: AuditOperation::Decrypt,
+                key_fingerprint: key().fingerprint(),
+                payload_len: 31,
+                outcome: AuditOutcome::Success,
+                context: Some(String::from("success"))
+            }
+        ])
+    }
+
+    #[test]
+    fn authentication_failure_is_recorded() {
+        sink();
+        let encrypted_and_iv = encrypt_bytes_with_iv(&key(), iv(), b"This is a text.").unwrap();
+        let wrong_key = Key { u8_array: [4u8; 32] };
+        assert!(with_context("auth-failure", || decrypt_bytes(&wrong_key, &encrypted_and_iv)).is_err());
+        let events = events_for("auth-failure");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].operation, AuditOperation::Decrypt);
+        assert_eq!(events[0].key_fingerprint, wrong_key.fingerprint());
+        assert_eq!(events[0].outcome, AuditOutcome::Failure("authentication"))
+    }
+
+    #[test]
+    fn utf8_failure_is_recorded() {
+        sink();
+        let encrypted_and_iv = encrypt_bytes_with_iv(&key(), iv(), &[133u8, 133u8]).unwrap();
+        assert!(with_context("utf8-failure", || decrypt(&key(), encrypted_and_iv)).is_err());
+        let events = events_for("utf8-failure");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].payload_len, 18);
+        assert_eq!(events[0].outcome, AuditOutcome::Failure("invalid_utf8"))
+    }
+
+    #[test]
+    #[cfg(panic = "unwind")]
+    fn panicking_sink_does_not_affect_outcome() {
+        sink();
+        let encrypted_and_iv = with_context("panicking", || {
+            encrypt_bytes_with_iv(&key(), iv(), b"This is a text.")
+        }).unwrap();
+        assert_eq!(
+            with_context("panicking", || decrypt_bytes(&key(), &encrypted_and_iv)).unwrap(),
+            b"This is a text.".to_vec()
+        );
+        assert_eq!(events_for("panicking").len(), 2)
+    }
+
+    #[test]
+    fn context_is_restored_after_scope() {
+        with_context("outer", || {
+            with_context("inner", || ());
+            assert_eq!(CONTEXT.with(|current| current.borrow().clone()), Some(String::from("outer")))
+        });
+        assert_eq!(CONTEXT.with(|current| current.borrow().clone()), None)
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn failures_decided_around_decryption_are_reported_as_such() {
+        use crate::context::{decrypt_with_context, encrypt_with_iv_and_context, EncryptionContext};
+        use crate::failure_policy::FailurePolicy;
+        use crate::sequenced::{SequencedDecryptor, SequencedEncryptor};
+        use std::time::Duration;
+
+        sink();
+        let encrypted_and_iv = encrypt_with_iv_and_context(&key(), iv(), b"This is a text.", &EncryptionContext::new().with("tenant", "a")).unwrap();
+        let policy = FailurePolicy::new(1, Duration::from_secs(60));
+        let message = SequencedEncryptor::new(&key(), "device").encrypt(b"This is a text.").unwrap();
+        let mut decryptor = SequencedDecryptor::new(&key(), "device");
+        with_context("around", || {
+            match decrypt_with_context(&key(), &encrypted_and_iv, &EncryptionContext::new().with("tenant", "b")) {
+                Err(DecryptionError::ProbableContextMismatch) => assert!(true),
+                _ => assert!(false, "Should err ProbableContextMismatch")
+            }
+            policy.guard("client", &key(), || decrypt_bytes(&key(), &encrypted_and_iv)).unwrap_err();
+            match policy.guard("client", &key(), || decrypt_bytes(&key(), &encrypted_and_iv)) {
+                Err(DecryptionError::RateLimited) => assert!(true),
+                _ => assert!(false, "Should err RateLimited")
+            }
+            decryptor.decrypt(&message).unwrap();
+            match decryptor.decrypt(&message) {
+                Err(DecryptionError::Replayed) => assert!(true),
+                _ => assert!(false, "Should err Replayed")
+            }
+        });
+        let events = events_for("around");
+        let outcomes: Vec<AuditOutcome> = events.iter().map(|event| event.outcome.clone()).collect();
+        assert_eq!(outcomes, vec![
+            AuditOutcome::Failure("probable_context_mismatch"),
+            AuditOutcome::Failure("authentication"),
+            AuditOutcome::Failure("rate_limited"),
+            AuditOutcome::Success,
+            AuditOutcome::Failure("replayed")
+        ]);
+        assert_eq!(events[2].payload_len, 0);
+        assert_eq!(events[4].payload_len, message.len())
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn streams_report_one_event_each() {
+        use crate::stream::{StreamDecryptor, StreamEncryptor, CHUNK_SIZE};
+        use std::io::{Read, Write};
+
+        sink();
+        let plaintext = vec![7u8; CHUNK_SIZE + 1];
+        let mut encrypted = Vec::new();
+        with_context("stream", || {
+            let mut encryptor = StreamEncryptor::new(&key(), &mut encrypted).unwrap();
+            encryptor.write_all(&plaintext).unwrap();
+            encryptor.finish().unwrap();
+        });
+        with_context("stream", || {
+            StreamDecryptor::new(&key(), encrypted.as_slice()).unwrap().read_to_end(&mut Vec::new()).unwrap();
+            let truncated = &encrypted[..encrypted.len() - 1 - TAG_LEN];
+            StreamDecryptor::new(&key(), truncated).unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+        });
+        let events = events_for("stream");
+        assert_eq!(events.iter().map(|event| (event.operation, event.outcome.clone())).collect::<Vec<_>>(), vec![
+            (AuditOperation::Encrypt, AuditOutcome::Success),
+            (AuditOperation::Decrypt, AuditOutcome::Success),
+            (AuditOperation::Decrypt, AuditOutcome::Failure("truncated"))
+        ]);
+        assert!(events.iter().all(|event| event.key_fingerprint == key().fingerprint()));
+        assert_eq!(events[0].payload_len, plaintext.len());
+        assert_eq!(events[1].payload_len, encrypted.len() - crate::stream::HEADER_LEN)
+    }
+
+    #[test]
+    #[cfg(all(feature = "sjcl", feature = "rng"))]
+    fn sjcl_is_audited() {
+        use crate::sjcl::{decrypt_sjcl, encrypt_sjcl, SjclSecret};
+
+        sink();
+        let json = with_context("sjcl", || encrypt_sjcl(SjclSecret::Key(&key()), b"This is a text.", b""));
+        with_context("sjcl", || {
+            decrypt_sjcl(SjclSecret::Key(&key()), &json).unwrap();
+            assert!(decrypt_sjcl(SjclSecret::Key(&Key { u8_array: [4u8; 32] }), &json).is_err());
+        });
+        let outcomes: Vec<AuditOutcome> = events_for("sjcl").into_iter().map(|event| event.outcome).collect();
+        assert_eq!(outcomes, vec![AuditOutcome::Success, AuditOutcome::Success, AuditOutcome::Failure("authentication")])
+    }
+}
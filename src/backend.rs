@@ -0,0 +1,255 @@
+//! Envelope and token functions over any AEAD implementation.
+//!
+//! The crate-root functions are these with the built-in `Aes256Gcm`
+//! backend, plus audit and tracing instrumentation and the key check
+//! value, which need a `Key`. Implement `SimpleAead` to reuse `Iv`,
+//! `EncryptedAndIv` and tokens with another backend, e.g. a hardware one:
+//! envelopes carry a 12-byte iv, so the backend's `NONCE_SIZE` must be 12.
+//!
+//! `Key::to_cipher`, `Key::to_aead_key` and `Iv::to_nonce` bridge to the
+//! `aes-gcm` and `aead` crates directly, re-exported here at the versions
+//! this crate uses, for what it doesn't wrap (in-place APIs, custom AAD
+//! layouts). Whatever is built that way is outside this crate's format
+//! guarantees: only these functions and the crate-root ones produce
+//! envelopes and tokens that are guaranteed to stay readable.
+
+use aead::generic_array::typenum::{U12, U32};
+use aead::{generic_array::GenericArray, Aead, NewAead, Payload};
+
+pub use aead;
+pub use aes_gcm;
+pub use aes_gcm::Aes256Gcm;
+
+#[cfg(feature = "base64")]
+use crate::token::{parse_token, token_aad, TokenError};
+use crate::{DecryptionError, Encrypted, EncryptedAndIv, EncryptionError, Iv, Key};
+
+/// Key type of `Aes256Gcm::new`.
+pub type AeadKey = GenericArray<u8, U32>;
+
+/// Nonce type of `aead::Aead::encrypt` and `decrypt` for `Aes256Gcm`.
+pub type Nonce = GenericArray<u8, U12>;
+
+pub trait SimpleAead {
+    /// Nonce length in bytes; `nonce` arguments always have this length.
+    const NONCE_SIZE: usize;
+
+    /// Returns the ciphertext followed by the tag.
+    fn encrypt(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, EncryptionError>;
+
+    /// Takes the output of `encrypt`; must fail if it or `aad` was altered.
+    fn decrypt(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, DecryptionError>;
+}
+
+impl SimpleAead for Aes256Gcm {
+    const NONCE_SIZE: usize = 12;
+
+    fn encrypt(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        match Aead::encrypt(self, GenericArray::from_slice(nonce), Payload { msg: plaintext, aad }) {
+            Ok(ciphertext) => Ok(ciphertext),
+            Err(_) => Err(EncryptionError::GenericEncryptionError)
+        }
+    }
+
+    fn decrypt(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        match Aead::decrypt(self, GenericArray::from_slice(nonce), Payload { msg: ciphertext, aad }) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(_) => Err(DecryptionError::GenericDecryptionError)
+        }
+    }
+}
+
+/// The built-in backend for `key`.
+pub fn aes256_gcm(key: &Key) -> Aes256Gcm {
+    Aes256Gcm::new(key.to_aead_key())
+}
+
+impl Key {
+    pub fn to_aead_key(&self) -> AeadKey {
+        GenericArray::clone_from_slice(&self.u8_array)
+    }
+
+    /// Same as `aes256_gcm(self)`.
+    pub fn to_cipher(&self) -> Aes256Gcm {
+        aes256_gcm(self)
+    }
+}
+
+impl Iv {
+    pub fn to_nonce(&self) -> Nonce {
+        GenericArray::clone_from_slice(&self.u8_array)
+    }
+}
+
+/// Like `encrypt_bytes_with_iv_and_aad`. The iv must never be reused with the same key.
+pub fn encrypt_bytes_with_iv<A: SimpleAead>(aead: &A, iv: Iv, plaintext: &[u8], aad: &[u8]) -> Result<EncryptedAndIv, EncryptionError> {
+    if A::NONCE_SIZE != iv.u8_array.len() {
+        return Err(EncryptionError::GenericEncryptionError);
+    }
+    let ciphertext = aead.encrypt(&iv.u8_array, aad, plaintext)?;
+    Ok(EncryptedAndIv::from_parts(iv, Encrypted { u8_vec: ciphertext }))
+}
+
+/// Like `encrypt_bytes_with_aad`.
+#[cfg(feature = "rng")]
+pub fn encrypt_bytes<A: SimpleAead>(aead: &A, plaintext: &[u8], aad: &[u8]) -> Result<EncryptedAndIv, EncryptionError> {
+    encrypt_bytes_with_iv(aead, Iv::generate(), plaintext, aad)
+}
+
+/// Like `decrypt_bytes_with_aad`. A key check value in the envelope is
+/// ignored, checking it needs the `Key`.
+pub fn decrypt_bytes<A: SimpleAead>(aead: &A, encrypted_and_iv: &EncryptedAndIv, aad: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+    if A::NONCE_SIZE != encrypted_and_iv.iv.u8_array.len() {
+        return Err(DecryptionError::GenericDecryptionError);
+    }
+    aead.decrypt(&encrypted_and_iv.iv.u8_array, aad, &encrypted_and_iv.encrypted.u8_vec)
+}
+
+/// Like `token::encrypt_to_token`.
+#[cfg(all(feature = "base64", feature = "rng"))]
+pub fn encrypt_to_token<A: SimpleAead>(aead: &A, plaintext: &[u8]) -> Result<String, EncryptionError> {
+    Ok(encrypt_bytes(aead, plaintext, &[])?.to_token())
+}
+
+/// Like `token::decrypt_token`, footer included.
+#[cfg(feature = "base64")]
+pub fn decrypt_token<A: SimpleAead>(aead: &A, token: &str) -> Result<Vec<u8>, TokenError> {
+    let (encrypted_and_iv, footer) = parse_token(token).map_err(TokenError::InvalidTokenError)?;
+    decrypt_bytes(aead, &encrypted_and_iv, &token_aad(footer.as_deref())).map_err(TokenError::TokenDecryptionError)
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+    const IV: [u8; 12] = *b"012345678901";
+
+    /// TEST ONLY, not a cipher: XORs with the nonce and appends a checksum.
+    struct XorStub {
+        calls: Cell<usize>
+    }
+
+    impl XorStub {
+        fn checksum(nonce: &[u8], aad: &[u8], body: &[u8]) -> u8 {
+            nonce.iter().chain(aad).chain(body).fold(0u8, |sum, byte| sum.wrapping_mul(31).wrapping_add(*byte))
+        }
+    }
+
+    impl SimpleAead for XorStub {
+        const NONCE_SIZE: usize = 12;
+
+        fn encrypt(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+            self.calls.set(self.calls.get() + 1);
+            let mut ciphertext: Vec<u8> = plaintext.iter().zip(nonce.iter().cycle()).map(|(byte, pad)| byte ^ pad).collect();
+            ciphertext.push(XorStub::checksum(nonce, aad, &ciphertext));
+            Ok(ciphertext)
+        }
+
+        fn decrypt(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+            self.calls.set(self.calls.get() + 1);
+            let (body, checksum) = match ciphertext.split_last() {
+                Some((checksum, body)) => (body, *checksum),
+                None => return Err(DecryptionError::GenericDecryptionError)
+            };
+            if checksum != XorStub::checksum(nonce, aad, body) {
+                return Err(DecryptionError::GenericDecryptionError);
+            }
+            Ok(body.iter().zip(nonce.iter().cycle()).map(|(byte, pad)| byte ^ pad).collect())
+        }
+    }
+
+    struct WideNonceStub;
+
+    impl SimpleAead for WideNonceStub {
+        const NONCE_SIZE: usize = 24;
+
+        fn encrypt(&self, _nonce: &[u8], _aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+            Ok(plaintext.to_vec())
+        }
+
+        fn decrypt(&self, _nonce: &[u8], _aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+            Ok(ciphertext.to_vec())
+        }
+    }
+
+    #[test]
+    fn built_in_backend_matches_the_crate_root() {
+        let key = Key { u8_array: KEY };
+        let encrypted_and_iv = encrypt_bytes_with_iv(&aes256_gcm(&key), Iv { u8_array: IV }, b"This is a text.", b"ctx").unwrap();
+        assert_eq!(
+            encrypted_and_iv.encrypted.u8_vec,
+            crate::encrypt_bytes_with_iv_and_aad(&key, Iv { u8_array: IV }, b"This is a text.", b"ctx").unwrap().encrypted.u8_vec
+        );
+        assert_eq!(crate::decrypt_bytes_with_aad(&key, &encrypted_and_iv, b"ctx").unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    #[cfg(feature = "base64")]
+    fn bridge_reproduces_encrypt_with_iv() {
+        let key = Key { u8_array: KEY };
+        let iv = Iv { u8_array: IV };
+        let ciphertext = Aead::encrypt(&key.to_cipher(), &iv.to_nonce(), &b"This is a text."[..]).unwrap();
+        let expected = crate::encrypt_with_iv(&key, Iv { u8_array: IV }, &crate::Decrypted::from("This is a text.")).unwrap();
+        assert_eq!(ciphertext, expected.encrypted.u8_vec);
+        assert_eq!(Aead::encrypt(&Aes256Gcm::new(key.to_aead_key()), &iv.to_nonce(), &b"This is a text."[..]).unwrap(), ciphertext)
+    }
+
+    #[test]
+    fn custom_backend_is_used_for_envelopes() {
+        let stub = XorStub { calls: Cell::new(0) };
+        let encrypted_and_iv = encrypt_bytes_with_iv(&stub, Iv { u8_array: IV }, b"This is a text.", b"ctx").unwrap();
+        assert_eq!(encrypted_and_iv.encrypted.u8_vec.len(), b"This is a text.".len() + 1);
+        assert_eq!(decrypt_bytes(&stub, &encrypted_and_iv, b"ctx").unwrap(), b"This is a text.");
+        assert_eq!(stub.calls.get(), 2);
+        match decrypt_bytes(&stub, &encrypted_and_iv, b"other") {
+            Ok(_) => assert!(false, "Should err GenericDecryptionError"),
+            Err(e) => match e {
+                DecryptionError::GenericDecryptionError => assert!(true),
+                _ => assert!(false, "Should err GenericDecryptionError")
+            }
+        }
+        match crate::decrypt_bytes(&Key { u8_array: KEY }, &encrypted_and_iv) {
+            Ok(_) => assert!(false, "Should err GenericDecryptionError"),
+            Err(_) => assert!(true)
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "base64", feature = "rng"))]
+    fn custom_backend_is_used_for_tokens() {
+        let stub = XorStub { calls: Cell::new(0) };
+        let token = encrypt_to_token(&stub, b"This is a text.").unwrap();
+        assert_eq!(decrypt_token(&stub, &token).unwrap(), b"This is a text.");
+        assert_eq!(stub.calls.get(), 2)
+    }
+
+    #[test]
+    #[cfg(all(feature = "base64", feature = "rng"))]
+    fn footer_tokens_decrypt_and_their_footer_is_checked() {
+        let key = Key { u8_array: KEY };
+        let token = crate::token::encrypt_to_token_with_footer(&key, b"This is a text.", "tenant=acme").unwrap();
+        assert_eq!(decrypt_token(&aes256_gcm(&key), &token).unwrap(), b"This is a text.");
+        let body = &token[..token.find(crate::token::FOOTER_SEPARATOR).unwrap()];
+        let forged = format!("{}.{}", body, base64::encode_config("tenant=evil", base64::URL_SAFE_NO_PAD));
+        match decrypt_token(&aes256_gcm(&key), &forged) {
+            Ok(_) => assert!(false, "Should err TokenDecryptionError"),
+            Err(e) => match e {
+                TokenError::TokenDecryptionError(_) => assert!(true),
+                _ => assert!(false, "Should err TokenDecryptionError, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn backend_with_another_nonce_size_is_rejected() {
+        match encrypt_bytes_with_iv(&WideNonceStub, Iv { u8_array: IV }, b"This is a text.", &[]) {
+            Ok(_) => assert!(false, "Should err GenericEncryptionError"),
+            Err(e) => match e {
+                EncryptionError::GenericEncryptionError => assert!(true)
+            }
+        }
+    }
+}
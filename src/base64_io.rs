@@ -0,0 +1,209 @@
+//! Base64 encoding and decoding in fixed-size blocks, straight from and to
+//! `io` streams, for ciphertexts too large to hold twice in memory.
+//!
+//! Blocks are a multiple of 3 bytes (4 characters), so the output is the
+//! same as `base64::encode` of the whole value, and decoding accepts and
+//! rejects exactly what `base64::decode` does, with the same errors and
+//! offsets.
+
+use std::io::{self, BufRead, Read, Write};
+
+use crate::token::{InvalidTokenError, TOKEN_PREFIX};
+use crate::{Encrypted, EncryptedAndIv, InvalidEncryptedError, Iv, IV_LEN, TAG_LEN};
+
+/// Raw bytes per encoded block.
+const BLOCK_LEN: usize = 48 * 1024;
+const ENCODED_BLOCK_LEN: usize = BLOCK_LEN / 3 * 4;
+
+fn write_base64<W: Write>(bytes: &[u8], writer: &mut W) -> io::Result<()> {
+    let mut encoded = vec![0u8; ENCODED_BLOCK_LEN];
+    for block in bytes.chunks(BLOCK_LEN) {
+        let len = base64::encode_config_slice(block, base64::STANDARD, &mut encoded);
+        writer.write_all(&encoded[..len])?;
+    }
+    Ok(())
+}
+
+fn shift(e: base64::DecodeError, offset: usize) -> base64::DecodeError {
+    match e {
+        base64::DecodeError::InvalidByte(index, byte) => base64::DecodeError::InvalidByte(index + offset, byte),
+        base64::DecodeError::InvalidLastSymbol(index, byte) => base64::DecodeError::InvalidLastSymbol(index + offset, byte),
+        base64::DecodeError::InvalidLength => base64::DecodeError::InvalidLength
+    }
+}
+
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Decodes everything left in `reader`. Errors other than I/O ones are
+/// `io::ErrorKind::InvalidData` wrapping the `base64::DecodeError`.
+fn read_base64<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut block = Vec::with_capacity(ENCODED_BLOCK_LEN);
+    let mut offset = 0;
+    loop {
+        block.clear();
+        reader.by_ref().take(ENCODED_BLOCK_LEN as u64).read_to_end(&mut block)?;
+        let last = block.len() < ENCODED_BLOCK_LEN || reader.fill_buf()?.is_empty();
+        if !last && block.ends_with(b"=") {
+            // Padding before the end: let the decoder report it, in the context
+            // of the next characters as when decoding the whole value.
+            let mut window = block[block.len() - 4..].to_vec();
+            window.extend(reader.fill_buf()?.iter().take(4));
+            let e = base64::decode(&window).err().unwrap_or(base64::DecodeError::InvalidByte(3, b'='));
+            return Err(invalid_data(shift(e, offset + block.len() - 4)));
+        }
+        base64::decode_config_buf(&block, base64::STANDARD, &mut decoded).map_err(|e| invalid_data(shift(e, offset)))?;
+        offset += block.len();
+        if last {
+            return Ok(decoded);
+        }
+    }
+}
+
+/// Maps the `base64::DecodeError` in an error of `read_base64` with `f`.
+fn map_decode_error<E, F>(e: io::Error, f: F) -> io::Error
+where E: std::error::Error + Send + Sync + 'static, F: FnOnce(base64::DecodeError) -> E {
+    if e.kind() == io::ErrorKind::InvalidData {
+        if let Some(decode_error) = e.get_ref().and_then(|inner| inner.downcast_ref::<base64::DecodeError>()) {
+            return invalid_data(f(decode_error.clone()));
+        }
+    }
+    e
+}
+
+impl Encrypted {
+    /// Writes `String::from(self)` to `writer` without building it.
+    pub fn write_base64_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        write_base64(&self.u8_vec, &mut writer)
+    }
+
+    /// Reads the rest of `reader` like `Encrypted::try_from(&str)`. Invalid
+    /// base64 fails with `io::ErrorKind::InvalidData`, wrapping the
+    /// `InvalidEncryptedError`.
+    pub fn read_base64_from<R: BufRead>(mut reader: R) -> io::Result<Encrypted> {
+        match read_base64(&mut reader) {
+            Ok(u8_vec) if u8_vec.is_empty() => Err(invalid_data(InvalidEncryptedError::EmptyEncryptedError)),
+            Ok(u8_vec) => Ok(Encrypted { u8_vec }),
+            Err(e) => Err(map_decode_error(e, InvalidEncryptedError::InvalidEncryptedBase64Error))
+        }
+    }
+}
+
+impl EncryptedAndIv {
+    /// Writes `self.to_token()` to `writer` without building it.
+    pub fn write_token_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(TOKEN_PREFIX.as_bytes())?;
+        // 12 bytes encode to 16 characters without padding, so the two
+        // parts just follow each other.
+        write_base64(&self.iv.u8_array, &mut writer)?;
+        write_base64(&self.encrypted.u8_vec, &mut writer)
+    }
+
+    /// Reads the rest of `reader` as a base64 token without footer, like
+    /// `EncryptedAndIv::from_token_with(.., Encoding::Base64)`. Invalid
+    /// tokens fail with `io::ErrorKind::InvalidData`, wrapping the
+    /// `InvalidTokenError`.
+    pub fn read_token_from<R: BufRead>(mut reader: R) -> io::Result<EncryptedAndIv> {
+        let mut prefix = Vec::with_capacity(TOKEN_PREFIX.len());
+        reader.by_ref().take(TOKEN_PREFIX.len() as u64).read_to_end(&mut prefix)?;
+        if prefix.is_empty() {
+            return Err(invalid_data(InvalidTokenError::EmptyTokenError));
+        }
+        if prefix != TOKEN_PREFIX.as_bytes() {
+            return Err(invalid_data(InvalidTokenError::InvalidTokenPrefixError));
+        }
+        let mut bytes = read_base64(&mut reader).map_err(|e| map_decode_error(e, InvalidTokenError::InvalidTokenBase64Error))?;
+        if bytes.is_empty() {
+            return Err(invalid_data(InvalidTokenError::EmptyTokenError));
+        }
+        if bytes.len() < IV_LEN + TAG_LEN {
+            return Err(invalid_data(InvalidTokenError::InvalidTokenSizeError));
+        }
+        let u8_vec = bytes.split_off(IV_LEN);
+        let mut u8_array = [0u8; IV_LEN];
+        u8_array.copy_from_slice(&bytes);
+        Ok(EncryptedAndIv::from_parts(Iv { u8_array }, Encrypted { u8_vec }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::fs::{self, File};
+    use std::io::{BufReader, BufWriter};
+
+    fn ciphertext(len: usize) -> Vec<u8> {
+        (0..len).map(|index| (index.wrapping_mul(2654435761) >> 7) as u8).collect()
+    }
+
+    fn encrypted_error(text: &str) -> String {
+        match Encrypted::read_base64_from(text.as_bytes()) {
+            Ok(_) => String::from("ok"),
+            Err(e) => format!("{:?}", e.get_ref().unwrap().downcast_ref::<InvalidEncryptedError>().unwrap())
+        }
+    }
+
+    #[test]
+    fn large_ciphertext_round_trips_through_a_file() {
+        let encrypted = Encrypted { u8_vec: ciphertext(3 * BLOCK_LEN + 1000) };
+        let path = crate::test_path("base64-io");
+        encrypted.write_base64_to(BufWriter::new(File::create(&path).unwrap())).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        assert_eq!(written, String::from(&encrypted));
+        let read = Encrypted::read_base64_from(BufReader::new(File::open(&path).unwrap())).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.u8_vec, encrypted.u8_vec)
+    }
+
+    #[test]
+    fn matches_the_in_memory_forms_at_block_boundaries() {
+        for len in [0, 1, 2, 3, BLOCK_LEN - 1, BLOCK_LEN, BLOCK_LEN + 1, 2 * BLOCK_LEN].iter() {
+            let encrypted = Encrypted { u8_vec: ciphertext(*len) };
+            let mut written = Vec::new();
+            encrypted.write_base64_to(&mut written).unwrap();
+            assert_eq!(written, String::from(&encrypted).into_bytes());
+            // An empty value is rejected like `Encrypted::try_from("")`, see below.
+            if *len > 0 {
+                assert_eq!(Encrypted::read_base64_from(written.as_slice()).unwrap().u8_vec, encrypted.u8_vec);
+            }
+
+            let encrypted_and_iv = EncryptedAndIv::from_parts(Iv { u8_array: *b"012345678901" }, Encrypted { u8_vec: ciphertext(*len + TAG_LEN) });
+            let mut token = Vec::new();
+            encrypted_and_iv.write_token_to(&mut token).unwrap();
+            assert_eq!(token, encrypted_and_iv.to_token().into_bytes());
+            assert_eq!(EncryptedAndIv::read_token_from(token.as_slice()).unwrap().encrypted.u8_vec, encrypted_and_iv.encrypted.u8_vec)
+        }
+    }
+
+    #[test]
+    fn rejects_what_try_from_rejects_with_the_same_error() {
+        let valid = String::from(&Encrypted { u8_vec: ciphertext(BLOCK_LEN) });
+        let padded = String::from(&Encrypted { u8_vec: ciphertext(BLOCK_LEN - 1) });
+        for text in [
+            format!("{}!", valid),
+            format!("{}AAAA", padded),
+            format!("{}A", valid),
+            format!("{}AB==", valid),
+            format!("{}x{}", &valid[..100], &valid[101..]),
+            String::from("AB=A"),
+            String::new()
+        ].iter() {
+            let expected = match Encrypted::try_from(&text[..]) {
+                Ok(_) => String::from("ok"),
+                Err(e) => format!("{:?}", e)
+            };
+            assert_eq!(encrypted_error(text), expected, "for ...{}", &text[text.len().saturating_sub(8)..])
+        }
+    }
+
+    #[test]
+    fn token_errors_are_the_parsing_ones() {
+        for (token, expected) in [("saes2:MDEy", "InvalidTokenPrefixError"), ("saes1:MDEy", "InvalidTokenSizeError"), ("saes1:MD!y", "InvalidTokenBase64Error"), ("", "EmptyTokenError"), ("saes1:", "EmptyTokenError")].iter() {
+            let e = EncryptedAndIv::read_token_from(token.as_bytes()).err().unwrap();
+            assert!(format!("{:?}", e.get_ref().unwrap().downcast_ref::<InvalidTokenError>().unwrap()).starts_with(expected))
+        }
+    }
+}
@@ -0,0 +1,287 @@
+//! UniFFI bindings for Kotlin and Swift consumers, enabled with the `uniffi` feature.
+//!
+//! The exported names mirror the Rust API: `Key` is an object built with
+//! `Key.fromBase64(...)` or `Key.generate()`, `EncryptedAndIv` is a data
+//! class holding the raw `encrypted` and `iv` bytes, `encryptToToken` and
+//! `decryptToken` work on combined tokens, and the error enums become typed
+//! exceptions carrying the same variant names.
+//!
+//! Build the shared library, then generate the foreign bindings from it:
+//!
+//! ```sh
+//! cargo rustc --lib --features uniffi --crate-type cdylib
+//! cargo run --features uniffi-cli --bin uniffi-bindgen -- \
+//!     generate --library target/debug/libsimple_aes256_gcm.so --language kotlin --out-dir out
+//! ```
+
+use std::convert::{TryFrom, TryInto};
+use std::sync::Arc;
+use std::{error, fmt};
+
+use crate as saes;
+
+#[derive(Debug, Clone, PartialEq, uniffi::Error)]
+pub enum InvalidKeyError {
+    InvalidKeySizeError,
+    InvalidKeyBase64Error
+}
+
+impl From<saes::InvalidKeyError> for InvalidKeyError {
+    fn from(e: saes::InvalidKeyError) -> Self {
+        match e {
+            saes::InvalidKeyError::InvalidKeySizeError => InvalidKeyError::InvalidKeySizeError,
+            saes::InvalidKeyError::InvalidKeyBase64Error(_) => InvalidKeyError::InvalidKeyBase64Error
+        }
+    }
+}
+
+impl fmt::Display for InvalidKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidKeyError::InvalidKeySizeError => write!(f, "Please provide a 32-byte, base64-encoded, key"),
+            InvalidKeyError::InvalidKeyBase64Error => write!(f, "Please provide a valid base64"),
+        }
+    }
+}
+
+impl error::Error for InvalidKeyError {}
+
+#[derive(Debug, Clone, PartialEq, uniffi::Error)]
+pub enum EncryptionError {
+    GenericEncryptionError
+}
+
+impl From<saes::EncryptionError> for EncryptionError {
+    fn from(e: saes::EncryptionError) -> Self {
+        match e {
+            saes::EncryptionError::GenericEncryptionError => EncryptionError::GenericEncryptionError
+        }
+    }
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncryptionError::GenericEncryptionError => write!(f, "Encryption error"),
+        }
+    }
+}
+
+impl error::Error for EncryptionError {}
+
+/// Mirrors `simple_aes256_gcm::DecryptionError`, plus `InvalidIvSizeError`
+/// for envelopes whose `iv` isn't 12 bytes long (the Rust type system rules
+/// that out on the Rust side).
+#[derive(Debug, Clone, PartialEq, uniffi::Error)]
+pub enum DecryptionError {
+    InvalidIvSizeError,
+    InvalidUTF8DecryptionError,
+    ProbableWrongKey,
+    GenericDecryptionError,
+    RateLimited,
+    ProbableContextMismatch,
+    Replayed,
+    OutOfWindow
+}
+
+impl From<saes::DecryptionError> for DecryptionError {
+    fn from(e: saes::DecryptionError) -> Self {
+        match e {
+            saes::DecryptionError::InvalidUTF8DecryptionError(_) => DecryptionError::InvalidUTF8DecryptionError,
+            saes::DecryptionError::ProbableWrongKey => DecryptionError::ProbableWrongKey,
+            saes::DecryptionError::GenericDecryptionError => DecryptionError::GenericDecryptionError,
+            saes::DecryptionError::RateLimited => DecryptionError::RateLimited,
+            saes::DecryptionError::ProbableContextMismatch => DecryptionError::ProbableContextMismatch,
+            saes::DecryptionError::Replayed => DecryptionError::Replayed,
+            saes::DecryptionError::OutOfWindow => DecryptionError::OutOfWindow
+        }
+    }
+}
+
+impl fmt::Display for DecryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecryptionError::InvalidIvSizeError => write!(f, "Please provide a 12-byte, base64-encoded, iv"),
+            DecryptionError::InvalidUTF8DecryptionError => write!(f, "Decryption error: invalid UTF-8"),
+            DecryptionError::ProbableWrongKey => write!(f, "Decryption error: key check value mismatch, probably the wrong key"),
+            DecryptionError::GenericDecryptionError => write!(f, "Decryption error"),
+            DecryptionError::RateLimited => write!(f, "Decryption error: too many failures, try again later"),
+            DecryptionError::ProbableContextMismatch => write!(f, "Decryption error: authentication failed, probably not the encryption context used to encrypt (or the wrong key)"),
+            DecryptionError::Replayed => write!(f, "Decryption error: message already received"),
+            DecryptionError::OutOfWindow => write!(f, "Decryption error: message too old for the replay window"),
+        }
+    }
+}
+
+impl error::Error for DecryptionError {}
+
+/// Mirrors `token::TokenError`. A token that doesn't parse carries the
+/// parse error's message.
+#[derive(Debug, Clone, PartialEq, uniffi::Error)]
+pub enum TokenError {
+    InvalidTokenError { message: String },
+    TokenDecryptionError { error: DecryptionError }
+}
+
+impl From<saes::token::TokenError> for TokenError {
+    fn from(e: saes::token::TokenError) -> Self {
+        match e {
+            saes::token::TokenError::InvalidTokenError(e) => TokenError::InvalidTokenError { message: e.to_string() },
+            saes::token::TokenError::TokenDecryptionError(e) => TokenError::TokenDecryptionError { error: e.into() }
+        }
+    }
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenError::InvalidTokenError { message } => write!(f, "{}", message),
+            TokenError::TokenDecryptionError { error } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl error::Error for TokenError {}
+
+#[derive(uniffi::Object)]
+pub struct Key {
+    inner: saes::Key
+}
+
+#[uniffi::export]
+impl Key {
+    #[uniffi::constructor]
+    pub fn from_base64(base64_key: String) -> Result<Arc<Self>, InvalidKeyError> {
+        Ok(Arc::new(Self { inner: saes::Key::try_from(base64_key)? }))
+    }
+
+    #[uniffi::constructor]
+    pub fn generate() -> Arc<Self> {
+        Arc::new(Self { inner: saes::Key::generate() })
+    }
+
+    pub fn to_base64(&self) -> String {
+        base64::encode(&self.inner.u8_array)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct EncryptedAndIv {
+    pub encrypted: Vec<u8>,
+    pub iv: Vec<u8>
+}
+
+impl From<saes::EncryptedAndIv> for EncryptedAndIv {
+    fn from(encrypted_and_iv: saes::EncryptedAndIv) -> Self {
+        Self {
+            encrypted: encrypted_and_iv.encrypted.u8_vec,
+            iv: encrypted_and_iv.iv.u8_array.to_vec()
+        }
+    }
+}
+
+impl TryFrom<EncryptedAndIv> for saes::EncryptedAndIv {
+    type Error = DecryptionError;
+    fn try_from(encrypted_and_iv: EncryptedAndIv) -> Result<Self, DecryptionError> {
+        let u8_array: [u8; 12] = match encrypted_and_iv.iv.as_slice().try_into() {
+            Ok(value) => value,
+            Err(_) => return Err(DecryptionError::InvalidIvSizeError)
+        };
+        Ok(saes::EncryptedAndIv::from_parts(saes::Iv { u8_array }, saes::Encrypted { u8_vec: encrypted_and_iv.encrypted }))
+    }
+}
+
+#[uniffi::export]
+pub fn encrypt(key: Arc<Key>, plaintext: String) -> Result<EncryptedAndIv, EncryptionError> {
+    Ok(saes::encrypt(&key.inner, &saes::Decrypted::from(&plaintext[..]))?.into())
+}
+
+#[uniffi::export]
+pub fn decrypt(key: Arc<Key>, encrypted_and_iv: EncryptedAndIv) -> Result<String, DecryptionError> {
+    Ok(saes::decrypt(&key.inner, encrypted_and_iv.try_into()?)?)
+}
+
+#[uniffi::export]
+pub fn encrypt_to_token(key: Arc<Key>, plaintext: Vec<u8>) -> Result<String, EncryptionError> {
+    Ok(saes::token::encrypt_to_token(&key.inner, &plaintext)?)
+}
+
+/// Verifies the footer, if the token has one.
+#[uniffi::export]
+pub fn decrypt_token(key: Arc<Key>, token: String) -> Result<Vec<u8>, TokenError> {
+    Ok(saes::token::decrypt_token(&key.inner, &token)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE64_KEY: &str = "MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=";
+
+    #[test]
+    fn key_from_base64_round_trips() {
+        assert_eq!(Key::from_base64(String::from(BASE64_KEY)).unwrap().to_base64(), BASE64_KEY)
+    }
+
+    #[test]
+    fn key_from_base64_maps_errors() {
+        assert_eq!(Key::from_base64(String::from("012")).err(), Some(InvalidKeyError::InvalidKeyBase64Error));
+        assert_eq!(Key::from_base64(String::from("MDEy")).err(), Some(InvalidKeyError::InvalidKeySizeError))
+    }
+
+    #[test]
+    fn encrypt_decrypt_is_iso() {
+        let key = Key::generate();
+        let encrypted_and_iv = encrypt(key.clone(), String::from("This is a text.")).unwrap();
+        assert_eq!(encrypted_and_iv.iv.len(), 12);
+        assert_eq!(decrypt(key, encrypted_and_iv).unwrap(), "This is a text.")
+    }
+
+    #[test]
+    fn decrypt_values_encrypted_on_the_rust_side() {
+        let key = saes::Key::try_from(BASE64_KEY).unwrap();
+        let encrypted_and_iv = saes::encrypt(&key, &saes::Decrypted::from("This is a text.")).unwrap();
+        assert_eq!(
+            decrypt(Key::from_base64(String::from(BASE64_KEY)).unwrap(), encrypted_and_iv.into()).unwrap(),
+            "This is a text."
+        )
+    }
+
+    #[test]
+    fn decrypt_tampered_ciphertext_fails() {
+        let key = Key::generate();
+        let mut encrypted_and_iv = encrypt(key.clone(), String::from("This is a text.")).unwrap();
+        encrypted_and_iv.encrypted[0] ^= 1;
+        assert_eq!(decrypt(key, encrypted_and_iv).err(), Some(DecryptionError::GenericDecryptionError))
+    }
+
+    #[test]
+    fn tokens_round_trip_with_the_rust_side() {
+        let key = Key::from_base64(String::from(BASE64_KEY)).unwrap();
+        let token = encrypt_to_token(key.clone(), b"This is a text.".to_vec()).unwrap();
+        assert_eq!(saes::token::decrypt_token(&key.inner, &token).unwrap(), b"This is a text.");
+        let footer_token = saes::token::encrypt_to_token_with_footer(&key.inner, b"This is a text.", "tenant=acme").unwrap();
+        assert_eq!(decrypt_token(key, footer_token).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn decrypt_token_maps_errors() {
+        let token = encrypt_to_token(Key::generate(), b"This is a text.".to_vec()).unwrap();
+        assert_eq!(
+            decrypt_token(Key::generate(), token).err(),
+            Some(TokenError::TokenDecryptionError { error: DecryptionError::GenericDecryptionError })
+        );
+        assert_eq!(
+            decrypt_token(Key::generate(), String::from("saes1:")).err(),
+            Some(TokenError::InvalidTokenError { message: String::from("Please provide a non-empty token") })
+        )
+    }
+
+    #[test]
+    fn decrypt_invalid_iv_size_fails() {
+        let key = Key::generate();
+        let mut encrypted_and_iv = encrypt(key.clone(), String::from("This is a text.")).unwrap();
+        encrypted_and_iv.iv.pop();
+        assert_eq!(decrypt(key, encrypted_and_iv).err(), Some(DecryptionError::InvalidIvSizeError))
+    }
+}
@@ -0,0 +1,117 @@
+//! `bytes::Bytes` conversions, for stacks (hyper, tower, tonic) that pass
+//! payloads around as `Bytes`.
+//!
+//! `Encrypted` owns a `Vec<u8>`: `into_bytes` hands that buffer over as is,
+//! and `from_bytes` takes it back without a copy when the `Bytes` is the
+//! only handle on a whole buffer, as `into_bytes` returns it. A slice of a
+//! larger buffer, or a buffer shared with other handles, is copied.
+//! Plaintext needs no conversion: `&Bytes` derefs to `&[u8]`, so
+//! `encrypt_bytes(&key, &payload)` reads it where it is.
+
+use std::{error, fmt};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::{Encrypted, EncryptedAndIv, Iv, IV_LEN, TAG_LEN};
+
+/// Too short to hold a tag, plus an iv for `EncryptedAndIv::from_bytes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidEncryptedSizeError(pub usize);
+
+impl fmt::Display for InvalidEncryptedSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Encrypted value too short: {} bytes", self.0)
+    }
+}
+
+impl error::Error for InvalidEncryptedSizeError {}
+
+impl Encrypted {
+    /// The ciphertext and tag, without copying them.
+    pub fn into_bytes(self) -> Bytes {
+        Bytes::from(self.u8_vec)
+    }
+
+    /// Fails if `bytes` is shorter than a tag. See the module docs for when
+    /// this copies.
+    pub fn from_bytes(bytes: Bytes) -> Result<Encrypted, InvalidEncryptedSizeError> {
+        if bytes.len() < TAG_LEN {
+            return Err(InvalidEncryptedSizeError(bytes.len()));
+        }
+        Ok(Encrypted { u8_vec: Vec::from(bytes) })
+    }
+}
+
+impl From<Encrypted> for Bytes {
+    fn from(encrypted: Encrypted) -> Bytes {
+        encrypted.into_bytes()
+    }
+}
+
+impl EncryptedAndIv {
+    /// `iv || ciphertext and tag`, the binary form of tokens, in a single
+    /// allocation.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut bytes = BytesMut::with_capacity(IV_LEN + self.encrypted.u8_vec.len());
+        bytes.put_slice(&self.iv.u8_array);
+        bytes.put_slice(&self.encrypted.u8_vec);
+        bytes.freeze()
+    }
+
+    /// Reads `to_bytes`. The ciphertext is copied out of `bytes`, which
+    /// starts with the iv.
+    pub fn from_bytes(bytes: &Bytes) -> Result<EncryptedAndIv, InvalidEncryptedSizeError> {
+        if bytes.len() < IV_LEN + TAG_LEN {
+            return Err(InvalidEncryptedSizeError(bytes.len()));
+        }
+        let mut u8_array = [0u8; IV_LEN];
+        u8_array.copy_from_slice(&bytes[..IV_LEN]);
+        Ok(EncryptedAndIv::from_parts(Iv { u8_array }, Encrypted { u8_vec: bytes[IV_LEN..].to_vec() }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_bytes, encrypt_bytes_with_iv, Key};
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+    const IV: [u8; 12] = *b"012345678901";
+
+    #[test]
+    fn encrypted_converts_to_and_from_bytes_without_copying() {
+        let payload = Bytes::from_static(b"This is a text.");
+        let encrypted_and_iv = encrypt_bytes_with_iv(&Key { u8_array: KEY }, Iv { u8_array: IV }, &payload).unwrap();
+        let (iv, encrypted) = encrypted_and_iv.into_parts();
+        let pointer = encrypted.u8_vec.as_ptr();
+        let bytes = encrypted.into_bytes();
+        assert_eq!(bytes.as_ptr(), pointer);
+        let encrypted = Encrypted::from_bytes(bytes).unwrap();
+        assert_eq!(encrypted.u8_vec.as_ptr(), pointer);
+        assert_eq!(decrypt_bytes(&Key { u8_array: KEY }, &EncryptedAndIv::from_parts(iv, encrypted)).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn slices_and_shared_buffers_are_copied() {
+        let encrypted = encrypt_bytes_with_iv(&Key { u8_array: KEY }, Iv { u8_array: IV }, b"This is a text.").unwrap().into_parts().1;
+        let bytes = Bytes::from(encrypted);
+        let shared = bytes.clone();
+        assert_eq!(shared.as_ptr(), bytes.as_ptr());
+        assert_ne!(Encrypted::from_bytes(shared).unwrap().u8_vec.as_ptr(), bytes.as_ptr());
+        let tail = bytes.slice(1..);
+        assert_eq!(tail.as_ptr(), bytes[1..].as_ptr());
+        assert_eq!(Encrypted::from_bytes(tail).unwrap().u8_vec, bytes[1..].to_vec())
+    }
+
+    #[test]
+    fn wire_bytes_round_trip_and_short_values_fail() {
+        let encrypted_and_iv = encrypt_bytes_with_iv(&Key { u8_array: KEY }, Iv { u8_array: IV }, b"This is a text.").unwrap();
+        let bytes = encrypted_and_iv.to_bytes();
+        assert_eq!(bytes.len(), IV_LEN + 15 + TAG_LEN);
+        assert_eq!(bytes[..IV_LEN], IV);
+        let parsed = EncryptedAndIv::from_bytes(&bytes).unwrap();
+        assert_eq!(decrypt_bytes(&Key { u8_array: KEY }, &parsed).unwrap(), b"This is a text.");
+        assert_eq!(EncryptedAndIv::from_bytes(&bytes.slice(..IV_LEN + TAG_LEN - 1)).err(), Some(InvalidEncryptedSizeError(IV_LEN + TAG_LEN - 1)));
+        assert_eq!(Encrypted::from_bytes(Bytes::from_static(&[0u8; TAG_LEN - 1])).err(), Some(InvalidEncryptedSizeError(TAG_LEN - 1)))
+    }
+}
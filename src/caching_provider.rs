@@ -0,0 +1,454 @@
+//! Caching the keys a `KeyProvider` resolves, e.g. to spare a remote
+//! secrets manager a fetch on every request.
+//!
+//! `CachingKeyProvider` wraps a `KeyProvider`, or with the `tokio` feature
+//! an `AsyncKeyProvider`, and is one itself. A key is kept for the TTL
+//! after it was fetched. An unknown or unavailable id is remembered for the
+//! shorter negative TTL, so a burst of lookups reaches the provider once;
+//! backend failures are never cached. Concurrent lookups of an id that
+//! isn't cached wait for a single fetch. Above `max_entries` the least
+//! recently used entry is evicted. Evicted, expired and purged keys are
+//! wiped.
+//!
+//! `purge(key_id)` and `purge_all()` are for incident response: the next
+//! lookup fetches again, and a fetch in flight during the purge isn't kept.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use crate::key_ring::{Clock, SystemClock};
+#[cfg(feature = "tokio")]
+use crate::provider::AsyncKeyProvider;
+use crate::provider::{KeyProvider, ProviderError};
+use crate::Key;
+
+pub const DEFAULT_MAX_ENTRIES: usize = 1024;
+
+pub const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+struct CachedKey(Key);
+
+impl Drop for CachedKey {
+    fn drop(&mut self) {
+        crate::secure_buffer::wipe(&mut self.0.u8_array);
+    }
+}
+
+enum Slot {
+    Found(CachedKey),
+    Missing(ProviderError),
+    /// Being fetched by the lookup holding this ticket.
+    Fetching(u64)
+}
+
+struct Entry {
+    slot: Slot,
+    expires_at: SystemTime,
+    last_used: u64
+}
+
+struct State {
+    entries: HashMap<String, Entry>,
+    /// Orders uses and numbers fetches.
+    tick: u64
+}
+
+enum Lookup {
+    Hit(Result<Key, ProviderError>),
+    Wait,
+    Fetch(u64)
+}
+
+struct Cache {
+    ttl: Duration,
+    negative_ttl: Duration,
+    max_entries: usize,
+    clock: Arc<dyn Clock>,
+    state: Mutex<State>,
+    fetched: Condvar,
+    #[cfg(feature = "tokio")]
+    fetched_async: tokio::sync::Notify
+}
+
+impl Cache {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The cached result for `key_id`, or else whether to wait for the
+    /// fetch in flight or to fetch, under a new ticket.
+    fn lookup(&self, state: &mut State, key_id: &str) -> Lookup {
+        let now = self.clock.now();
+        state.tick += 1;
+        let tick = state.tick;
+        if let Some(entry) = state.entries.get_mut(key_id) {
+            match &entry.slot {
+                Slot::Fetching(_) => return Lookup::Wait,
+                Slot::Found(cached) if now < entry.expires_at => {
+                    entry.last_used = tick;
+                    return Lookup::Hit(Ok(Key { u8_array: cached.0.u8_array }));
+                },
+                Slot::Missing(e) if now < entry.expires_at => {
+                    entry.last_used = tick;
+                    return Lookup::Hit(Err(e.clone()));
+                },
+                _ => {}
+            }
+        }
+        state.entries.insert(String::from(key_id), Entry { slot: Slot::Fetching(tick), expires_at: now, last_used: tick });
+        Lookup::Fetch(tick)
+    }
+}
+
+/// A fetch in flight. Dropping it, even unwinding from a panicking
+/// provider or cancelled with its future, forgets the fetch and wakes the
+/// lookups waiting for it.
+struct Fetch<'a> {
+    cache: &'a Cache,
+    key_id: &'a str,
+    ticket: u64
+}
+
+impl Fetch<'_> {
+    fn finish(self, result: &Result<Key, ProviderError>) {
+        let cache = self.cache;
+        let mut state = cache.lock();
+        match state.entries.get(self.key_id) {
+            Some(Entry { slot: Slot::Fetching(ticket), .. }) if *ticket == self.ticket => {},
+            _ => return
+        }
+        let entry = state.entries.get_mut(self.key_id).unwrap();
+        let now = cache.clock.now();
+        match result {
+            Ok(key) => {
+                entry.slot = Slot::Found(CachedKey(Key { u8_array: key.u8_array }));
+                entry.expires_at = now + cache.ttl;
+            },
+            Err(e @ ProviderError::UnknownKeyIdError(_)) | Err(e @ ProviderError::KeyUnavailableError(_)) => {
+                entry.slot = Slot::Missing(e.clone());
+                entry.expires_at = now + cache.negative_ttl;
+            },
+            Err(ProviderError::ProviderBackendError(_)) => {
+                state.entries.remove(self.key_id);
+            }
+        }
+        while state.entries.len() > cache.max_entries {
+            let oldest = state.entries.iter()
+                .filter(|(_, entry)| !matches!(entry.slot, Slot::Fetching(_)))
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key_id, _)| key_id.clone());
+            match oldest {
+                Some(key_id) => state.entries.remove(&key_id),
+                None => break
+            };
+        }
+    }
+}
+
+impl Drop for Fetch<'_> {
+    fn drop(&mut self) {
+        {
+            let mut state = self.cache.lock();
+            if let Some(Entry { slot: Slot::Fetching(ticket), .. }) = state.entries.get(self.key_id) {
+                if *ticket == self.ticket {
+                    state.entries.remove(self.key_id);
+                }
+            }
+        }
+        self.cache.fetched.notify_all();
+        #[cfg(feature = "tokio")]
+        self.cache.fetched_async.notify_waiters();
+    }
+}
+
+pub struct CachingKeyProvider<P> {
+    provider: P,
+    cache: Cache
+}
+
+impl<P> CachingKeyProvider<P> {
+    /// Caches the keys `provider` resolves for `ttl`, with the
+    /// `DEFAULT_NEGATIVE_TTL` and `DEFAULT_MAX_ENTRIES`.
+    pub fn new(provider: P, ttl: Duration) -> CachingKeyProvider<P> {
+        CachingKeyProvider {
+            provider,
+            cache: Cache {
+                ttl,
+                negative_ttl: DEFAULT_NEGATIVE_TTL,
+                max_entries: DEFAULT_MAX_ENTRIES,
+                clock: Arc::new(SystemClock),
+                state: Mutex::new(State { entries: HashMap::new(), tick: 0 }),
+                fetched: Condvar::new(),
+                #[cfg(feature = "tokio")]
+                fetched_async: tokio::sync::Notify::new()
+            }
+        }
+    }
+
+    /// How long an unknown or unavailable id is remembered.
+    pub fn with_negative_ttl(mut self, negative_ttl: Duration) -> CachingKeyProvider<P> {
+        self.cache.negative_ttl = negative_ttl;
+        self
+    }
+
+    /// How many ids are cached, keys and negative results together.
+    pub fn with_max_entries(mut self, max_entries: usize) -> CachingKeyProvider<P> {
+        self.cache.max_entries = max_entries;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> CachingKeyProvider<P> {
+        self.cache.clock = clock;
+        self
+    }
+
+    /// Wipes and forgets the key or negative result of `key_id`.
+    pub fn purge(&self, key_id: &str) {
+        self.cache.lock().entries.remove(key_id);
+    }
+
+    /// Wipes and forgets every cached key and negative result.
+    pub fn purge_all(&self) {
+        self.cache.lock().entries.clear();
+    }
+
+    /// Cached keys and negative results, expired ones included until
+    /// looked up again or evicted.
+    pub fn len(&self) -> usize {
+        self.cache.lock().entries.values().filter(|entry| !matches!(entry.slot, Slot::Fetching(_))).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<P: KeyProvider> KeyProvider for CachingKeyProvider<P> {
+    fn key_for(&self, key_id: &str) -> Result<Key, ProviderError> {
+        let mut state = self.cache.lock();
+        let ticket = loop {
+            match self.cache.lookup(&mut state, key_id) {
+                Lookup::Hit(result) => return result,
+                Lookup::Wait => state = self.cache.fetched.wait(state).unwrap_or_else(|e| e.into_inner()),
+                Lookup::Fetch(ticket) => break ticket
+            }
+        };
+        drop(state);
+        let fetch = Fetch { cache: &self.cache, key_id, ticket };
+        let result = self.provider.key_for(key_id);
+        fetch.finish(&result);
+        result
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<P: AsyncKeyProvider + Sync> AsyncKeyProvider for CachingKeyProvider<P> {
+    async fn key_for(&self, key_id: &str) -> Result<Key, ProviderError> {
+        let ticket = loop {
+            let mut fetched = std::pin::pin!(self.cache.fetched_async.notified());
+            {
+                let mut state = self.cache.lock();
+                match self.cache.lookup(&mut state, key_id) {
+                    Lookup::Hit(result) => return result,
+                    Lookup::Fetch(ticket) => break ticket,
+                    // Registered before unlocking, so the wake-up can't be missed.
+                    Lookup::Wait => fetched.as_mut().enable()
+                };
+            }
+            fetched.await;
+        };
+        let fetch = Fetch { cache: &self.cache, key_id, ticket };
+        let result = self.provider.key_for(key_id).await;
+        fetch.finish(&result);
+        result
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "tokio")]
+    use std::future::Future;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    #[cfg(not(target_os = "wasi"))]
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+
+    struct ManualClock(Mutex<SystemTime>);
+
+    impl ManualClock {
+        fn advance(&self, duration: Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[derive(Default)]
+    struct CountingProvider {
+        fetches: AtomicUsize,
+        delay: Duration
+    }
+
+    fn resolve(key_id: &str) -> Result<Key, ProviderError> {
+        match key_id {
+            "tenant-1" => Ok(Key { u8_array: [1u8; 32] }),
+            "tenant-2" => Ok(Key { u8_array: [2u8; 32] }),
+            "tenant-3" => Ok(Key { u8_array: [3u8; 32] }),
+            "offline" => Err(ProviderError::ProviderBackendError(String::from("connection refused"))),
+            "panic" => panic!("provider panicked"),
+            _ => Err(ProviderError::UnknownKeyIdError(String::from(key_id)))
+        }
+    }
+
+    impl KeyProvider for CountingProvider {
+        fn key_for(&self, key_id: &str) -> Result<Key, ProviderError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(self.delay);
+            resolve(key_id)
+        }
+    }
+
+    fn cache() -> (CachingKeyProvider<CountingProvider>, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock(Mutex::new(UNIX_EPOCH)));
+        let cache = CachingKeyProvider::new(CountingProvider::default(), Duration::from_secs(60)).with_clock(clock.clone());
+        (cache, clock)
+    }
+
+    fn fetches(cache: &CachingKeyProvider<CountingProvider>) -> usize {
+        cache.provider.fetches.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn hits_until_the_ttl_expires() {
+        let (cache, clock) = cache();
+        assert_eq!(cache.key_for("tenant-1").unwrap().u8_array, [1u8; 32]);
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(cache.key_for("tenant-1").unwrap().u8_array, [1u8; 32]);
+        assert_eq!(fetches(&cache), 1);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.key_for("tenant-1").unwrap().u8_array, [1u8; 32]);
+        assert_eq!(fetches(&cache), 2)
+    }
+
+    #[test]
+    fn unknown_ids_are_cached_for_the_negative_ttl_only() {
+        let (cache, clock) = cache();
+        for _ in 0..3 {
+            match cache.key_for("tenant-9") {
+                Ok(_) => assert!(false, "Should err UnknownKeyIdError"),
+                Err(e) => match e {
+                    ProviderError::UnknownKeyIdError(key_id) => assert_eq!(key_id, "tenant-9"),
+                    _ => assert!(false, "Should err UnknownKeyIdError, got {}", e)
+                }
+            }
+        }
+        assert_eq!(fetches(&cache), 1);
+        clock.advance(DEFAULT_NEGATIVE_TTL);
+        assert!(cache.key_for("tenant-9").is_err());
+        assert_eq!(fetches(&cache), 2);
+
+        for _ in 0..2 {
+            assert!(cache.key_for("offline").is_err());
+        }
+        assert_eq!(fetches(&cache), 4)
+    }
+
+    #[test]
+    fn purge_forces_a_refetch_and_size_is_bounded() {
+        let (cache, _) = cache();
+        let cache = cache.with_max_entries(2);
+        for key_id in ["tenant-1", "tenant-2", "tenant-1", "tenant-3"].iter() {
+            cache.key_for(key_id).unwrap();
+        }
+        assert_eq!((cache.len(), fetches(&cache)), (2, 3));
+        cache.key_for("tenant-1").unwrap();
+        assert_eq!(fetches(&cache), 3);
+
+        cache.purge("tenant-1");
+        cache.key_for("tenant-1").unwrap();
+        cache.key_for("tenant-3").unwrap();
+        assert_eq!(fetches(&cache), 4);
+        cache.purge_all();
+        assert!(cache.is_empty());
+        cache.key_for("tenant-3").unwrap();
+        assert_eq!(fetches(&cache), 5)
+    }
+
+    #[test]
+    #[cfg(not(target_os = "wasi"))]
+    fn concurrent_misses_fetch_once() {
+        let cache = Arc::new(CachingKeyProvider::new(CountingProvider { fetches: AtomicUsize::new(0), delay: Duration::from_millis(50) }, Duration::from_secs(60)));
+        let barrier = Arc::new(Barrier::new(8));
+        let threads: Vec<_> = (0..8).map(|_| {
+            let (cache, barrier) = (Arc::clone(&cache), Arc::clone(&barrier));
+            thread::spawn(move || {
+                barrier.wait();
+                cache.key_for("tenant-1").unwrap().u8_array
+            })
+        }).collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), [1u8; 32]);
+        }
+        assert_eq!(fetches(&cache), 1)
+    }
+
+    #[test]
+    #[cfg(panic = "unwind")]
+    fn a_panicking_provider_leaves_no_fetch_in_flight() {
+        let (cache, _) = cache();
+        for _ in 0..2 {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cache.key_for("panic")));
+            assert!(result.is_err());
+        }
+        assert_eq!((fetches(&cache), cache.len()), (2, 0))
+    }
+
+    #[cfg(feature = "tokio")]
+    #[derive(Default)]
+    struct RemoteProvider {
+        fetches: AtomicUsize
+    }
+
+    #[cfg(feature = "tokio")]
+    impl AsyncKeyProvider for RemoteProvider {
+        fn key_for(&self, key_id: &str) -> impl Future<Output = Result<Key, ProviderError>> + Send {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            let key_id = String::from(key_id);
+            async move {
+                for _ in 0..3 {
+                    tokio::task::yield_now().await;
+                }
+                resolve(&key_id)
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio")]
+    async fn async_lookups_share_the_cache_and_the_fetch() {
+        use crate::provider::decrypt_with_async_provider;
+        use crate::{encrypt_bytes_with_iv, Iv};
+
+        let clock = Arc::new(ManualClock(Mutex::new(UNIX_EPOCH)));
+        let cache = CachingKeyProvider::new(RemoteProvider::default(), Duration::from_secs(60)).with_clock(clock.clone());
+        let fetches = |cache: &CachingKeyProvider<RemoteProvider>| cache.provider.fetches.load(Ordering::SeqCst);
+        let lookup = || cache.key_for("tenant-1");
+        let (first, second, third) = tokio::join!(lookup(), lookup(), lookup());
+        assert_eq!([first.unwrap().u8_array, second.unwrap().u8_array, third.unwrap().u8_array], [[1u8; 32]; 3]);
+        assert_eq!(fetches(&cache), 1);
+
+        let sample = encrypt_bytes_with_iv(&Key { u8_array: [1u8; 32] }, Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap();
+        assert_eq!(decrypt_with_async_provider(&cache, "tenant-1", &sample).await.unwrap(), b"This is a text.");
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(decrypt_with_async_provider(&cache, "tenant-1", &sample).await.unwrap(), b"This is a text.");
+        assert_eq!(fetches(&cache), 2)
+    }
+}
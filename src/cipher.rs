@@ -0,0 +1,402 @@
+//! One place to configure encryption options.
+//!
+//! ```
+//! use simple_aes256_gcm::{cipher::CipherBuilder, encoding::Encoding, Key};
+//!
+//! let cipher = CipherBuilder::new(&Key::generate())
+//!     .aad(b"invoices")
+//!     .encoding(Encoding::Base64UrlNoPad)
+//!     .build()
+//!     .unwrap();
+//! let token = cipher.encrypt_to_token(b"This is a text.").unwrap();
+//! assert_eq!(cipher.decrypt_token(&token).unwrap(), b"This is a text.");
+//! ```
+//!
+//! A `Cipher` built with no options behaves exactly like the free functions
+//! `encrypt_bytes`, `decrypt_bytes`, `token::encrypt_to_token`,
+//! `token::encrypt_to_token_with_footer` and `token::decrypt_token`.
+//!
+//! A token footer is authenticated as in `token`. With `aad` configured as
+//! well, a footer token authenticates the 8-byte big-endian length of
+//! `aad`, `aad`, then the footer's associated data.
+//!
+//! Options can be combined freely; `build` only rejects a padding block
+//! outside `1..=MAX_PADDING_BLOCK`. The padded copy of a plaintext is
+//! wiped once sealed or unpadded.
+
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::{error, fmt};
+
+use crate::encoding::Encoding;
+use crate::failure_policy::{self, FailurePolicy};
+use crate::secure_buffer::wipe;
+use crate::token::{append_footer, footer_aad, parse_token_with, TokenError};
+use crate::{decrypt_bytes_with_aad, encrypt_bytes_with_iv_and_aad, DecryptionError, EncryptedAndIv, EncryptionError, Iv, Key};
+
+/// Largest block accepted by `CipherBuilder::pad_to_multiple_of`.
+pub const MAX_PADDING_BLOCK: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CipherBuilderError {
+    /// Padding blocks must be between 1 and `MAX_PADDING_BLOCK` bytes.
+    InvalidPaddingBlockError(usize)
+}
+
+impl fmt::Display for CipherBuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CipherBuilderError::InvalidPaddingBlockError(block) => write!(f, "Padding block must be between 1 and {} bytes, got {}", MAX_PADDING_BLOCK, block),
+        }
+    }
+}
+
+impl error::Error for CipherBuilderError {}
+
+pub struct CipherBuilder {
+    key: Key,
+    aad: Vec<u8>,
+    encoding: Encoding,
+    padding_block: Option<usize>,
+    failure_policy: Option<Arc<FailurePolicy>>
+}
+
+impl CipherBuilder {
+    pub fn new(key: &Key) -> CipherBuilder {
+        CipherBuilder {
+            key: Key { u8_array: key.u8_array },
+            aad: Vec::new(),
+            encoding: Encoding::default(),
+            padding_block: None,
+            failure_policy: None
+        }
+    }
+
+    /// Associated data authenticated with every message; decryption needs the same bytes.
+    pub fn aad(mut self, aad: &[u8]) -> CipherBuilder {
+        self.aad = aad.to_vec();
+        self
+    }
+
+    /// Encoding of the token body, `Encoding::Base64` by default.
+    pub fn encoding(mut self, encoding: Encoding) -> CipherBuilder {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Hides the exact plaintext length: a 4-byte big-endian length is
+    /// prepended and zeros appended up to a multiple of `block` bytes.
+    /// Padded messages only decrypt through a `Cipher` with the same setting.
+    pub fn pad_to_multiple_of(mut self, block: usize) -> CipherBuilder {
+        self.padding_block = Some(block);
+        self
+    }
+
+    /// Throttles failing decryptions, per the context id given to
+    /// `decrypt_for` and `decrypt_token_for`. `decrypt` and `decrypt_token`
+    /// count under the empty context id. The policy can be shared between
+    /// ciphers.
+    pub fn failure_policy(mut self, failure_policy: Arc<FailurePolicy>) -> CipherBuilder {
+        self.failure_policy = Some(failure_policy);
+        self
+    }
+
+    /// Only the padding block can make a configuration invalid. Every other
+    /// combination works by construction: each `Encoding` is text, so any
+    /// `Cipher` can write and read tokens, and there are no raw-output,
+    /// tag-length or cipher-suite options to conflict with it.
+    pub fn build(self) -> Result<Cipher, CipherBuilderError> {
+        if let Some(block) = self.padding_block {
+            if block == 0 || block > MAX_PADDING_BLOCK {
+                return Err(CipherBuilderError::InvalidPaddingBlockError(block));
+            }
+        }
+        Ok(Cipher {
+            key: self.key,
+            aad: self.aad,
+            encoding: self.encoding,
+            padding_block: self.padding_block,
+            failure_policy: self.failure_policy
+        })
+    }
+}
+
+pub struct Cipher {
+    key: Key,
+    aad: Vec<u8>,
+    encoding: Encoding,
+    padding_block: Option<usize>,
+    failure_policy: Option<Arc<FailurePolicy>>
+}
+
+impl Cipher {
+    /// The length prefix, `plaintext` and zeros, in one allocation so that
+    /// wiping it leaves no copy behind.
+    fn pad(plaintext: &[u8], block: usize) -> Result<Vec<u8>, EncryptionError> {
+        let len: u32 = match plaintext.len().try_into() {
+            Ok(value) => value,
+            Err(_) => return Err(EncryptionError::GenericEncryptionError)
+        };
+        let padded_len = (4 + plaintext.len()).div_ceil(block) * block;
+        let mut padded = Vec::with_capacity(padded_len);
+        padded.extend_from_slice(&len.to_be_bytes());
+        padded.extend_from_slice(plaintext);
+        padded.resize(padded_len, 0);
+        Ok(padded)
+    }
+
+    /// Takes the plaintext out of `padded`, then wipes it, also on failure.
+    fn unpad(&self, mut padded: Vec<u8>) -> Result<Vec<u8>, DecryptionError> {
+        if self.padding_block.is_none() {
+            return Ok(padded);
+        }
+        let len = match padded.get(..4) {
+            Some(prefix) => u32::from_be_bytes(prefix.try_into().unwrap()) as usize,
+            None => usize::MAX
+        };
+        let result = match padded.get(4..).and_then(|rest| rest.get(..len)) {
+            Some(plaintext) => Ok(plaintext.to_vec()),
+            None => Err(DecryptionError::GenericDecryptionError)
+        };
+        wipe(&mut padded);
+        result
+    }
+
+    fn encrypt_with_iv(&self, iv: Iv, plaintext: &[u8]) -> Result<EncryptedAndIv, EncryptionError> {
+        self.encrypt_with_iv_and_aad(iv, plaintext, &self.aad)
+    }
+
+    fn encrypt_with_iv_and_aad(&self, iv: Iv, plaintext: &[u8], aad: &[u8]) -> Result<EncryptedAndIv, EncryptionError> {
+        let block = match self.padding_block {
+            Some(value) => value,
+            None => return encrypt_bytes_with_iv_and_aad(&self.key, iv, plaintext, aad)
+        };
+        let mut padded = Cipher::pad(plaintext, block)?;
+        let result = encrypt_bytes_with_iv_and_aad(&self.key, iv, &padded, aad);
+        wipe(&mut padded);
+        result
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<EncryptedAndIv, EncryptionError> {
+        self.encrypt_with_iv(Iv::generate(), plaintext)
+    }
+
+    /// Not throttled: the failure policy only applies to `decrypt_for`
+    /// and `decrypt_token_for`.
+    pub fn decrypt(&self, encrypted_and_iv: &EncryptedAndIv) -> Result<Vec<u8>, DecryptionError> {
+        self.decrypt_with_aad_for(None, encrypted_and_iv, &self.aad)
+    }
+
+    /// Fails with `DecryptionError::RateLimited` while `context_id` is over
+    /// the failure policy's threshold.
+    pub fn decrypt_for(&self, context_id: &str, encrypted_and_iv: &EncryptedAndIv) -> Result<Vec<u8>, DecryptionError> {
+        self.decrypt_with_aad_for(Some(context_id), encrypted_and_iv, &self.aad)
+    }
+
+    fn decrypt_with_aad_for(&self, context_id: Option<&str>, encrypted_and_iv: &EncryptedAndIv, aad: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        let decrypt = || self.unpad(decrypt_bytes_with_aad(&self.key, encrypted_and_iv, aad)?);
+        match (&self.failure_policy, context_id) {
+            (Some(failure_policy), Some(context_id)) => failure_policy.guard(context_id, &self.key, decrypt),
+            _ => decrypt()
+        }
+    }
+
+    pub fn encrypt_to_token(&self, plaintext: &[u8]) -> Result<String, EncryptionError> {
+        Ok(self.encrypt(plaintext)?.to_token_with(self.encoding))
+    }
+
+    /// The associated data of a token with `footer`, see the module docs.
+    fn token_aad(&self, footer: Option<&str>) -> Vec<u8> {
+        let footer_aad = match footer {
+            Some(value) => footer_aad(value),
+            None => return self.aad.clone()
+        };
+        if self.aad.is_empty() {
+            return footer_aad;
+        }
+        let mut aad = (self.aad.len() as u64).to_be_bytes().to_vec();
+        aad.extend_from_slice(&self.aad);
+        aad.extend_from_slice(&footer_aad);
+        aad
+    }
+
+    /// Like `token::encrypt_to_token_with_footer`.
+    pub fn encrypt_to_token_with_footer(&self, plaintext: &[u8], footer: &str) -> Result<String, EncryptionError> {
+        let encrypted_and_iv = self.encrypt_with_iv_and_aad(Iv::generate(), plaintext, &self.token_aad(Some(footer)))?;
+        Ok(append_footer(encrypted_and_iv.to_token_with(self.encoding), footer))
+    }
+
+    /// Not throttled, like `decrypt`.
+    pub fn decrypt_token(&self, token: &str) -> Result<Vec<u8>, TokenError> {
+        self.open_token(None, token)
+    }
+
+    /// Malformed tokens are not counted as failures, but aren't parsed while
+    /// `context_id` is limited.
+    pub fn decrypt_token_for(&self, context_id: &str, token: &str) -> Result<Vec<u8>, TokenError> {
+        if self.failure_policy.as_ref().is_some_and(|failure_policy| failure_policy.is_limited(context_id)) {
+            return Err(TokenError::TokenDecryptionError(failure_policy::rate_limited(&self.key)));
+        }
+        self.open_token(Some(context_id), token)
+    }
+
+    fn open_token(&self, context_id: Option<&str>, token: &str) -> Result<Vec<u8>, TokenError> {
+        let (encrypted_and_iv, footer) = parse_token_with(token, self.encoding).map_err(TokenError::InvalidTokenError)?;
+        self.decrypt_with_aad_for(context_id, &encrypted_and_iv, &self.token_aad(footer.as_deref())).map_err(TokenError::TokenDecryptionError)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::token::{decrypt_token, encrypt_to_token, encrypt_to_token_with_footer};
+    use crate::{decrypt_bytes, encrypt_bytes, encrypt_bytes_with_iv};
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+    const IV: [u8; 12] = *b"012345678901";
+
+    #[test]
+    fn defaults_match_the_free_functions() {
+        let key = Key { u8_array: KEY };
+        let cipher = CipherBuilder::new(&key).build().unwrap();
+        assert_eq!(
+            cipher.encrypt_with_iv(Iv { u8_array: IV }, b"This is a text.").unwrap().to_token(),
+            encrypt_bytes_with_iv(&key, Iv { u8_array: IV }, b"This is a text.").unwrap().to_token()
+        );
+        assert_eq!(cipher.decrypt(&encrypt_bytes(&key, b"This is a text.").unwrap()).unwrap(), b"This is a text.");
+        assert_eq!(decrypt_bytes(&key, &cipher.encrypt(b"This is a text.").unwrap()).unwrap(), b"This is a text.");
+        assert_eq!(cipher.decrypt_token(&encrypt_to_token(&key, b"This is a text.").unwrap()).unwrap(), b"This is a text.");
+        assert_eq!(decrypt_token(&key, &cipher.encrypt_to_token(b"This is a text.").unwrap()).unwrap(), b"This is a text.");
+        let footer_token = encrypt_to_token_with_footer(&key, b"This is a text.", "tenant=acme").unwrap();
+        assert_eq!(cipher.decrypt_token(&footer_token).unwrap(), b"This is a text.");
+        assert_eq!(decrypt_token(&key, &cipher.encrypt_to_token_with_footer(b"This is a text.", "tenant=acme").unwrap()).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn footers_are_bound_together_with_the_configured_aad() {
+        let key = Key { u8_array: KEY };
+        let cipher = CipherBuilder::new(&key).aad(b"invoices").build().unwrap();
+        let token = cipher.encrypt_to_token_with_footer(b"This is a text.", "tenant=acme").unwrap();
+        assert_eq!(cipher.decrypt_token(&token).unwrap(), b"This is a text.");
+        let body = &token[..token.find(crate::token::FOOTER_SEPARATOR).unwrap()];
+        let forged = format!("{}.{}", body, base64::encode_config("tenant=evil", base64::URL_SAFE_NO_PAD));
+        for (cipher, token) in [(&cipher, &forged), (&CipherBuilder::new(&key).aad(b"payroll").build().unwrap(), &token), (&CipherBuilder::new(&key).build().unwrap(), &token)] {
+            match cipher.decrypt_token(token) {
+                Ok(_) => assert!(false, "Should err TokenDecryptionError"),
+                Err(e) => match e {
+                    TokenError::TokenDecryptionError(_) => assert!(true),
+                    _ => assert!(false, "Should err TokenDecryptionError, got {}", e)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn aad_and_url_safe_encoding() {
+        let key = Key { u8_array: KEY };
+        let cipher = CipherBuilder::new(&key).aad(b"invoices").encoding(Encoding::Base64UrlNoPad).build().unwrap();
+        let token = cipher.encrypt_to_token(&[0xffu8; 40]).unwrap();
+        assert!(!token.contains('+') && !token.contains('/') && !token.ends_with('='));
+        assert_eq!(cipher.decrypt_token(&token).unwrap(), vec![0xffu8; 40]);
+
+        let other_context = CipherBuilder::new(&key).aad(b"payroll").encoding(Encoding::Base64UrlNoPad).build().unwrap();
+        match other_context.decrypt_token(&token) {
+            Ok(_) => assert!(false, "Should err TokenDecryptionError"),
+            Err(e) => match e {
+                TokenError::TokenDecryptionError(_) => assert!(true),
+                _ => assert!(false, "Should err TokenDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    fn padding_hides_the_length_and_round_trips() {
+        let cipher = CipherBuilder::new(&Key { u8_array: KEY }).pad_to_multiple_of(32).build().unwrap();
+        let short = cipher.encrypt(b"yes").unwrap();
+        let long = cipher.encrypt(b"a somewhat longer answer").unwrap();
+        assert_eq!(short.encrypted.u8_vec.len(), long.encrypted.u8_vec.len());
+        assert_eq!(cipher.decrypt(&short).unwrap(), b"yes");
+        assert_eq!(cipher.decrypt(&long).unwrap(), b"a somewhat longer answer")
+    }
+
+    #[test]
+    fn aad_padding_and_encoding_combined() {
+        let cipher = CipherBuilder::new(&Key { u8_array: KEY }).aad(b"ctx").pad_to_multiple_of(16).encoding(Encoding::Base64UrlNoPad).build().unwrap();
+        let token = cipher.encrypt_to_token(b"This is a text.").unwrap();
+        assert_eq!(cipher.decrypt_token(&token).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn every_valid_combination_builds_and_round_trips() {
+        use std::time::Duration;
+
+        let key = Key { u8_array: KEY };
+        let policy = Arc::new(FailurePolicy::new(2, Duration::from_secs(60)));
+        for encoding in crate::encoding::ENCODINGS.iter() {
+            for aad in [&b""[..], b"ctx"] {
+                for padding_block in [None, Some(1), Some(MAX_PADDING_BLOCK)] {
+                    for failure_policy in [None, Some(policy.clone())] {
+                        let mut builder = CipherBuilder::new(&key).aad(aad).encoding(*encoding);
+                        if let Some(block) = padding_block {
+                            builder = builder.pad_to_multiple_of(block);
+                        }
+                        if let Some(failure_policy) = failure_policy {
+                            builder = builder.failure_policy(failure_policy);
+                        }
+                        let cipher = builder.build().unwrap();
+                        let token = cipher.encrypt_to_token(b"This is a text.").unwrap();
+                        assert_eq!(cipher.decrypt_token(&token).unwrap(), b"This is a text.", "{:?} {:?} {:?}", encoding, aad, padding_block);
+                        let token = cipher.encrypt_to_token_with_footer(b"This is a text.", "kid-1").unwrap();
+                        assert_eq!(cipher.decrypt_token(&token).unwrap(), b"This is a text.");
+                        assert_eq!(cipher.decrypt(&cipher.encrypt(b"").unwrap()).unwrap(), b"")
+                    }
+                }
+            }
+        }
+        for block in [0, MAX_PADDING_BLOCK + 1] {
+            match CipherBuilder::new(&key).pad_to_multiple_of(block).build() {
+                Ok(_) => assert!(false, "Should err InvalidPaddingBlockError"),
+                Err(e) => assert_eq!(e, CipherBuilderError::InvalidPaddingBlockError(block))
+            }
+        }
+    }
+
+    #[test]
+    fn failure_policy_limits_per_context() {
+        use std::time::Duration;
+
+        let key = Key { u8_array: KEY };
+        let policy = Arc::new(FailurePolicy::new(2, Duration::from_secs(60)));
+        let cipher = CipherBuilder::new(&key).failure_policy(policy.clone()).build().unwrap();
+        let token = cipher.encrypt_to_token(b"This is a text.").unwrap();
+        let wrong_key = CipherBuilder::new(&Key { u8_array: [1u8; 32] }).failure_policy(policy).build().unwrap();
+        for _ in 0..2 {
+            assert!(wrong_key.decrypt_token_for("client", &token).is_err());
+        }
+        match cipher.decrypt_token_for("client", &token) {
+            Ok(_) => assert!(false, "Should err RateLimited"),
+            Err(e) => match e {
+                TokenError::TokenDecryptionError(DecryptionError::RateLimited) => assert!(true),
+                _ => assert!(false, "Should err RateLimited")
+            }
+        }
+        assert_eq!(cipher.decrypt_token_for("other", &token).unwrap(), b"This is a text.");
+        assert_eq!(cipher.decrypt_token(&token).unwrap(), b"This is a text.");
+
+        // Context-less failures don't land in a shared "" bucket.
+        for _ in 0..2 {
+            assert!(wrong_key.decrypt_token(&token).is_err());
+            assert!(wrong_key.decrypt(&cipher.encrypt(b"x").unwrap()).is_err());
+        }
+        assert_eq!(cipher.decrypt_token_for("", &token).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn invalid_padding_block_fails_at_build() {
+        match CipherBuilder::new(&Key { u8_array: KEY }).pad_to_multiple_of(0).build() {
+            Ok(_) => assert!(false, "Should err InvalidPaddingBlockError"),
+            Err(e) => assert_eq!(e, CipherBuilderError::InvalidPaddingBlockError(0))
+        }
+    }
+}
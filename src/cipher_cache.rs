@@ -0,0 +1,229 @@
+//! Reusing prepared ciphers across calls with many different keys.
+//!
+//! Every free function builds the AES key schedule anew. `CipherCache`
+//! keeps the prepared `Aes256Gcm` of the most recently used keys, up to a
+//! fixed capacity, evicting the least recently used one. Entries are
+//! looked up by a full SHA-256 digest of the key, never by the 8-byte
+//! `Key::fingerprint`, so two keys can't share an entry. The cache is
+//! behind a mutex and can be shared between threads; the lock is only
+//! held for lookups, not while encrypting.
+//!
+//! Evicted and purged ciphers are dropped as soon as no call still uses
+//! them. `purge` is meant for key revocation: the next use of the key, if
+//! any, prepares a new cipher.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use aes_gcm::Aes256Gcm;
+use sha2::{Digest, Sha256};
+
+use crate::backend::{self, aes256_gcm};
+#[cfg(feature = "rng")]
+use crate::Iv;
+use crate::{instrument, DecryptionError, EncryptedAndIv, Key};
+#[cfg(feature = "rng")]
+use crate::EncryptionError;
+
+struct Entry {
+    cipher: Arc<Aes256Gcm>,
+    check_value: [u8; 3],
+    last_used: u64
+}
+
+struct State {
+    entries: HashMap<[u8; 32], Entry>,
+    clock: u64,
+    stats: CipherCacheStats
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CipherCacheStats {
+    pub hits: u64,
+    /// Lookups that prepared a new cipher.
+    pub misses: u64,
+    pub evictions: u64
+}
+
+pub struct CipherCache {
+    capacity: usize,
+    state: Mutex<State>
+}
+
+fn cache_key(key: &Key) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(b"simple-aes256-gcm cipher cache")
+        .chain_update(key.u8_array)
+        .finalize()
+        .into()
+}
+
+impl CipherCache {
+    /// A cache holding at most `capacity` ciphers (at least one).
+    pub fn new(capacity: usize) -> CipherCache {
+        CipherCache {
+            capacity: capacity.max(1),
+            state: Mutex::new(State { entries: HashMap::new(), clock: 0, stats: CipherCacheStats::default() })
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // Entries are inserted whole, a panic elsewhere can't leave one half-built.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn get(&self, key: &Key) -> (Arc<Aes256Gcm>, [u8; 3]) {
+        let cache_key = cache_key(key);
+        let mut state = self.lock();
+        state.clock += 1;
+        let clock = state.clock;
+        if let Some(entry) = state.entries.get_mut(&cache_key) {
+            entry.last_used = clock;
+            let found = (Arc::clone(&entry.cipher), entry.check_value);
+            state.stats.hits += 1;
+            return found;
+        }
+        state.stats.misses += 1;
+        if state.entries.len() >= self.capacity {
+            let oldest = state.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(cache_key, _)| *cache_key);
+            if let Some(oldest) = oldest {
+                state.entries.remove(&oldest);
+                state.stats.evictions += 1;
+            }
+        }
+        let cipher = Arc::new(aes256_gcm(key));
+        let check_value = key.check_value();
+        state.entries.insert(cache_key, Entry { cipher: Arc::clone(&cipher), check_value, last_used: clock });
+        (cipher, check_value)
+    }
+
+    /// Drops the cipher for `key`, returning whether there was one.
+    pub fn purge(&self, key: &Key) -> bool {
+        self.lock().entries.remove(&cache_key(key)).is_some()
+    }
+
+    pub fn clear(&self) {
+        self.lock().entries.clear()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stats(&self) -> CipherCacheStats {
+        self.lock().stats
+    }
+}
+
+/// Same as `encrypt_bytes`, with the cipher from `cache`.
+#[cfg(feature = "rng")]
+pub fn encrypt_cached(cache: &CipherCache, key: &Key, plaintext: &[u8]) -> Result<EncryptedAndIv, EncryptionError> {
+    let (cipher, _) = cache.get(key);
+    instrument::encrypt(key, plaintext.len(), || backend::encrypt_bytes_with_iv(&*cipher, Iv::generate(), plaintext, &[]))
+}
+
+/// Same as `decrypt_bytes`, with the cipher from `cache`.
+pub fn decrypt_cached(cache: &CipherCache, key: &Key, encrypted_and_iv: &EncryptedAndIv) -> Result<Vec<u8>, DecryptionError> {
+    let (cipher, check_value) = cache.get(key);
+    instrument::decrypt(key, encrypted_and_iv.encrypted.u8_vec.len(), || {
+        if encrypted_and_iv.kcv.is_some() && encrypted_and_iv.kcv != Some(check_value) {
+            return Err(DecryptionError::ProbableWrongKey);
+        }
+        backend::decrypt_bytes(&*cipher, encrypted_and_iv, &[])
+    })
+}
+
+#[cfg(test)]
+#[cfg(feature = "rng")]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_bytes, encrypt_bytes_with_iv, encrypt_with_kcv, Decrypted};
+
+    fn key(byte: u8) -> Key {
+        Key { u8_array: [byte; 32] }
+    }
+
+    #[test]
+    fn cached_matches_uncached() {
+        let cache = CipherCache::new(4);
+        let uncached = encrypt_bytes_with_iv(&key(1), Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap();
+        for _ in 0..3 {
+            assert_eq!(decrypt_cached(&cache, &key(1), &uncached).unwrap(), b"This is a text.");
+            let cached = encrypt_cached(&cache, &key(1), b"This is a text.").unwrap();
+            assert_eq!(decrypt_bytes(&key(1), &cached).unwrap(), b"This is a text.");
+        }
+        let (cipher, _) = cache.get(&key(1));
+        let with_cached_cipher = backend::encrypt_bytes_with_iv(&*cipher, Iv { u8_array: *b"012345678901" }, b"This is a text.", &[]).unwrap();
+        assert_eq!(with_cached_cipher.encrypted.u8_vec, uncached.encrypted.u8_vec);
+        assert_eq!(cache.stats(), CipherCacheStats { hits: 6, misses: 1, evictions: 0 })
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let cache = CipherCache::new(2);
+        encrypt_cached(&cache, &key(1), b"a").unwrap();
+        encrypt_cached(&cache, &key(2), b"b").unwrap();
+        encrypt_cached(&cache, &key(1), b"a").unwrap();
+        encrypt_cached(&cache, &key(3), b"c").unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.stats().evictions, 1);
+        assert!(!cache.purge(&key(2)));
+        assert!(cache.purge(&key(1)));
+        assert!(cache.purge(&key(3)));
+        assert!(cache.is_empty())
+    }
+
+    #[test]
+    fn purge_forces_a_new_cipher() {
+        let cache = CipherCache::new(2);
+        let (first, _) = cache.get(&key(1));
+        assert!(cache.purge(&key(1)));
+        let (second, _) = cache.get(&key(1));
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(cache.stats().misses, 2);
+        drop(first);
+        cache.clear();
+        assert_eq!(Arc::strong_count(&second), 1)
+    }
+
+    #[test]
+    fn wrong_key_is_detected_with_kcv() {
+        let cache = CipherCache::new(2);
+        let encrypted_and_iv = encrypt_with_kcv(&key(1), &Decrypted::from("This is a text.")).unwrap();
+        match decrypt_cached(&cache, &key(2), &encrypted_and_iv) {
+            Ok(_) => assert!(false, "Should err ProbableWrongKey"),
+            Err(e) => match e {
+                DecryptionError::ProbableWrongKey => assert!(true),
+                _ => assert!(false, "Should err ProbableWrongKey")
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(target_os = "wasi"))]
+    fn shared_between_threads() {
+        let cache = Arc::new(CipherCache::new(8));
+        let handles: Vec<_> = (0..4u8).map(|index| {
+            let cache = Arc::clone(&cache);
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    let encrypted_and_iv = encrypt_cached(&cache, &key(index), b"This is a text.").unwrap();
+                    assert_eq!(decrypt_cached(&cache, &key(index), &encrypted_and_iv).unwrap(), b"This is a text.");
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(cache.stats().misses, 4)
+    }
+}
@@ -0,0 +1,311 @@
+//! `clap` value parsers for keys and tokens.
+//!
+//! `KeyValueParser` accepts three syntaxes:
+//!
+//! - `@/path/to/file`: a key file (see `key_file`), or a legacy file
+//!   holding the base64 key (surrounding whitespace ignored) or the raw 32
+//!   bytes;
+//! - `env:VAR_NAME`: a variable holding the base64 key;
+//! - anything else: the base64 key itself. It then shows up in `ps` and the
+//!   shell history, prefer the other two.
+//!
+//! `TokenValueParser` accepts a combined token (see `token`) in any
+//! encoding. Errors name the syntax that was attempted, the file and the
+//! variable, never the key; they can give its decoded length.
+//!
+//! Neither `Key` nor `EncryptedAndIv` is `Clone`, which clap requires of
+//! parsed values, so both come in an `Arc`:
+//!
+//! ```ignore
+//! #[derive(clap::Parser)]
+//! struct Args {
+//!     #[arg(long, value_parser = KeyValueParser)]
+//!     key: Arc<Key>,
+//!     #[arg(value_parser = TokenValueParser)]
+//!     token: Arc<EncryptedAndIv>
+//! }
+//! ```
+//!
+//! examples/clap_key.rs is a complete program.
+
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::sync::Arc;
+use std::{error, fmt, fs, io};
+
+use clap::builder::TypedValueParser;
+use clap::error::ErrorKind;
+use clap::{Arg, Command};
+
+use crate::key_file::{KeyFile, KeyFileError, KEY_FILE_MAGIC};
+use crate::token::InvalidTokenError;
+use crate::{EncryptedAndIv, InvalidKeyError, Key};
+
+pub const KEY_FILE_PREFIX: &str = "@";
+
+pub const KEY_ENV_PREFIX: &str = "env:";
+
+/// Which syntax a key argument used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyArgSyntax {
+    Base64,
+    /// The path, after the `@`.
+    File(String),
+    /// The variable name, after `env:`.
+    EnvVar(String)
+}
+
+impl fmt::Display for KeyArgSyntax {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyArgSyntax::Base64 => write!(f, "base64 key"),
+            KeyArgSyntax::File(path) => write!(f, "key file {}{}", KEY_FILE_PREFIX, path),
+            KeyArgSyntax::EnvVar(name) => write!(f, "key variable {}{}", KEY_ENV_PREFIX, name),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum KeyArgError {
+    /// Not valid base64. The decode error isn't kept: it quotes a byte of the key.
+    KeyArgBase64Error(KeyArgSyntax),
+    /// Valid base64 of this many bytes instead of 32.
+    KeyArgSizeError(KeyArgSyntax, usize),
+    KeyFileError(KeyArgSyntax, io::Error),
+    /// Starts like a key file but doesn't load as one.
+    KeyFileFormatError(KeyArgSyntax, KeyFileError),
+    /// The variable isn't set or isn't UTF-8.
+    KeyEnvVarError(KeyArgSyntax)
+}
+
+impl fmt::Display for KeyArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyArgError::KeyArgBase64Error(syntax) => write!(f, "{}: not valid base64", syntax),
+            KeyArgError::KeyArgSizeError(syntax, len) => write!(f, "{}: decodes to {} bytes, a key is 32", syntax, len),
+            KeyArgError::KeyFileError(syntax, e) => write!(f, "{}: {}", syntax, e),
+            KeyArgError::KeyFileFormatError(syntax, e) => write!(f, "{}: {}", syntax, e),
+            KeyArgError::KeyEnvVarError(syntax) => write!(f, "{}: not set or not UTF-8", syntax),
+        }
+    }
+}
+
+impl error::Error for KeyArgError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            KeyArgError::KeyFileError(_, e) => Some(e),
+            KeyArgError::KeyFileFormatError(_, e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl KeyArgError {
+    pub fn syntax(&self) -> &KeyArgSyntax {
+        match self {
+            KeyArgError::KeyArgBase64Error(syntax) => syntax,
+            KeyArgError::KeyArgSizeError(syntax, _) => syntax,
+            KeyArgError::KeyFileError(syntax, _) => syntax,
+            KeyArgError::KeyFileFormatError(syntax, _) => syntax,
+            KeyArgError::KeyEnvVarError(syntax) => syntax,
+        }
+    }
+}
+
+fn decode_key(base64_key: &str, syntax: KeyArgSyntax) -> Result<Key, KeyArgError> {
+    match Key::try_from(base64_key.trim()) {
+        Ok(key) => Ok(key),
+        Err(InvalidKeyError::InvalidKeyBase64Error(_)) => Err(KeyArgError::KeyArgBase64Error(syntax)),
+        Err(InvalidKeyError::InvalidKeySizeError) => {
+            let len = base64::decode(base64_key.trim()).map_or(0, |mut bytes| {
+                crate::secure_buffer::wipe(&mut bytes);
+                bytes.len()
+            });
+            Err(KeyArgError::KeyArgSizeError(syntax, len))
+        }
+    }
+}
+
+/// Resolves a key argument in one of the syntaxes of the module docs.
+/// What was read from a file or a variable is wiped once decoded.
+pub fn parse_key_arg(arg: &str) -> Result<Key, KeyArgError> {
+    if let Some(path) = arg.strip_prefix(KEY_FILE_PREFIX) {
+        let syntax = KeyArgSyntax::File(String::from(path));
+        let mut content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) => return Err(KeyArgError::KeyFileError(syntax, e))
+        };
+        let key = if content.starts_with(KEY_FILE_MAGIC.as_bytes()) {
+            KeyFile::decode_or_legacy(&content).map(|(key, _)| key).map_err(|e| KeyArgError::KeyFileFormatError(syntax, e))
+        } else if content.len() == 32 {
+            let mut u8_array = [0u8; 32];
+            u8_array.copy_from_slice(&content);
+            Ok(Key { u8_array })
+        } else {
+            match std::str::from_utf8(&content) {
+                Ok(base64_key) => decode_key(base64_key, syntax),
+                Err(_) => Err(KeyArgError::KeyArgBase64Error(syntax))
+            }
+        };
+        crate::secure_buffer::wipe(&mut content);
+        key
+    } else if let Some(name) = arg.strip_prefix(KEY_ENV_PREFIX) {
+        let syntax = KeyArgSyntax::EnvVar(String::from(name));
+        let value = match std::env::var(name) {
+            Ok(value) => value,
+            Err(_) => return Err(KeyArgError::KeyEnvVarError(syntax))
+        };
+        let key = decode_key(&value, syntax);
+        crate::secure_buffer::wipe(&mut value.into_bytes());
+        key
+    } else {
+        decode_key(arg, KeyArgSyntax::Base64)
+    }
+}
+
+fn invalid_value(cmd: &Command, arg: Option<&Arg>, message: &dyn fmt::Display) -> clap::Error {
+    let name = arg.map_or_else(|| String::from("..."), |arg| arg.to_string());
+    clap::Error::raw(ErrorKind::ValueValidation, format!("invalid value for '{}': {}\n", name, message)).with_cmd(cmd)
+}
+
+/// Parses a key argument with `parse_key_arg`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeyValueParser;
+
+impl TypedValueParser for KeyValueParser {
+    type Value = Arc<Key>;
+
+    fn parse_ref(&self, cmd: &Command, arg: Option<&Arg>, value: &OsStr) -> Result<Arc<Key>, clap::Error> {
+        match value.to_str() {
+            Some(value) => parse_key_arg(value).map(Arc::new).map_err(|e| invalid_value(cmd, arg, &e)),
+            None => Err(invalid_value(cmd, arg, &"not UTF-8"))
+        }
+    }
+}
+
+/// Parses a combined token with `EncryptedAndIv::from_token`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokenValueParser;
+
+struct TokenArgError(InvalidTokenError);
+
+impl fmt::Display for TokenArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "token: {}", self.0)
+    }
+}
+
+impl TypedValueParser for TokenValueParser {
+    type Value = Arc<EncryptedAndIv>;
+
+    fn parse_ref(&self, cmd: &Command, arg: Option<&Arg>, value: &OsStr) -> Result<Arc<EncryptedAndIv>, clap::Error> {
+        match value.to_str() {
+            Some(value) => EncryptedAndIv::from_token(value.trim()).map(Arc::new).map_err(|e| invalid_value(cmd, arg, &TokenArgError(e))),
+            None => Err(invalid_value(cmd, arg, &"token: not UTF-8"))
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::key_file::KeyMeta;
+    use crate::token::decrypt_token;
+    use crate::{decrypt_bytes, encrypt_bytes_with_iv, Iv};
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+    const BASE64_KEY: &str = "MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=";
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        crate::test_path(&format!("clap-{}", name))
+    }
+
+    fn command() -> Command {
+        Command::new("tool")
+            .arg(Arg::new("key").long("key").value_parser(KeyValueParser))
+            .arg(Arg::new("token").long("token").value_parser(TokenValueParser))
+    }
+
+    fn parse_error(args: &[&str]) -> String {
+        match command().try_get_matches_from(args) {
+            Ok(_) => panic!("Should err ValueValidation"),
+            Err(e) => {
+                assert_eq!(e.kind(), ErrorKind::ValueValidation);
+                e.to_string()
+            }
+        }
+    }
+
+    #[test]
+    fn each_syntax_resolves() {
+        assert_eq!(parse_key_arg(BASE64_KEY).unwrap().u8_array, KEY);
+
+        let base64_file = temp_path("base64");
+        fs::write(&base64_file, format!("{}\n", BASE64_KEY)).unwrap();
+        let raw_file = temp_path("raw");
+        fs::write(&raw_file, KEY).unwrap();
+        for path in [&base64_file, &raw_file].iter() {
+            assert_eq!(parse_key_arg(&format!("@{}", path.display())).unwrap().u8_array, KEY);
+        }
+        fs::remove_file(base64_file).unwrap();
+        fs::remove_file(raw_file).unwrap();
+
+        let key_file = temp_path("key-file");
+        let _ = fs::remove_file(&key_file);
+        KeyFile::save(&key_file, &Key { u8_array: KEY }, &KeyMeta::new(std::time::SystemTime::now())).unwrap();
+        assert_eq!(parse_key_arg(&format!("@{}", key_file.display())).unwrap().u8_array, KEY);
+        let mut content = fs::read(&key_file).unwrap();
+        let index = content.len() / 2;
+        content[index] ^= 1;
+        fs::write(&key_file, content).unwrap();
+        match parse_key_arg(&format!("@{}", key_file.display())) {
+            Ok(_) => assert!(false, "Should err KeyFileFormatError"),
+            Err(e) => match e {
+                KeyArgError::KeyFileFormatError(_, KeyFileError::ChecksumMismatchError) => assert!(true),
+                _ => assert!(false, "Should err KeyFileFormatError, got {}", e)
+            }
+        }
+        fs::remove_file(key_file).unwrap();
+
+        std::env::set_var("SIMPLE_AES256_GCM_CLAP_TEST_KEY", BASE64_KEY);
+        let matches = command().try_get_matches_from(["tool", "--key", "env:SIMPLE_AES256_GCM_CLAP_TEST_KEY"]).unwrap();
+        assert_eq!(matches.get_one::<Arc<Key>>("key").unwrap().u8_array, KEY)
+    }
+
+    #[test]
+    fn errors_name_the_syntax_but_not_the_key() {
+        let missing = temp_path("missing");
+        let message = parse_error(&["tool", "--key", &format!("@{}", missing.display())]);
+        assert!(message.contains(&format!("key file @{}", missing.display())), "{}", message);
+        assert!(message.contains("--key"), "{}", message);
+
+        let short_key = "MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTI=";
+        let message = parse_error(&["tool", "--key", short_key]);
+        assert!(message.contains("base64 key: decodes to 23 bytes, a key is 32"), "{}", message);
+        assert!(!message.contains(short_key), "{}", message);
+
+        let message = parse_error(&["tool", "--key", "MDEy!zQ1"]);
+        assert!(message.contains("base64 key: not valid base64") && !message.contains("MDEy"), "{}", message);
+
+        match parse_key_arg("env:SIMPLE_AES256_GCM_CLAP_TEST_UNSET") {
+            Ok(_) => assert!(false, "Should err KeyEnvVarError"),
+            Err(e) => match e {
+                KeyArgError::KeyEnvVarError(_) => assert_eq!(e.to_string(), "key variable env:SIMPLE_AES256_GCM_CLAP_TEST_UNSET: not set or not UTF-8"),
+                _ => assert!(false, "Should err KeyEnvVarError, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn tokens_parse_and_bad_tokens_fail() {
+        let key = Key { u8_array: KEY };
+        let token = encrypt_bytes_with_iv(&key, Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap().to_token();
+        let matches = command().try_get_matches_from(["tool", "--token", &token]).unwrap();
+        let parsed = matches.get_one::<Arc<EncryptedAndIv>>("token").unwrap();
+        assert_eq!(decrypt_bytes(&key, parsed).unwrap(), decrypt_token(&key, &token).unwrap());
+
+        let message = parse_error(&["tool", "--token", "saes2:AAAA"]);
+        assert!(message.contains("'--token <token>': token:"), "{}", message)
+    }
+}
@@ -0,0 +1,334 @@
+//! CMS AuthEnvelopedData (RFC 5083) with a pre-shared key-encryption key.
+//!
+//! `to_cms_auth_enveloped` writes a DER `ContentInfo` holding an
+//! `AuthEnvelopedData` with a single `KEKRecipientInfo`: a fresh
+//! content-encryption key, wrapped with AES-256 key wrap (RFC 3394) under
+//! the KEK and identified by `kek_id`, encrypts the content with
+//! AES-256-GCM (RFC 5084), 12-byte nonce and 16-byte tag. It is the output
+//! of `openssl cms -encrypt -aes-256-gcm -secretkey <kek> -secretkeyid
+//! <kek_id> -binary -outform DER`, and `openssl cms -decrypt` with the same
+//! key and id reads it.
+//!
+//! `from_cms_auth_enveloped` reads the structures with the KEK recipient
+//! whose key identifier is `kek_id`, authenticated attributes included:
+//! their DER is the associated data. Anything else is rejected with its
+//! own error rather than a generic one: other content types, recipient
+//! types, key wrap or content encryption algorithms, and GCM parameters.
+
+use std::convert::{TryFrom, TryInto};
+use std::{error, fmt};
+
+use ::cms::cert::x509::attr::Attributes;
+use ::cms::cert::x509::spki::AlgorithmIdentifierOwned;
+use ::cms::content_info::{CmsVersion, ContentInfo};
+use ::cms::enveloped_data::{EncryptedContentInfo, KekIdentifier, KekRecipientInfo, OriginatorInfo, RecipientInfo, RecipientInfos};
+use aes_kw::KekAes256;
+use der::asn1::{ObjectIdentifier, OctetString, SetOfVec};
+use der::{Any, Decode, Encode, Sequence};
+
+use crate::secure_buffer::wipe;
+use crate::{decrypt_bytes_with_aad, encrypt_bytes_with_iv_and_aad, DecryptionError, Encrypted, EncryptedAndIv, EncryptionError, Iv, Key, IV_LEN, TAG_LEN};
+
+const ID_CT_AUTH_ENVELOPED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.16.1.23");
+const ID_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.1");
+const ID_AES256_WRAP: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.45");
+const ID_AES256_GCM: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.46");
+
+/// The content-encryption key wrapped with AES key wrap.
+const WRAPPED_KEY_LEN: usize = 32 + 8;
+
+#[derive(Debug, Clone)]
+pub enum CmsError {
+    /// Not DER, not the structures of RFC 5652 and 5083, or detached content.
+    InvalidCmsError,
+    /// The `ContentInfo` isn't AuthEnvelopedData; holds its content type.
+    UnsupportedContentTypeError(String),
+    /// No `KEKRecipientInfo`, only other recipient types.
+    UnsupportedRecipientInfoError,
+    /// `KEKRecipientInfo`s, none for `kek_id`.
+    UnknownKekIdError,
+    /// Holds the key encryption algorithm; only AES-256 key wrap is supported.
+    UnsupportedKeyEncryptionAlgorithmError(String),
+    /// Holds the content encryption algorithm; only AES-256-GCM is supported.
+    UnsupportedContentEncryptionAlgorithmError(String),
+    /// AES-GCM parameters other than a 12-byte nonce and a 16-byte tag.
+    UnsupportedGcmParametersError,
+    /// The wrapped key doesn't unwrap: most likely the wrong KEK.
+    KeyUnwrapError,
+    CmsEncryptionError(EncryptionError),
+    CmsDecryptionError(DecryptionError)
+}
+
+impl fmt::Display for CmsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CmsError::InvalidCmsError => write!(f, "Invalid CMS AuthEnvelopedData"),
+            CmsError::UnsupportedContentTypeError(oid) => write!(f, "Unsupported CMS content type {}", oid),
+            CmsError::UnsupportedRecipientInfoError => write!(f, "No KEK recipient, other recipient types are unsupported"),
+            CmsError::UnknownKekIdError => write!(f, "No KEK recipient with this key identifier"),
+            CmsError::UnsupportedKeyEncryptionAlgorithmError(oid) => write!(f, "Unsupported key encryption algorithm {}", oid),
+            CmsError::UnsupportedContentEncryptionAlgorithmError(oid) => write!(f, "Unsupported content encryption algorithm {}", oid),
+            CmsError::UnsupportedGcmParametersError => write!(f, "Unsupported AES-GCM parameters"),
+            CmsError::KeyUnwrapError => write!(f, "The content-encryption key doesn't unwrap, probably the wrong KEK"),
+            CmsError::CmsEncryptionError(e) => write!(f, "{}", e),
+            CmsError::CmsDecryptionError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for CmsError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CmsError::CmsEncryptionError(e) => Some(e),
+            CmsError::CmsDecryptionError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// RFC 5083, which the `cms` crate doesn't define.
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+struct AuthEnvelopedData {
+    version: CmsVersion,
+    #[asn1(context_specific = "0", tag_mode = "IMPLICIT", constructed = "true", optional = "true")]
+    originator_info: Option<OriginatorInfo>,
+    recipient_infos: RecipientInfos,
+    auth_encrypted_content_info: EncryptedContentInfo,
+    #[asn1(context_specific = "1", tag_mode = "IMPLICIT", constructed = "true", optional = "true")]
+    auth_attrs: Option<Attributes>,
+    mac: OctetString,
+    #[asn1(context_specific = "2", tag_mode = "IMPLICIT", constructed = "true", optional = "true")]
+    unauth_attrs: Option<Attributes>
+}
+
+/// RFC 5084.
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+struct GcmParameters {
+    nonce: OctetString,
+    #[asn1(default = "default_icv_len")]
+    icv_len: u8
+}
+
+fn default_icv_len() -> u8 {
+    12
+}
+
+fn encoding_error<E>(_: E) -> CmsError {
+    CmsError::CmsEncryptionError(EncryptionError::GenericEncryptionError)
+}
+
+/// Encrypts `plaintext` for the holders of `kek`, see the module docs.
+pub fn to_cms_auth_enveloped(kek_id: &[u8], kek: &Key, plaintext: &[u8]) -> Result<Vec<u8>, CmsError> {
+    let mut cek = Key::generate();
+    let mut wrapped = [0u8; WRAPPED_KEY_LEN];
+    let wrap_result = KekAes256::from(kek.u8_array).wrap(&cek.u8_array, &mut wrapped);
+    let iv = Iv::generate();
+    let nonce = iv.u8_array;
+    let encrypt_result = encrypt_bytes_with_iv_and_aad(&cek, iv, plaintext, &[]);
+    wipe(&mut cek.u8_array);
+    wrap_result.map_err(encoding_error)?;
+    let (_, encrypted) = encrypt_result.map_err(CmsError::CmsEncryptionError)?.into_parts();
+    let (ciphertext, tag) = encrypted.u8_vec.split_at(encrypted.u8_vec.len() - TAG_LEN);
+
+    let recipient = RecipientInfo::Kekri(KekRecipientInfo {
+        version: CmsVersion::V4,
+        kek_id: KekIdentifier { kek_identifier: OctetString::new(kek_id).map_err(encoding_error)?, date: None, other: None },
+        key_enc_alg: AlgorithmIdentifierOwned { oid: ID_AES256_WRAP, parameters: None },
+        encrypted_key: OctetString::new(&wrapped[..]).map_err(encoding_error)?
+    });
+    let gcm_parameters = GcmParameters { nonce: OctetString::new(&nonce[..]).map_err(encoding_error)?, icv_len: TAG_LEN as u8 };
+    let auth_enveloped_data = AuthEnvelopedData {
+        version: CmsVersion::V0,
+        originator_info: None,
+        recipient_infos: RecipientInfos(SetOfVec::try_from(vec![recipient]).map_err(encoding_error)?),
+        auth_encrypted_content_info: EncryptedContentInfo {
+            content_type: ID_DATA,
+            content_enc_alg: AlgorithmIdentifierOwned { oid: ID_AES256_GCM, parameters: Some(Any::encode_from(&gcm_parameters).map_err(encoding_error)?) },
+            encrypted_content: Some(OctetString::new(ciphertext).map_err(encoding_error)?)
+        },
+        auth_attrs: None,
+        mac: OctetString::new(tag).map_err(encoding_error)?,
+        unauth_attrs: None
+    };
+    let content_info = ContentInfo { content_type: ID_CT_AUTH_ENVELOPED_DATA, content: Any::encode_from(&auth_enveloped_data).map_err(encoding_error)? };
+    content_info.to_der().map_err(encoding_error)
+}
+
+/// Decrypts the output of `to_cms_auth_enveloped`, or of OpenSSL, see the module docs.
+pub fn from_cms_auth_enveloped(kek_id: &[u8], kek: &Key, der: &[u8]) -> Result<Vec<u8>, CmsError> {
+    let content_info = ContentInfo::from_der(der).map_err(|_| CmsError::InvalidCmsError)?;
+    if content_info.content_type != ID_CT_AUTH_ENVELOPED_DATA {
+        return Err(CmsError::UnsupportedContentTypeError(content_info.content_type.to_string()));
+    }
+    let auth_enveloped_data: AuthEnvelopedData = content_info.content.decode_as().map_err(|_| CmsError::InvalidCmsError)?;
+
+    let kek_recipients: Vec<&KekRecipientInfo> = auth_enveloped_data.recipient_infos.0.iter().filter_map(|recipient| match recipient {
+        RecipientInfo::Kekri(kek_recipient) => Some(kek_recipient),
+        _ => None
+    }).collect();
+    if kek_recipients.is_empty() {
+        return Err(CmsError::UnsupportedRecipientInfoError);
+    }
+    let recipient = match kek_recipients.iter().find(|recipient| recipient.kek_id.kek_identifier.as_bytes() == kek_id) {
+        Some(recipient) => recipient,
+        None => return Err(CmsError::UnknownKekIdError)
+    };
+    if recipient.key_enc_alg.oid != ID_AES256_WRAP {
+        return Err(CmsError::UnsupportedKeyEncryptionAlgorithmError(recipient.key_enc_alg.oid.to_string()));
+    }
+
+    let content = &auth_enveloped_data.auth_encrypted_content_info;
+    if content.content_enc_alg.oid != ID_AES256_GCM {
+        return Err(CmsError::UnsupportedContentEncryptionAlgorithmError(content.content_enc_alg.oid.to_string()));
+    }
+    let gcm_parameters: GcmParameters = match &content.content_enc_alg.parameters {
+        Some(parameters) => parameters.decode_as().map_err(|_| CmsError::InvalidCmsError)?,
+        None => return Err(CmsError::UnsupportedGcmParametersError)
+    };
+    let nonce: [u8; IV_LEN] = match gcm_parameters.nonce.as_bytes().try_into() {
+        Ok(nonce) if usize::from(gcm_parameters.icv_len) == TAG_LEN && auth_enveloped_data.mac.as_bytes().len() == TAG_LEN => nonce,
+        _ => return Err(CmsError::UnsupportedGcmParametersError)
+    };
+    let ciphertext = match &content.encrypted_content {
+        Some(ciphertext) => ciphertext.as_bytes(),
+        None => return Err(CmsError::InvalidCmsError)
+    };
+    let aad = match &auth_enveloped_data.auth_attrs {
+        Some(auth_attrs) => auth_attrs.to_der().map_err(|_| CmsError::InvalidCmsError)?,
+        None => Vec::new()
+    };
+
+    let wrapped = recipient.encrypted_key.as_bytes();
+    if wrapped.len() != WRAPPED_KEY_LEN {
+        return Err(CmsError::KeyUnwrapError);
+    }
+    let mut cek = Key { u8_array: [0u8; 32] };
+    KekAes256::from(kek.u8_array).unwrap(wrapped, &mut cek.u8_array).map_err(|_| CmsError::KeyUnwrapError)?;
+    let mut u8_vec = Vec::with_capacity(ciphertext.len() + TAG_LEN);
+    u8_vec.extend_from_slice(ciphertext);
+    u8_vec.extend_from_slice(auth_enveloped_data.mac.as_bytes());
+    let encrypted_and_iv = EncryptedAndIv::from_parts(Iv { u8_array: nonce }, Encrypted { u8_vec });
+    let result = decrypt_bytes_with_aad(&cek, &encrypted_and_iv, &aad);
+    wipe(&mut cek.u8_array);
+    result.map_err(CmsError::CmsDecryptionError)
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use ::cms::enveloped_data::PasswordRecipientInfo;
+
+    const KEK: [u8; 32] = *b"01234567890123456789012345678901";
+
+    fn kek() -> Key {
+        Key { u8_array: KEK }
+    }
+
+    /// Decodes `der`, applies `change` and re-encodes.
+    fn altered<F: FnOnce(&mut AuthEnvelopedData)>(der: &[u8], change: F) -> Vec<u8> {
+        let content_info = ContentInfo::from_der(der).unwrap();
+        let mut auth_enveloped_data: AuthEnvelopedData = content_info.content.decode_as().unwrap();
+        change(&mut auth_enveloped_data);
+        ContentInfo { content_type: content_info.content_type, content: Any::encode_from(&auth_enveloped_data).unwrap() }.to_der().unwrap()
+    }
+
+    fn assert_fails_with(der: &[u8], expected: &str) {
+        match from_cms_auth_enveloped(b"kek-01", &kek(), der) {
+            Ok(_) => assert!(false, "Should err {}", expected),
+            Err(e) => assert_eq!(format!("{:?}", e), expected)
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for plaintext in [&b""[..], b"This is a text.", &[7u8; 1000][..]].iter() {
+            let der = to_cms_auth_enveloped(b"kek-01", &kek(), plaintext).unwrap();
+            assert_eq!(from_cms_auth_enveloped(b"kek-01", &kek(), &der).unwrap(), *plaintext)
+        }
+    }
+
+    // Made with `openssl cms -encrypt -aes-256-gcm -secretkey 3031...3031
+    // -secretkeyid 6b656b2d3031 -binary -outform DER` (OpenSSL 3.0).
+    #[test]
+    fn decrypts_openssl_output() {
+        let der = include_bytes!("../tests/fixtures/cms_auth_enveloped.der");
+        assert_eq!(from_cms_auth_enveloped(b"kek-01", &kek(), der).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn authenticated_attributes_are_the_associated_data() {
+        let der = include_bytes!("../tests/fixtures/cms_auth_enveloped.der");
+        let with_attribute = altered(der, |auth_enveloped_data| {
+            let attribute = ::cms::cert::x509::attr::Attribute { oid: ID_DATA, values: SetOfVec::try_from(vec![Any::encode_from(&ID_DATA).unwrap()]).unwrap() };
+            auth_enveloped_data.auth_attrs = Some(SetOfVec::try_from(vec![attribute]).unwrap());
+        });
+        assert_fails_with(&with_attribute, "CmsDecryptionError(GenericDecryptionError)")
+    }
+
+    #[test]
+    fn wrong_kek_or_kek_id_fails() {
+        let der = to_cms_auth_enveloped(b"kek-01", &kek(), b"This is a text.").unwrap();
+        match from_cms_auth_enveloped(b"kek-01", &Key { u8_array: [0u8; 32] }, &der) {
+            Ok(_) => assert!(false, "Should err KeyUnwrapError"),
+            Err(e) => match e {
+                CmsError::KeyUnwrapError => assert!(true),
+                _ => assert!(false, "Should err KeyUnwrapError, got {}", e)
+            }
+        }
+        match from_cms_auth_enveloped(b"kek-02", &kek(), &der) {
+            Ok(_) => assert!(false, "Should err UnknownKekIdError"),
+            Err(e) => match e {
+                CmsError::UnknownKekIdError => assert!(true),
+                _ => assert!(false, "Should err UnknownKekIdError, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported_structures_have_specific_errors() {
+        let der = to_cms_auth_enveloped(b"kek-01", &kek(), b"This is a text.").unwrap();
+
+        assert_fails_with(b"not DER", "InvalidCmsError");
+        let enveloped = ContentInfo { content_type: ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.3"), content: ContentInfo::from_der(&der).unwrap().content }.to_der().unwrap();
+        assert_fails_with(&enveloped, "UnsupportedContentTypeError(\"1.2.840.113549.1.7.3\")");
+
+        let password_recipient = altered(&der, |auth_enveloped_data| {
+            let recipient = RecipientInfo::Pwri(PasswordRecipientInfo {
+                version: CmsVersion::V0,
+                key_derivation_alg: None,
+                key_enc_alg: AlgorithmIdentifierOwned { oid: ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.16.3.9"), parameters: None },
+                enc_key: OctetString::new(&[0u8; 40][..]).unwrap()
+            });
+            auth_enveloped_data.recipient_infos = RecipientInfos(SetOfVec::try_from(vec![recipient]).unwrap());
+        });
+        assert_fails_with(&password_recipient, "UnsupportedRecipientInfoError");
+
+        let aes128_wrap = altered(&der, |auth_enveloped_data| {
+            let mut recipients = auth_enveloped_data.recipient_infos.0.clone().into_vec();
+            if let RecipientInfo::Kekri(recipient) = &mut recipients[0] {
+                recipient.key_enc_alg.oid = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.5");
+            }
+            auth_enveloped_data.recipient_infos = RecipientInfos(SetOfVec::try_from(recipients).unwrap());
+        });
+        assert_fails_with(&aes128_wrap, "UnsupportedKeyEncryptionAlgorithmError(\"2.16.840.1.101.3.4.1.5\")");
+
+        let aes128_gcm = altered(&der, |auth_enveloped_data| {
+            auth_enveloped_data.auth_encrypted_content_info.content_enc_alg.oid = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.6");
+        });
+        assert_fails_with(&aes128_gcm, "UnsupportedContentEncryptionAlgorithmError(\"2.16.840.1.101.3.4.1.6\")");
+
+        let short_tag = altered(&der, |auth_enveloped_data| {
+            let parameters = GcmParameters { nonce: OctetString::new(&[0u8; 12][..]).unwrap(), icv_len: 12 };
+            auth_enveloped_data.auth_encrypted_content_info.content_enc_alg.parameters = Some(Any::encode_from(&parameters).unwrap());
+        });
+        assert_fails_with(&short_tag, "UnsupportedGcmParametersError");
+
+        let tampered = altered(&der, |auth_enveloped_data| {
+            let mut mac = auth_enveloped_data.mac.as_bytes().to_vec();
+            mac[0] ^= 1;
+            auth_enveloped_data.mac = OctetString::new(mac).unwrap();
+        });
+        assert_fails_with(&tampered, "CmsDecryptionError(GenericDecryptionError)")
+    }
+}
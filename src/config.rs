@@ -0,0 +1,162 @@
+//! Partially encrypted config files.
+//!
+//! Secret values are written as `ENC[<token>]` markers, `<token>` being a
+//! combined token (see `token`), e.g. `password: ENC[saes1:...]`.
+//! `decrypt_markers` substitutes each marker with its plaintext and copies
+//! everything else, comments and formatting included, byte for byte.
+
+use std::{error, fmt};
+
+use crate::token::{decrypt_token, TokenError};
+use crate::Key;
+#[cfg(feature = "rng")]
+use crate::{token::encrypt_to_token, EncryptionError};
+
+pub(crate) const MARKER_START: &str = "ENC[";
+pub(crate) const MARKER_END: char = ']';
+
+#[derive(Debug, Clone)]
+pub enum MarkerErrorKind {
+    /// `ENC[` without a closing `]`.
+    UnterminatedMarkerError,
+    /// Malformed or unknown token, or failed decryption.
+    MarkerTokenError(TokenError),
+    /// The plaintext can't be substituted into text.
+    InvalidMarkerUtf8Error
+}
+
+/// A marker that couldn't be resolved, located by the byte offset of its
+/// `ENC[` and its 1-based line.
+#[derive(Debug, Clone)]
+pub struct MarkerError {
+    pub offset: usize,
+    pub line: usize,
+    pub kind: MarkerErrorKind
+}
+
+impl fmt::Display for MarkerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            MarkerErrorKind::UnterminatedMarkerError => write!(f, "Unterminated ENC[ marker at line {} (byte {})", self.line, self.offset),
+            MarkerErrorKind::MarkerTokenError(e) => write!(f, "Invalid ENC[ marker at line {} (byte {}): {}", self.line, self.offset, e),
+            MarkerErrorKind::InvalidMarkerUtf8Error => write!(f, "ENC[ marker at line {} (byte {}) doesn't decrypt to UTF-8", self.line, self.offset),
+        }
+    }
+}
+
+impl error::Error for MarkerError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.kind {
+            MarkerErrorKind::MarkerTokenError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// Returns `text` with every `ENC[...]` marker replaced by its plaintext.
+pub fn decrypt_markers(key: &Key, text: &str) -> Result<String, MarkerError> {
+    let mut decrypted = String::with_capacity(text.len());
+    let mut rest = 0;
+    while let Some(found) = text[rest..].find(MARKER_START) {
+        let offset = rest + found;
+        let error = |kind| MarkerError { offset, line: text[..offset].matches('\n').count() + 1, kind };
+        let token_start = offset + MARKER_START.len();
+        let token_end = match text[token_start..].find(MARKER_END) {
+            Some(value) => token_start + value,
+            None => return Err(error(MarkerErrorKind::UnterminatedMarkerError))
+        };
+        let plaintext = decrypt_token(key, &text[token_start..token_end]).map_err(|e| error(MarkerErrorKind::MarkerTokenError(e)))?;
+        decrypted.push_str(&text[rest..offset]);
+        match std::str::from_utf8(&plaintext) {
+            Ok(value) => decrypted.push_str(value),
+            Err(_) => return Err(error(MarkerErrorKind::InvalidMarkerUtf8Error))
+        }
+        rest = token_end + 1;
+    }
+    decrypted.push_str(&text[rest..]);
+    Ok(decrypted)
+}
+
+/// Encrypts `plaintext` into an `ENC[...]` marker.
+#[cfg(feature = "rng")]
+pub fn encrypt_value_to_marker(key: &Key, plaintext: &str) -> Result<String, EncryptionError> {
+    Ok(format!("{}{}{}", MARKER_START, encrypt_to_token(key, plaintext.as_bytes())?, MARKER_END))
+}
+
+#[cfg(test)]
+#[cfg(feature = "rng")]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    fn fixture(key: &Key, corrupt_api_token: bool) -> String {
+        let mut api_token = encrypt_value_to_marker(key, "t0k3n").unwrap();
+        if corrupt_api_token {
+            api_token = api_token.replacen("saes1:", "saes1:AAAA", 1);
+        }
+        format!(
+            "# database settings\ndatabase:\n  user: app\n  password: {}\n  # keep quoted\n  dsn: \"postgres://app:{}@db/app\"\napi:\n  token:   {}   # rotated monthly\n",
+            encrypt_value_to_marker(key, "hunter2").unwrap(),
+            encrypt_value_to_marker(key, "s3cr3t").unwrap(),
+            api_token
+        )
+    }
+
+    #[test]
+    fn decrypt_markers_substitutes_and_preserves_the_rest() {
+        let key = Key { u8_array: KEY };
+        assert_eq!(
+            decrypt_markers(&key, &fixture(&key, false)).unwrap(),
+            "# database settings\ndatabase:\n  user: app\n  password: hunter2\n  # keep quoted\n  dsn: \"postgres://app:s3cr3t@db/app\"\napi:\n  token:   t0k3n   # rotated monthly\n"
+        )
+    }
+
+    #[test]
+    fn corrupted_marker_is_located() {
+        let key = Key { u8_array: KEY };
+        let text = fixture(&key, true);
+        match decrypt_markers(&key, &text) {
+            Ok(_) => assert!(false, "Should err MarkerTokenError"),
+            Err(e) => {
+                assert_eq!(e.line, 8);
+                assert_eq!(&text[e.offset..e.offset + 4], "ENC[");
+                match e.kind {
+                    MarkerErrorKind::MarkerTokenError(_) => assert!(true),
+                    _ => assert!(false, "Should err MarkerTokenError")
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_marker_format_is_rejected() {
+        match decrypt_markers(&Key { u8_array: KEY }, "a: 1\nb: ENC[vault:v1:abc]\n") {
+            Ok(_) => assert!(false, "Should err MarkerTokenError"),
+            Err(e) => {
+                assert_eq!((e.line, e.offset), (2, 8));
+                match e.kind {
+                    MarkerErrorKind::MarkerTokenError(TokenError::InvalidTokenError(_)) => assert!(true),
+                    _ => assert!(false, "Should err MarkerTokenError")
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unterminated_marker_is_rejected() {
+        match decrypt_markers(&Key { u8_array: KEY }, "password: ENC[saes1:abc\n") {
+            Ok(_) => assert!(false, "Should err UnterminatedMarkerError"),
+            Err(e) => match e.kind {
+                MarkerErrorKind::UnterminatedMarkerError => assert!(true),
+                _ => assert!(false, "Should err UnterminatedMarkerError")
+            }
+        }
+    }
+
+    #[test]
+    fn text_without_markers_is_unchanged() {
+        assert_eq!(decrypt_markers(&Key { u8_array: KEY }, "a: [1, 2]\n").unwrap(), "a: [1, 2]\n")
+    }
+}
@@ -0,0 +1,162 @@
+//! Key-value encryption contexts, authenticated as associated data.
+//!
+//! An `EncryptionContext` (tenant, table, column, ...) is turned into AAD
+//! with a canonical encoding, so the same pairs authenticate whatever order
+//! they were inserted in. For each pair, in ascending byte order of the
+//! keys:
+//!
+//! `key length (4, big-endian) || key (UTF-8) || value length (4, big-endian) || value (UTF-8)`
+//!
+//! Keys are unique, and the lengths make the encoding unambiguous. An empty
+//! context encodes to no bytes at all: it is the same as no AAD, so
+//! `decrypt_with_context` with an empty context opens what `encrypt_bytes`
+//! sealed. The context isn't part of the output; it must be given again to
+//! decrypt.
+
+use std::collections::BTreeMap;
+use std::iter::FromIterator;
+
+use crate::{encrypt_bytes_with_iv_and_aad, instrument, open, DecryptionError, EncryptedAndIv, EncryptionError, Iv, Key};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncryptionContext {
+    pairs: BTreeMap<String, String>
+}
+
+impl EncryptionContext {
+    pub fn new() -> EncryptionContext {
+        EncryptionContext::default()
+    }
+
+    /// Adds `key`, replacing its previous value.
+    pub fn with(mut self, key: &str, value: &str) -> EncryptionContext {
+        self.insert(key, value);
+        self
+    }
+
+    /// Returns the previous value of `key`.
+    pub fn insert(&mut self, key: &str, value: &str) -> Option<String> {
+        self.pairs.insert(String::from(key), String::from(value))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs.get(key).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// The pairs, sorted by key.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// The canonical encoding described in the module docs.
+    pub fn to_aad(&self) -> Vec<u8> {
+        let mut aad = Vec::new();
+        for (key, value) in self.iter() {
+            for field in [key, value].iter() {
+                aad.extend_from_slice(&(field.len() as u32).to_be_bytes());
+                aad.extend_from_slice(field.as_bytes());
+            }
+        }
+        aad
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for EncryptionContext {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(pairs: I) -> EncryptionContext {
+        EncryptionContext { pairs: pairs.into_iter().map(|(key, value)| (key.into(), value.into())).collect() }
+    }
+}
+
+/// Encrypts with a freshly generated iv, authenticating `context`.
+#[cfg(feature = "rng")]
+pub fn encrypt_with_context(key: &Key, plaintext: &[u8], context: &EncryptionContext) -> Result<EncryptedAndIv, EncryptionError> {
+    encrypt_with_iv_and_context(key, Iv::generate(), plaintext, context)
+}
+
+/// Encrypts with a caller-supplied iv, authenticating `context`. The iv must
+/// never be reused with the same key.
+pub fn encrypt_with_iv_and_context(key: &Key, iv: Iv, plaintext: &[u8], context: &EncryptionContext) -> Result<EncryptedAndIv, EncryptionError> {
+    encrypt_bytes_with_iv_and_aad(key, iv, plaintext, &context.to_aad())
+}
+
+/// Decrypts what was encrypted under `context`. A failed authentication is
+/// `DecryptionError::ProbableContextMismatch`, unless the envelope records
+/// an `aad_digest` showing that the context is the right one.
+pub fn decrypt_with_context(key: &Key, encrypted_and_iv: &EncryptedAndIv, context: &EncryptionContext) -> Result<Vec<u8>, DecryptionError> {
+    let aad = context.to_aad();
+    instrument::decrypt(key, encrypted_and_iv.encrypted.u8_vec.len(), || match open(key, encrypted_and_iv, &aad) {
+        Err(DecryptionError::GenericDecryptionError) if encrypted_and_iv.aad_matches(&aad) != Some(true) => Err(DecryptionError::ProbableContextMismatch),
+        result => result
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::{aad_digest, decrypt_bytes, encrypt_bytes_with_iv};
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+    const IV: [u8; 12] = *b"012345678901";
+
+    fn column_context() -> EncryptionContext {
+        EncryptionContext::new().with("tenant", "acme").with("table", "users").with("column", "email")
+    }
+
+    #[test]
+    fn insertion_order_does_not_matter() {
+        let reordered: EncryptionContext = vec![("column", "email"), ("tenant", "acme"), ("table", "users")].into_iter().collect();
+        assert_eq!(reordered.to_aad(), column_context().to_aad());
+        let encrypted_and_iv = encrypt_with_iv_and_context(&Key { u8_array: KEY }, Iv { u8_array: IV }, b"a@example.com", &column_context()).unwrap();
+        assert_eq!(decrypt_with_context(&Key { u8_array: KEY }, &encrypted_and_iv, &reordered).unwrap(), b"a@example.com")
+    }
+
+    #[test]
+    fn encoding_is_sorted_and_length_prefixed() {
+        let context = EncryptionContext::new().with("b", "").with("a", "xy");
+        assert_eq!(context.to_aad(), b"\0\0\0\x01a\0\0\0\x02xy\0\0\0\x01b\0\0\0\0".to_vec());
+        // Without the lengths, both would be "abc".
+        assert_ne!(EncryptionContext::new().with("a", "bc").to_aad(), EncryptionContext::new().with("ab", "c").to_aad())
+    }
+
+    #[test]
+    fn a_different_or_missing_context_fails_as_a_probable_mismatch() {
+        let key = Key { u8_array: KEY };
+        let encrypted_and_iv = encrypt_with_iv_and_context(&key, Iv { u8_array: IV }, b"a@example.com", &column_context()).unwrap();
+        for context in [column_context().with("tenant", "other"), column_context().with("row", "1"), EncryptionContext::new()].iter() {
+            match decrypt_with_context(&key, &encrypted_and_iv, context) {
+                Ok(_) => assert!(false, "Should err ProbableContextMismatch"),
+                Err(e) => match e {
+                    DecryptionError::ProbableContextMismatch => assert!(e.to_string().contains("encryption context")),
+                    _ => assert!(false, "Should err ProbableContextMismatch, got {}", e)
+                }
+            }
+        }
+        assert!(decrypt_bytes(&key, &encrypted_and_iv).is_err());
+
+        // A recorded digest shows the context is right: then it's not blamed.
+        let with_digest = encrypted_and_iv.with_aad_digest(aad_digest(&column_context().to_aad()));
+        match decrypt_with_context(&Key { u8_array: [0u8; 32] }, &with_digest, &column_context()) {
+            Err(DecryptionError::GenericDecryptionError) => assert!(true),
+            _ => assert!(false, "Should err GenericDecryptionError")
+        }
+    }
+
+    #[test]
+    fn empty_context_is_no_aad() {
+        let key = Key { u8_array: KEY };
+        assert!(EncryptionContext::new().to_aad().is_empty());
+        let without = encrypt_bytes_with_iv(&key, Iv { u8_array: IV }, b"This is a text.").unwrap();
+        let with_empty = encrypt_with_iv_and_context(&key, Iv { u8_array: IV }, b"This is a text.", &EncryptionContext::new()).unwrap();
+        assert_eq!(with_empty.encrypted.u8_vec, without.encrypted.u8_vec);
+        assert_eq!(decrypt_with_context(&key, &without, &EncryptionContext::new()).unwrap(), b"This is a text.")
+    }
+}
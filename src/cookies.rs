@@ -0,0 +1,153 @@
+//! Encrypted cookie values, enabled with the `cookie` feature.
+//!
+//! The value is `base64url-nopad(iv (12) || ciphertext and tag)`, so only
+//! `A-Z a-z 0-9 - _` appear in it. The cookie name is authenticated as
+//! associated data: a value moved to another cookie no longer decrypts.
+
+use std::convert::TryInto;
+use std::{error, fmt};
+
+use crate::{decrypt_bytes_with_aad, encrypt_bytes_with_aad, DecryptionError, Encrypted, EncryptedAndIv, EncryptionError, Iv, Key, IV_LEN, TAG_LEN};
+
+/// Browsers only guarantee 4096 bytes per cookie, name and `=` included.
+pub const MAX_COOKIE_SIZE: usize = 4096;
+
+const AAD_LABEL: &[u8] = b"simple-aes256-gcm cookie:";
+
+#[derive(Debug, Clone)]
+pub enum CookieError {
+    /// `name=value` would exceed `MAX_COOKIE_SIZE`.
+    CookieTooLargeError,
+    InvalidCookieBase64Error(base64::DecodeError),
+    /// Shorter than an iv and a tag.
+    InvalidCookieSizeError,
+    CookieEncryptionError(EncryptionError),
+    /// Wrong key, tampered value, or value issued for another cookie name.
+    CookieDecryptionError(DecryptionError)
+}
+
+impl fmt::Display for CookieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CookieError::CookieTooLargeError => write!(f, "Encrypted cookie larger than {} bytes", MAX_COOKIE_SIZE),
+            CookieError::InvalidCookieBase64Error(_) => write!(f, "Please provide a valid base64"),
+            CookieError::InvalidCookieSizeError => write!(f, "Cookie value too short"),
+            CookieError::CookieEncryptionError(e) => write!(f, "{}", e),
+            CookieError::CookieDecryptionError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for CookieError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CookieError::InvalidCookieBase64Error(e) => Some(e),
+            CookieError::CookieEncryptionError(e) => Some(e),
+            CookieError::CookieDecryptionError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+fn aad(name: &str) -> Vec<u8> {
+    let mut aad = AAD_LABEL.to_vec();
+    aad.extend_from_slice(name.as_bytes());
+    aad
+}
+
+/// Length of the encrypted value for a `value_len`-byte payload.
+pub fn encrypted_cookie_value_len(value_len: usize) -> usize {
+    let bytes = IV_LEN + value_len + TAG_LEN;
+    (bytes * 4).div_ceil(3)
+}
+
+pub fn encrypt_cookie_value(key: &Key, name: &str, value: &[u8]) -> Result<String, CookieError> {
+    if name.len() + 1 + encrypted_cookie_value_len(value.len()) > MAX_COOKIE_SIZE {
+        return Err(CookieError::CookieTooLargeError);
+    }
+    let encrypted_and_iv = encrypt_bytes_with_aad(key, value, &aad(name)).map_err(CookieError::CookieEncryptionError)?;
+    let mut bytes = encrypted_and_iv.iv.u8_array.to_vec();
+    bytes.extend_from_slice(&encrypted_and_iv.encrypted.u8_vec);
+    Ok(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
+}
+
+pub fn decrypt_cookie_value(key: &Key, name: &str, token: &str) -> Result<Vec<u8>, CookieError> {
+    let bytes = base64::decode_config(token, base64::URL_SAFE_NO_PAD).map_err(CookieError::InvalidCookieBase64Error)?;
+    if bytes.len() < IV_LEN + TAG_LEN {
+        return Err(CookieError::InvalidCookieSizeError);
+    }
+    let encrypted_and_iv = EncryptedAndIv::from_parts(Iv { u8_array: bytes[..IV_LEN].try_into().unwrap() }, Encrypted { u8_vec: bytes[IV_LEN..].to_vec() });
+    decrypt_bytes_with_aad(key, &encrypted_and_iv, &aad(name)).map_err(CookieError::CookieDecryptionError)
+}
+
+/// Builds a `cookie::Cookie` holding the encrypted value; set its path,
+/// `Secure`, `HttpOnly` and so on as usual.
+pub fn encrypted_cookie(key: &Key, name: &str, value: &[u8]) -> Result<cookie::Cookie<'static>, CookieError> {
+    Ok(cookie::Cookie::new(String::from(name), encrypt_cookie_value(key, name, value)?))
+}
+
+/// Decrypts a cookie built with `encrypted_cookie`, under its own name.
+pub fn decrypt_cookie(key: &Key, cookie: &cookie::Cookie) -> Result<Vec<u8>, CookieError> {
+    decrypt_cookie_value(key, cookie.name(), cookie.value())
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    #[test]
+    fn cookie_value_round_trips() {
+        let key = Key { u8_array: KEY };
+        let token = encrypt_cookie_value(&key, "session", b"user=42").unwrap();
+        assert_eq!(decrypt_cookie_value(&key, "session", &token).unwrap(), b"user=42")
+    }
+
+    #[test]
+    fn value_under_another_name_fails() {
+        let key = Key { u8_array: KEY };
+        let token = encrypt_cookie_value(&key, "session", b"user=42").unwrap();
+        match decrypt_cookie_value(&key, "admin_session", &token) {
+            Ok(_) => assert!(false, "Should err CookieDecryptionError"),
+            Err(e) => match e {
+                CookieError::CookieDecryptionError(_) => assert!(true),
+                _ => assert!(false, "Should err CookieDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    fn size_stays_within_the_cookie_limit() {
+        let key = Key { u8_array: KEY };
+        let token = encrypt_cookie_value(&key, "session", &[7u8; 3000]).unwrap();
+        assert_eq!(token.len(), encrypted_cookie_value_len(3000));
+        assert!("session".len() + 1 + token.len() <= MAX_COOKIE_SIZE);
+        match encrypt_cookie_value(&key, "session", &[7u8; 3100]) {
+            Ok(_) => assert!(false, "Should err CookieTooLargeError"),
+            Err(e) => match e {
+                CookieError::CookieTooLargeError => assert!(true),
+                _ => assert!(false, "Should err CookieTooLargeError")
+            }
+        }
+    }
+
+    #[test]
+    fn only_url_safe_characters_appear() {
+        let key = Key { u8_array: KEY };
+        for len in 0..64 {
+            let token = encrypt_cookie_value(&key, "session", &vec![0xffu8; len]).unwrap();
+            assert_eq!(token.len(), encrypted_cookie_value_len(len));
+            assert!(token.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_'), "{}", token)
+        }
+    }
+
+    #[test]
+    fn cookie_crate_integration_round_trips() {
+        let key = Key { u8_array: KEY };
+        let cookie = encrypted_cookie(&key, "session", b"user=42").unwrap();
+        let parsed = cookie::Cookie::parse(cookie.to_string()).unwrap();
+        assert_eq!(decrypt_cookie(&key, &parsed).unwrap(), b"user=42")
+    }
+}
@@ -0,0 +1,272 @@
+//! Column-level encryption of CSV files, for exports where a few columns
+//! are sensitive and the rest must stay readable.
+//!
+//! `encrypt_csv_columns` copies the header and every row, replacing the
+//! cells of the named columns with combined tokens (see `token`);
+//! `decrypt_csv_columns` reverses it. `encrypt_csv_indices` and
+//! `decrypt_csv_indices` select columns by 0-based index instead, for
+//! files without a header row. Rows are read and written one at a time.
+//!
+//! Column order and every other cell are kept as they are, bytes
+//! included, and empty cells stay empty. Quoting is redone by the `csv`
+//! writer: cells holding a delimiter, quote or newline are quoted, others
+//! aren't, and records end with `\n`. Otherwise a file written that way
+//! comes back byte for byte.
+//!
+//! Errors give the row, counting from 1 and including the header row, and
+//! the column index, never the cell's value.
+
+use std::{error, fmt, io};
+
+use csv::{ByteRecord, ReaderBuilder, Writer};
+
+use crate::token::{decrypt_token, encrypt_to_token, InvalidTokenError, TokenError};
+use crate::{EncryptionError, Key};
+
+#[derive(Debug)]
+pub enum CsvError {
+    /// The input isn't valid CSV, e.g. rows of different lengths, or reading or writing failed.
+    CsvFormatError(csv::Error),
+    /// No header has this name.
+    UnknownColumnError(String),
+    /// The row has no cell at this column index.
+    MissingCsvColumnError(u64, usize),
+    CsvEncryptionError(u64, usize, EncryptionError),
+    /// The cell isn't a token, or doesn't decrypt with this key.
+    CsvCellDecryptionError(u64, usize, TokenError)
+}
+
+impl fmt::Display for CsvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CsvError::CsvFormatError(e) => write!(f, "{}", e),
+            CsvError::UnknownColumnError(name) => write!(f, "No column named {:?}", name),
+            CsvError::MissingCsvColumnError(row, column) => write!(f, "Row {} has no column {}", row, column),
+            CsvError::CsvEncryptionError(row, column, e) => write!(f, "Could not encrypt row {} column {}: {}", row, column, e),
+            CsvError::CsvCellDecryptionError(row, column, e) => write!(f, "Could not decrypt row {} column {}: {}", row, column, e),
+        }
+    }
+}
+
+impl error::Error for CsvError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CsvError::CsvFormatError(e) => Some(e),
+            CsvError::CsvEncryptionError(_, _, e) => Some(e),
+            CsvError::CsvCellDecryptionError(_, _, e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<csv::Error> for CsvError {
+    fn from(e: csv::Error) -> CsvError {
+        CsvError::CsvFormatError(e)
+    }
+}
+
+enum Columns<'a> {
+    Named(&'a [&'a str]),
+    Indexed(&'a [usize])
+}
+
+/// Copies `reader` to `writer`, replacing each non-empty selected cell with
+/// `map(row, column, cell)`.
+fn rewrite<R, W, F>(reader: R, writer: W, columns: Columns, mut map: F) -> Result<(), CsvError>
+where
+    R: io::Read,
+    W: io::Write,
+    F: FnMut(u64, usize, &[u8]) -> Result<Vec<u8>, CsvError>
+{
+    let mut reader = ReaderBuilder::new().has_headers(false).from_reader(reader);
+    let mut writer = Writer::from_writer(writer);
+    let mut record = ByteRecord::new();
+    let mut row = 0;
+    let selected = match columns {
+        Columns::Indexed(indices) => indices.to_vec(),
+        Columns::Named(names) => {
+            let has_header = reader.read_byte_record(&mut record)?;
+            let mut indices = Vec::with_capacity(names.len());
+            for name in names {
+                match record.iter().position(|header| header == name.as_bytes()) {
+                    Some(index) if has_header => indices.push(index),
+                    _ => return Err(CsvError::UnknownColumnError(String::from(*name)))
+                }
+            }
+            if has_header {
+                row = 1;
+                writer.write_byte_record(&record)?;
+            }
+            indices
+        }
+    };
+    let mut output = ByteRecord::new();
+    while reader.read_byte_record(&mut record)? {
+        row += 1;
+        if let Some(column) = selected.iter().find(|column| **column >= record.len()) {
+            return Err(CsvError::MissingCsvColumnError(row, *column));
+        }
+        output.clear();
+        for (column, cell) in record.iter().enumerate() {
+            if cell.is_empty() || !selected.contains(&column) {
+                output.push_field(cell);
+            } else {
+                output.push_field(&map(row, column, cell)?);
+            }
+        }
+        writer.write_byte_record(&output)?;
+    }
+    writer.flush().map_err(csv::Error::from)?;
+    Ok(())
+}
+
+fn encrypt_cell(key: &Key, row: u64, column: usize, cell: &[u8]) -> Result<Vec<u8>, CsvError> {
+    match encrypt_to_token(key, cell) {
+        Ok(token) => Ok(token.into_bytes()),
+        Err(e) => Err(CsvError::CsvEncryptionError(row, column, e))
+    }
+}
+
+fn decrypt_cell(key: &Key, row: u64, column: usize, cell: &[u8]) -> Result<Vec<u8>, CsvError> {
+    // Tokens are ASCII, so a cell that isn't UTF-8 can't start with the prefix.
+    let result = match std::str::from_utf8(cell) {
+        Ok(token) => decrypt_token(key, token),
+        Err(_) => Err(TokenError::InvalidTokenError(InvalidTokenError::InvalidTokenPrefixError))
+    };
+    result.map_err(|e| CsvError::CsvCellDecryptionError(row, column, e))
+}
+
+/// Encrypts the cells of the `columns` named in the header row, see the module docs.
+pub fn encrypt_csv_columns<R: io::Read, W: io::Write>(reader: R, writer: W, key: &Key, columns: &[&str]) -> Result<(), CsvError> {
+    rewrite(reader, writer, Columns::Named(columns), |row, column, cell| encrypt_cell(key, row, column, cell))
+}
+
+/// Reverses `encrypt_csv_columns`.
+pub fn decrypt_csv_columns<R: io::Read, W: io::Write>(reader: R, writer: W, key: &Key, columns: &[&str]) -> Result<(), CsvError> {
+    rewrite(reader, writer, Columns::Named(columns), |row, column, cell| decrypt_cell(key, row, column, cell))
+}
+
+/// Encrypts the cells at the 0-based `indices` of every row, for files without a header row.
+pub fn encrypt_csv_indices<R: io::Read, W: io::Write>(reader: R, writer: W, key: &Key, indices: &[usize]) -> Result<(), CsvError> {
+    rewrite(reader, writer, Columns::Indexed(indices), |row, column, cell| encrypt_cell(key, row, column, cell))
+}
+
+/// Reverses `encrypt_csv_indices`.
+pub fn decrypt_csv_indices<R: io::Read, W: io::Write>(reader: R, writer: W, key: &Key, indices: &[usize]) -> Result<(), CsvError> {
+    rewrite(reader, writer, Columns::Indexed(indices), |row, column, cell| decrypt_cell(key, row, column, cell))
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+    const FIXTURE: &str = include_str!("../tests/fixtures/customers.csv");
+
+    fn records(csv: &[u8]) -> Vec<Vec<String>> {
+        let mut reader = ReaderBuilder::new().has_headers(false).from_reader(csv);
+        reader.records().map(|record| record.unwrap().iter().map(String::from).collect()).collect()
+    }
+
+    fn encrypt_fixture(key: &Key) -> Vec<u8> {
+        let mut encrypted = Vec::new();
+        encrypt_csv_columns(FIXTURE.as_bytes(), &mut encrypted, key, &["email", "notes"]).unwrap();
+        encrypted
+    }
+
+    #[test]
+    fn named_columns_round_trip_and_others_are_untouched() {
+        let key = Key { u8_array: KEY };
+        let encrypted = encrypt_fixture(&key);
+        let text = String::from_utf8(encrypted.clone()).unwrap();
+        for secret in ["jane@example.com", "j.smith@example.com", "not phone", "line two"].iter() {
+            assert!(!text.contains(secret), "{} should be encrypted", secret)
+        }
+        let (original, rewritten) = (records(FIXTURE.as_bytes()), records(&encrypted));
+        assert_eq!(rewritten.len(), original.len());
+        assert_eq!(rewritten[0], original[0], "the header is kept");
+        for (before, after) in original.iter().zip(rewritten.iter()).skip(1) {
+            for column in [0, 1, 4].iter() {
+                assert_eq!(after[*column], before[*column])
+            }
+            for column in [2, 3].iter() {
+                assert_eq!(after[*column].is_empty(), before[*column].is_empty(), "empty cells stay empty");
+                assert!(before[*column].is_empty() || after[*column] != before[*column])
+            }
+        }
+        assert!(rewritten[1][2].starts_with("saes1:"));
+
+        let mut decrypted = Vec::new();
+        decrypt_csv_columns(&encrypted[..], &mut decrypted, &key, &["email", "notes"]).unwrap();
+        assert_eq!(String::from_utf8(decrypted).unwrap(), FIXTURE)
+    }
+
+    #[test]
+    fn headerless_files_select_by_index() {
+        let key = Key { u8_array: KEY };
+        let input = "1,\"Doe, Jane\",secret one\n2,Bob,\"secret\ntwo\"\n";
+        let mut encrypted = Vec::new();
+        encrypt_csv_indices(input.as_bytes(), &mut encrypted, &key, &[2]).unwrap();
+        let rewritten = records(&encrypted);
+        assert_eq!((rewritten[0][1].as_str(), rewritten[1][0].as_str()), ("Doe, Jane", "2"));
+        assert!(!String::from_utf8(encrypted.clone()).unwrap().contains("secret"));
+        let mut decrypted = Vec::new();
+        decrypt_csv_indices(&encrypted[..], &mut decrypted, &key, &[2]).unwrap();
+        assert_eq!(String::from_utf8(decrypted).unwrap(), input)
+    }
+
+    #[test]
+    fn unknown_column_name_is_an_error() {
+        match encrypt_csv_columns(FIXTURE.as_bytes(), Vec::new(), &Key { u8_array: KEY }, &["email", "phone"]) {
+            Ok(_) => assert!(false, "Should err UnknownColumnError"),
+            Err(e) => match e {
+                CsvError::UnknownColumnError(name) => assert_eq!(name, "phone"),
+                _ => assert!(false, "Should err UnknownColumnError, got {}", e)
+            }
+        }
+        match encrypt_csv_indices(FIXTURE.as_bytes(), Vec::new(), &Key { u8_array: KEY }, &[5]) {
+            Ok(_) => assert!(false, "Should err MissingCsvColumnError"),
+            Err(e) => match e {
+                CsvError::MissingCsvColumnError(1, 5) => assert!(true),
+                _ => assert!(false, "Should err MissingCsvColumnError, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn failing_cells_report_their_row() {
+        let key = Key { u8_array: KEY };
+        let encrypted = String::from_utf8(encrypt_fixture(&key)).unwrap();
+        // Row 4 is the third record; row 3 spans two lines.
+        let corrupted = encrypted.replacen("\n3,\"Smith, J.\",saes1:", "\n3,\"Smith, J.\",saes1:AAAA", 1);
+        match decrypt_csv_columns(corrupted.as_bytes(), Vec::new(), &key, &["email", "notes"]) {
+            Ok(_) => assert!(false, "Should err CsvCellDecryptionError"),
+            Err(e) => {
+                assert!(!e.to_string().contains("smith"));
+                match e {
+                    CsvError::CsvCellDecryptionError(4, 2, TokenError::TokenDecryptionError(_)) => assert!(true),
+                    _ => assert!(false, "Should err CsvCellDecryptionError at row 4, got {}", e)
+                }
+            }
+        }
+        match decrypt_csv_columns(FIXTURE.as_bytes(), Vec::new(), &key, &["email"]) {
+            Ok(_) => assert!(false, "Should err CsvCellDecryptionError"),
+            Err(e) => match e {
+                CsvError::CsvCellDecryptionError(2, 2, TokenError::InvalidTokenError(_)) => assert!(true),
+                _ => assert!(false, "Should err CsvCellDecryptionError at row 2, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn ragged_rows_are_format_errors() {
+        match encrypt_csv_indices("a,b\nc\n".as_bytes(), Vec::new(), &Key { u8_array: KEY }, &[0]) {
+            Ok(_) => assert!(false, "Should err CsvFormatError"),
+            Err(e) => match e {
+                CsvError::CsvFormatError(_) => assert!(true),
+                _ => assert!(false, "Should err CsvFormatError, got {}", e)
+            }
+        }
+    }
+}
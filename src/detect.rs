@@ -0,0 +1,210 @@
+//! Recognising which of the crate's text formats a value is in.
+//!
+//! `detect_format` and `decrypt_auto` try the formats in this order, on the
+//! input with surrounding whitespace trimmed:
+//!
+//! 1. `ConfigMarker`: `ENC[<token>]`, see `config`;
+//! 2. `Token`: a combined token in any encoding, footer included, see `token`;
+//! 3. `JsonEnvelope` (`json` feature): the serde form of `EncryptedAndIv`;
+//! 4. `Prefixed`: `<prefix>:v<N>:<base64>`, any prefix, see `prefixed`;
+//! 5. `HexPair`: `<iv, 24 hex digits>:<ciphertext and tag, hex>`.
+//!
+//! A format is tried by parsing only: an input that isn't structurally a
+//! candidate, such as a token prefix followed by something that doesn't
+//! decode, is never decrypted. The shapes are nearly disjoint; the order
+//! settles the rest, e.g. `saes1:v1:...` starts like a token but only
+//! parses as a prefixed string. `decrypt_auto` decrypts the first format
+//! that parses and, if that fails, the next one, if any.
+
+use std::{error, fmt};
+
+use crate::encoding::Encoding;
+use crate::prefixed::from_prefixed_string;
+use crate::token::{parse_token, token_aad};
+use crate::{decrypt_bytes_with_aad, DecryptionError, Encrypted, EncryptedAndIv, Iv, IV_LEN, TAG_LEN};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectedFormat {
+    ConfigMarker,
+    Token,
+    #[cfg(feature = "json")]
+    JsonEnvelope,
+    Prefixed,
+    HexPair
+}
+
+/// Every format, in the order they are tried.
+pub const DETECTED_FORMATS: &[DetectedFormat] = &[
+    DetectedFormat::ConfigMarker,
+    DetectedFormat::Token,
+    #[cfg(feature = "json")]
+    DetectedFormat::JsonEnvelope,
+    DetectedFormat::Prefixed,
+    DetectedFormat::HexPair
+];
+
+#[derive(Debug, Clone)]
+pub enum AutoDecryptError {
+    /// No format parses; holds every format tried.
+    UnrecognizedFormatError(Vec<DetectedFormat>),
+    /// The formats that parsed, none of which decrypted, and the last
+    /// decryption error.
+    AutoDecryptionError(Vec<DetectedFormat>, DecryptionError)
+}
+
+impl fmt::Display for AutoDecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AutoDecryptError::UnrecognizedFormatError(attempted) => write!(f, "Unrecognized format, tried {:?}", attempted),
+            AutoDecryptError::AutoDecryptionError(attempted, e) => write!(f, "{} (as {:?})", e, attempted),
+        }
+    }
+}
+
+impl error::Error for AutoDecryptError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            AutoDecryptError::AutoDecryptionError(_, e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+fn parse_token_and_aad(token: &str) -> Option<(EncryptedAndIv, Vec<u8>)> {
+    let (encrypted_and_iv, footer) = parse_token(token).ok()?;
+    Some((encrypted_and_iv, token_aad(footer.as_deref())))
+}
+
+/// The envelope and the associated data to decrypt it with.
+fn parse(format: DetectedFormat, input: &str) -> Option<(EncryptedAndIv, Vec<u8>)> {
+    match format {
+        DetectedFormat::ConfigMarker => {
+            let token = input.strip_prefix("ENC[")?.strip_suffix(']')?;
+            parse_token_and_aad(token)
+        },
+        DetectedFormat::Token => parse_token_and_aad(input),
+        #[cfg(feature = "json")]
+        DetectedFormat::JsonEnvelope => {
+            if !input.starts_with('{') {
+                return None;
+            }
+            serde_json::from_str(input).ok().map(|encrypted_and_iv| (encrypted_and_iv, Vec::new()))
+        },
+        DetectedFormat::Prefixed => {
+            let prefix = input.split(':').next()?;
+            if prefix.is_empty() || !prefix.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_') {
+                return None;
+            }
+            from_prefixed_string(prefix, input).ok().map(|(_, encrypted_and_iv)| (encrypted_and_iv, Vec::new()))
+        },
+        DetectedFormat::HexPair => {
+            let (iv, ciphertext) = input.split_at_checked(IV_LEN * 2)?;
+            let ciphertext = ciphertext.strip_prefix(':')?;
+            if ciphertext.len() < TAG_LEN * 2 {
+                return None;
+            }
+            let iv = Iv::decode_with(iv, Encoding::Hex).ok()?;
+            let encrypted = Encrypted::decode_with(ciphertext, Encoding::Hex).ok()?;
+            Some((EncryptedAndIv::from_parts(iv, encrypted), Vec::new()))
+        }
+    }
+}
+
+/// The first format `input` parses as, without decrypting anything.
+pub fn detect_format(input: &str) -> Option<DetectedFormat> {
+    let input = input.trim();
+    DETECTED_FORMATS.iter().copied().find(|format| parse(*format, input).is_some())
+}
+
+/// Decrypts `input` in whichever format it is, returning that format.
+pub fn decrypt_auto(key: &crate::Key, input: &str) -> Result<(Vec<u8>, DetectedFormat), AutoDecryptError> {
+    let input = input.trim();
+    let mut attempted = Vec::new();
+    let mut last_error = None;
+    for format in DETECTED_FORMATS.iter().copied() {
+        let (encrypted_and_iv, aad) = match parse(format, input) {
+            Some(parsed) => parsed,
+            None => continue
+        };
+        attempted.push(format);
+        match decrypt_bytes_with_aad(key, &encrypted_and_iv, &aad) {
+            Ok(plaintext) => return Ok((plaintext, format)),
+            Err(e) => last_error = Some(e)
+        }
+    }
+    match last_error {
+        Some(e) => Err(AutoDecryptError::AutoDecryptionError(attempted, e)),
+        None => Err(AutoDecryptError::UnrecognizedFormatError(DETECTED_FORMATS.to_vec()))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::prefixed::to_prefixed_string;
+    use crate::{encrypt_bytes_with_iv, Key};
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    fn envelope() -> EncryptedAndIv {
+        encrypt_bytes_with_iv(&Key { u8_array: KEY }, Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap()
+    }
+
+    fn fixtures() -> Vec<(String, DetectedFormat)> {
+        let encrypted_and_iv = envelope();
+        let hex_pair = format!("{}:{}", encrypted_and_iv.iv.encode_with(Encoding::Hex), encrypted_and_iv.encrypted.encode_with(Encoding::Hex));
+        vec![
+            (format!("ENC[{}]", encrypted_and_iv.to_token()), DetectedFormat::ConfigMarker),
+            (encrypted_and_iv.to_token(), DetectedFormat::Token),
+            (encrypted_and_iv.to_token_with(Encoding::Base32), DetectedFormat::Token),
+            #[cfg(feature = "json")]
+            (serde_json::to_string(&encrypted_and_iv).unwrap(), DetectedFormat::JsonEnvelope),
+            (format!("  {}\n", to_prefixed_string("vault", 3, &encrypted_and_iv)), DetectedFormat::Prefixed),
+            (hex_pair, DetectedFormat::HexPair)
+        ]
+    }
+
+    #[test]
+    fn each_format_is_routed_to_its_parser() {
+        for (input, format) in fixtures() {
+            assert_eq!(detect_format(&input), Some(format), "{}", input);
+            assert_eq!(decrypt_auto(&Key { u8_array: KEY }, &input).unwrap(), (b"This is a text.".to_vec(), format))
+        }
+    }
+
+    #[test]
+    fn token_lookalikes_follow_the_priority_order() {
+        let prefixed = to_prefixed_string("saes1", 1, &envelope());
+        assert!(prefixed.starts_with(crate::token::TOKEN_PREFIX));
+        assert_eq!(detect_format(&prefixed), Some(DetectedFormat::Prefixed));
+
+        let marker = format!("ENC[{}]", to_prefixed_string("vault", 1, &envelope()));
+        assert_eq!(detect_format(&marker), None)
+    }
+
+    #[test]
+    fn garbage_lists_the_formats_tried() {
+        for input in ["", "hello world", "saes1:not base64!", "ENC[saes1:AAAA]", "{\"iv\": 1}", "vault:v1:AAAA", "0123:abcd"].iter() {
+            assert_eq!(detect_format(input), None, "{}", input);
+            match decrypt_auto(&Key { u8_array: KEY }, input) {
+                Ok(_) => assert!(false, "Should err UnrecognizedFormatError"),
+                Err(e) => match e {
+                    AutoDecryptError::UnrecognizedFormatError(attempted) => assert_eq!(attempted, DETECTED_FORMATS.to_vec()),
+                    _ => assert!(false, "Should err UnrecognizedFormatError, got {}", e)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_key_reports_the_parsed_format() {
+        match decrypt_auto(&Key { u8_array: [0u8; 32] }, &envelope().to_token()) {
+            Ok(_) => assert!(false, "Should err AutoDecryptionError"),
+            Err(e) => match e {
+                AutoDecryptError::AutoDecryptionError(attempted, DecryptionError::GenericDecryptionError) => assert_eq!(attempted, vec![DetectedFormat::Token]),
+                _ => assert!(false, "Should err AutoDecryptionError, got {}", e)
+            }
+        }
+    }
+}
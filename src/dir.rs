@@ -0,0 +1,528 @@
+//! Encrypting a directory tree, with an optional integrity manifest.
+//!
+//! Every file is encrypted to the same relative path in the destination,
+//! as `iv || ciphertext and tag`, with its relative path as associated data:
+//! a file moved or renamed in the encrypted tree fails to decrypt.
+//!
+//! Per-file authentication can't tell that a file was dropped, or that one
+//! was added. For that, `encrypt_dir` can write a manifest, `MANIFEST_NAME`
+//! at the root, listing every file with its ciphertext length and SHA-256
+//! digest. It is encrypted under the same key, so it can't be edited to
+//! match a changed tree, and `decrypt_dir` checks the tree against it.
+//!
+//! The manifest plaintext is versioned: a `MANIFEST_HEADER` line, then one
+//! `<sha256 hex> <length> <path>` line per file, sorted by path, with `/`
+//! separators.
+//!
+//! The `_parallel` variants spread files over `jobs` threads. Their output
+//! and reports are the same as the serial functions', in path order. On
+//! WASI, which has no threads, they run serially.
+//!
+//! Both stop at the first I/O error: no new file is started, files being
+//! processed are finished, and the first error in path order is returned.
+//! There is no keep-going mode; files that fail to decrypt are not errors
+//! but land in `DirReport::failed`, so they never stop the others.
+//!
+//! With the `tracing` feature, each call is a span, entered by every job,
+//! with an event per file: its lengths and outcome, never its path, which
+//! can say as much as its contents.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::{error, fmt, thread};
+
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "rng")]
+use crate::encrypt_bytes_with_aad;
+use crate::{decrypt_bytes_with_aad, DecryptionError, Encrypted, EncryptedAndIv, EncryptionError, Iv, Key, IV_LEN};
+
+pub const MANIFEST_NAME: &str = ".saes-manifest";
+
+pub const MANIFEST_HEADER: &str = "simple-aes256-gcm manifest v1";
+
+const MANIFEST_AAD: &[u8] = b"simple-aes256-gcm manifest";
+
+const FILE_AAD_PREFIX: &str = "simple-aes256-gcm file:";
+
+#[derive(Debug)]
+pub enum DirError {
+    DirIoError(io::Error),
+    /// Not valid UTF-8, contains a newline, or is the manifest's name.
+    InvalidPathError(PathBuf),
+    DirEncryptionError(EncryptionError),
+    /// Wrong key or tampered manifest.
+    ManifestDecryptionError(DecryptionError),
+    /// The manifest decrypted but isn't a known version or is malformed.
+    InvalidManifestError
+}
+
+impl fmt::Display for DirError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DirError::DirIoError(e) => write!(f, "{}", e),
+            DirError::InvalidPathError(path) => write!(f, "Unsupported path {}", path.display()),
+            DirError::DirEncryptionError(e) => write!(f, "{}", e),
+            DirError::ManifestDecryptionError(e) => write!(f, "Could not decrypt the manifest: {}", e),
+            DirError::InvalidManifestError => write!(f, "Invalid manifest"),
+        }
+    }
+}
+
+impl error::Error for DirError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DirError::DirIoError(e) => Some(e),
+            DirError::DirEncryptionError(e) => Some(e),
+            DirError::ManifestDecryptionError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for DirError {
+    fn from(e: io::Error) -> DirError {
+        DirError::DirIoError(e)
+    }
+}
+
+/// Relative paths use `/` separators.
+#[derive(Debug, Default)]
+pub struct DirReport {
+    /// Whether a manifest was found and the tree was checked against it.
+    pub manifest_verified: bool,
+    pub decrypted: Vec<String>,
+    /// In the manifest, not in the tree.
+    pub missing: Vec<String>,
+    /// In the tree, not in the manifest. Not decrypted.
+    pub extra: Vec<String>,
+    /// Length or digest differs from the manifest. Not decrypted.
+    pub mismatched: Vec<String>,
+    /// Could not be decrypted: wrong key, tampered or moved.
+    pub failed: Vec<String>
+}
+
+impl DirReport {
+    /// Whether the tree was verified against a manifest and every file in it decrypted.
+    pub fn is_complete(&self) -> bool {
+        self.manifest_verified && self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty() && self.failed.is_empty()
+    }
+}
+
+struct ManifestEntry {
+    len: u64,
+    digest: String
+}
+
+fn digest_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn file_aad(relative: &str) -> Vec<u8> {
+    format!("{}{}", FILE_AAD_PREFIX, relative).into_bytes()
+}
+
+/// Files under `root`, by relative path, sorted.
+fn list_files(root: &Path) -> Result<Vec<String>, DirError> {
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in fs::read_dir(root.join(&relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let name = match path.to_str() {
+                Some(name) if !name.contains('\n') => name.replace(std::path::MAIN_SEPARATOR, "/"),
+                _ => return Err(DirError::InvalidPathError(path))
+            };
+            files.push(name);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn write_file(root: &Path, relative: &str, contents: &[u8]) -> Result<(), DirError> {
+    let path = root.join(relative);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+#[cfg(feature = "rng")]
+fn to_file_bytes(encrypted_and_iv: EncryptedAndIv) -> Vec<u8> {
+    let mut bytes = encrypted_and_iv.iv.u8_array.to_vec();
+    bytes.extend(encrypted_and_iv.encrypted.u8_vec);
+    bytes
+}
+
+fn decrypt_file_bytes(key: &Key, bytes: &[u8], aad: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+    if bytes.len() < IV_LEN {
+        return Err(DecryptionError::GenericDecryptionError);
+    }
+    let mut iv = [0u8; IV_LEN];
+    iv.copy_from_slice(&bytes[..IV_LEN]);
+    let encrypted_and_iv = EncryptedAndIv::from_parts(Iv { u8_array: iv }, Encrypted { u8_vec: bytes[IV_LEN..].to_vec() });
+    decrypt_bytes_with_aad(key, &encrypted_and_iv, aad)
+}
+
+fn parse_manifest(plaintext: &[u8]) -> Result<BTreeMap<String, ManifestEntry>, DirError> {
+    let text = std::str::from_utf8(plaintext).map_err(|_| DirError::InvalidManifestError)?;
+    let mut lines = text.lines();
+    if lines.next() != Some(MANIFEST_HEADER) {
+        return Err(DirError::InvalidManifestError);
+    }
+    let mut entries = BTreeMap::new();
+    for line in lines {
+        let mut fields = line.splitn(3, ' ');
+        let (digest, len, path) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(digest), Some(len), Some(path)) if digest.len() == 64 => (digest, len, path),
+            _ => return Err(DirError::InvalidManifestError)
+        };
+        let len = len.parse().map_err(|_| DirError::InvalidManifestError)?;
+        if entries.insert(path.to_string(), ManifestEntry { len, digest: digest.to_string() }).is_some() {
+            return Err(DirError::InvalidManifestError);
+        }
+    }
+    Ok(entries)
+}
+
+/// Runs `work` on each item over `jobs` threads (0 for one per CPU),
+/// returning the results in item order. After an error no new item is
+/// started, and the first error in item order is returned.
+fn run_jobs<T, R, F>(items: &[T], jobs: usize, work: F) -> Result<Vec<R>, DirError>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R, DirError> + Sync
+{
+    let jobs = match jobs {
+        0 => thread::available_parallelism().map_or(1, |count| count.get()),
+        jobs => jobs
+    }.min(items.len());
+    if jobs <= 1 || cfg!(target_os = "wasi") {
+        return items.iter().map(work).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<Option<Result<R, DirError>>>>());
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                // Checked before claiming: a claimed item is always run.
+                if failed.load(Ordering::SeqCst) {
+                    break;
+                }
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                if index >= items.len() {
+                    break;
+                }
+                let result = work(&items[index]);
+                if result.is_err() {
+                    failed.store(true, Ordering::SeqCst);
+                }
+                results.lock().unwrap_or_else(|e| e.into_inner())[index] = Some(result);
+            });
+        }
+    });
+    // Unclaimed items only follow an error; the first one in item order is returned.
+    let mut outputs = Vec::with_capacity(items.len());
+    for result in results.into_inner().unwrap_or_else(|e| e.into_inner()).into_iter().flatten() {
+        outputs.push(result?);
+    }
+    Ok(outputs)
+}
+
+/// Encrypts every file under `source` into `destination`, returning the
+/// number of files. With `manifest`, also writes `MANIFEST_NAME`.
+#[cfg(feature = "rng")]
+pub fn encrypt_dir(key: &Key, source: &Path, destination: &Path, manifest: bool) -> Result<usize, DirError> {
+    encrypt_dir_parallel(key, source, destination, manifest, 1)
+}
+
+/// `encrypt_dir` over `jobs` threads, 0 for one per CPU.
+#[cfg(feature = "rng")]
+pub fn encrypt_dir_parallel(key: &Key, source: &Path, destination: &Path, manifest: bool, jobs: usize) -> Result<usize, DirError> {
+    let files = list_files(source)?;
+    if let Some(relative) = files.iter().find(|relative| *relative == MANIFEST_NAME) {
+        return Err(DirError::InvalidPathError(PathBuf::from(relative)));
+    }
+    #[cfg(feature = "tracing")]
+    let span = tracing::debug_span!("encrypt_dir", operation = "encrypt", key_fingerprint = %key.fingerprint(), jobs);
+    #[cfg(feature = "tracing")]
+    let _entered = span.enter();
+    let lines = run_jobs(&files, jobs, |relative| {
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        let plaintext = fs::read(source.join(relative))?;
+        let encrypted_and_iv = encrypt_bytes_with_aad(key, &plaintext, &file_aad(relative)).map_err(DirError::DirEncryptionError)?;
+        let bytes = to_file_bytes(encrypted_and_iv);
+        write_file(destination, relative, &bytes)?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(plaintext_len = plaintext.len(), file_len = bytes.len(), "file encrypted");
+        Ok(format!("{} {} {}\n", digest_hex(&bytes), bytes.len(), relative))
+    })?;
+    if manifest {
+        let manifest_text = format!("{}\n{}", MANIFEST_HEADER, lines.concat());
+        let encrypted_and_iv = encrypt_bytes_with_aad(key, manifest_text.as_bytes(), MANIFEST_AAD).map_err(DirError::DirEncryptionError)?;
+        write_file(destination, MANIFEST_NAME, &to_file_bytes(encrypted_and_iv))?;
+    }
+    #[cfg(feature = "tracing")]
+    tracing::debug!(files = files.len(), manifest, "directory encrypted");
+    Ok(files.len())
+}
+
+enum Outcome {
+    Decrypted,
+    Extra,
+    Mismatched,
+    Failed
+}
+
+/// Decrypts a tree written by `encrypt_dir` into `destination`.
+///
+/// With a manifest, only files matching their entry are decrypted, and
+/// the report lists the missing, extra and mismatched ones; without one,
+/// every file is decrypted and `manifest_verified` is false. Per-file
+/// problems go to the report, errors are for I/O and the manifest itself.
+pub fn decrypt_dir(key: &Key, source: &Path, destination: &Path) -> Result<DirReport, DirError> {
+    decrypt_dir_parallel(key, source, destination, 1)
+}
+
+/// `decrypt_dir` over `jobs` threads, 0 for one per CPU.
+pub fn decrypt_dir_parallel(key: &Key, source: &Path, destination: &Path, jobs: usize) -> Result<DirReport, DirError> {
+    #[cfg(feature = "tracing")]
+    let span = tracing::debug_span!("decrypt_dir", operation = "decrypt", key_fingerprint = %key.fingerprint(), jobs);
+    #[cfg(feature = "tracing")]
+    let _entered = span.enter();
+    let mut files = list_files(source)?;
+    let mut report = DirReport::default();
+    let manifest = match files.iter().position(|relative| relative == MANIFEST_NAME) {
+        Some(index) => {
+            files.remove(index);
+            let plaintext = decrypt_file_bytes(key, &fs::read(source.join(MANIFEST_NAME))?, MANIFEST_AAD).map_err(DirError::ManifestDecryptionError)?;
+            Some(parse_manifest(&plaintext)?)
+        },
+        None => None
+    };
+    report.manifest_verified = manifest.is_some();
+    if let Some(manifest) = &manifest {
+        report.missing = manifest.keys().filter(|relative| files.binary_search(relative).is_err()).cloned().collect();
+    }
+    let outcomes = run_jobs(&files, jobs, |relative| {
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        let bytes = fs::read(source.join(relative))?;
+        if let Some(manifest) = &manifest {
+            match manifest.get(relative) {
+                None => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(file_len = bytes.len(), outcome = "extra", "file not in the manifest");
+                    return Ok(Outcome::Extra);
+                },
+                Some(entry) if entry.len != bytes.len() as u64 || entry.digest != digest_hex(&bytes) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(file_len = bytes.len(), outcome = "mismatched", "file differs from the manifest");
+                    return Ok(Outcome::Mismatched);
+                },
+                Some(_) => {}
+            }
+        }
+        match decrypt_file_bytes(key, &bytes, &file_aad(relative)) {
+            Ok(plaintext) => {
+                write_file(destination, relative, &plaintext)?;
+                #[cfg(feature = "tracing")]
+                tracing::debug!(file_len = bytes.len(), plaintext_len = plaintext.len(), outcome = "decrypted", "file decrypted");
+                Ok(Outcome::Decrypted)
+            },
+            Err(_e) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(file_len = bytes.len(), outcome = "failed", failure = crate::instrument::decryption_failure_class(&_e), "file decryption failed");
+                Ok(Outcome::Failed)
+            }
+        }
+    })?;
+    for (relative, outcome) in files.into_iter().zip(outcomes) {
+        match outcome {
+            Outcome::Decrypted => report.decrypted.push(relative),
+            Outcome::Extra => report.extra.push(relative),
+            Outcome::Mismatched => report.mismatched.push(relative),
+            Outcome::Failed => report.failed.push(relative)
+        }
+    }
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        manifest_verified = report.manifest_verified, decrypted = report.decrypted.len(), missing = report.missing.len(),
+        extra = report.extra.len(), mismatched = report.mismatched.len(), failed = report.failed.len(), "directory decrypted"
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+#[cfg(feature = "rng")]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    /// A fresh, empty directory under the system temp directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = crate::test_path(&format!("dir-{}", name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn encrypted_tree(name: &str, manifest: bool) -> PathBuf {
+        let plain = temp_dir(&format!("{}-plain", name));
+        write_file(&plain, "a.txt", b"first").unwrap();
+        write_file(&plain, "sub/b.txt", b"second").unwrap();
+        write_file(&plain, "sub/c.txt", b"third").unwrap();
+        let encrypted = temp_dir(&format!("{}-encrypted", name));
+        assert_eq!(encrypt_dir(&Key { u8_array: KEY }, &plain, &encrypted, manifest).unwrap(), 3);
+        encrypted
+    }
+
+    #[test]
+    fn round_trips_and_verifies_a_complete_tree() {
+        let encrypted = encrypted_tree("complete", true);
+        let decrypted = temp_dir("complete-decrypted");
+        let report = decrypt_dir(&Key { u8_array: KEY }, &encrypted, &decrypted).unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.decrypted, vec!["a.txt", "sub/b.txt", "sub/c.txt"]);
+        assert_eq!(fs::read(decrypted.join("sub/b.txt")).unwrap(), b"second")
+    }
+
+    #[test]
+    fn reports_missing_extra_and_mismatched_files() {
+        let encrypted = encrypted_tree("damaged", true);
+        fs::remove_file(encrypted.join("sub/b.txt")).unwrap();
+        fs::copy(encrypted.join("a.txt"), encrypted.join("stray.txt")).unwrap();
+        let mut corrupted = fs::read(encrypted.join("sub/c.txt")).unwrap();
+        corrupted[IV_LEN] ^= 1;
+        fs::write(encrypted.join("sub/c.txt"), corrupted).unwrap();
+
+        let decrypted = temp_dir("damaged-decrypted");
+        let report = decrypt_dir(&Key { u8_array: KEY }, &encrypted, &decrypted).unwrap();
+        assert!(report.manifest_verified);
+        assert!(!report.is_complete());
+        assert_eq!(report.decrypted, vec!["a.txt"]);
+        assert_eq!(report.missing, vec!["sub/b.txt"]);
+        assert_eq!(report.extra, vec!["stray.txt"]);
+        assert_eq!(report.mismatched, vec!["sub/c.txt"]);
+        assert!(report.failed.is_empty());
+        assert!(!decrypted.join("sub/c.txt").exists())
+    }
+
+    #[test]
+    fn moved_file_fails_without_manifest() {
+        let encrypted = encrypted_tree("moved", false);
+        fs::rename(encrypted.join("sub/b.txt"), encrypted.join("b.txt")).unwrap();
+        let report = decrypt_dir(&Key { u8_array: KEY }, &encrypted, &temp_dir("moved-decrypted")).unwrap();
+        assert!(!report.manifest_verified);
+        assert_eq!(report.decrypted, vec!["a.txt", "sub/c.txt"]);
+        assert_eq!(report.failed, vec!["b.txt"])
+    }
+
+    #[test]
+    fn manifest_under_another_key_fails() {
+        let encrypted = encrypted_tree("wrong-key", true);
+        match decrypt_dir(&Key { u8_array: [1u8; 32] }, &encrypted, &temp_dir("wrong-key-decrypted")) {
+            Ok(_) => assert!(false, "Should err ManifestDecryptionError"),
+            Err(e) => match e {
+                DirError::ManifestDecryptionError(_) => assert!(true),
+                _ => assert!(false, "Should err ManifestDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_manifest_version_is_rejected() {
+        match parse_manifest(b"simple-aes256-gcm manifest v2\n") {
+            Ok(_) => assert!(false, "Should err InvalidManifestError"),
+            Err(e) => match e {
+                DirError::InvalidManifestError => assert!(true),
+                _ => assert!(false, "Should err InvalidManifestError")
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_runs_match_the_serial_one() {
+        let plain = temp_dir("parallel-plain");
+        for index in 0..40 {
+            write_file(&plain, &format!("d{}/f{}.bin", index % 5, index), &vec![index as u8; index * 1000]).unwrap();
+        }
+        let serial = temp_dir("parallel-serial");
+        let parallel = temp_dir("parallel-parallel");
+        assert_eq!(encrypt_dir(&Key { u8_array: KEY }, &plain, &serial, true).unwrap(), 40);
+        assert_eq!(encrypt_dir_parallel(&Key { u8_array: KEY }, &plain, &parallel, true, 4).unwrap(), 40);
+        fs::remove_file(parallel.join("d3/f8.bin")).unwrap();
+        fs::remove_file(serial.join("d3/f8.bin")).unwrap();
+
+        let serial_out = temp_dir("parallel-serial-decrypted");
+        let parallel_out = temp_dir("parallel-parallel-decrypted");
+        let serial_report = decrypt_dir(&Key { u8_array: KEY }, &serial, &serial_out).unwrap();
+        let parallel_report = decrypt_dir_parallel(&Key { u8_array: KEY }, &parallel, &parallel_out, 4).unwrap();
+        assert_eq!(parallel_report.decrypted, serial_report.decrypted);
+        assert_eq!(parallel_report.missing, vec!["d3/f8.bin"]);
+        assert_eq!(list_files(&parallel_out).unwrap(), list_files(&serial_out).unwrap());
+        for relative in list_files(&serial_out).unwrap() {
+            assert_eq!(fs::read(parallel_out.join(&relative)).unwrap(), fs::read(serial_out.join(&relative)).unwrap(), "{}", relative)
+        }
+        assert_eq!(list_files(&serial_out).unwrap().len(), 39)
+    }
+
+    #[test]
+    fn parallel_io_errors_are_returned_not_panicked() {
+        let plain = temp_dir("io-error-plain");
+        for index in 0..64 {
+            write_file(&plain, &format!("{:02}.txt", index), b"contents").unwrap();
+        }
+        for _ in 0..5 {
+            let encrypted = temp_dir("io-error-encrypted");
+            // A directory in the way makes every other file fail to write.
+            for index in (1..64).step_by(2) {
+                fs::create_dir_all(encrypted.join(format!("{:02}.txt", index))).unwrap();
+            }
+            match encrypt_dir_parallel(&Key { u8_array: KEY }, &plain, &encrypted, false, 4) {
+                Ok(_) => assert!(false, "Should err DirIoError"),
+                Err(e) => match e {
+                    DirError::DirIoError(_) => assert!(true),
+                    _ => assert!(false, "Should err DirIoError, got {}", e)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn run_jobs_returns_the_first_error_in_item_order() {
+        let items: Vec<usize> = (0..16).collect();
+        let result = run_jobs(&items, 4, |&item| match item {
+            0 => {
+                thread::sleep(std::time::Duration::from_millis(50));
+                Err(DirError::InvalidPathError(PathBuf::from("0")))
+            },
+            1 => Err(DirError::InvalidPathError(PathBuf::from("1"))),
+            _ => Ok(item)
+        });
+        match result {
+            Ok(_) => assert!(false, "Should err InvalidPathError"),
+            Err(e) => match e {
+                DirError::InvalidPathError(path) => assert_eq!(path, PathBuf::from("0")),
+                _ => assert!(false, "Should err InvalidPathError, got {}", e)
+            }
+        }
+        assert_eq!(run_jobs(&items, 4, |&item| Ok(item * 2)).unwrap(), items.iter().map(|item| item * 2).collect::<Vec<_>>())
+    }
+}
@@ -0,0 +1,344 @@
+//! Encrypted dotenv files (`.env.enc`).
+//!
+//! `encrypt_file` replaces each value with an `ENC[<token>]` marker (see
+//! `config`) and copies names, `export` prefixes, comments, blank lines
+//! and inline comments as they are. The marker holds the value exactly as
+//! written, quotes included, so `decrypt_file` gives back the original
+//! file byte for byte. `load_encrypted` returns the decrypted pairs with
+//! quotes removed, and never touches the process environment.
+//!
+//! Values can be unquoted (ending at ` #` or the end of the line), single
+//! quoted (literal) or double quoted (with `\n`, `\r`, `\t`, `\"` and
+//! `\\` escapes); quoted values can span lines. Empty values and values
+//! that are already markers are left as they are. Errors name the line
+//! and the variable, never its value.
+
+use std::ops::Range;
+use std::path::Path;
+use std::{error, fmt, fs, io};
+
+use crate::config::{MARKER_END, MARKER_START};
+use crate::token::{decrypt_token, TokenError};
+use crate::Key;
+#[cfg(feature = "rng")]
+use crate::{config::encrypt_value_to_marker, EncryptionError};
+
+#[derive(Debug)]
+pub enum DotenvError {
+    DotenvIoError(io::Error),
+    /// The line is neither blank, a comment nor `[export ]NAME=value`.
+    InvalidDotenvLineError(usize),
+    /// A quoted value starting on this line isn't closed.
+    UnterminatedQuoteError(usize),
+    #[cfg(feature = "rng")]
+    DotenvEncryptionError(EncryptionError),
+    /// The variable's marker is malformed, or doesn't decrypt with this key.
+    DotenvMarkerError(String, TokenError),
+    /// The variable doesn't decrypt to UTF-8.
+    InvalidDotenvUtf8Error(String)
+}
+
+impl fmt::Display for DotenvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DotenvError::DotenvIoError(e) => write!(f, "{}", e),
+            DotenvError::InvalidDotenvLineError(line) => write!(f, "Invalid dotenv line {}", line),
+            DotenvError::UnterminatedQuoteError(line) => write!(f, "Unterminated quoted value at line {}", line),
+            #[cfg(feature = "rng")]
+            DotenvError::DotenvEncryptionError(e) => write!(f, "{}", e),
+            DotenvError::DotenvMarkerError(name, e) => write!(f, "Could not decrypt {}: {}", name, e),
+            DotenvError::InvalidDotenvUtf8Error(name) => write!(f, "{} doesn't decrypt to UTF-8", name),
+        }
+    }
+}
+
+impl error::Error for DotenvError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DotenvError::DotenvIoError(e) => Some(e),
+            #[cfg(feature = "rng")]
+            DotenvError::DotenvEncryptionError(e) => Some(e),
+            DotenvError::DotenvMarkerError(_, e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for DotenvError {
+    fn from(e: io::Error) -> DotenvError {
+        DotenvError::DotenvIoError(e)
+    }
+}
+
+/// A variable, its value being `text[value]` as written.
+struct Entry<'a> {
+    name: &'a str,
+    value: Range<usize>
+}
+
+fn skip_blanks(text: &str, mut cursor: usize) -> usize {
+    while text[cursor..].starts_with([' ', '\t']) {
+        cursor += 1;
+    }
+    cursor
+}
+
+fn end_of_line(text: &str, from: usize) -> usize {
+    text[from..].find('\n').map_or(text.len(), |found| from + found)
+}
+
+fn parse(text: &str) -> Result<Vec<Entry<'_>>, DotenvError> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    let mut line = 1;
+    while pos < text.len() {
+        let line_end = end_of_line(text, pos);
+        let trimmed = text[pos..line_end].trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            pos = line_end + 1;
+            line += 1;
+            continue;
+        }
+        let invalid = DotenvError::InvalidDotenvLineError(line);
+
+        let mut cursor = skip_blanks(text, pos);
+        if text[cursor..].starts_with("export") && text[cursor + 6..].starts_with([' ', '\t']) {
+            cursor = skip_blanks(text, cursor + 6);
+        }
+        let name_len = text[cursor..line_end].bytes().take_while(|byte| byte.is_ascii_alphanumeric() || b"_.-".contains(byte)).count();
+        if name_len == 0 {
+            return Err(invalid);
+        }
+        let name = &text[cursor..cursor + name_len];
+        cursor = skip_blanks(text, cursor + name_len);
+        if !text[cursor..].starts_with('=') {
+            return Err(invalid);
+        }
+        let start = skip_blanks(text, cursor + 1);
+
+        let end = match text[start..].chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => {
+                let mut escaped = false;
+                let close = text[start + 1..].char_indices().find(|(_, c)| {
+                    let closes = *c == quote && !escaped;
+                    escaped = quote == '"' && *c == '\\' && !escaped;
+                    closes
+                });
+                match close {
+                    Some((close, _)) => start + 1 + close + 1,
+                    None => return Err(DotenvError::UnterminatedQuoteError(line))
+                }
+            },
+            _ => {
+                let rest = &text[start..line_end];
+                let comment = rest.char_indices()
+                    .find(|(index, c)| *c == '#' && rest[..*index].ends_with([' ', '\t']))
+                    .map_or(rest.len(), |(index, _)| index);
+                start + rest[..comment].trim_end().len()
+            }
+        };
+        let line_end = end_of_line(text, end);
+        let after = text[end..line_end].trim();
+        if !(after.is_empty() || after.starts_with('#')) {
+            return Err(invalid);
+        }
+        line += text[pos..line_end].matches('\n').count() + 1;
+        entries.push(Entry { name, value: start..end });
+        pos = line_end + 1;
+    }
+    Ok(entries)
+}
+
+/// Returns `text` with each entry's value replaced by `map(name, value)`.
+fn rewrite<F>(text: &str, mut map: F) -> Result<String, DotenvError>
+where
+    F: FnMut(&str, &str) -> Result<Option<String>, DotenvError>
+{
+    let mut rewritten = String::with_capacity(text.len());
+    let mut rest = 0;
+    for entry in parse(text)? {
+        if let Some(value) = map(entry.name, &text[entry.value.clone()])? {
+            rewritten.push_str(&text[rest..entry.value.start]);
+            rewritten.push_str(&value);
+            rest = entry.value.end;
+        }
+    }
+    rewritten.push_str(&text[rest..]);
+    Ok(rewritten)
+}
+
+fn is_marker(value: &str) -> bool {
+    value.starts_with(MARKER_START) && value.ends_with(MARKER_END)
+}
+
+/// The value as written, decrypted if it is a marker.
+fn decrypt_value(key: &Key, name: &str, value: &str) -> Result<Option<String>, DotenvError> {
+    if !is_marker(value) {
+        return Ok(None);
+    }
+    let plaintext = decrypt_token(key, &value[MARKER_START.len()..value.len() - 1])
+        .map_err(|e| DotenvError::DotenvMarkerError(String::from(name), e))?;
+    match String::from_utf8(plaintext) {
+        Ok(plaintext) => Ok(Some(plaintext)),
+        Err(_) => Err(DotenvError::InvalidDotenvUtf8Error(String::from(name)))
+    }
+}
+
+fn unquote(value: &str) -> String {
+    if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        return String::from(&value[1..value.len() - 1]);
+    }
+    if !(value.len() >= 2 && value.starts_with('"') && value.ends_with('"')) {
+        return String::from(value);
+    }
+    let mut unquoted = String::with_capacity(value.len());
+    let mut chars = value[1..value.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unquoted.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unquoted.push('\n'),
+            Some('r') => unquoted.push('\r'),
+            Some('t') => unquoted.push('\t'),
+            Some(escaped @ ('"' | '\\')) => unquoted.push(escaped),
+            Some(other) => {
+                unquoted.push('\\');
+                unquoted.push(other)
+            },
+            None => unquoted.push('\\')
+        }
+    }
+    unquoted
+}
+
+/// Encrypts each value of the dotenv `text`, see the module docs.
+#[cfg(feature = "rng")]
+pub fn encrypt_dotenv(key: &Key, text: &str) -> Result<String, DotenvError> {
+    rewrite(text, |_, value| {
+        if value.is_empty() || is_marker(value) {
+            return Ok(None);
+        }
+        encrypt_value_to_marker(key, value).map(Some).map_err(DotenvError::DotenvEncryptionError)
+    })
+}
+
+/// Reverses `encrypt_dotenv`.
+pub fn decrypt_dotenv(key: &Key, text: &str) -> Result<String, DotenvError> {
+    rewrite(text, |name, value| decrypt_value(key, name, value))
+}
+
+/// The variables of the dotenv `text` in order, decrypted and unquoted.
+pub fn parse_encrypted(key: &Key, text: &str) -> Result<Vec<(String, String)>, DotenvError> {
+    let mut vars = Vec::new();
+    for entry in parse(text)? {
+        let written = &text[entry.value];
+        let value = decrypt_value(key, entry.name, written)?;
+        vars.push((String::from(entry.name), unquote(value.as_deref().unwrap_or(written))));
+    }
+    Ok(vars)
+}
+
+#[cfg(feature = "rng")]
+pub fn encrypt_file<P: AsRef<Path>, Q: AsRef<Path>>(key: &Key, src: P, dst: Q) -> Result<(), DotenvError> {
+    let encrypted = encrypt_dotenv(key, &fs::read_to_string(src)?)?;
+    Ok(fs::write(dst, encrypted)?)
+}
+
+pub fn decrypt_file<P: AsRef<Path>, Q: AsRef<Path>>(key: &Key, src: P, dst: Q) -> Result<(), DotenvError> {
+    let decrypted = decrypt_dotenv(key, &fs::read_to_string(src)?)?;
+    Ok(fs::write(dst, decrypted)?)
+}
+
+/// Reads an encrypted dotenv file, see `parse_encrypted`.
+pub fn load_encrypted<P: AsRef<Path>>(key: &Key, path: P) -> Result<Vec<(String, String)>, DotenvError> {
+    parse_encrypted(key, &fs::read_to_string(path)?)
+}
+
+#[cfg(test)]
+#[cfg(feature = "rng")]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+    const FIXTURE: &str = include_str!("../tests/fixtures/sample.env");
+
+    fn expected() -> Vec<(String, String)> {
+        [
+            ("DB_HOST", "db.internal"),
+            ("DB_PASSWORD", "hunter2"),
+            ("API_URL", "https://example.com/#anchor"),
+            ("GREETING", "hello # not a comment"),
+            ("LITERAL", "no \\n escapes # here"),
+            ("PRIVATE_KEY", "-----BEGIN KEY-----\nabc\n-----END KEY-----"),
+            ("ESCAPED", "line1\nline2 \"quoted\" \\"),
+            ("SPACED", "value with spaces"),
+            ("EMPTY", ""),
+            ("HASH", "a#b")
+        ].iter().map(|(name, value)| (String::from(*name), String::from(*value))).collect()
+    }
+
+    #[test]
+    fn round_trips_the_fixture_byte_for_byte() {
+        let key = Key { u8_array: KEY };
+        let encrypted = encrypt_dotenv(&key, FIXTURE).unwrap();
+        for secret in ["hunter2", "example.com", "not a comment", "BEGIN KEY", "line1", "with spaces", "a#b"].iter() {
+            assert!(!encrypted.contains(secret), "{} should be encrypted", secret)
+        }
+        for kept in ["# Database\n", "\n\n\n", "export DB_PASSWORD=ENC[saes1:", "]   # rotated monthly\n", "  SPACED = ENC[", "EMPTY=\n"].iter() {
+            assert!(encrypted.contains(kept), "{:?} should be kept", kept)
+        }
+        assert_eq!(encrypt_dotenv(&key, &encrypted).unwrap(), encrypted, "markers are left as they are");
+        assert_eq!(decrypt_dotenv(&key, &encrypted).unwrap(), FIXTURE);
+        assert_eq!(parse_encrypted(&key, &encrypted).unwrap(), expected());
+        assert_eq!(parse_encrypted(&key, FIXTURE).unwrap(), expected())
+    }
+
+    #[test]
+    fn files_round_trip_and_load() {
+        let key = Key { u8_array: KEY };
+        let dir = crate::test_path("dotenv");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".env"), FIXTURE).unwrap();
+        encrypt_file(&key, dir.join(".env"), dir.join(".env.enc")).unwrap();
+        let loaded = load_encrypted(&key, dir.join(".env.enc"));
+        decrypt_file(&key, dir.join(".env.enc"), dir.join(".env.dec")).unwrap();
+        let decrypted = fs::read_to_string(dir.join(".env.dec")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded.unwrap(), expected());
+        assert_eq!(decrypted, FIXTURE)
+    }
+
+    #[test]
+    fn corrupted_token_names_the_variable() {
+        let key = Key { u8_array: KEY };
+        let encrypted = encrypt_dotenv(&key, "A=1\nDB_PASSWORD=hunter2\n").unwrap();
+        let corrupted = encrypted.replacen("DB_PASSWORD=ENC[saes1:", "DB_PASSWORD=ENC[saes1:AAAA", 1);
+        match parse_encrypted(&key, &corrupted) {
+            Ok(_) => assert!(false, "Should err DotenvMarkerError"),
+            Err(e) => {
+                assert!(!e.to_string().contains("hunter2"));
+                match e {
+                    DotenvError::DotenvMarkerError(name, TokenError::TokenDecryptionError(_)) => assert_eq!(name, "DB_PASSWORD"),
+                    _ => assert!(false, "Should err DotenvMarkerError")
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_lines_are_located() {
+        for (text, line) in [("A=1\nnot a variable\n", 2), ("A=1\nB=\"x\" y\n", 2), ("=1\n", 1)].iter() {
+            match parse(text) {
+                Err(DotenvError::InvalidDotenvLineError(found)) => assert_eq!(found, *line),
+                _ => assert!(false, "Should err InvalidDotenvLineError for {:?}", text)
+            }
+        }
+        match parse("A=1\n\nB=\"never\nclosed\n") {
+            Err(DotenvError::UnterminatedQuoteError(3)) => assert!(true),
+            _ => assert!(false, "Should err UnterminatedQuoteError")
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! Text encodings for binary values.
+//!
+//! Decoding is strict: only the encoding's own alphabet, padding and
+//! length are accepted. Strictness alone can't tell every encoding apart
+//! (lowercase hex is also valid base64), so combined tokens additionally
+//! carry a per-encoding prefix, see `Encoding::token_prefix`.
+
+use std::convert::TryInto;
+use std::{error, fmt};
+
+use crate::{Encrypted, InvalidEncryptedError, InvalidIvError, Iv};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Standard alphabet with `=` padding, the crate's historical default.
+    #[default]
+    Base64,
+    /// URL and filename safe alphabet (`-`, `_`), without padding.
+    Base64UrlNoPad,
+    /// Lowercase hexadecimal; uppercase digits are accepted when decoding.
+    Hex,
+    /// RFC 4648 base32: `A-Z`, `2-7`, `=` padding.
+    Base32
+}
+
+pub const ENCODINGS: [Encoding; 4] = [Encoding::Base64, Encoding::Base64UrlNoPad, Encoding::Hex, Encoding::Base32];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvalidEncodingError {
+    InvalidBase64Error(base64::DecodeError),
+    /// Invalid character at this byte offset.
+    InvalidHexError(usize),
+    /// Invalid character at this byte offset.
+    InvalidBase32Error(usize),
+    /// The length doesn't fit the encoding (odd hex, unpadded base32).
+    InvalidEncodingLengthError
+}
+
+impl fmt::Display for InvalidEncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidEncodingError::InvalidBase64Error(_) => write!(f, "Please provide a valid base64"),
+            InvalidEncodingError::InvalidHexError(offset) => write!(f, "Invalid hex character at offset {}", offset),
+            InvalidEncodingError::InvalidBase32Error(offset) => write!(f, "Invalid base32 character at offset {}", offset),
+            InvalidEncodingError::InvalidEncodingLengthError => write!(f, "Invalid encoded length"),
+        }
+    }
+}
+
+impl error::Error for InvalidEncodingError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            InvalidEncodingError::InvalidBase64Error(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+const HEX_ALPHABET: &[u8; 16] = b"0123456789abcdef";
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        text.push(HEX_ALPHABET[(byte >> 4) as usize] as char);
+        text.push(HEX_ALPHABET[(byte & 0x0f) as usize] as char);
+    }
+    text
+}
+
+fn decode_hex(text: &str) -> Result<Vec<u8>, InvalidEncodingError> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(2) {
+        return Err(InvalidEncodingError::InvalidEncodingLengthError);
+    }
+    let mut bytes = Vec::with_capacity(text.len() / 2);
+    for (index, pair) in text.chunks(2).enumerate() {
+        match (hex_value(pair[0]), hex_value(pair[1])) {
+            (Some(high), Some(low)) => bytes.push(high << 4 | low),
+            (None, _) => return Err(InvalidEncodingError::InvalidHexError(index * 2)),
+            (_, None) => return Err(InvalidEncodingError::InvalidHexError(index * 2 + 1))
+        }
+    }
+    Ok(bytes)
+}
+
+fn encode_base32(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    for chunk in bytes.chunks(5) {
+        let mut block = [0u8; 5];
+        block[..chunk.len()].copy_from_slice(chunk);
+        let bits = block.iter().fold(0u64, |bits, byte| bits << 8 | *byte as u64);
+        let symbols = (chunk.len() * 8).div_ceil(5);
+        for index in 0..8 {
+            if index < symbols {
+                text.push(BASE32_ALPHABET[(bits >> (35 - index * 5) & 0x1f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn decode_base32(text: &str) -> Result<Vec<u8>, InvalidEncodingError> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(8) {
+        return Err(InvalidEncodingError::InvalidEncodingLengthError);
+    }
+    let mut bytes = Vec::with_capacity(text.len() / 8 * 5);
+    for (block_index, block) in text.chunks(8).enumerate() {
+        let symbols = block.iter().position(|byte| *byte == b'=').unwrap_or(8);
+        let len = match symbols {
+            8 => 5,
+            7 => 4,
+            5 => 3,
+            4 => 2,
+            2 => 1,
+            _ => return Err(InvalidEncodingError::InvalidEncodingLengthError)
+        };
+        if len < 5 && block_index != text.len() / 8 - 1 {
+            return Err(InvalidEncodingError::InvalidBase32Error(block_index * 8 + symbols));
+        }
+        let mut bits = 0u64;
+        for (index, byte) in block.iter().enumerate() {
+            let value = if index < symbols {
+                match BASE32_ALPHABET.iter().position(|symbol| symbol == byte) {
+                    Some(value) => value as u64,
+                    None => return Err(InvalidEncodingError::InvalidBase32Error(block_index * 8 + index))
+                }
+            } else if *byte == b'=' {
+                0
+            } else {
+                return Err(InvalidEncodingError::InvalidBase32Error(block_index * 8 + index));
+            };
+            bits = bits << 5 | value;
+        }
+        // Unused trailing bits must be zero, so that every byte string has one encoding.
+        if bits & ((1u64 << (40 - len * 8)) - 1) != 0 {
+            return Err(InvalidEncodingError::InvalidBase32Error(block_index * 8 + symbols - 1));
+        }
+        for index in 0..len {
+            bytes.push((bits >> (32 - index * 8)) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+impl Encoding {
+    pub fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Base64 => base64::encode(bytes),
+            Encoding::Base64UrlNoPad => base64::encode_config(bytes, base64::URL_SAFE_NO_PAD),
+            Encoding::Hex => encode_hex(bytes),
+            Encoding::Base32 => encode_base32(bytes)
+        }
+    }
+
+    pub fn decode(self, text: &str) -> Result<Vec<u8>, InvalidEncodingError> {
+        match self {
+            Encoding::Base64 => base64::decode(text).map_err(InvalidEncodingError::InvalidBase64Error),
+            Encoding::Base64UrlNoPad => base64::decode_config(text, base64::URL_SAFE_NO_PAD).map_err(InvalidEncodingError::InvalidBase64Error),
+            Encoding::Hex => decode_hex(text),
+            Encoding::Base32 => decode_base32(text)
+        }
+    }
+
+    /// Length of `encode` for `len` bytes.
+    pub const fn encoded_len(self, len: usize) -> usize {
+        match self {
+            Encoding::Base64 => len.div_ceil(3) * 4,
+            Encoding::Base64UrlNoPad => (len * 4).div_ceil(3),
+            Encoding::Hex => len * 2,
+            Encoding::Base32 => len.div_ceil(5) * 8
+        }
+    }
+
+    /// Largest byte length whose encoding fits in `encoded_len` characters.
+    pub const fn max_decoded_len(self, encoded_len: usize) -> usize {
+        match self {
+            Encoding::Base64 => encoded_len / 4 * 3,
+            Encoding::Base64UrlNoPad => encoded_len / 4 * 3 + (encoded_len % 4 * 3) / 4,
+            Encoding::Hex => encoded_len / 2,
+            Encoding::Base32 => encoded_len / 8 * 5
+        }
+    }
+
+    /// Prefix of combined tokens in this encoding. `saes1:` is kept for
+    /// base64 so that existing tokens stay valid.
+    pub fn token_prefix(self) -> &'static str {
+        match self {
+            Encoding::Base64 => "saes1:",
+            Encoding::Base64UrlNoPad => "saes1u:",
+            Encoding::Hex => "saes1x:",
+            Encoding::Base32 => "saes1b:"
+        }
+    }
+}
+
+impl Iv {
+    pub fn encode_with(&self, encoding: Encoding) -> String {
+        encoding.encode(&self.u8_array)
+    }
+
+    pub fn decode_with(text: &str, encoding: Encoding) -> Result<Iv, InvalidIvError> {
+        let iv = match encoding.decode(text) {
+            Ok(data) => data,
+            Err(InvalidEncodingError::InvalidBase64Error(e)) => return Err(InvalidIvError::InvalidIvBase64Error(e)),
+            Err(e) => return Err(InvalidIvError::InvalidIvEncodingError(e))
+        };
+        match iv.as_slice().try_into() {
+            Ok(u8_array) => Ok(Iv { u8_array }),
+            Err(_) => Err(InvalidIvError::InvalidIvSizeError)
+        }
+    }
+}
+
+impl Encrypted {
+    pub fn encode_with(&self, encoding: Encoding) -> String {
+        encoding.encode(&self.u8_vec)
+    }
+
+    /// Ciphertexts have no fixed length, so unlike `Iv::decode_with` this
+    /// can't always notice a value written in another encoding; prefer
+    /// combined tokens when the encoding isn't fixed by context.
+    pub fn decode_with(text: &str, encoding: Encoding) -> Result<Encrypted, InvalidEncryptedError> {
+        if text.is_empty() {
+            return Err(InvalidEncryptedError::EmptyEncryptedError);
+        }
+        match encoding.decode(text) {
+            Ok(u8_vec) => Ok(Encrypted { u8_vec }),
+            Err(InvalidEncodingError::InvalidBase64Error(e)) => Err(InvalidEncryptedError::InvalidEncryptedBase64Error(e)),
+            Err(e) => Err(InvalidEncryptedError::InvalidEncryptedEncodingError(e))
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc4648_vectors() {
+        let vectors = [
+            ("", "", "", ""),
+            ("f", "Zg==", "66", "MY======"),
+            ("fo", "Zm8=", "666f", "MZXQ===="),
+            ("foo", "Zm9v", "666f6f", "MZXW6==="),
+            ("foob", "Zm9vYg==", "666f6f62", "MZXW6YQ="),
+            ("fooba", "Zm9vYmE=", "666f6f6261", "MZXW6YTB"),
+            ("foobar", "Zm9vYmFy", "666f6f626172", "MZXW6YTBOI======")
+        ];
+        for (plain, base64, hex, base32) in vectors.iter() {
+            assert_eq!(Encoding::Base64.encode(plain.as_bytes()), *base64);
+            assert_eq!(Encoding::Hex.encode(plain.as_bytes()), *hex);
+            assert_eq!(Encoding::Base32.encode(plain.as_bytes()), *base32);
+            assert_eq!(Encoding::Hex.decode(hex).unwrap(), plain.as_bytes());
+            assert_eq!(Encoding::Base32.decode(base32).unwrap(), plain.as_bytes())
+        }
+    }
+
+    #[test]
+    fn every_encoding_round_trips() {
+        let bytes: Vec<u8> = (0..=255u8).collect();
+        for encoding in ENCODINGS.iter() {
+            for len in 0..40 {
+                assert_eq!(encoding.decode(&encoding.encode(&bytes[..len])).unwrap(), &bytes[..len], "{:?}", encoding)
+            }
+        }
+    }
+
+    #[test]
+    fn iv_and_encrypted_round_trip_in_every_encoding() {
+        let iv = Iv { u8_array: *b"012345678901" };
+        let encrypted = Encrypted { u8_vec: vec![0, 1, 2, 250, 251, 252, 253, 254, 255] };
+        for encoding in ENCODINGS.iter() {
+            assert_eq!(Iv::decode_with(&iv.encode_with(*encoding), *encoding).unwrap().u8_array, iv.u8_array);
+            assert_eq!(Encrypted::decode_with(&encrypted.encode_with(*encoding), *encoding).unwrap().u8_vec, encrypted.u8_vec)
+        }
+    }
+
+    #[test]
+    fn iv_in_another_encoding_is_rejected() {
+        // Some ivs, like b"012345678901", encode the same in both base64 alphabets.
+        let iv = Iv { u8_array: [0xff; 12] };
+        for encoding in ENCODINGS.iter() {
+            for other in ENCODINGS.iter().filter(|other| *other != encoding) {
+                assert!(Iv::decode_with(&iv.encode_with(*encoding), *other).is_err(), "{:?} read as {:?}", encoding, other)
+            }
+        }
+    }
+
+    #[test]
+    fn encoded_len_and_max_decoded_len_match_encode() {
+        for encoding in ENCODINGS.iter() {
+            for len in 0..40 {
+                assert_eq!(encoding.encode(&vec![0xa5u8; len]).len(), encoding.encoded_len(len), "{:?} of {} bytes", encoding, len);
+            }
+            for encoded_len in 0..60 {
+                let max = encoding.max_decoded_len(encoded_len);
+                assert!(encoding.encoded_len(max) <= encoded_len && encoding.encoded_len(max + 1) > encoded_len, "{:?} in {} chars", encoding, encoded_len)
+            }
+        }
+    }
+
+    #[test]
+    fn strict_alphabets() {
+        assert_eq!(Encoding::Hex.decode("0g"), Err(InvalidEncodingError::InvalidHexError(1)));
+        assert_eq!(Encoding::Hex.decode("abc"), Err(InvalidEncodingError::InvalidEncodingLengthError));
+        assert_eq!(Encoding::Base32.decode("mzxw6==="), Err(InvalidEncodingError::InvalidBase32Error(0)));
+        assert_eq!(Encoding::Base32.decode("MZXW6"), Err(InvalidEncodingError::InvalidEncodingLengthError));
+        assert_eq!(Encoding::Base32.decode("MZXW7==="), Err(InvalidEncodingError::InvalidBase32Error(4)));
+        assert!(Encoding::Base64.decode("Zm9v_g==").is_err());
+        assert!(Encoding::Base64UrlNoPad.decode("Zm9v+g").is_err())
+    }
+}
@@ -0,0 +1,130 @@
+//! A map whose values are stored encrypted in memory.
+//!
+//! This is defense in depth, not isolation: the map's key lives in the
+//! same process, so anything that can read the whole address space can
+//! decrypt the values. What it buys is that a partial leak (a heap dump
+//! shipped with a bug report, a stray debug print of the storage) doesn't
+//! expose every value at once, and that plaintexts only exist, zeroized on
+//! drop, while a caller holds the result of `get`.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use zeroize::Zeroizing;
+
+use crate::{decrypt_bytes, encrypt_bytes, DecryptionError, EncryptedAndIv, EncryptionError, Key};
+
+pub struct EncryptedMap<K> {
+    key: Key,
+    entries: HashMap<K, EncryptedAndIv>
+}
+
+impl<K: Eq + Hash> EncryptedMap<K> {
+    /// An empty map with a freshly generated key.
+    pub fn new() -> EncryptedMap<K> {
+        EncryptedMap::with_key(Key::generate())
+    }
+
+    pub fn with_key(key: Key) -> EncryptedMap<K> {
+        EncryptedMap { key, entries: HashMap::new() }
+    }
+
+    /// Encrypts `plaintext` under a fresh iv, replacing any previous value.
+    pub fn insert(&mut self, k: K, plaintext: &[u8]) -> Result<(), EncryptionError> {
+        let encrypted_and_iv = encrypt_bytes(&self.key, plaintext)?;
+        self.entries.insert(k, encrypted_and_iv);
+        Ok(())
+    }
+
+    /// Decrypts the value for `k`, `Ok(None)` if there is none.
+    pub fn get(&self, k: &K) -> Result<Option<Zeroizing<Vec<u8>>>, DecryptionError> {
+        match self.entries.get(k) {
+            Some(encrypted_and_iv) => Ok(Some(Zeroizing::new(decrypt_bytes(&self.key, encrypted_and_iv)?))),
+            None => Ok(None)
+        }
+    }
+
+    /// Returns whether there was a value for `k`.
+    pub fn remove(&mut self, k: &K) -> bool {
+        self.entries.remove(k).is_some()
+    }
+
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.entries.contains_key(k)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The map's keys, in arbitrary order. Values are only reachable through `get`.
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.entries.keys()
+    }
+}
+
+impl<K: Eq + Hash> Default for EncryptedMap<K> {
+    fn default() -> EncryptedMap<K> {
+        EncryptedMap::new()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_then_get_round_trips() {
+        let mut map = EncryptedMap::new();
+        map.insert("db", b"hunter2").unwrap();
+        map.insert("api", b"t0k3n").unwrap();
+        assert_eq!(map.get(&"db").unwrap().unwrap().as_slice(), b"hunter2");
+        assert_eq!(map.get(&"api").unwrap().unwrap().as_slice(), b"t0k3n");
+        assert!(map.get(&"other").unwrap().is_none());
+        assert_eq!(map.len(), 2);
+        let mut keys: Vec<&&str> = map.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec![&"api", &"db"])
+    }
+
+    #[test]
+    fn values_are_stored_encrypted() {
+        let mut map = EncryptedMap::new();
+        map.insert(1, b"This is a text.").unwrap();
+        let stored = &map.entries[&1].encrypted.u8_vec;
+        assert!(!stored.windows(4).any(|window| window == b"This"))
+    }
+
+    #[test]
+    fn get_after_remove_is_none() {
+        let mut map = EncryptedMap::with_key(Key { u8_array: [1u8; 32] });
+        map.insert("db", b"hunter2").unwrap();
+        assert!(map.remove(&"db"));
+        assert!(!map.remove(&"db"));
+        assert!(map.get(&"db").unwrap().is_none());
+        assert!(map.is_empty())
+    }
+
+    #[test]
+    fn maps_cant_read_each_others_values() {
+        let mut first = EncryptedMap::with_key(Key { u8_array: [1u8; 32] });
+        let mut second = EncryptedMap::with_key(Key { u8_array: [2u8; 32] });
+        first.insert("db", b"hunter2").unwrap();
+        second.insert("db", b"s3cr3t").unwrap();
+        std::mem::swap(&mut first.entries, &mut second.entries);
+        for map in [&first, &second].iter() {
+            match map.get(&"db") {
+                Ok(_) => assert!(false, "Should err GenericDecryptionError"),
+                Err(e) => match e {
+                    DecryptionError::GenericDecryptionError => assert!(true),
+                    _ => assert!(false, "Should err GenericDecryptionError")
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Decrypting `ENC[...]` environment variables at startup.
+//!
+//! A variable whose whole value is an `ENC[<token>]` marker (see `config`)
+//! is replaced by its plaintext; every other variable passes through as is.
+//! Errors name the variable but never include its value.
+
+use std::{error, fmt};
+
+use crate::config::{MARKER_END, MARKER_START};
+use crate::token::{decrypt_token, TokenError};
+use crate::Key;
+
+#[derive(Debug, Clone)]
+pub enum EnvDecryptError {
+    /// The variable looks like a marker but its token is malformed.
+    InvalidEnvMarkerError(String),
+    /// Wrong key or tampered value.
+    EnvDecryptionError(String),
+    /// The plaintext isn't valid UTF-8 and can't be an environment value.
+    InvalidEnvUtf8Error(String)
+}
+
+impl fmt::Display for EnvDecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EnvDecryptError::InvalidEnvMarkerError(name) => write!(f, "Invalid ENC[ marker in ${}", name),
+            EnvDecryptError::EnvDecryptionError(name) => write!(f, "Could not decrypt ${}", name),
+            EnvDecryptError::InvalidEnvUtf8Error(name) => write!(f, "${} doesn't decrypt to UTF-8", name),
+        }
+    }
+}
+
+impl error::Error for EnvDecryptError {}
+
+/// Resolves the markers in `vars`, keeping their order.
+pub fn decrypt_vars<I>(key: &Key, vars: I) -> Result<Vec<(String, String)>, EnvDecryptError>
+where
+    I: IntoIterator<Item = (String, String)>
+{
+    let mut resolved = Vec::new();
+    for (name, value) in vars {
+        if !(value.starts_with(MARKER_START) && value.ends_with(MARKER_END)) {
+            resolved.push((name, value));
+            continue;
+        }
+        let plaintext = match decrypt_token(key, &value[MARKER_START.len()..value.len() - 1]) {
+            Ok(value) => value,
+            Err(TokenError::InvalidTokenError(_)) => return Err(EnvDecryptError::InvalidEnvMarkerError(name)),
+            Err(TokenError::TokenDecryptionError(_)) => return Err(EnvDecryptError::EnvDecryptionError(name))
+        };
+        match String::from_utf8(plaintext) {
+            Ok(plaintext) => resolved.push((name, plaintext)),
+            Err(_) => return Err(EnvDecryptError::InvalidEnvUtf8Error(name))
+        }
+    }
+    Ok(resolved)
+}
+
+/// Returns the process environment with every marker decrypted.
+///
+/// Variables whose name or value isn't valid UTF-8 are left out.
+pub fn decrypt_environment(key: &Key) -> Result<Vec<(String, String)>, EnvDecryptError> {
+    let vars = std::env::vars_os().filter_map(|(name, value)| match (name.into_string(), value.into_string()) {
+        (Ok(name), Ok(value)) => Some((name, value)),
+        _ => None
+    });
+    decrypt_vars(key, vars)
+}
+
+/// Decrypts the process environment and writes the plaintexts back with
+/// `std::env::set_var`. Nothing is written unless every marker resolves.
+///
+/// # Safety
+///
+/// Modifying the environment races with any other thread reading it,
+/// including through libc. Only call this at the start of `main`, before
+/// spawning threads or starting an async runtime.
+pub unsafe fn export_decrypted_environment(key: &Key) -> Result<(), EnvDecryptError> {
+    for (name, value) in decrypt_environment(key)? {
+        std::env::set_var(name, value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(feature = "rng")]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::config::encrypt_value_to_marker;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    fn scoped_vars(prefix: &str, vars: &[(&str, String)]) -> Vec<(String, String)> {
+        for (name, value) in vars {
+            std::env::set_var(format!("{}{}", prefix, name), value);
+        }
+        let key = Key { u8_array: KEY };
+        let resolved = decrypt_environment(&key);
+        for (name, _) in vars {
+            std::env::remove_var(format!("{}{}", prefix, name));
+        }
+        let mut resolved: Vec<(String, String)> = match resolved {
+            Ok(value) => value.into_iter().filter(|(name, _)| name.starts_with(prefix)).collect(),
+            Err(e) => panic!("{}", e)
+        };
+        resolved.sort();
+        resolved
+    }
+
+    #[test]
+    fn decrypt_environment_resolves_markers_and_passes_others_through() {
+        let key = Key { u8_array: KEY };
+        let resolved = scoped_vars("SAES_ENV_TEST_OK_", &[
+            ("DB_PASSWORD", encrypt_value_to_marker(&key, "hunter2").unwrap()),
+            ("DB_HOST", String::from("db.internal"))
+        ]);
+        assert_eq!(resolved, vec![
+            (String::from("SAES_ENV_TEST_OK_DB_HOST"), String::from("db.internal")),
+            (String::from("SAES_ENV_TEST_OK_DB_PASSWORD"), String::from("hunter2"))
+        ])
+    }
+
+    #[test]
+    fn malformed_marker_names_the_variable_only() {
+        match decrypt_vars(&Key { u8_array: KEY }, vec![(String::from("API_TOKEN"), String::from("ENC[saes1:not base64!]"))]) {
+            Ok(_) => assert!(false, "Should err InvalidEnvMarkerError"),
+            Err(e) => {
+                assert_eq!(e.to_string(), "Invalid ENC[ marker in $API_TOKEN");
+                match e {
+                    EnvDecryptError::InvalidEnvMarkerError(name) => assert_eq!(name, "API_TOKEN"),
+                    _ => assert!(false, "Should err InvalidEnvMarkerError")
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_key_fails_with_decryption_error() {
+        let marker = encrypt_value_to_marker(&Key { u8_array: [0u8; 32] }, "hunter2").unwrap();
+        match decrypt_vars(&Key { u8_array: KEY }, vec![(String::from("DB_PASSWORD"), marker)]) {
+            Ok(_) => assert!(false, "Should err EnvDecryptionError"),
+            Err(e) => match e {
+                EnvDecryptError::EnvDecryptionError(name) => assert_eq!(name, "DB_PASSWORD"),
+                _ => assert!(false, "Should err EnvDecryptionError")
+            }
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! One error type for the crate-root operations, and stable numeric codes.
+//!
+//! `ErrorCode` values are part of the public API, for applications to put
+//! in their logs or responses. A code is never renumbered or reused, even
+//! after its variant is removed; new variants take the next free value.
+//!
+//! The C interface (`ffi`) has its own, equally stable `SAES_*` status
+//! codes, which also cover null pointers, non-UTF-8 input and panics. Its
+//! functions take raw bytes, so iv and ciphertext parsing errors don't
+//! occur there. The other codes map this way:
+//!
+//! ```text
+//! ErrorCode                                  C interface
+//! InvalidKeyBase64Error (1)                  SAES_ERR_INVALID_KEY_BASE64 (2)
+//! InvalidKeySizeError (2)                    SAES_ERR_INVALID_KEY_SIZE (3)
+//! GenericEncryptionError (8)                 SAES_ERR_ENCRYPTION (5)
+//! InvalidUTF8DecryptionError (9)             SAES_ERR_DECRYPTION_INVALID_UTF8 (7)
+//! ProbableWrongKey (10)                      SAES_ERR_PROBABLE_WRONG_KEY (8)
+//! GenericDecryptionError (11), RateLimited (12), ProbableContextMismatch (13),
+//! Replayed (14), OutOfWindow (15)            SAES_ERR_DECRYPTION (6)
+//! ```
+
+use std::convert::TryFrom;
+use std::{error, fmt};
+
+#[cfg(feature = "base64")]
+use crate::InvalidEncryptedError;
+use crate::{DecryptionError, EncryptionError, InvalidIvError, InvalidKeyError};
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    InvalidKeyError(InvalidKeyError),
+    InvalidIvError(InvalidIvError),
+    #[cfg(feature = "base64")]
+    InvalidEncryptedError(InvalidEncryptedError),
+    EncryptionError(EncryptionError),
+    DecryptionError(DecryptionError)
+}
+
+impl Error {
+    pub fn code(&self) -> u32 {
+        ErrorCode::from(self).into()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidKeyError(e) => write!(f, "{}", e),
+            Error::InvalidIvError(e) => write!(f, "{}", e),
+            #[cfg(feature = "base64")]
+            Error::InvalidEncryptedError(e) => write!(f, "{}", e),
+            Error::EncryptionError(e) => write!(f, "{}", e),
+            Error::DecryptionError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::InvalidKeyError(e) => Some(e),
+            Error::InvalidIvError(e) => Some(e),
+            #[cfg(feature = "base64")]
+            Error::InvalidEncryptedError(e) => Some(e),
+            Error::EncryptionError(e) => Some(e),
+            Error::DecryptionError(e) => Some(e),
+        }
+    }
+}
+
+impl From<InvalidKeyError> for Error {
+    fn from(e: InvalidKeyError) -> Error {
+        Error::InvalidKeyError(e)
+    }
+}
+
+impl From<InvalidIvError> for Error {
+    fn from(e: InvalidIvError) -> Error {
+        Error::InvalidIvError(e)
+    }
+}
+
+#[cfg(feature = "base64")]
+impl From<InvalidEncryptedError> for Error {
+    fn from(e: InvalidEncryptedError) -> Error {
+        Error::InvalidEncryptedError(e)
+    }
+}
+
+impl From<EncryptionError> for Error {
+    fn from(e: EncryptionError) -> Error {
+        Error::EncryptionError(e)
+    }
+}
+
+impl From<DecryptionError> for Error {
+    fn from(e: DecryptionError) -> Error {
+        Error::DecryptionError(e)
+    }
+}
+
+/// One code per error variant. Variants only present with the `base64`
+/// feature keep their code when it is disabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ErrorCode {
+    InvalidKeyBase64Error = 1,
+    InvalidKeySizeError = 2,
+    InvalidIvSizeError = 3,
+    InvalidIvBase64Error = 4,
+    InvalidIvEncodingError = 5,
+    InvalidEncryptedBase64Error = 6,
+    InvalidEncryptedEncodingError = 7,
+    GenericEncryptionError = 8,
+    InvalidUTF8DecryptionError = 9,
+    ProbableWrongKey = 10,
+    GenericDecryptionError = 11,
+    RateLimited = 12,
+    ProbableContextMismatch = 13,
+    Replayed = 14,
+    OutOfWindow = 15,
+    EmptyEncryptedError = 16
+}
+
+/// Every code, in numeric order.
+pub const ERROR_CODES: [ErrorCode; 16] = [
+    ErrorCode::InvalidKeyBase64Error,
+    ErrorCode::InvalidKeySizeError,
+    ErrorCode::InvalidIvSizeError,
+    ErrorCode::InvalidIvBase64Error,
+    ErrorCode::InvalidIvEncodingError,
+    ErrorCode::InvalidEncryptedBase64Error,
+    ErrorCode::InvalidEncryptedEncodingError,
+    ErrorCode::GenericEncryptionError,
+    ErrorCode::InvalidUTF8DecryptionError,
+    ErrorCode::ProbableWrongKey,
+    ErrorCode::GenericDecryptionError,
+    ErrorCode::RateLimited,
+    ErrorCode::ProbableContextMismatch,
+    ErrorCode::Replayed,
+    ErrorCode::OutOfWindow,
+    ErrorCode::EmptyEncryptedError
+];
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> u32 {
+        code as u32
+    }
+}
+
+/// Fails with the unknown value.
+impl TryFrom<u32> for ErrorCode {
+    type Error = u32;
+
+    fn try_from(value: u32) -> Result<ErrorCode, u32> {
+        match ERROR_CODES.iter().find(|code| **code as u32 == value) {
+            Some(code) => Ok(*code),
+            None => Err(value)
+        }
+    }
+}
+
+impl From<&Error> for ErrorCode {
+    fn from(e: &Error) -> ErrorCode {
+        match e {
+            Error::InvalidKeyError(e) => match e {
+                #[cfg(feature = "base64")]
+                InvalidKeyError::InvalidKeyBase64Error(_) => ErrorCode::InvalidKeyBase64Error,
+                InvalidKeyError::InvalidKeySizeError => ErrorCode::InvalidKeySizeError,
+            },
+            Error::InvalidIvError(e) => match e {
+                InvalidIvError::InvalidIvSizeError => ErrorCode::InvalidIvSizeError,
+                #[cfg(feature = "base64")]
+                InvalidIvError::InvalidIvBase64Error(_) => ErrorCode::InvalidIvBase64Error,
+                #[cfg(feature = "base64")]
+                InvalidIvError::InvalidIvEncodingError(_) => ErrorCode::InvalidIvEncodingError,
+            },
+            #[cfg(feature = "base64")]
+            Error::InvalidEncryptedError(e) => match e {
+                InvalidEncryptedError::InvalidEncryptedBase64Error(_) => ErrorCode::InvalidEncryptedBase64Error,
+                InvalidEncryptedError::InvalidEncryptedEncodingError(_) => ErrorCode::InvalidEncryptedEncodingError,
+                InvalidEncryptedError::EmptyEncryptedError => ErrorCode::EmptyEncryptedError,
+            },
+            Error::EncryptionError(e) => match e {
+                EncryptionError::GenericEncryptionError => ErrorCode::GenericEncryptionError,
+            },
+            Error::DecryptionError(e) => match e {
+                DecryptionError::InvalidUTF8DecryptionError(_) => ErrorCode::InvalidUTF8DecryptionError,
+                DecryptionError::ProbableWrongKey => ErrorCode::ProbableWrongKey,
+                DecryptionError::GenericDecryptionError => ErrorCode::GenericDecryptionError,
+                DecryptionError::RateLimited => ErrorCode::RateLimited,
+                DecryptionError::ProbableContextMismatch => ErrorCode::ProbableContextMismatch,
+                DecryptionError::Replayed => ErrorCode::Replayed,
+                DecryptionError::OutOfWindow => ErrorCode::OutOfWindow,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_table_is_stable() {
+        let table: Vec<(u32, String)> = ERROR_CODES.iter().map(|code| (u32::from(*code), format!("{:?}", code))).collect();
+        assert_eq!(table, vec![
+            (1, String::from("InvalidKeyBase64Error")),
+            (2, String::from("InvalidKeySizeError")),
+            (3, String::from("InvalidIvSizeError")),
+            (4, String::from("InvalidIvBase64Error")),
+            (5, String::from("InvalidIvEncodingError")),
+            (6, String::from("InvalidEncryptedBase64Error")),
+            (7, String::from("InvalidEncryptedEncodingError")),
+            (8, String::from("GenericEncryptionError")),
+            (9, String::from("InvalidUTF8DecryptionError")),
+            (10, String::from("ProbableWrongKey")),
+            (11, String::from("GenericDecryptionError")),
+            (12, String::from("RateLimited")),
+            (13, String::from("ProbableContextMismatch")),
+            (14, String::from("Replayed")),
+            (15, String::from("OutOfWindow")),
+            (16, String::from("EmptyEncryptedError"))
+        ])
+    }
+
+    #[test]
+    fn codes_round_trip_through_u32() {
+        for code in ERROR_CODES.iter() {
+            assert_eq!(ErrorCode::try_from(u32::from(*code)), Ok(*code))
+        }
+        assert_eq!(ErrorCode::try_from(0), Err(0));
+        assert_eq!(ErrorCode::try_from(17), Err(17))
+    }
+
+    #[test]
+    fn error_code_matches_the_variant() {
+        assert_eq!(Error::from(InvalidKeyError::InvalidKeySizeError).code(), 2);
+        assert_eq!(Error::from(EncryptionError::GenericEncryptionError).code(), 8);
+        assert_eq!(Error::from(DecryptionError::ProbableWrongKey).code(), 10);
+        assert_eq!(Error::from(DecryptionError::GenericDecryptionError).to_string(), "Decryption error")
+    }
+}
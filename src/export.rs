@@ -0,0 +1,158 @@
+//! Serializing key material on purpose.
+//!
+//! `Key` implements neither `Serialize` nor `Deserialize`, so a struct
+//! holding one can't be derived into a log line or an API response by
+//! accident. Code that really means to write a key out goes through
+//! `Key::export_dangerously()`, which stands out in review:
+//!
+//! ```
+//! # #[cfg(feature = "json")] {
+//! use simple_aes256_gcm::Key;
+//!
+//! let key = Key { u8_array: *b"01234567890123456789012345678901" };
+//! let json = serde_json::to_string(&key.export_dangerously()).unwrap();
+//! assert_eq!(json, "\"MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=\"");
+//! # }
+//! ```
+//!
+//! The serialized form is the key's standard base64 string. The stack
+//! buffers used for encoding and decoding are wiped afterwards, and so is
+//! the `ExportableKey` when dropped; copies made by the serializer itself
+//! are out of this crate's reach.
+
+use std::fmt;
+
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+
+use crate::secure_buffer::wipe;
+use crate::Key;
+
+/// Base64 length of a 32-byte key.
+const ENCODED_LEN: usize = 44;
+
+/// A copy of a key that serializes as base64.
+pub struct ExportableKey {
+    u8_array: [u8; 32]
+}
+
+impl Key {
+    /// Returns a copy of the key that implements `Serialize`.
+    pub fn export_dangerously(&self) -> ExportableKey {
+        ExportableKey { u8_array: self.u8_array }
+    }
+}
+
+impl ExportableKey {
+    pub fn into_key(self) -> Key {
+        Key { u8_array: self.u8_array }
+    }
+
+    fn serialize_with_buffer<S: Serializer>(&self, serializer: S, buffer: &mut [u8; ENCODED_LEN]) -> Result<S::Ok, S::Error> {
+        let len = base64::encode_config_slice(&self.u8_array, base64::STANDARD, buffer);
+        let result = serializer.serialize_str(std::str::from_utf8(&buffer[..len]).expect("base64 is ASCII"));
+        wipe(buffer);
+        result
+    }
+
+    fn from_str_with_buffer(text: &str, buffer: &mut [u8; 64]) -> Option<ExportableKey> {
+        // Longer inputs can't be a key and would overflow the buffer.
+        if text.len() != ENCODED_LEN {
+            return None;
+        }
+        let exported = match base64::decode_config_slice(text, base64::STANDARD, buffer) {
+            Ok(32) => {
+                let mut u8_array = [0u8; 32];
+                u8_array.copy_from_slice(&buffer[..32]);
+                Some(ExportableKey { u8_array })
+            },
+            _ => None
+        };
+        wipe(buffer);
+        exported
+    }
+}
+
+impl Drop for ExportableKey {
+    fn drop(&mut self) {
+        wipe(&mut self.u8_array);
+    }
+}
+
+
+impl Serialize for ExportableKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.serialize_with_buffer(serializer, &mut [0u8; ENCODED_LEN])
+    }
+}
+
+struct ExportableKeyVisitor;
+
+impl<'de> Visitor<'de> for ExportableKeyVisitor {
+    type Value = ExportableKey;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a base64-encoded 32-byte key")
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<ExportableKey, E> {
+        match ExportableKey::from_str_with_buffer(text, &mut [0u8; 64]) {
+            Some(exported) => Ok(exported),
+            // The input is the key, or close to it: never echo it back.
+            None => Err(E::custom("invalid exported key"))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ExportableKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ExportableKey, D::Error> {
+        deserializer.deserialize_str(ExportableKeyVisitor)
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    #[test]
+    fn exported_key_round_trips() {
+        let json = serde_json::to_string(&Key { u8_array: KEY }.export_dangerously()).unwrap();
+        assert_eq!(json, "\"MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=\"");
+        let exported: ExportableKey = serde_json::from_str(&json).unwrap();
+        assert_eq!(exported.into_key().u8_array, KEY)
+    }
+
+    #[test]
+    fn invalid_exported_key_is_rejected_without_echoing_it() {
+        for json in ["\"MDEyMzQ1Njc4OTAxMjM0NTY3ODkw\"", "\"MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MD!=\"", "42"].iter() {
+            match serde_json::from_str::<ExportableKey>(json) {
+                Ok(_) => panic!("Should err for {}", json),
+                Err(e) => assert!(!e.to_string().contains("MDEy"))
+            }
+        }
+    }
+
+    #[test]
+    fn buffers_are_wiped() {
+        let exported = Key { u8_array: KEY }.export_dangerously();
+        let mut encoded = [0u8; ENCODED_LEN];
+        let mut json = Vec::new();
+        exported.serialize_with_buffer(&mut serde_json::Serializer::new(&mut json), &mut encoded).unwrap();
+        assert_eq!(json, b"\"MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=\"");
+        assert_eq!(encoded, [0u8; ENCODED_LEN]);
+
+        let mut decoded = [0u8; 64];
+        let exported = ExportableKey::from_str_with_buffer("MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=", &mut decoded).unwrap();
+        assert_eq!(exported.u8_array, KEY);
+        assert_eq!(decoded, [0u8; 64])
+    }
+
+    #[test]
+    fn wipe_zeroes_every_byte() {
+        let mut buffer = KEY;
+        wipe(&mut buffer);
+        assert_eq!(buffer, [0u8; 32])
+    }
+}
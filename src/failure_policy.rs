@@ -0,0 +1,312 @@
+//! Throttling of repeated decryption failures, against oracles and brute
+//! force.
+//!
+//! A `FailurePolicy` counts the failures of each caller-supplied context
+//! (a client id, an IP, a session) in a sliding window. Once a context
+//! has `threshold` failures inside the window, its decryptions fail with
+//! `DecryptionError::RateLimited` without touching the ciphertext, until
+//! enough of those failures have aged out. Attach a policy with
+//! `CipherBuilder::failure_policy` or `KeyRing::with_failure_policy`, and
+//! decrypt with their `*_for(context_id, ..)` methods.
+//!
+//! Context-less calls (`decrypt`, `decrypt_token`) aren't throttled.
+//!
+//! Counts live in a `FailureStore`, in memory by default; implement the
+//! trait to share them between processes. `on_threshold` is called each
+//! time a context reaches the threshold, for alerting.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::audit::{self, AuditOperation};
+use crate::key_ring::{Clock, SystemClock};
+use crate::{DecryptionError, Key};
+
+/// Failure timestamps per context.
+pub trait FailureStore: Send + Sync {
+    /// Records a failure at `now` and returns the number of failures of
+    /// `context_id` since `since`, this one included.
+    fn record_failure(&self, context_id: &str, now: SystemTime, since: SystemTime) -> u32;
+
+    /// Like `record_failure`, but only if `context_id` has fewer than
+    /// `limit` failures since `since`, as one atomic step; `None` otherwise.
+    fn try_record_failure(&self, context_id: &str, now: SystemTime, since: SystemTime, limit: u32) -> Option<u32>;
+
+    /// Takes back one failure recorded at `at`.
+    fn remove_failure(&self, context_id: &str, at: SystemTime);
+
+    /// The number of failures of `context_id` since `since`.
+    fn failures_since(&self, context_id: &str, since: SystemTime) -> u32;
+
+    fn reset(&self, context_id: &str);
+}
+
+/// Keeps the failures of the current window, dropping older ones as it goes.
+#[derive(Default)]
+pub struct InMemoryFailureStore {
+    failures: Mutex<HashMap<String, VecDeque<SystemTime>>>
+}
+
+impl InMemoryFailureStore {
+    pub fn new() -> InMemoryFailureStore {
+        InMemoryFailureStore::default()
+    }
+}
+
+fn expire(failures: &mut VecDeque<SystemTime>, since: SystemTime) {
+    while failures.front().is_some_and(|failure| *failure < since) {
+        failures.pop_front();
+    }
+}
+
+impl FailureStore for InMemoryFailureStore {
+    fn record_failure(&self, context_id: &str, now: SystemTime, since: SystemTime) -> u32 {
+        let mut contexts = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        let failures = contexts.entry(String::from(context_id)).or_default();
+        expire(failures, since);
+        failures.push_back(now);
+        failures.len() as u32
+    }
+
+    fn try_record_failure(&self, context_id: &str, now: SystemTime, since: SystemTime, limit: u32) -> Option<u32> {
+        let mut contexts = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        let failures = contexts.entry(String::from(context_id)).or_default();
+        expire(failures, since);
+        if failures.len() as u32 >= limit {
+            return None;
+        }
+        failures.push_back(now);
+        Some(failures.len() as u32)
+    }
+
+    fn remove_failure(&self, context_id: &str, at: SystemTime) {
+        let mut contexts = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(failures) = contexts.get_mut(context_id) {
+            if let Some(index) = failures.iter().rposition(|failure| *failure == at) {
+                failures.remove(index);
+            }
+            if failures.is_empty() {
+                contexts.remove(context_id);
+            }
+        }
+    }
+
+    fn failures_since(&self, context_id: &str, since: SystemTime) -> u32 {
+        let mut contexts = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        match contexts.get_mut(context_id) {
+            Some(failures) => {
+                expire(failures, since);
+                let count = failures.len() as u32;
+                if failures.is_empty() {
+                    contexts.remove(context_id);
+                }
+                count
+            },
+            None => 0
+        }
+    }
+
+    fn reset(&self, context_id: &str) {
+        self.failures.lock().unwrap_or_else(|e| e.into_inner()).remove(context_id);
+    }
+}
+
+/// Called with a context id and its failure count.
+type ThresholdCallback = Arc<dyn Fn(&str, u32) + Send + Sync>;
+
+pub struct FailurePolicy {
+    threshold: u32,
+    window: Duration,
+    reset_on_success: bool,
+    store: Arc<dyn FailureStore>,
+    on_threshold: Option<ThresholdCallback>,
+    clock: Arc<dyn Clock>
+}
+
+impl FailurePolicy {
+    /// Limits each context to `threshold` failures per `window`, in memory.
+    pub fn new(threshold: u32, window: Duration) -> FailurePolicy {
+        FailurePolicy {
+            threshold,
+            window,
+            reset_on_success: false,
+            store: Arc::new(InMemoryFailureStore::new()),
+            on_threshold: None,
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    /// Forgets a context's failures when one of its decryptions succeeds.
+    pub fn reset_on_success(mut self, reset_on_success: bool) -> FailurePolicy {
+        self.reset_on_success = reset_on_success;
+        self
+    }
+
+    pub fn with_store(mut self, store: Arc<dyn FailureStore>) -> FailurePolicy {
+        self.store = store;
+        self
+    }
+
+    /// Called with the context id and its failure count when the failure
+    /// that reaches the threshold is recorded.
+    pub fn on_threshold<F: Fn(&str, u32) + Send + Sync + 'static>(mut self, callback: F) -> FailurePolicy {
+        self.on_threshold = Some(Arc::new(callback));
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> FailurePolicy {
+        self.clock = clock;
+        self
+    }
+
+    fn window_start(&self, now: SystemTime) -> SystemTime {
+        now.checked_sub(self.window).unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
+    /// Whether `context_id` is currently over its threshold.
+    pub fn is_limited(&self, context_id: &str) -> bool {
+        self.store.failures_since(context_id, self.window_start(self.clock.now())) >= self.threshold
+    }
+
+    pub fn record_failure(&self, context_id: &str) {
+        let now = self.clock.now();
+        let failures = self.store.record_failure(context_id, now, self.window_start(now));
+        self.failed(context_id, failures);
+    }
+
+    fn failed(&self, context_id: &str, failures: u32) {
+        if failures == self.threshold {
+            if let Some(callback) = &self.on_threshold {
+                callback(context_id, failures);
+            }
+        }
+    }
+
+    pub fn record_success(&self, context_id: &str) {
+        if self.reset_on_success {
+            self.store.reset(context_id);
+        }
+    }
+
+    /// Runs `decrypt` unless `context_id` is limited, and records its outcome.
+    ///
+    /// The attempt is counted as a failure before `decrypt` runs, in the
+    /// same store operation as the check, and taken back if it succeeds: so
+    /// concurrent calls can't get more than `threshold` attempts past the
+    /// check. A limited call decrypts nothing and is audited as a
+    /// `"rate_limited"` decryption with `key`, of `payload_len` 0.
+    pub fn guard<T, F>(&self, context_id: &str, key: &Key, decrypt: F) -> Result<T, DecryptionError>
+    where F: FnOnce() -> Result<T, DecryptionError> {
+        let now = self.clock.now();
+        let failures = match self.store.try_record_failure(context_id, now, self.window_start(now), self.threshold) {
+            Some(failures) => failures,
+            None => return Err(rate_limited(key))
+        };
+        let result = decrypt();
+        match &result {
+            Ok(_) => {
+                self.store.remove_failure(context_id, now);
+                self.record_success(context_id);
+            },
+            Err(_) => self.failed(context_id, failures)
+        }
+        result
+    }
+}
+
+/// Audits a rejected decryption with `key` and returns the error for it.
+pub(crate) fn rate_limited(key: &Key) -> DecryptionError {
+    audit::record(AuditOperation::Decrypt, key, 0, Some("rate_limited"));
+    DecryptionError::RateLimited
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    struct ManualClock(Mutex<SystemTime>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    const KEY: Key = Key { u8_array: [0u8; 32] };
+
+    fn fail() -> Result<(), DecryptionError> {
+        Err(DecryptionError::GenericDecryptionError)
+    }
+
+    #[test]
+    fn threshold_trips_after_n_failures_and_the_window_slides() {
+        let clock = Arc::new(ManualClock(Mutex::new(UNIX_EPOCH)));
+        let alerts = Arc::new(Mutex::new(Vec::new()));
+        let alerted = alerts.clone();
+        let policy = FailurePolicy::new(3, Duration::from_secs(60))
+            .with_clock(clock.clone())
+            .on_threshold(move |context_id, failures| alerted.lock().unwrap().push((String::from(context_id), failures)));
+        for _ in 0..3 {
+            *clock.0.lock().unwrap() += Duration::from_secs(10);
+            assert!(policy.guard("client", &KEY, fail).is_err());
+        }
+        match policy.guard("client", &KEY, || Ok(())) {
+            Ok(_) => assert!(false, "Should err RateLimited"),
+            Err(e) => match e {
+                DecryptionError::RateLimited => assert!(true),
+                _ => assert!(false, "Should err RateLimited")
+            }
+        }
+        assert_eq!(*alerts.lock().unwrap(), vec![(String::from("client"), 3)]);
+
+        // The first failure, at 10s, leaves the window at 71s.
+        *clock.0.lock().unwrap() = UNIX_EPOCH + Duration::from_secs(71);
+        assert!(!policy.is_limited("client"));
+        assert!(policy.guard("client", &KEY, || Ok(())).is_ok())
+    }
+
+    #[test]
+    fn contexts_are_independent() {
+        let policy = FailurePolicy::new(2, Duration::from_secs(60));
+        policy.record_failure("a");
+        policy.record_failure("a");
+        assert!(policy.is_limited("a"));
+        assert!(!policy.is_limited("b"));
+        assert_eq!(policy.guard("b", &KEY, || Ok(1)).unwrap(), 1);
+        policy.record_failure("b");
+        assert!(!policy.is_limited("b"))
+    }
+
+    #[test]
+    fn attempts_in_flight_count_against_the_threshold() {
+        let policy = FailurePolicy::new(1, Duration::from_secs(60));
+        let outer = policy.guard("client", &KEY, || {
+            match policy.guard("client", &KEY, || Ok(())) {
+                Ok(_) => assert!(false, "Should err RateLimited"),
+                Err(e) => match e {
+                    DecryptionError::RateLimited => assert!(true),
+                    _ => assert!(false, "Should err RateLimited")
+                }
+            }
+            Ok(())
+        });
+        assert!(outer.is_ok());
+        // The successful attempt was taken back.
+        assert!(!policy.is_limited("client"));
+        assert!(policy.guard("client", &KEY, || Ok(())).is_ok())
+    }
+
+    #[test]
+    fn success_resets_only_when_enabled() {
+        for (reset_on_success, limited) in [(false, true), (true, false)].iter() {
+            let policy = FailurePolicy::new(2, Duration::from_secs(60)).reset_on_success(*reset_on_success);
+            assert!(policy.guard("client", &KEY, fail).is_err());
+            assert!(policy.guard("client", &KEY, || Ok(())).is_ok());
+            assert!(policy.guard("client", &KEY, fail).is_err());
+            assert_eq!(policy.is_limited("client"), *limited)
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! C-compatible interface, enabled with the `ffi` feature.
+//!
+//! Every function returns one of the `SAES_*` status codes below; the values
+//! are stable and will never be reused for a different condition. Buffers
+//! handed back through out-parameters are owned by the caller and must be
+//! released with `saes_free_buf`. No Rust panic ever crosses the boundary:
+//! it is reported as `SAES_ERR_PANIC` instead. The status codes are not
+//! `errors::ErrorCode` values; the module docs of `errors` map one table
+//! to the other.
+//!
+//! The shared library is built with
+//! `cargo rustc --release --lib --features ffi --crate-type cdylib`; the
+//! crate itself is only an rlib, so Rust dependents don't link one.
+//!
+//! The C header lives in `include/simple_aes256_gcm.h` and is generated with
+//! `cbindgen --config cbindgen.toml --output include/simple_aes256_gcm.h`.
+
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::panic::{self, UnwindSafe};
+use std::{ptr, slice};
+
+use crate::{decrypt, encrypt, DecryptionError, Decrypted, Encrypted, EncryptedAndIv, InvalidKeyError, Iv, Key};
+
+/// Length of a raw key, in bytes.
+pub const SAES_KEY_LEN: usize = 32;
+/// Length of a raw iv, in bytes.
+pub const SAES_IV_LEN: usize = 12;
+
+/// The operation succeeded.
+pub const SAES_OK: i32 = 0;
+/// A required pointer argument was null.
+pub const SAES_ERR_NULL_POINTER: i32 = 1;
+/// The key is not valid base64 (`InvalidKeyError::InvalidKeyBase64Error`).
+pub const SAES_ERR_INVALID_KEY_BASE64: i32 = 2;
+/// The key does not decode to 32 bytes (`InvalidKeyError::InvalidKeySizeError`).
+pub const SAES_ERR_INVALID_KEY_SIZE: i32 = 3;
+/// An input string or plaintext is not valid UTF-8.
+pub const SAES_ERR_INVALID_INPUT_UTF8: i32 = 4;
+/// Encryption failed (`EncryptionError::GenericEncryptionError`).
+pub const SAES_ERR_ENCRYPTION: i32 = 5;
+/// Decryption failed: wrong key, wrong iv or tampered data
+/// (`DecryptionError::GenericDecryptionError`).
+pub const SAES_ERR_DECRYPTION: i32 = 6;
+/// The decrypted value is not valid UTF-8
+/// (`DecryptionError::InvalidUTF8DecryptionError`).
+pub const SAES_ERR_DECRYPTION_INVALID_UTF8: i32 = 7;
+/// The key doesn't match the value's key check value
+/// (`DecryptionError::ProbableWrongKey`).
+pub const SAES_ERR_PROBABLE_WRONG_KEY: i32 = 8;
+/// A Rust panic was caught before it could unwind into the caller.
+pub const SAES_ERR_PANIC: i32 = 99;
+
+fn guard<F: FnOnce() -> i32 + UnwindSafe>(f: F) -> i32 {
+    panic::catch_unwind(f).unwrap_or(SAES_ERR_PANIC)
+}
+
+unsafe fn read_key(key: *const u8) -> Key {
+    let mut u8_array = [0u8; SAES_KEY_LEN];
+    u8_array.copy_from_slice(slice::from_raw_parts(key, SAES_KEY_LEN));
+    Key { u8_array }
+}
+
+unsafe fn read_bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len)
+    }
+}
+
+unsafe fn write_buf(bytes: Vec<u8>, out_buf: *mut *mut u8, out_len: *mut usize) {
+    let boxed = bytes.into_boxed_slice();
+    *out_len = boxed.len();
+    *out_buf = Box::into_raw(boxed) as *mut u8;
+}
+
+/// Decodes a NUL-terminated base64 key into the 32 bytes pointed to by `out_key`.
+///
+/// # Safety
+///
+/// `base64_key` must be a valid NUL-terminated string and `out_key` must point
+/// to at least `SAES_KEY_LEN` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn saes_key_from_base64(base64_key: *const c_char, out_key: *mut u8) -> i32 {
+    guard(|| {
+        if base64_key.is_null() || out_key.is_null() {
+            return SAES_ERR_NULL_POINTER;
+        }
+        let base64_key = match CStr::from_ptr(base64_key).to_str() {
+            Ok(value) => value,
+            Err(_) => return SAES_ERR_INVALID_INPUT_UTF8
+        };
+        match Key::try_from(base64_key) {
+            Ok(key) => {
+                ptr::copy_nonoverlapping(key.u8_array.as_ptr(), out_key, SAES_KEY_LEN);
+                SAES_OK
+            },
+            Err(InvalidKeyError::InvalidKeyBase64Error(_)) => SAES_ERR_INVALID_KEY_BASE64,
+            Err(InvalidKeyError::InvalidKeySizeError) => SAES_ERR_INVALID_KEY_SIZE
+        }
+    })
+}
+
+/// Encrypts `plaintext_len` bytes of UTF-8 `plaintext` with a freshly generated iv.
+///
+/// On success the iv is written to `out_iv` and the ciphertext is returned in
+/// a newly allocated buffer through `out_buf` / `out_len`.
+///
+/// # Safety
+///
+/// `key` must point to `SAES_KEY_LEN` readable bytes, `plaintext` to
+/// `plaintext_len` readable bytes (it may be null when `plaintext_len` is 0),
+/// `out_iv` to `SAES_IV_LEN` writable bytes, and `out_buf` / `out_len` must be
+/// valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn saes_encrypt(
+    key: *const u8,
+    plaintext: *const u8,
+    plaintext_len: usize,
+    out_iv: *mut u8,
+    out_buf: *mut *mut u8,
+    out_len: *mut usize
+) -> i32 {
+    guard(|| {
+        if key.is_null() || (plaintext.is_null() && plaintext_len > 0) || out_iv.is_null() || out_buf.is_null() || out_len.is_null() {
+            return SAES_ERR_NULL_POINTER;
+        }
+        let key = read_key(key);
+        let plaintext = match std::str::from_utf8(read_bytes(plaintext, plaintext_len)) {
+            Ok(value) => value,
+            Err(_) => return SAES_ERR_INVALID_INPUT_UTF8
+        };
+        match encrypt(&key, &Decrypted::from(plaintext)) {
+            Ok(encrypted_and_iv) => {
+                ptr::copy_nonoverlapping(encrypted_and_iv.iv.u8_array.as_ptr(), out_iv, SAES_IV_LEN);
+                write_buf(encrypted_and_iv.encrypted.u8_vec, out_buf, out_len);
+                SAES_OK
+            },
+            Err(_) => SAES_ERR_ENCRYPTION
+        }
+    })
+}
+
+/// Decrypts `ciphertext_len` bytes of `ciphertext` produced with `iv`.
+///
+/// On success the UTF-8 plaintext (not NUL-terminated) is returned in a newly
+/// allocated buffer through `out_buf` / `out_len`.
+///
+/// # Safety
+///
+/// `key` must point to `SAES_KEY_LEN` readable bytes, `iv` to `SAES_IV_LEN`
+/// readable bytes, `ciphertext` to `ciphertext_len` readable bytes, and
+/// `out_buf` / `out_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn saes_decrypt(
+    key: *const u8,
+    iv: *const u8,
+    ciphertext: *const u8,
+    ciphertext_len: usize,
+    out_buf: *mut *mut u8,
+    out_len: *mut usize
+) -> i32 {
+    guard(|| {
+        if key.is_null() || iv.is_null() || (ciphertext.is_null() && ciphertext_len > 0) || out_buf.is_null() || out_len.is_null() {
+            return SAES_ERR_NULL_POINTER;
+        }
+        let key = read_key(key);
+        let mut iv_array = [0u8; SAES_IV_LEN];
+        iv_array.copy_from_slice(slice::from_raw_parts(iv, SAES_IV_LEN));
+        let encrypted_and_iv = EncryptedAndIv::from_parts(Iv { u8_array: iv_array }, Encrypted { u8_vec: read_bytes(ciphertext, ciphertext_len).to_vec() });
+        match decrypt(&key, encrypted_and_iv) {
+            Ok(plaintext) => {
+                write_buf(plaintext.into_bytes(), out_buf, out_len);
+                SAES_OK
+            },
+            Err(DecryptionError::InvalidUTF8DecryptionError(_)) => SAES_ERR_DECRYPTION_INVALID_UTF8,
+            Err(DecryptionError::ProbableWrongKey) => SAES_ERR_PROBABLE_WRONG_KEY,
+            // `decrypt` has no failure policy, context nor sequence
+            // numbers, so it never returns the last four.
+            Err(DecryptionError::GenericDecryptionError) | Err(DecryptionError::RateLimited) | Err(DecryptionError::ProbableContextMismatch)
+            | Err(DecryptionError::Replayed) | Err(DecryptionError::OutOfWindow) => SAES_ERR_DECRYPTION
+        }
+    })
+}
+
+/// Releases a buffer returned by `saes_encrypt` or `saes_decrypt`.
+///
+/// # Safety
+///
+/// `buf` / `len` must be exactly a pair previously returned by this library,
+/// and must not be used after this call. A null `buf` is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn saes_free_buf(buf: *mut u8, len: usize) {
+    if buf.is_null() {
+        return;
+    }
+    let _ = panic::catch_unwind(|| {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buf, len)));
+    });
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    const BASE64_KEY: &str = "MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=";
+
+    fn key() -> [u8; SAES_KEY_LEN] {
+        let base64_key = CString::new(BASE64_KEY).unwrap();
+        let mut key = [0u8; SAES_KEY_LEN];
+        assert_eq!(unsafe { saes_key_from_base64(base64_key.as_ptr(), key.as_mut_ptr()) }, SAES_OK);
+        key
+    }
+
+    fn encrypt_via_ffi(key: &[u8; SAES_KEY_LEN], plaintext: &[u8]) -> ([u8; SAES_IV_LEN], Vec<u8>) {
+        let mut iv = [0u8; SAES_IV_LEN];
+        let mut buf: *mut u8 = ptr::null_mut();
+        let mut len: usize = 0;
+        let code = unsafe {
+            saes_encrypt(key.as_ptr(), plaintext.as_ptr(), plaintext.len(), iv.as_mut_ptr(), &mut buf, &mut len)
+        };
+        assert_eq!(code, SAES_OK);
+        let ciphertext = unsafe { slice::from_raw_parts(buf, len) }.to_vec();
+        unsafe { saes_free_buf(buf, len) };
+        (iv, ciphertext)
+    }
+
+    fn decrypt_via_ffi(key: &[u8; SAES_KEY_LEN], iv: &[u8; SAES_IV_LEN], ciphertext: &[u8]) -> Result<Vec<u8>, i32> {
+        let mut buf: *mut u8 = ptr::null_mut();
+        let mut len: usize = 0;
+        let code = unsafe {
+            saes_decrypt(key.as_ptr(), iv.as_ptr(), ciphertext.as_ptr(), ciphertext.len(), &mut buf, &mut len)
+        };
+        if code != SAES_OK {
+            return Err(code);
+        }
+        let plaintext = unsafe { slice::from_raw_parts(buf, len) }.to_vec();
+        unsafe { saes_free_buf(buf, len) };
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn key_from_base64_matches_key_try_from() {
+        assert_eq!(key(), Key::try_from(BASE64_KEY).unwrap().u8_array)
+    }
+
+    #[test]
+    fn key_from_base64_invalid_base64_fails() {
+        let base64_key = CString::new("012").unwrap();
+        let mut key = [0u8; SAES_KEY_LEN];
+        assert_eq!(unsafe { saes_key_from_base64(base64_key.as_ptr(), key.as_mut_ptr()) }, SAES_ERR_INVALID_KEY_BASE64)
+    }
+
+    #[test]
+    fn key_from_base64_invalid_size_fails() {
+        let base64_key = CString::new("MDEy").unwrap();
+        let mut key = [0u8; SAES_KEY_LEN];
+        assert_eq!(unsafe { saes_key_from_base64(base64_key.as_ptr(), key.as_mut_ptr()) }, SAES_ERR_INVALID_KEY_SIZE)
+    }
+
+    #[test]
+    fn key_from_base64_null_pointer_fails() {
+        let mut key = [0u8; SAES_KEY_LEN];
+        assert_eq!(unsafe { saes_key_from_base64(ptr::null(), key.as_mut_ptr()) }, SAES_ERR_NULL_POINTER)
+    }
+
+    #[test]
+    fn encrypt_decrypt_is_iso() {
+        let key = key();
+        let (iv, ciphertext) = encrypt_via_ffi(&key, b"This is a text.");
+        assert_eq!(decrypt_via_ffi(&key, &iv, &ciphertext).unwrap(), b"This is a text.".to_vec())
+    }
+
+    #[test]
+    fn decrypt_values_produced_by_rust() {
+        let key = Key::try_from(BASE64_KEY).unwrap();
+        let encrypted_and_iv = encrypt(&key, &Decrypted::from("This is a text.")).unwrap();
+        assert_eq!(
+            decrypt_via_ffi(&key.u8_array, &encrypted_and_iv.iv.u8_array, &encrypted_and_iv.encrypted.u8_vec).unwrap(),
+            b"This is a text.".to_vec()
+        )
+    }
+
+    #[test]
+    fn decrypt_with_wrong_key_fails() {
+        let (iv, ciphertext) = encrypt_via_ffi(&key(), b"This is a text.");
+        assert_eq!(decrypt_via_ffi(&[0u8; SAES_KEY_LEN], &iv, &ciphertext), Err(SAES_ERR_DECRYPTION))
+    }
+
+    #[test]
+    fn decrypt_tampered_ciphertext_fails() {
+        let key = key();
+        let (iv, mut ciphertext) = encrypt_via_ffi(&key, b"This is a text.");
+        ciphertext[0] ^= 1;
+        assert_eq!(decrypt_via_ffi(&key, &iv, &ciphertext), Err(SAES_ERR_DECRYPTION))
+    }
+
+    #[test]
+    fn encrypt_non_utf8_plaintext_fails() {
+        let key = key();
+        let plaintext: &[u8] = &[133u8, 133u8];
+        let mut iv = [0u8; SAES_IV_LEN];
+        let mut buf: *mut u8 = ptr::null_mut();
+        let mut len: usize = 0;
+        let code = unsafe {
+            saes_encrypt(key.as_ptr(), plaintext.as_ptr(), plaintext.len(), iv.as_mut_ptr(), &mut buf, &mut len)
+        };
+        assert_eq!(code, SAES_ERR_INVALID_INPUT_UTF8);
+        assert!(buf.is_null())
+    }
+
+    #[test]
+    fn guard_catches_panics() {
+        assert_eq!(guard(|| panic!("boom")), SAES_ERR_PANIC)
+    }
+
+    #[test]
+    fn free_buf_ignores_null() {
+        unsafe { saes_free_buf(ptr::null_mut(), 0) };
+        assert!(true)
+    }
+}
@@ -0,0 +1,142 @@
+//! Transparent encryption of files in a git repository, as git clean and
+//! smudge filters, the way git-crypt does it.
+//!
+//! `clean` (run by git when staging) encrypts a file's content to
+//! `GIT_FILTER_MAGIC || iv (12) || ciphertext and tag`, and `smudge` (run
+//! on checkout) decrypts it back. The iv is synthetic: HMAC-SHA256, under a
+//! key derived from the encryption key, of the content, truncated to 12
+//! bytes. The same content always encrypts to the same bytes, so unchanged
+//! files never show up as modified, and only identical contents share an
+//! iv. The price is that equal files are visibly equal in the repository.
+//!
+//! Both pass through what they are not meant for: `clean` leaves content
+//! that already starts with the magic as is, and `smudge` content that
+//! doesn't, such as files committed before the filter was set up.
+//!
+//! Git runs filters as commands reading stdin and writing stdout;
+//! examples/git_filter.rs is one. With it built and on the `PATH` as
+//! `saes-git-filter`, and the base64 key in `$SAES_GIT_KEY`:
+//!
+//! ```text
+//! # .gitattributes
+//! secrets/** filter=saes
+//!
+//! # .git/config, or git config filter.saes.clean ... and so on
+//! [filter "saes"]
+//!     clean = saes-git-filter clean
+//!     smudge = saes-git-filter smudge
+//!     required = true
+//! ```
+//!
+//! `required` makes git fail rather than commit plaintext when the filter
+//! can't run.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{decrypt_bytes, encrypt_bytes_with_iv, DecryptionError, Encrypted, EncryptedAndIv, EncryptionError, Iv, Key, IV_LEN, TAG_LEN};
+
+/// Starts every cleaned file. The NUL bytes make git treat it as binary.
+pub const GIT_FILTER_MAGIC: &[u8] = b"\0SAES-GIT1\0";
+
+const IV_KEY_LABEL: &[u8] = b"simple-aes256-gcm git filter iv";
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+fn synthetic_iv(key: &Key, content: &[u8]) -> Iv {
+    let iv_key = hmac_sha256(&key.u8_array, IV_KEY_LABEL);
+    let mut u8_array = [0u8; IV_LEN];
+    u8_array.copy_from_slice(&hmac_sha256(&iv_key, content)[..IV_LEN]);
+    Iv { u8_array }
+}
+
+/// Whether `content` is the output of `clean`, judging by its magic.
+pub fn is_encrypted(content: &[u8]) -> bool {
+    content.starts_with(GIT_FILTER_MAGIC)
+}
+
+/// Encrypts `content` deterministically, see the module docs. Already
+/// encrypted content is returned as is.
+pub fn clean(key: &Key, content: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    if is_encrypted(content) {
+        return Ok(content.to_vec());
+    }
+    let encrypted_and_iv = encrypt_bytes_with_iv(key, synthetic_iv(key, content), content)?;
+    let mut cleaned = Vec::with_capacity(GIT_FILTER_MAGIC.len() + IV_LEN + encrypted_and_iv.encrypted.u8_vec.len());
+    cleaned.extend_from_slice(GIT_FILTER_MAGIC);
+    cleaned.extend_from_slice(&encrypted_and_iv.iv.u8_array);
+    cleaned.extend_from_slice(&encrypted_and_iv.encrypted.u8_vec);
+    Ok(cleaned)
+}
+
+/// Decrypts the output of `clean`. Content without the magic is returned
+/// as is; content with it fails unless it decrypts under `key` to the
+/// content its iv was derived from.
+pub fn smudge(key: &Key, content: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+    if !is_encrypted(content) {
+        return Ok(content.to_vec());
+    }
+    let body = &content[GIT_FILTER_MAGIC.len()..];
+    if body.len() < IV_LEN + TAG_LEN {
+        return Err(DecryptionError::GenericDecryptionError);
+    }
+    let mut u8_array = [0u8; IV_LEN];
+    u8_array.copy_from_slice(&body[..IV_LEN]);
+    let encrypted_and_iv = EncryptedAndIv::from_parts(Iv { u8_array }, Encrypted { u8_vec: body[IV_LEN..].to_vec() });
+    let plaintext = decrypt_bytes(key, &encrypted_and_iv)?;
+    if synthetic_iv(key, &plaintext).u8_array != u8_array {
+        return Err(DecryptionError::GenericDecryptionError);
+    }
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    #[test]
+    fn clean_then_smudge_round_trips() {
+        let key = Key { u8_array: KEY };
+        for content in [&b""[..], b"API_TOKEN=hunter2\n", &[0u8, 1, 2, 255][..]].iter() {
+            let cleaned = clean(&key, content).unwrap();
+            assert!(is_encrypted(&cleaned));
+            assert_eq!(cleaned.len(), GIT_FILTER_MAGIC.len() + IV_LEN + content.len() + TAG_LEN);
+            assert_eq!(smudge(&key, &cleaned).unwrap(), *content)
+        }
+    }
+
+    #[test]
+    fn clean_is_deterministic_per_key_and_content() {
+        let key = Key { u8_array: KEY };
+        let cleaned = clean(&key, b"API_TOKEN=hunter2\n").unwrap();
+        assert_eq!(clean(&key, b"API_TOKEN=hunter2\n").unwrap(), cleaned);
+        assert_ne!(clean(&key, b"API_TOKEN=hunter3\n").unwrap()[..GIT_FILTER_MAGIC.len() + IV_LEN], cleaned[..GIT_FILTER_MAGIC.len() + IV_LEN]);
+        assert_ne!(clean(&Key { u8_array: [0u8; 32] }, b"API_TOKEN=hunter2\n").unwrap(), cleaned)
+    }
+
+    #[test]
+    fn each_direction_passes_through_what_it_is_not_for() {
+        let key = Key { u8_array: KEY };
+        let cleaned = clean(&key, b"API_TOKEN=hunter2\n").unwrap();
+        assert_eq!(clean(&key, &cleaned).unwrap(), cleaned);
+        assert_eq!(smudge(&key, b"committed before the filter\n").unwrap(), b"committed before the filter\n")
+    }
+
+    #[test]
+    fn tampered_truncated_or_other_key_content_fails() {
+        let key = Key { u8_array: KEY };
+        let cleaned = clean(&key, b"API_TOKEN=hunter2\n").unwrap();
+        let mut tampered = cleaned.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        for content in [tampered, cleaned[..GIT_FILTER_MAGIC.len() + 20].to_vec()].iter() {
+            assert!(smudge(&key, content).is_err());
+        }
+        assert!(smudge(&Key { u8_array: [0u8; 32] }, &cleaned).is_err())
+    }
+}
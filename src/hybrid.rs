@@ -0,0 +1,270 @@
+//! Sealed boxes: public-key encryption, enabled with the `hybrid` feature.
+//!
+//! The sender only needs the recipient's X25519 public key. `seal` works as follows:
+//!
+//! 1. Generate an ephemeral X25519 key pair `(e, E)`.
+//! 2. `shared = X25519(e, R)` where `R` is the recipient public key. An
+//!    all-zero `shared` (low-order `R`) is rejected.
+//! 3. `okm = HKDF-SHA256(ikm = shared, salt = E || R, info = "simple-aes256-gcm hybrid v1", L = 44)`.
+//! 4. `key = okm[0..32]`, `iv = okm[32..44]`. Both are unique to the
+//!    ephemeral key, so the iv is never reused under one key and isn't stored.
+//! 5. `ciphertext = AES-256-GCM(key, iv, plaintext)`, no associated data.
+//!
+//! The sealed box is `E (32) || ciphertext and tag`, base64-encoded in its
+//! string form. `open` recomputes `shared = X25519(r, E)` and the same HKDF
+//! output; binding `E` and `R` into the salt means any change to `E`
+//! yields another key and fails authentication.
+
+use std::convert::{TryFrom, TryInto};
+use std::{error, fmt};
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::StaticSecret;
+
+use crate::{decrypt_bytes, encrypt_bytes_with_iv, DecryptionError, Encrypted, EncryptedAndIv, EncryptionError, Iv, Key, TAG_LEN};
+
+const INFO: &[u8] = b"simple-aes256-gcm hybrid v1";
+
+pub struct PublicKey {
+    pub u8_array: [u8; 32]
+}
+
+pub struct SecretKey {
+    pub u8_array: [u8; 32]
+}
+
+impl SecretKey {
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey {
+            u8_array: x25519_dalek::PublicKey::from(&StaticSecret::from(self.u8_array)).to_bytes()
+        }
+    }
+}
+
+pub fn generate_keypair() -> (PublicKey, SecretKey) {
+    let secret_key = SecretKey {
+        u8_array: rand::random::<[u8; 32]>()
+    };
+    (secret_key.public_key(), secret_key)
+}
+
+pub struct SealedBox {
+    pub ephemeral_public: [u8; 32],
+    pub encrypted: Encrypted
+}
+
+fn derive(shared: &[u8; 32], ephemeral_public: &[u8; 32], recipient_public: &[u8; 32]) -> Option<(Key, Iv)> {
+    if shared.iter().all(|byte| *byte == 0) {
+        return None;
+    }
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral_public);
+    salt[32..].copy_from_slice(recipient_public);
+    let mut okm = [0u8; 44];
+    Hkdf::<Sha256>::new(Some(&salt), shared).expand(INFO, &mut okm).expect("44 bytes is a valid HKDF-SHA256 length");
+    Some((Key { u8_array: okm[..32].try_into().unwrap() }, Iv { u8_array: okm[32..].try_into().unwrap() }))
+}
+
+fn seal_with_ephemeral(recipient_public: &PublicKey, ephemeral: &SecretKey, plaintext: &[u8]) -> Result<SealedBox, EncryptionError> {
+    let ephemeral_public = ephemeral.public_key().u8_array;
+    let shared = StaticSecret::from(ephemeral.u8_array).diffie_hellman(&x25519_dalek::PublicKey::from(recipient_public.u8_array));
+    let (key, iv) = match derive(shared.as_bytes(), &ephemeral_public, &recipient_public.u8_array) {
+        Some(value) => value,
+        None => return Err(EncryptionError::GenericEncryptionError)
+    };
+    Ok(SealedBox {
+        ephemeral_public,
+        encrypted: encrypt_bytes_with_iv(&key, iv, plaintext)?.encrypted
+    })
+}
+
+/// Encrypts `plaintext` so that only the holder of the matching `SecretKey` can open it.
+pub fn seal(recipient_public: &PublicKey, plaintext: &[u8]) -> Result<SealedBox, EncryptionError> {
+    seal_with_ephemeral(recipient_public, &generate_keypair().1, plaintext)
+}
+
+pub fn open(recipient_secret: &SecretKey, sealed_box: &SealedBox) -> Result<Vec<u8>, DecryptionError> {
+    let shared = StaticSecret::from(recipient_secret.u8_array).diffie_hellman(&x25519_dalek::PublicKey::from(sealed_box.ephemeral_public));
+    let (key, iv) = match derive(shared.as_bytes(), &sealed_box.ephemeral_public, &recipient_secret.public_key().u8_array) {
+        Some(value) => value,
+        None => return Err(DecryptionError::GenericDecryptionError)
+    };
+    let encrypted_and_iv = EncryptedAndIv::from_parts(iv, Encrypted { u8_vec: sealed_box.encrypted.u8_vec.clone() });
+    decrypt_bytes(&key, &encrypted_and_iv)
+}
+
+#[derive(Debug, Clone)]
+pub enum InvalidPublicKeyError {
+    InvalidPublicKeySizeError,
+    InvalidPublicKeyBase64Error(base64::DecodeError)
+}
+
+impl fmt::Display for InvalidPublicKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidPublicKeyError::InvalidPublicKeySizeError => write!(f, "Please provide a 32-byte, base64-encoded, public key"),
+            InvalidPublicKeyError::InvalidPublicKeyBase64Error(_) => write!(f, "Please provide a valid base64"),
+        }
+    }
+}
+
+impl error::Error for InvalidPublicKeyError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            InvalidPublicKeyError::InvalidPublicKeySizeError => None,
+            InvalidPublicKeyError::InvalidPublicKeyBase64Error(e) => Some(e),
+        }
+    }
+}
+
+impl TryFrom<&str> for PublicKey {
+    type Error = InvalidPublicKeyError;
+    fn try_from(base64_public_key: &str) -> Result<Self, InvalidPublicKeyError> {
+        let public_key = match base64::decode(base64_public_key) {
+            Ok(data) => data,
+            Err(e) => return Err(InvalidPublicKeyError::InvalidPublicKeyBase64Error(e))
+        };
+        match public_key.as_slice().try_into() {
+            Ok(u8_array) => Ok(PublicKey { u8_array }),
+            Err(_) => Err(InvalidPublicKeyError::InvalidPublicKeySizeError)
+        }
+    }
+}
+
+impl From<&PublicKey> for String {
+    fn from(public_key: &PublicKey) -> String {
+        base64::encode(&public_key.u8_array)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from(self))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum InvalidSealedBoxError {
+    InvalidSealedBoxSizeError,
+    InvalidSealedBoxBase64Error(base64::DecodeError)
+}
+
+impl fmt::Display for InvalidSealedBoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidSealedBoxError::InvalidSealedBoxSizeError => write!(f, "Sealed box too short"),
+            InvalidSealedBoxError::InvalidSealedBoxBase64Error(_) => write!(f, "Please provide a valid base64"),
+        }
+    }
+}
+
+impl error::Error for InvalidSealedBoxError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            InvalidSealedBoxError::InvalidSealedBoxSizeError => None,
+            InvalidSealedBoxError::InvalidSealedBoxBase64Error(e) => Some(e),
+        }
+    }
+}
+
+impl TryFrom<&str> for SealedBox {
+    type Error = InvalidSealedBoxError;
+    fn try_from(base64_sealed_box: &str) -> Result<Self, InvalidSealedBoxError> {
+        let sealed_box = match base64::decode(base64_sealed_box) {
+            Ok(data) => data,
+            Err(e) => return Err(InvalidSealedBoxError::InvalidSealedBoxBase64Error(e))
+        };
+        if sealed_box.len() < 32 + TAG_LEN {
+            return Err(InvalidSealedBoxError::InvalidSealedBoxSizeError);
+        }
+        Ok(SealedBox {
+            ephemeral_public: sealed_box[..32].try_into().unwrap(),
+            encrypted: Encrypted { u8_vec: sealed_box[32..].to_vec() }
+        })
+    }
+}
+
+impl From<&SealedBox> for String {
+    fn from(sealed_box: &SealedBox) -> String {
+        let mut bytes = sealed_box.ephemeral_public.to_vec();
+        bytes.extend_from_slice(&sealed_box.encrypted.u8_vec);
+        base64::encode(&bytes)
+    }
+}
+
+impl fmt::Display for SealedBox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from(self))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    // Produced independently with Python's `cryptography`: recipient secret
+    // [1; 32], ephemeral secret [2; 32], plaintext "This is a text.".
+    const FIXTURE: &str = "zo060cy2M+x7cMF4FKXHbs0CloUFDTRHRboFhw5YfVm0MkYYB3+DwZjN1f2o1zLdEspyZLRxRHqq6aQnSuld";
+
+    #[test]
+    fn seal_then_open_is_iso() {
+        let (public_key, secret_key) = generate_keypair();
+        let sealed_box = seal(&public_key, b"This is a text.").unwrap();
+        assert_eq!(open(&secret_key, &sealed_box).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn open_with_wrong_secret_key_fails() {
+        let (public_key, _) = generate_keypair();
+        let sealed_box = seal(&public_key, b"This is a text.").unwrap();
+        match open(&generate_keypair().1, &sealed_box) {
+            Ok(_) => assert!(false, "Should err GenericDecryptionError"),
+            Err(e) => match e {
+                DecryptionError::GenericDecryptionError => assert!(true),
+                _ => assert!(false, "Should err GenericDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_ephemeral_public_key_fails() {
+        let (public_key, secret_key) = generate_keypair();
+        let mut sealed_box = seal(&public_key, b"This is a text.").unwrap();
+        sealed_box.ephemeral_public[0] ^= 1;
+        match open(&secret_key, &sealed_box) {
+            Ok(_) => assert!(false, "Should err GenericDecryptionError"),
+            Err(e) => match e {
+                DecryptionError::GenericDecryptionError => assert!(true),
+                _ => assert!(false, "Should err GenericDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    fn sealed_box_base64_round_trips() {
+        let (public_key, secret_key) = generate_keypair();
+        let public_key = PublicKey::try_from(&String::from(&public_key)[..]).unwrap();
+        let sealed_box = SealedBox::try_from(&seal(&public_key, b"This is a text.").unwrap().to_string()[..]).unwrap();
+        assert_eq!(open(&secret_key, &sealed_box).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn construction_matches_fixture() {
+        let recipient = SecretKey { u8_array: [1u8; 32] };
+        let sealed_box = seal_with_ephemeral(&recipient.public_key(), &SecretKey { u8_array: [2u8; 32] }, b"This is a text.").unwrap();
+        assert_eq!(sealed_box.to_string(), FIXTURE);
+        assert_eq!(open(&recipient, &SealedBox::try_from(FIXTURE).unwrap()).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn low_order_public_key_is_rejected() {
+        match seal(&PublicKey { u8_array: [0u8; 32] }, b"This is a text.") {
+            Ok(_) => assert!(false, "Should err GenericEncryptionError"),
+            Err(e) => match e {
+                EncryptionError::GenericEncryptionError => assert!(true)
+            }
+        }
+    }
+}
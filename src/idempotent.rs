@@ -0,0 +1,85 @@
+//! Encryption that gives the same bytes for the same record, for jobs that
+//! re-encrypt identical rows on every run and rely on unchanged output.
+//!
+//! `encrypt_idempotent` derives the iv instead of drawing it:
+//! `HKDF-SHA256(ikm = key, salt = "simple-aes256-gcm idempotent iv",
+//! info = record_id || SHA-256(plaintext), L = 12)`. The output is an
+//! ordinary envelope, iv included, that `decrypt_bytes` decrypts.
+//!
+//! The tradeoff: anyone seeing two outputs can tell whether they hold the
+//! same plaintext under the same record id, and whether a record changed
+//! between runs. Equal plaintexts under different record ids get
+//! unrelated ivs and ciphertexts. An iv repeats only for the same key,
+//! record id and plaintext, where the repetition is the point; otherwise
+//! ivs are as unlikely to collide as random ones, so the usual limit of
+//! 2^32 encryptions per key applies. Use it only for values whose equality
+//! isn't itself a secret.
+
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+
+use crate::{encrypt_bytes_with_iv, EncryptedAndIv, EncryptionError, Iv, Key, IV_LEN};
+
+const IV_SALT: &[u8] = b"simple-aes256-gcm idempotent iv";
+
+fn idempotent_iv(key: &Key, record_id: &[u8], plaintext: &[u8]) -> Iv {
+    // The digest has a fixed length, so where the record id ends is unambiguous.
+    let mut info = Vec::with_capacity(record_id.len() + 32);
+    info.extend_from_slice(record_id);
+    info.extend_from_slice(&Sha256::digest(plaintext));
+    let mut u8_array = [0u8; IV_LEN];
+    Hkdf::<Sha256>::new(Some(IV_SALT), &key.u8_array).expand(&info, &mut u8_array).expect("12 bytes is a valid HKDF-SHA256 length");
+    Iv { u8_array }
+}
+
+/// Encrypts `plaintext` with an iv derived from `key`, `record_id` and
+/// `plaintext`, see the module docs.
+pub fn encrypt_idempotent(key: &Key, record_id: &[u8], plaintext: &[u8]) -> Result<EncryptedAndIv, EncryptionError> {
+    encrypt_bytes_with_iv(key, idempotent_iv(key, record_id, plaintext), plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use crate::decrypt_bytes;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    fn key() -> Key {
+        Key { u8_array: KEY }
+    }
+
+    #[test]
+    fn same_inputs_give_identical_output() {
+        let first = encrypt_idempotent(&key(), b"row-42", b"This is a text.").unwrap();
+        let second = encrypt_idempotent(&key(), b"row-42", b"This is a text.").unwrap();
+        assert_eq!((first.iv.u8_array, &first.encrypted.u8_vec), (second.iv.u8_array, &second.encrypted.u8_vec));
+        assert_eq!(decrypt_bytes(&key(), &first).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn any_changed_input_changes_iv_and_ciphertext() {
+        let original = encrypt_idempotent(&key(), b"row-42", b"This is a text.").unwrap();
+        let others = [
+            encrypt_idempotent(&key(), b"row-42", b"This is a text!").unwrap(),
+            encrypt_idempotent(&key(), b"row-43", b"This is a text.").unwrap(),
+            encrypt_idempotent(&Key { u8_array: [0u8; 32] }, b"row-42", b"This is a text.").unwrap()
+        ];
+        for other in others.iter() {
+            assert_ne!(other.iv.u8_array, original.iv.u8_array);
+            assert_ne!(other.encrypted.u8_vec, original.encrypted.u8_vec);
+        }
+    }
+
+    #[test]
+    fn record_ids_do_not_collide() {
+        let mut ivs = HashSet::new();
+        for record in 0..10_000u32 {
+            let iv = encrypt_idempotent(&key(), &record.to_be_bytes(), b"same plaintext").unwrap().iv.u8_array;
+            assert!(ivs.insert(iv), "record {}", record);
+        }
+        // Shifting bytes between the record id and the plaintext isn't a collision.
+        assert_ne!(idempotent_iv(&key(), b"ab", b"c").u8_array, idempotent_iv(&key(), b"a", b"bc").u8_array)
+    }
+}
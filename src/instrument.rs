@@ -0,0 +1,496 @@
+//! Instrumentation of the core operations: audit hooks, with the
+//! `tracing` feature, spans and events, and with the `metrics` feature,
+//! these counters and histograms through the `metrics` facade:
+//!
+//! - `simple_aes256_gcm_encrypt_total` and `simple_aes256_gcm_decrypt_total`,
+//!   every attempt, labelled with `api` (`oneshot` or `stream`),
+//! - `simple_aes256_gcm_decrypt_failures_total`, labelled with `api` and the
+//!   failure `class`,
+//! - `simple_aes256_gcm_payload_bytes`, the input length (plaintext for
+//!   encryption, ciphertext for decryption), and
+//!   `simple_aes256_gcm_duration_seconds`, both labelled with `operation`
+//!   and `api`.
+//!
+//! A stream is one operation, a `stream` span from `new`, recorded when
+//! `StreamEncryptor::finish` succeeds or when `StreamDecryptor` reaches
+//! the final chunk or fails, header included. Its event carries the
+//! number of chunks and the total length, and a failure its class:
+//! `truncated`, `authentication`, `invalid_header` or `io`. The async
+//! streams are recorded the same way, from `poll_close`, the final chunk
+//! or the first failed one. `dir` and `temp_file` add their own spans
+//! around these.
+//!
+//! Only non-sensitive facts are recorded: operation, payload lengths, key
+//! fingerprint, duration and failure class. Key bytes, ivs and plaintext
+//! never reach a span, an event, a metric label or an audit sink.
+
+#[cfg(any(feature = "tracing", feature = "metrics"))]
+use std::time::Instant;
+
+use crate::audit::{self, AuditOperation};
+#[cfg(feature = "metrics")]
+use std::time::Duration;
+
+use crate::stream::StreamError;
+use crate::{DecryptionError, EncryptedAndIv, EncryptionError, Key};
+
+pub(crate) fn encryption_failure_class(e: &EncryptionError) -> &'static str {
+    match e {
+        EncryptionError::GenericEncryptionError => "generic"
+    }
+}
+
+pub(crate) fn decryption_failure_class(e: &DecryptionError) -> &'static str {
+    match e {
+        DecryptionError::InvalidUTF8DecryptionError(_) => "invalid_utf8",
+        DecryptionError::ProbableWrongKey => "probable_wrong_key",
+        DecryptionError::GenericDecryptionError => "authentication",
+        DecryptionError::RateLimited => "rate_limited",
+        DecryptionError::ProbableContextMismatch => "probable_context_mismatch",
+        DecryptionError::Replayed => "replayed",
+        DecryptionError::OutOfWindow => "out_of_window"
+    }
+}
+
+pub(crate) fn stream_failure_class(e: &StreamError) -> &'static str {
+    match e {
+        StreamError::Truncated => "truncated",
+        StreamError::InvalidStreamHeaderError => "invalid_header",
+        StreamError::ChunkAuthenticationError(_) => "authentication",
+        StreamError::StreamIoError(_) => "io"
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn record_metrics(operation: &'static str, api: &'static str, payload_len: usize, duration: Duration) {
+    let total = if operation == "encrypt" { "simple_aes256_gcm_encrypt_total" } else { "simple_aes256_gcm_decrypt_total" };
+    metrics::counter!(total, "api" => api).increment(1);
+    metrics::histogram!("simple_aes256_gcm_payload_bytes", "operation" => operation, "api" => api).record(payload_len as f64);
+    metrics::histogram!("simple_aes256_gcm_duration_seconds", "operation" => operation, "api" => api).record(duration.as_secs_f64());
+}
+
+#[cfg(feature = "metrics")]
+fn record_decrypt_failure(api: &'static str, class: &'static str) {
+    metrics::counter!("simple_aes256_gcm_decrypt_failures_total", "api" => api, "class" => class).increment(1);
+}
+
+/// Span, chunk count, payload length and start of a stream, recorded once.
+pub(crate) struct StreamInstrument {
+    key_fingerprint: String,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    #[cfg(any(feature = "tracing", feature = "metrics"))]
+    started: Instant,
+    chunks: usize,
+    payload_len: usize,
+    recorded: bool
+}
+
+impl StreamInstrument {
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn new(operation: &'static str, key: &Key) -> StreamInstrument {
+        let key_fingerprint = key.fingerprint();
+        StreamInstrument {
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!("stream", operation, key_fingerprint = %key_fingerprint),
+            key_fingerprint,
+            #[cfg(any(feature = "tracing", feature = "metrics"))]
+            started: Instant::now(),
+            chunks: 0,
+            payload_len: 0,
+            recorded: false
+        }
+    }
+
+    pub(crate) fn add(&mut self, len: usize) {
+        self.payload_len += len;
+    }
+
+    pub(crate) fn chunk(&mut self) {
+        self.chunks += 1;
+    }
+
+    #[cfg(feature = "rng")]
+    pub(crate) fn encrypted(&mut self) {
+        if !std::mem::replace(&mut self.recorded, true) {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(parent: &self.span, chunks = self.chunks, plaintext_len = self.payload_len, duration_us = self.started.elapsed().as_micros() as u64, "stream encrypted");
+            #[cfg(feature = "metrics")]
+            record_metrics("encrypt", "stream", self.payload_len, self.started.elapsed());
+            audit::record_fingerprint(AuditOperation::Encrypt, &self.key_fingerprint, self.payload_len, None);
+        }
+    }
+
+    pub(crate) fn decrypted(&mut self, failure: Option<&StreamError>) {
+        if !std::mem::replace(&mut self.recorded, true) {
+            #[cfg(feature = "tracing")]
+            {
+                let duration_us = self.started.elapsed().as_micros() as u64;
+                match failure {
+                    None => tracing::debug!(parent: &self.span, chunks = self.chunks, ciphertext_len = self.payload_len, duration_us, "stream decrypted"),
+                    Some(e) => tracing::warn!(parent: &self.span, failure = stream_failure_class(e), chunks = self.chunks, ciphertext_len = self.payload_len, duration_us, "stream decryption failed")
+                }
+            }
+            #[cfg(feature = "metrics")]
+            {
+                record_metrics("decrypt", "stream", self.payload_len, self.started.elapsed());
+                if let Some(e) = failure {
+                    record_decrypt_failure("stream", stream_failure_class(e));
+                }
+            }
+            audit::record_fingerprint(AuditOperation::Decrypt, &self.key_fingerprint, self.payload_len, failure.map(stream_failure_class));
+        }
+    }
+}
+
+/// What an encryption returns, for the recorded ciphertext length.
+pub(crate) trait CiphertextLen {
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    fn ciphertext_len(&self) -> usize;
+}
+
+impl CiphertextLen for EncryptedAndIv {
+    fn ciphertext_len(&self) -> usize {
+        self.encrypted.u8_vec.len()
+    }
+}
+
+pub(crate) fn encrypt<T, F>(key: &Key, plaintext_len: usize, f: F) -> Result<T, EncryptionError>
+where T: CiphertextLen, F: FnOnce() -> Result<T, EncryptionError> {
+    #[cfg(feature = "tracing")]
+    let span = tracing::debug_span!("encrypt", operation = "encrypt", key_fingerprint = %key.fingerprint(), plaintext_len);
+    #[cfg(feature = "tracing")]
+    let _entered = span.enter();
+    #[cfg(any(feature = "tracing", feature = "metrics"))]
+    let started = Instant::now();
+
+    let result = f();
+
+    #[cfg(feature = "tracing")]
+    {
+        let duration_us = started.elapsed().as_micros() as u64;
+        match &result {
+            Ok(encrypted) => tracing::debug!(ciphertext_len = encrypted.ciphertext_len(), duration_us, "encrypted"),
+            Err(e) => tracing::warn!(failure = encryption_failure_class(e), duration_us, "encryption failed")
+        }
+    }
+    #[cfg(feature = "metrics")]
+    record_metrics("encrypt", "oneshot", plaintext_len, started.elapsed());
+    audit::record(AuditOperation::Encrypt, key, plaintext_len, result.as_ref().err().map(encryption_failure_class));
+    result
+}
+
+pub(crate) fn decrypt<T, F>(key: &Key, ciphertext_len: usize, f: F) -> Result<T, DecryptionError>
+where T: AsRef<[u8]>, F: FnOnce() -> Result<T, DecryptionError> {
+    #[cfg(feature = "tracing")]
+    let span = tracing::debug_span!("decrypt", operation = "decrypt", key_fingerprint = %key.fingerprint(), ciphertext_len);
+    #[cfg(feature = "tracing")]
+    let _entered = span.enter();
+    #[cfg(any(feature = "tracing", feature = "metrics"))]
+    let started = Instant::now();
+
+    let result = f();
+
+    #[cfg(feature = "tracing")]
+    {
+        let duration_us = started.elapsed().as_micros() as u64;
+        match &result {
+            Ok(plaintext) => tracing::debug!(plaintext_len = plaintext.as_ref().len(), duration_us, "decrypted"),
+            Err(e) => tracing::warn!(failure = decryption_failure_class(e), duration_us, "decryption failed")
+        }
+    }
+    #[cfg(feature = "metrics")]
+    {
+        record_metrics("decrypt", "oneshot", ciphertext_len, started.elapsed());
+        if let Err(e) = &result {
+            record_decrypt_failure("oneshot", decryption_failure_class(e));
+        }
+    }
+    audit::record(AuditOperation::Decrypt, key, ciphertext_len, result.as_ref().err().map(decryption_failure_class));
+    result
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture<F: FnOnce()>(f: F) -> String {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+        let output = captured.0.lock().unwrap().clone();
+        String::from_utf8(output).unwrap()
+    }
+
+    const SECRET: &str = "Super secret plaintext";
+
+    fn key() -> Key {
+        Key { u8_array: [7u8; 32] }
+    }
+
+    fn iv() -> Iv {
+        Iv { u8_array: [9u8; 12] }
+    }
+
+    #[test]
+    fn encrypt_records_lengths_and_fingerprint() {
+        let output = capture(|| {
+            encrypt_with_iv(&key(), iv(), &Decrypted::from(SECRET)).unwrap();
+        });
+        assert!(output.contains("DEBUG"));
+        assert!(output.contains("encrypted"));
+        assert!(output.contains(&format!("key_fingerprint={}", key().fingerprint())));
+        assert!(output.contains(&format!("plaintext_len={}", SECRET.len())));
+        assert!(output.contains(&format!("ciphertext_len={}", SECRET.len() + 16)));
+        assert!(output.contains("duration_us="));
+        assert!(!output.contains(SECRET))
+    }
+
+    #[test]
+    fn decrypt_records_success_at_debug() {
+        let encrypted_and_iv = encrypt_with_iv(&key(), iv(), &Decrypted::from(SECRET)).unwrap();
+        let output = capture(|| {
+            decrypt(&key(), encrypted_and_iv).unwrap();
+        });
+        assert!(output.contains("DEBUG"));
+        assert!(output.contains("decrypted"));
+        assert!(output.contains(&format!("plaintext_len={}", SECRET.len())));
+        assert!(!output.contains("WARN"));
+        assert!(!output.contains(SECRET))
+    }
+
+    #[test]
+    fn decrypt_records_authentication_failure_at_warn() {
+        let encrypted_and_iv = encrypt_with_iv(&key(), iv(), &Decrypted::from(SECRET)).unwrap();
+        let output = capture(|| {
+            decrypt_bytes(&Key { u8_array: [8u8; 32] }, &encrypted_and_iv).unwrap_err();
+        });
+        assert!(output.contains("WARN"));
+        assert!(output.contains("failure=\"authentication\""));
+        assert!(output.contains(&format!("key_fingerprint={}", Key { u8_array: [8u8; 32] }.fingerprint())));
+        assert!(!output.contains(SECRET))
+    }
+
+    #[test]
+    fn decrypt_records_utf8_failure_at_warn() {
+        let encrypted_and_iv = encrypt_bytes_with_iv(&key(), iv(), &[133u8, 133u8]).unwrap();
+        let output = capture(|| {
+            decrypt(&key(), encrypted_and_iv).unwrap_err();
+        });
+        assert!(output.contains("WARN"));
+        assert!(output.contains("failure=\"invalid_utf8\""))
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn stream_records_chunks_length_and_failure_class() {
+        use crate::stream::{StreamDecryptor, StreamEncryptor, CHUNK_SIZE};
+        use std::io::{Read, Write};
+
+        let plaintext = SECRET.repeat(CHUNK_SIZE / SECRET.len() + 1);
+        let mut encrypted = Vec::new();
+        let output = capture(|| {
+            let mut encryptor = StreamEncryptor::new(&key(), &mut encrypted).unwrap();
+            encryptor.write_all(plaintext.as_bytes()).unwrap();
+            encryptor.finish().unwrap();
+        });
+        assert!(output.contains("stream encrypted"));
+        assert!(output.contains("operation=\"encrypt\""));
+        assert!(output.contains(&format!("key_fingerprint={}", key().fingerprint())));
+        assert!(output.contains("chunks=2"));
+        assert!(output.contains(&format!("plaintext_len={}", plaintext.len())));
+        assert!(!output.contains(SECRET));
+
+        let output = capture(|| {
+            let mut decrypted = Vec::new();
+            StreamDecryptor::new(&key(), encrypted.as_slice()).unwrap().read_to_end(&mut decrypted).unwrap();
+        });
+        assert!(output.contains("stream decrypted"));
+        assert!(output.contains("chunks=2"));
+        assert!(output.contains(&format!("ciphertext_len={}", encrypted.len() - stream::HEADER_LEN)));
+        assert!(!output.contains("WARN"));
+        assert!(!output.contains(SECRET));
+
+        let truncated = &encrypted[..stream::HEADER_LEN + CHUNK_SIZE + TAG_LEN];
+        let output = capture(|| {
+            StreamDecryptor::new(&key(), truncated).unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+        });
+        assert!(output.contains("WARN"));
+        assert!(output.contains("failure=\"truncated\""));
+        assert!(output.contains("chunks=0"));
+        assert!(!output.contains(SECRET));
+
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let output = capture(|| {
+            StreamDecryptor::new(&key(), tampered.as_slice()).unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+        });
+        assert!(output.contains("failure=\"authentication\""));
+        assert!(output.contains("chunks=1"));
+        assert_eq!(output.matches("stream decryption failed").count(), 1);
+        assert!(!output.contains(SECRET));
+
+        let mut versioned = encrypted.clone();
+        versioned[0] ^= 0x80;
+        let output = capture(|| {
+            assert!(StreamDecryptor::new(&key(), versioned.as_slice()).is_err());
+        });
+        assert!(output.contains("failure=\"invalid_header\""))
+    }
+
+    #[test]
+    #[cfg(all(feature = "rng", feature = "futures-io"))]
+    fn async_stream_is_recorded_like_the_sync_one() {
+        use crate::async_stream::{AsyncStreamDecryptor, AsyncStreamEncryptor};
+        use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+        let output = capture(|| async_std::task::block_on(async {
+            let mut encryptor = AsyncStreamEncryptor::new(&key(), Vec::new());
+            encryptor.write_all(SECRET.as_bytes()).await.unwrap();
+            encryptor.close().await.unwrap();
+            let mut tampered = encryptor.into_inner();
+            *tampered.last_mut().unwrap() ^= 1;
+            let mut decryptor = AsyncStreamDecryptor::new(&key(), tampered.as_slice()).await.unwrap();
+            decryptor.read_to_end(&mut Vec::new()).await.unwrap_err();
+        }));
+        assert!(output.contains("stream encrypted"));
+        assert!(output.contains(&format!("plaintext_len={}", SECRET.len())));
+        assert!(output.contains("failure=\"authentication\""));
+        assert!(!output.contains(SECRET))
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn dir_records_each_file_without_its_path_or_contents() {
+        use std::fs;
+
+        let source = crate::test_path("instrument-dir-source");
+        let encrypted = crate::test_path("instrument-dir-encrypted");
+        let decrypted = crate::test_path("instrument-dir-decrypted");
+        for path in [&source, &encrypted, &decrypted] {
+            let _ = fs::remove_dir_all(path);
+        }
+        fs::create_dir_all(source.join("private")).unwrap();
+        fs::write(source.join("private/secret-name.txt"), SECRET).unwrap();
+        fs::write(source.join("other.txt"), SECRET).unwrap();
+        let output = capture(|| {
+            dir::encrypt_dir(&key(), &source, &encrypted, true).unwrap();
+        });
+        assert!(output.contains("encrypt_dir"));
+        assert_eq!(output.matches("file encrypted").count(), 2);
+        assert!(output.contains("files=2"));
+        assert!(!output.contains("secret-name") && !output.contains(SECRET));
+
+        let damaged = encrypted.join("other.txt");
+        let mut bytes = fs::read(&damaged).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        fs::write(&damaged, bytes).unwrap();
+        let output = capture(|| {
+            dir::decrypt_dir(&key(), &encrypted, &decrypted).unwrap();
+        });
+        assert!(output.contains("decrypt_dir"));
+        assert!(output.contains("outcome=\"decrypted\""));
+        assert!(output.contains("outcome=\"mismatched\""));
+        assert!(output.contains("decrypted=1") && output.contains("mismatched=1"));
+        assert!(!output.contains("secret-name") && !output.contains(SECRET));
+        for path in [&source, &encrypted, &decrypted] {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod metrics_tests {
+    use crate::*;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use std::collections::HashMap;
+
+    /// `name{label=value,...}` to counter values and histogram lengths.
+    fn capture<F: FnOnce()>(f: F) -> HashMap<String, u64> {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, f);
+        snapshotter.snapshot().into_vec().into_iter().map(|(key, _, _, value)| {
+            let labels: Vec<String> = key.key().labels().map(|label| format!("{}={}", label.key(), label.value())).collect();
+            let value = match value {
+                DebugValue::Counter(count) => count,
+                DebugValue::Histogram(values) => values.len() as u64,
+                DebugValue::Gauge(_) => panic!("no gauges are recorded")
+            };
+            (format!("{}{{{}}}", key.key().name(), labels.join(",")), value)
+        }).collect()
+    }
+
+    fn key() -> Key {
+        Key { u8_array: [7u8; 32] }
+    }
+
+    #[test]
+    fn oneshot_counters_move_on_success_and_authentication_failure() {
+        let encrypted_and_iv = encrypt_bytes_with_iv(&key(), Iv { u8_array: [9u8; 12] }, b"This is a text.").unwrap();
+        let metrics = capture(|| {
+            encrypt_bytes_with_iv(&key(), Iv { u8_array: [9u8; 12] }, b"This is a text.").unwrap();
+            decrypt_bytes(&key(), &encrypted_and_iv).unwrap();
+            decrypt_bytes(&Key { u8_array: [8u8; 32] }, &encrypted_and_iv).unwrap_err();
+        });
+        assert_eq!(metrics["simple_aes256_gcm_encrypt_total{api=oneshot}"], 1);
+        assert_eq!(metrics["simple_aes256_gcm_decrypt_total{api=oneshot}"], 2);
+        assert_eq!(metrics["simple_aes256_gcm_decrypt_failures_total{api=oneshot,class=authentication}"], 1);
+        assert_eq!(metrics["simple_aes256_gcm_payload_bytes{operation=decrypt,api=oneshot}"], 2);
+        assert_eq!(metrics["simple_aes256_gcm_duration_seconds{operation=encrypt,api=oneshot}"], 1);
+        assert_eq!(metrics.len(), 7, "{:?}", metrics);
+        let fingerprint = key().fingerprint();
+        assert!(metrics.keys().all(|name| !name.contains(&fingerprint) && !name.contains("text")))
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn stream_is_recorded_once_and_failures_are_classified() {
+        use crate::stream::{StreamDecryptor, StreamEncryptor};
+        use std::io::{Read, Write};
+
+        let plaintext = vec![1u8; stream::CHUNK_SIZE + 10];
+        let mut encrypted = Vec::new();
+        let metrics = capture(|| {
+            let mut encryptor = StreamEncryptor::new(&key(), &mut encrypted).unwrap();
+            encryptor.write_all(&plaintext).unwrap();
+            encryptor.finish().unwrap();
+        });
+        assert_eq!(metrics["simple_aes256_gcm_encrypt_total{api=stream}"], 1);
+        assert_eq!(metrics["simple_aes256_gcm_payload_bytes{operation=encrypt,api=stream}"], 1);
+
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let metrics = capture(|| {
+            let mut decrypted = Vec::new();
+            StreamDecryptor::new(&key(), encrypted.as_slice()).unwrap().read_to_end(&mut decrypted).unwrap();
+            assert_eq!(decrypted, plaintext);
+            let mut decryptor = StreamDecryptor::new(&key(), tampered.as_slice()).unwrap();
+            decryptor.read_to_end(&mut Vec::new()).unwrap_err();
+            decryptor.next_chunk().unwrap_err();
+        });
+        assert_eq!(metrics["simple_aes256_gcm_decrypt_total{api=stream}"], 2);
+        assert_eq!(metrics["simple_aes256_gcm_decrypt_failures_total{api=stream,class=authentication}"], 1);
+        assert!(!metrics.contains_key("simple_aes256_gcm_decrypt_total{api=oneshot}"))
+    }
+}
@@ -0,0 +1,329 @@
+//! Encrypting selected fields of a JSON document in place.
+//!
+//! Paths are dotted keys with bracketed array indexes, `user.ssn` or
+//! `cards[0].pan`; `[*]` matches every element of an array. Each string,
+//! number or boolean leaf a path names is replaced by a combined token
+//! (`saes1:...`, see `token`) of its JSON form, so decryption gives back
+//! the original type. The rest of the document is left untouched.
+//!
+//! The concrete path of a field (`cards[1].pan`, never `cards[*].pan`) is
+//! bound as associated data: a token moved to another field, or an array
+//! reordered, fails to decrypt. Both functions check every path before
+//! changing anything, so on error the document is as it was.
+
+use std::{error, fmt};
+
+use serde_json::Value;
+
+use crate::token::TOKEN_PREFIX;
+use crate::{decrypt_bytes_with_aad, DecryptionError, EncryptedAndIv, Key};
+#[cfg(feature = "rng")]
+use crate::{encrypt_bytes_with_aad, EncryptionError};
+
+#[derive(Debug, Clone)]
+pub enum JsonFieldError {
+    /// The path doesn't follow the `a.b[0].c` syntax.
+    InvalidJsonPathError(String),
+    /// Nothing exists at this path.
+    MissingJsonFieldError(String),
+    /// The value at this path is an object, an array or null.
+    UnsupportedJsonFieldError(String),
+    /// The value at this path isn't a token.
+    NotEncryptedFieldError(String),
+    #[cfg(feature = "rng")]
+    JsonFieldEncryptionError(EncryptionError),
+    JsonFieldDecryptionError(String, DecryptionError)
+}
+
+impl fmt::Display for JsonFieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonFieldError::InvalidJsonPathError(path) => write!(f, "Invalid path {}", path),
+            JsonFieldError::MissingJsonFieldError(path) => write!(f, "No field at {}", path),
+            JsonFieldError::UnsupportedJsonFieldError(path) => write!(f, "Field {} isn't a string, number or boolean", path),
+            JsonFieldError::NotEncryptedFieldError(path) => write!(f, "Field {} isn't encrypted", path),
+            #[cfg(feature = "rng")]
+            JsonFieldError::JsonFieldEncryptionError(e) => write!(f, "{}", e),
+            JsonFieldError::JsonFieldDecryptionError(path, e) => write!(f, "Field {}: {}", path, e),
+        }
+    }
+}
+
+impl error::Error for JsonFieldError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "rng")]
+            JsonFieldError::JsonFieldEncryptionError(e) => Some(e),
+            JsonFieldError::JsonFieldDecryptionError(_, e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+    Wildcard
+}
+
+fn parse_path(path: &str) -> Result<Vec<Segment>, JsonFieldError> {
+    let invalid = || JsonFieldError::InvalidJsonPathError(String::from(path));
+    let mut segments = Vec::new();
+    for part in path.split('.') {
+        let (name, mut rest) = match part.find('[') {
+            Some(bracket) => part.split_at(bracket),
+            None => (part, "")
+        };
+        if name.is_empty() && (rest.is_empty() || !segments.is_empty()) {
+            return Err(invalid());
+        }
+        if !name.is_empty() {
+            segments.push(Segment::Key(String::from(name)));
+        }
+        while !rest.is_empty() {
+            let close = rest.find(']').ok_or_else(invalid)?;
+            let index = &rest[1..close];
+            segments.push(match index {
+                "*" => Segment::Wildcard,
+                _ if !index.is_empty() && index.bytes().all(|byte| byte.is_ascii_digit()) => {
+                    Segment::Index(index.parse().map_err(|_| invalid())?)
+                },
+                _ => return Err(invalid())
+            });
+            rest = &rest[close + 1..];
+            if !rest.is_empty() && !rest.starts_with('[') {
+                return Err(invalid());
+            }
+        }
+    }
+    Ok(segments)
+}
+
+fn display_path(concrete: &[Segment]) -> String {
+    let mut path = String::new();
+    for segment in concrete {
+        match segment {
+            Segment::Key(name) if path.is_empty() => path.push_str(name),
+            Segment::Key(name) => {
+                path.push('.');
+                path.push_str(name)
+            },
+            Segment::Index(index) => path.push_str(&format!("[{}]", index)),
+            Segment::Wildcard => unreachable!("concrete paths have no wildcards")
+        }
+    }
+    path
+}
+
+/// Expands `segments` against `value` into concrete paths.
+fn resolve(value: &Value, segments: &[Segment], prefix: &mut Vec<Segment>, path: &str, found: &mut Vec<Vec<Segment>>) -> Result<(), JsonFieldError> {
+    let missing = || JsonFieldError::MissingJsonFieldError(String::from(path));
+    let (segment, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => {
+            found.push(prefix.clone());
+            return Ok(());
+        }
+    };
+    match (segment, value) {
+        (Segment::Key(name), Value::Object(map)) => {
+            let child = map.get(name).ok_or_else(missing)?;
+            prefix.push(segment.clone());
+            resolve(child, rest, prefix, path, found)?;
+        },
+        (Segment::Index(index), Value::Array(items)) => {
+            let child = items.get(*index).ok_or_else(missing)?;
+            prefix.push(segment.clone());
+            resolve(child, rest, prefix, path, found)?;
+        },
+        (Segment::Wildcard, Value::Array(items)) => {
+            for (index, child) in items.iter().enumerate() {
+                prefix.push(Segment::Index(index));
+                resolve(child, rest, prefix, path, found)?;
+                prefix.pop();
+            }
+            return Ok(());
+        },
+        _ => return Err(missing())
+    }
+    prefix.pop();
+    Ok(())
+}
+
+fn field_mut<'a>(value: &'a mut Value, concrete: &[Segment]) -> &'a mut Value {
+    concrete.iter().fold(value, |value, segment| match segment {
+        Segment::Key(name) => &mut value[name.as_str()],
+        Segment::Index(index) => &mut value[*index],
+        Segment::Wildcard => unreachable!("concrete paths have no wildcards")
+    })
+}
+
+/// The concrete fields named by `paths`, with their display path.
+fn fields(value: &Value, paths: &[&str]) -> Result<Vec<(Vec<Segment>, String)>, JsonFieldError> {
+    let mut found = Vec::new();
+    for path in paths {
+        let segments = parse_path(path)?;
+        resolve(value, &segments, &mut Vec::new(), path, &mut found)?;
+    }
+    Ok(found.into_iter().map(|concrete| {
+        let display = display_path(&concrete);
+        (concrete, display)
+    }).collect())
+}
+
+fn field_aad(path: &str) -> Vec<u8> {
+    let mut aad = b"simple-aes256-gcm json field:".to_vec();
+    aad.extend_from_slice(path.as_bytes());
+    aad
+}
+
+/// Replaces the leaves at `paths` with tokens, see the module docs.
+#[cfg(feature = "rng")]
+pub fn encrypt_json_fields(key: &Key, value: &mut Value, paths: &[&str]) -> Result<(), JsonFieldError> {
+    let mut tokens = Vec::new();
+    for (concrete, display) in fields(value, paths)? {
+        let leaf = field_mut(value, &concrete);
+        match leaf {
+            Value::String(_) | Value::Number(_) | Value::Bool(_) => {},
+            _ => return Err(JsonFieldError::UnsupportedJsonFieldError(display))
+        }
+        let plaintext = serde_json::to_vec(leaf).expect("serializing a JSON leaf can't fail");
+        let encrypted_and_iv = encrypt_bytes_with_aad(key, &plaintext, &field_aad(&display)).map_err(JsonFieldError::JsonFieldEncryptionError)?;
+        tokens.push((concrete, encrypted_and_iv.to_token()));
+    }
+    for (concrete, token) in tokens {
+        *field_mut(value, &concrete) = Value::String(token);
+    }
+    Ok(())
+}
+
+/// Reverses `encrypt_json_fields` called with the same `paths`.
+pub fn decrypt_json_fields(key: &Key, value: &mut Value, paths: &[&str]) -> Result<(), JsonFieldError> {
+    let mut leaves = Vec::new();
+    for (concrete, display) in fields(value, paths)? {
+        let token = match field_mut(value, &concrete) {
+            Value::String(token) if token.starts_with(TOKEN_PREFIX) => token,
+            _ => return Err(JsonFieldError::NotEncryptedFieldError(display))
+        };
+        let encrypted_and_iv = EncryptedAndIv::from_token(token).map_err(|_| JsonFieldError::NotEncryptedFieldError(display.clone()))?;
+        let plaintext = decrypt_bytes_with_aad(key, &encrypted_and_iv, &field_aad(&display))
+            .map_err(|e| JsonFieldError::JsonFieldDecryptionError(display.clone(), e))?;
+        let leaf = serde_json::from_slice(&plaintext)
+            .map_err(|_| JsonFieldError::JsonFieldDecryptionError(display.clone(), DecryptionError::GenericDecryptionError))?;
+        leaves.push((concrete, leaf));
+    }
+    for (concrete, leaf) in leaves {
+        *field_mut(value, &concrete) = leaf;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "rng")]
+    use serde_json::json;
+
+    #[cfg(feature = "rng")]
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    #[cfg(feature = "rng")]
+    fn document() -> Value {
+        json!({
+            "id": 42,
+            "user": { "name": "Ada", "ssn": "078-05-1120", "age": 36, "admin": true },
+            "cards": [
+                { "pan": "4111111111111111", "brand": "visa" },
+                { "pan": "5500000000000004", "brand": "mastercard" }
+            ],
+            "tags": ["a", "b"]
+        })
+    }
+
+    #[cfg(feature = "rng")]
+    fn is_token(value: &Value) -> bool {
+        value.as_str().is_some_and(|text| text.starts_with(TOKEN_PREFIX))
+    }
+
+    #[test]
+    fn paths_parse() {
+        assert_eq!(parse_path("user.ssn").unwrap(), vec![Segment::Key(String::from("user")), Segment::Key(String::from("ssn"))]);
+        assert_eq!(parse_path("cards[0].pan").unwrap(), vec![Segment::Key(String::from("cards")), Segment::Index(0), Segment::Key(String::from("pan"))]);
+        assert_eq!(parse_path("[*][1]").unwrap(), vec![Segment::Wildcard, Segment::Index(1)]);
+        for path in ["", "user.", ".ssn", "cards[", "cards[x]", "cards[]", "cards[0]pan", "cards.[0]"].iter() {
+            match parse_path(path) {
+                Err(JsonFieldError::InvalidJsonPathError(_)) => assert!(true),
+                _ => assert!(false, "Should err InvalidJsonPathError for {:?}", path)
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn nested_object_fields_round_trip() {
+        let mut value = document();
+        encrypt_json_fields(&Key { u8_array: KEY }, &mut value, &["user.ssn", "user.age", "user.admin"]).unwrap();
+        assert!(is_token(&value["user"]["ssn"]) && is_token(&value["user"]["age"]) && is_token(&value["user"]["admin"]));
+        assert_eq!(value["user"]["name"], "Ada");
+        assert_eq!(value["cards"], document()["cards"]);
+        decrypt_json_fields(&Key { u8_array: KEY }, &mut value, &["user.ssn", "user.age", "user.admin"]).unwrap();
+        assert_eq!(value, document())
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn array_index_and_wildcard() {
+        let mut value = document();
+        encrypt_json_fields(&Key { u8_array: KEY }, &mut value, &["cards[1].pan"]).unwrap();
+        assert!(is_token(&value["cards"][1]["pan"]));
+        assert_eq!(value["cards"][0], document()["cards"][0]);
+        decrypt_json_fields(&Key { u8_array: KEY }, &mut value, &["cards[1].pan"]).unwrap();
+        assert_eq!(value, document());
+
+        encrypt_json_fields(&Key { u8_array: KEY }, &mut value, &["cards[*].pan", "tags[*]"]).unwrap();
+        assert!(is_token(&value["cards"][0]["pan"]) && is_token(&value["cards"][1]["pan"]) && is_token(&value["tags"][1]));
+        assert_eq!(value["cards"][1]["brand"], "mastercard");
+        decrypt_json_fields(&Key { u8_array: KEY }, &mut value, &["cards[*].pan", "tags[*]"]).unwrap();
+        assert_eq!(value, document())
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn missing_or_unsupported_paths_are_reported() {
+        for path in ["user.email", "cards[2].pan", "id.value", "tags[*].x"].iter() {
+            let mut value = document();
+            match encrypt_json_fields(&Key { u8_array: KEY }, &mut value, &["user.ssn", path]) {
+                Ok(_) => assert!(false, "Should err MissingJsonFieldError for {}", path),
+                Err(e) => match e {
+                    JsonFieldError::MissingJsonFieldError(missing) => assert_eq!(missing, *path),
+                    _ => assert!(false, "Should err MissingJsonFieldError for {}", path)
+                }
+            }
+            assert_eq!(value, document(), "nothing is encrypted on error")
+        }
+        match encrypt_json_fields(&Key { u8_array: KEY }, &mut document(), &["cards[0]"]) {
+            Err(JsonFieldError::UnsupportedJsonFieldError(path)) => assert_eq!(path, "cards[0]"),
+            _ => assert!(false, "Should err UnsupportedJsonFieldError")
+        }
+        match decrypt_json_fields(&Key { u8_array: KEY }, &mut document(), &["user.ssn"]) {
+            Err(JsonFieldError::NotEncryptedFieldError(path)) => assert_eq!(path, "user.ssn"),
+            _ => assert!(false, "Should err NotEncryptedFieldError")
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn moved_tokens_fail_to_decrypt() {
+        let mut value = document();
+        encrypt_json_fields(&Key { u8_array: KEY }, &mut value, &["cards[*].pan"]).unwrap();
+        let first = value["cards"][0]["pan"].take();
+        value["cards"][0]["pan"] = value["cards"][1]["pan"].take();
+        value["cards"][1]["pan"] = first;
+        match decrypt_json_fields(&Key { u8_array: KEY }, &mut value, &["cards[*].pan"]) {
+            Err(JsonFieldError::JsonFieldDecryptionError(path, DecryptionError::GenericDecryptionError)) => assert_eq!(path, "cards[0].pan"),
+            _ => assert!(false, "Should err JsonFieldDecryptionError")
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Known-answer tests for other implementations of the envelope and token
+//! formats.
+//!
+//! `generate_known_answer_tests(seed)` is deterministic: keys, ivs, AAD and
+//! plaintexts come from SHA-256 in counter mode over the seed, not from the
+//! OS RNG, so a suite can be regenerated and diffed. Every byte string is
+//! given in hex and in base64, the envelope `encrypted` both combined
+//! (ciphertext then tag) and split. `tests/golden/kat_suite.json` is the
+//! suite for seed 0, regenerated with
+//! `cargo run --example kat --features test-vectors > tests/golden/kat_suite.json`.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::{error, fmt};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::encoding::{Encoding, ENCODINGS};
+use crate::{decrypt_bytes_with_aad, encrypt_bytes_with_iv_and_aad, EncryptedAndIv, Iv, Key};
+
+pub const KAT_SUITE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KatBytes {
+    pub hex: String,
+    pub base64: String
+}
+
+impl KatBytes {
+    fn new(bytes: &[u8]) -> KatBytes {
+        KatBytes { hex: Encoding::Hex.encode(bytes), base64: Encoding::Base64.encode(bytes) }
+    }
+
+    /// The bytes, if both forms decode and agree.
+    fn decode(&self) -> Option<Vec<u8>> {
+        match (Encoding::Hex.decode(&self.hex), Encoding::Base64.decode(&self.base64)) {
+            (Ok(hex), Ok(base64)) if hex == base64 => Some(hex),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KatVector {
+    pub name: String,
+    pub key: KatBytes,
+    pub iv: KatBytes,
+    pub aad: KatBytes,
+    pub plaintext: KatBytes,
+    /// Ciphertext followed by the tag, `Encrypted.u8_vec`.
+    pub encrypted: KatBytes,
+    pub ciphertext: KatBytes,
+    pub tag: KatBytes,
+    /// Token per prefix, only for vectors without AAD (tokens have none).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tokens: BTreeMap<String, String>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KatSuite {
+    pub version: u32,
+    pub seed: u64,
+    pub vectors: Vec<KatVector>
+}
+
+#[derive(Debug, Clone)]
+pub enum KatError {
+    UnsupportedKatVersionError(u32),
+    /// A field doesn't decode, its hex and base64 forms differ, or a key or iv has the wrong length.
+    InvalidKatVectorError(String),
+    /// This implementation computes another output than the vector's.
+    KatMismatchError(String)
+}
+
+impl fmt::Display for KatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KatError::UnsupportedKatVersionError(version) => write!(f, "Unsupported known-answer test suite version {}", version),
+            KatError::InvalidKatVectorError(name) => write!(f, "Invalid known-answer test vector {}", name),
+            KatError::KatMismatchError(name) => write!(f, "Known-answer test vector {} doesn't match", name),
+        }
+    }
+}
+
+impl error::Error for KatError {}
+
+/// SHA-256(seed || label || counter) blocks, truncated to `len`.
+fn derive_bytes(seed: u64, label: &str, len: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(len);
+    let mut counter = 0u32;
+    while bytes.len() < len {
+        bytes.extend(Sha256::new()
+            .chain_update(b"simple-aes256-gcm kat")
+            .chain_update(seed.to_be_bytes())
+            .chain_update(label.as_bytes())
+            .chain_update(counter.to_be_bytes())
+            .finalize());
+        counter += 1;
+    }
+    bytes.truncate(len);
+    bytes
+}
+
+fn vector(seed: u64, name: &str, aad: &[u8], plaintext: &[u8], with_tokens: bool) -> KatVector {
+    let key: [u8; 32] = derive_bytes(seed, &format!("{} key", name), 32).as_slice().try_into().unwrap();
+    let iv: [u8; 12] = derive_bytes(seed, &format!("{} iv", name), 12).as_slice().try_into().unwrap();
+    let encrypted_and_iv = encrypt_bytes_with_iv_and_aad(&Key { u8_array: key }, Iv { u8_array: iv }, plaintext, aad)
+        .expect("AES-256-GCM encryption of a short plaintext can't fail");
+    let encrypted = &encrypted_and_iv.encrypted;
+    let mut tokens = BTreeMap::new();
+    if with_tokens {
+        for encoding in ENCODINGS.iter() {
+            tokens.insert(encoding.token_prefix().to_string(), encrypted_and_iv.to_token_with(*encoding));
+        }
+    }
+    KatVector {
+        name: name.to_string(),
+        key: KatBytes::new(&key),
+        iv: KatBytes::new(&iv),
+        aad: KatBytes::new(aad),
+        plaintext: KatBytes::new(plaintext),
+        encrypted: KatBytes::new(&encrypted.u8_vec),
+        ciphertext: KatBytes::new(encrypted.ciphertext_body()),
+        tag: KatBytes::new(encrypted.tag().expect("a ciphertext always ends with a tag")),
+        tokens
+    }
+}
+
+pub fn generate_known_answer_tests(seed: u64) -> KatSuite {
+    let random_plaintext = derive_bytes(seed, "detached-tag plaintext", 48);
+    let mut non_utf8 = derive_bytes(seed, "non-utf8 plaintext", 31);
+    non_utf8.insert(0, 0xff);
+    let vectors = vec![
+        vector(seed, "raw-encrypt", b"", b"This is a text.", false),
+        vector(seed, "token", b"", b"This is a text.", true),
+        vector(seed, "aad-text", b"user:42", b"This is a text.", false),
+        vector(seed, "aad-binary", &derive_bytes(seed, "aad-binary aad", 20), b"This is a text.", false),
+        vector(seed, "detached-tag", b"", &random_plaintext, false),
+        vector(seed, "empty-plaintext", b"", b"", true),
+        vector(seed, "empty-plaintext-aad", b"user:42", b"", false),
+        vector(seed, "non-utf8-plaintext", b"", &non_utf8, true)
+    ];
+    KatSuite { version: KAT_SUITE_VERSION, seed, vectors }
+}
+
+fn verify_vector(vector: &KatVector) -> Result<(), KatError> {
+    let invalid = || KatError::InvalidKatVectorError(vector.name.clone());
+    let mismatch = || KatError::KatMismatchError(vector.name.clone());
+    let key: [u8; 32] = vector.key.decode().ok_or_else(invalid)?.as_slice().try_into().map_err(|_| invalid())?;
+    let iv: [u8; 12] = vector.iv.decode().ok_or_else(invalid)?.as_slice().try_into().map_err(|_| invalid())?;
+    let aad = vector.aad.decode().ok_or_else(invalid)?;
+    let plaintext = vector.plaintext.decode().ok_or_else(invalid)?;
+    let expected = vector.encrypted.decode().ok_or_else(invalid)?;
+    let mut split = vector.ciphertext.decode().ok_or_else(invalid)?;
+    split.extend(vector.tag.decode().ok_or_else(invalid)?);
+
+    let key = Key { u8_array: key };
+    let encrypted_and_iv = encrypt_bytes_with_iv_and_aad(&key, Iv { u8_array: iv }, &plaintext, &aad).map_err(|_| mismatch())?;
+    if encrypted_and_iv.encrypted.u8_vec != expected || split != expected {
+        return Err(mismatch());
+    }
+    if decrypt_bytes_with_aad(&key, &encrypted_and_iv, &aad).ok().as_ref() != Some(&plaintext) {
+        return Err(mismatch());
+    }
+    for (prefix, token) in &vector.tokens {
+        let encoding = ENCODINGS.iter().find(|encoding| encoding.token_prefix() == prefix).ok_or_else(invalid)?;
+        if encrypted_and_iv.to_token_with(*encoding) != *token {
+            return Err(mismatch());
+        }
+        match EncryptedAndIv::from_token(token) {
+            Ok(parsed) if decrypt_bytes_with_aad(&key, &parsed, &[]).ok().as_ref() == Some(&plaintext) => {},
+            _ => return Err(mismatch())
+        }
+    }
+    Ok(())
+}
+
+/// Re-runs every vector of `suite` against this implementation.
+pub fn verify_kat_suite(suite: &KatSuite) -> Result<(), KatError> {
+    if suite.version != KAT_SUITE_VERSION {
+        return Err(KatError::UnsupportedKatVersionError(suite.version));
+    }
+    suite.vectors.iter().try_for_each(verify_vector)
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    const GOLDEN: &str = include_str!("../tests/golden/kat_suite.json");
+
+    #[test]
+    fn generation_matches_the_golden_suite() {
+        let golden: KatSuite = serde_json::from_str(GOLDEN).unwrap();
+        assert_eq!(
+            generate_known_answer_tests(0),
+            golden,
+            "regenerate with `cargo run --example kat --features test-vectors > tests/golden/kat_suite.json` if the change is intended"
+        )
+    }
+
+    #[test]
+    fn golden_suite_verifies() {
+        verify_kat_suite(&serde_json::from_str(GOLDEN).unwrap()).unwrap();
+        verify_kat_suite(&generate_known_answer_tests(7)).unwrap()
+    }
+
+    #[test]
+    fn generation_depends_on_the_seed() {
+        assert_ne!(generate_known_answer_tests(0).vectors[0].key, generate_known_answer_tests(1).vectors[0].key)
+    }
+
+    #[test]
+    fn altered_vector_fails() {
+        let mut suite = generate_known_answer_tests(0);
+        suite.vectors[2].aad = KatBytes::new(b"user:43");
+        match verify_kat_suite(&suite) {
+            Ok(_) => assert!(false, "Should err KatMismatchError"),
+            Err(e) => match e {
+                KatError::KatMismatchError(name) => assert_eq!(name, "aad-text"),
+                _ => assert!(false, "Should err KatMismatchError")
+            }
+        }
+        let mut suite = generate_known_answer_tests(0);
+        suite.vectors[0].key.hex = Encoding::Hex.encode(&[0u8; 32]);
+        match verify_kat_suite(&suite) {
+            Ok(_) => assert!(false, "Should err InvalidKatVectorError"),
+            Err(e) => match e {
+                KatError::InvalidKatVectorError(_) => assert!(true),
+                _ => assert!(false, "Should err InvalidKatVectorError")
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Memoized password key derivation, for callers that derive the same
+//! password and salt over and over.
+//!
+//! A `KdfCache` maps (kdf id, parameters, salt, password fingerprint) to
+//! the derived key. The password itself is never stored: its fingerprint
+//! is HMAC-SHA256 under a random key of the cache, so the cache can't be
+//! used to test candidate passwords without that key. Cached keys are wiped
+//! when evicted, purged or dropped. Holding a cache trades the KDF's cost
+//! against keys kept in memory; keep it small and purge it when done.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::password::{derive_kdf_key, KdfParams, PasswordError, PasswordParams};
+use crate::Key;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    kdf_id: u8,
+    params: (u32, u32, u32),
+    salt: Vec<u8>,
+    password_fingerprint: [u8; 32]
+}
+
+struct CachedKey(Key);
+
+impl Drop for CachedKey {
+    fn drop(&mut self) {
+        crate::secure_buffer::wipe(&mut self.0.u8_array);
+    }
+}
+
+struct Entries {
+    keys: HashMap<CacheKey, CachedKey>,
+    /// Least recently used first.
+    order: VecDeque<CacheKey>
+}
+
+type Derive = fn(&str, &[u8], &KdfParams) -> Result<Key, PasswordError>;
+
+pub struct KdfCache {
+    capacity: usize,
+    fingerprint_key: [u8; 32],
+    entries: Mutex<Entries>,
+    derive: Derive
+}
+
+impl KdfCache {
+    /// Keeps up to `capacity` keys, evicting the least recently used.
+    pub fn new(capacity: usize) -> KdfCache {
+        KdfCache::with_derive(capacity, derive_kdf_key)
+    }
+
+    fn with_derive(capacity: usize, derive: Derive) -> KdfCache {
+        KdfCache {
+            capacity,
+            fingerprint_key: rand::random(),
+            entries: Mutex::new(Entries { keys: HashMap::new(), order: VecDeque::new() }),
+            derive
+        }
+    }
+
+    fn fingerprint(&self, password: &str) -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.fingerprint_key).expect("HMAC accepts any key length");
+        mac.update(password.as_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wipes and forgets every cached key.
+    pub fn purge(&self) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.keys.clear();
+        entries.order.clear();
+    }
+
+    /// The key for `password`, `salt` and `params`, derived on a miss. The
+    /// lock isn't held while deriving, so concurrent misses for the same
+    /// entry may both derive.
+    pub fn get_or_derive(&self, password: &str, salt: &[u8], params: &PasswordParams) -> Result<Key, PasswordError> {
+        self.get_or_derive_kdf(password, salt, &KdfParams::Argon2id(*params))
+    }
+
+    pub(crate) fn get_or_derive_kdf(&self, password: &str, salt: &[u8], params: &KdfParams) -> Result<Key, PasswordError> {
+        let (kdf_id, m_cost, t_cost, p_cost) = params.to_fields();
+        let cache_key = CacheKey {
+            kdf_id,
+            params: (m_cost, t_cost, p_cost),
+            salt: salt.to_vec(),
+            password_fingerprint: self.fingerprint(password)
+        };
+        {
+            let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(cached) = entries.keys.get(&cache_key) {
+                let key = Key { u8_array: cached.0.u8_array };
+                entries.order.retain(|other| *other != cache_key);
+                entries.order.push_back(cache_key);
+                return Ok(key);
+            }
+        }
+        let key = (self.derive)(password, salt, params)?;
+        if self.capacity == 0 {
+            return Ok(key);
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.keys.insert(cache_key.clone(), CachedKey(Key { u8_array: key.u8_array })).is_none() {
+            entries.order.push_back(cache_key);
+        }
+        while entries.keys.len() > self.capacity {
+            match entries.order.pop_front() {
+                Some(oldest) => entries.keys.remove(&oldest),
+                None => break
+            };
+        }
+        Ok(key)
+    }
+}
+
+impl Key {
+    /// `Key::from_password` through `cache`.
+    pub fn from_password_cached(cache: &KdfCache, password: &str, salt: &[u8], params: &PasswordParams) -> Result<Key, PasswordError> {
+        cache.get_or_derive(password, salt, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const TEST_PARAMS: PasswordParams = PasswordParams { m_cost: 64, t_cost: 1, p_cost: 1 };
+
+    // One counter per test, as tests run in parallel.
+    macro_rules! counting_cache {
+        ($capacity:expr) => {{
+            static DERIVATIONS: AtomicUsize = AtomicUsize::new(0);
+            fn counting(password: &str, salt: &[u8], params: &KdfParams) -> Result<Key, PasswordError> {
+                DERIVATIONS.fetch_add(1, Ordering::SeqCst);
+                derive_kdf_key(password, salt, params)
+            }
+            (KdfCache::with_derive($capacity, counting), &DERIVATIONS)
+        }};
+    }
+
+    #[test]
+    fn second_derivation_is_served_from_the_cache() {
+        let (cache, derivations) = counting_cache!(4);
+        let first = Key::from_password_cached(&cache, "correct horse", b"0123456789abcdef", &TEST_PARAMS).unwrap();
+        let second = Key::from_password_cached(&cache, "correct horse", b"0123456789abcdef", &TEST_PARAMS).unwrap();
+        assert_eq!(derivations.load(Ordering::SeqCst), 1);
+        assert_eq!(first.u8_array, second.u8_array);
+        assert_eq!(first.u8_array, Key::from_password("correct horse", b"0123456789abcdef", &TEST_PARAMS).unwrap().u8_array)
+    }
+
+    #[test]
+    fn other_salts_passwords_and_params_miss() {
+        let (cache, derivations) = counting_cache!(8);
+        let key = Key::from_password_cached(&cache, "correct horse", b"0123456789abcdef", &TEST_PARAMS).unwrap();
+        let other_salt = Key::from_password_cached(&cache, "correct horse", b"fedcba9876543210", &TEST_PARAMS).unwrap();
+        Key::from_password_cached(&cache, "battery staple", b"0123456789abcdef", &TEST_PARAMS).unwrap();
+        Key::from_password_cached(&cache, "correct horse", b"0123456789abcdef", &PasswordParams { t_cost: 2, ..TEST_PARAMS }).unwrap();
+        assert_eq!(derivations.load(Ordering::SeqCst), 4);
+        assert_eq!(cache.len(), 4);
+        assert_ne!(key.u8_array, other_salt.u8_array)
+    }
+
+    #[test]
+    fn purge_forces_rederivation_and_size_is_bounded() {
+        let (cache, derivations) = counting_cache!(2);
+        for salt in [b"salt-one", b"salt-two", b"salt-333"].iter() {
+            Key::from_password_cached(&cache, "correct horse", *salt, &TEST_PARAMS).unwrap();
+        }
+        assert_eq!(cache.len(), 2);
+        Key::from_password_cached(&cache, "correct horse", b"salt-one", &TEST_PARAMS).unwrap();
+        assert_eq!(derivations.load(Ordering::SeqCst), 4);
+
+        cache.purge();
+        assert!(cache.is_empty());
+        Key::from_password_cached(&cache, "correct horse", b"salt-333", &TEST_PARAMS).unwrap();
+        assert_eq!(derivations.load(Ordering::SeqCst), 5)
+    }
+
+    #[test]
+    fn errors_are_not_cached() {
+        let (cache, derivations) = counting_cache!(2);
+        let params = PasswordParams { m_cost: crate::password::MAX_M_COST + 1, ..TEST_PARAMS };
+        for _ in 0..2 {
+            assert!(Key::from_password_cached(&cache, "correct horse", b"0123456789abcdef", &params).is_err());
+        }
+        assert_eq!((derivations.load(Ordering::SeqCst), cache.len()), (2, 0))
+    }
+}
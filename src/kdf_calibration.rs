@@ -0,0 +1,167 @@
+//! Picking password KDF parameters by measuring the host.
+//!
+//! `calibrate_kdf(target, algorithm)` doubles the cost, starting from a
+//! cheap one, until a derivation takes at least `target`, then tries one
+//! cost interpolated between the last two, keeping it if it still reaches
+//! `target`. Each cost is timed as the average of `CalibrationLimits::runs`
+//! derivations. For Argon2id the memory doubles first, up to
+//! `max_m_cost`, then the iterations; PBKDF2 only has iterations.
+//!
+//! The search stops at the caps and once `max_calibration_time` has
+//! passed, returning the largest cost measured, which may then fall short
+//! of `target`. The result goes into `encrypt_with_password_and_kdf`.
+//! Calibrate where the envelopes will be decrypted: a slower machine takes
+//! proportionally longer.
+
+use std::time::{Duration, Instant};
+
+use crate::password::{derive_kdf_key, KdfParams, PasswordParams, MAX_M_COST, MAX_PBKDF2_ITERATIONS, MAX_T_COST};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdfAlgorithm {
+    Argon2id,
+    Pbkdf2Sha256
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CalibrationLimits {
+    /// Argon2id memory ceiling in KiB, itself capped at `MAX_M_COST`.
+    pub max_m_cost: u32,
+    pub max_calibration_time: Duration,
+    /// Derivations averaged per measured cost, at least 1.
+    pub runs: u32
+}
+
+impl Default for CalibrationLimits {
+    /// 256 MiB, 10 seconds, 3 runs.
+    fn default() -> Self {
+        CalibrationLimits { max_m_cost: 256 * 1024, max_calibration_time: Duration::from_secs(10), runs: 3 }
+    }
+}
+
+const ARGON2_START_M_COST: u32 = 64;
+const PBKDF2_START_ITERATIONS: u32 = 1000;
+
+/// `calibrate_kdf_with` and the default `CalibrationLimits`.
+pub fn calibrate_kdf(target: Duration, algorithm: KdfAlgorithm) -> KdfParams {
+    calibrate_kdf_with(target, algorithm, &CalibrationLimits::default())
+}
+
+/// Parameters of `algorithm` whose derivation takes about `target` here,
+/// see the module docs.
+pub fn calibrate_kdf_with(target: Duration, algorithm: KdfAlgorithm, limits: &CalibrationLimits) -> KdfParams {
+    let deadline = Instant::now() + limits.max_calibration_time;
+    let runs = limits.runs.max(1);
+    match algorithm {
+        KdfAlgorithm::Argon2id => {
+            let argon2 = |m_cost, t_cost| KdfParams::Argon2id(PasswordParams { m_cost, t_cost, p_cost: 1 });
+            let max_m_cost = limits.max_m_cost.clamp(ARGON2_START_M_COST, MAX_M_COST);
+            let (m_cost, reached) = search(target, ARGON2_START_M_COST, max_m_cost, deadline, |m_cost| average_time(&argon2(m_cost, 1), runs));
+            if reached || m_cost < max_m_cost || Instant::now() >= deadline {
+                return argon2(m_cost, 1);
+            }
+            let (t_cost, _) = search(target, 2, MAX_T_COST, deadline, |t_cost| average_time(&argon2(m_cost, t_cost), runs));
+            argon2(m_cost, t_cost)
+        },
+        KdfAlgorithm::Pbkdf2Sha256 => {
+            let pbkdf2 = |iterations| KdfParams::Pbkdf2Sha256 { iterations };
+            let (iterations, _) = search(target, PBKDF2_START_ITERATIONS, MAX_PBKDF2_ITERATIONS, deadline, |iterations| average_time(&pbkdf2(iterations), runs));
+            pbkdf2(iterations)
+        }
+    }
+}
+
+fn average_time(params: &KdfParams, runs: u32) -> Duration {
+    let start = Instant::now();
+    for _ in 0..runs {
+        derive_kdf_key("simple-aes256-gcm calibration", b"calibration salt", params).expect("calibrated parameters are within the caps");
+    }
+    start.elapsed() / runs
+}
+
+/// The cost found from `start`, doubling up to `max`, and whether it
+/// reaches `target`.
+fn search<F: FnMut(u32) -> Duration>(target: Duration, start: u32, max: u32, deadline: Instant, mut measure: F) -> (u32, bool) {
+    let mut previous: Option<(u32, Duration)> = None;
+    let mut cost = start.min(max);
+    loop {
+        let time = measure(cost);
+        if time >= target {
+            if let Some((previous_cost, previous_time)) = previous {
+                let fraction = (target - previous_time).as_secs_f64() / (time - previous_time).as_secs_f64();
+                let estimate = previous_cost + ((cost - previous_cost) as f64 * fraction).ceil() as u32;
+                if estimate > previous_cost && estimate < cost && Instant::now() < deadline && measure(estimate) >= target {
+                    return (estimate, true);
+                }
+            }
+            return (cost, true);
+        }
+        if cost >= max || Instant::now() >= deadline {
+            return (cost, false);
+        }
+        previous = Some((cost, time));
+        cost = cost.saturating_mul(2).min(max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::password::{decrypt_with_password, encrypt_with_password_and_kdf};
+
+    const TARGET: Duration = Duration::from_millis(10);
+
+    fn limits(max_m_cost: u32) -> CalibrationLimits {
+        CalibrationLimits { max_m_cost, max_calibration_time: Duration::from_secs(20), runs: 2 }
+    }
+
+    // Timing on a loaded machine varies, hence the slack.
+    fn assert_takes_about_target(params: &KdfParams) {
+        assert!(average_time(params, 3) >= TARGET / 2, "{:?} is much faster than the target", params)
+    }
+
+    #[test]
+    fn argon2id_reaches_the_target_within_the_memory_cap() {
+        let params = calibrate_kdf_with(TARGET, KdfAlgorithm::Argon2id, &limits(16 * 1024));
+        match params {
+            KdfParams::Argon2id(argon2) => assert!(argon2.m_cost <= 16 * 1024 && argon2.p_cost == 1),
+            _ => panic!("Should be Argon2id")
+        }
+        assert_takes_about_target(&params);
+
+        let envelope = encrypt_with_password_and_kdf("correct horse", b"This is a text.", &params).unwrap();
+        assert_eq!(decrypt_with_password("correct horse", &envelope).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn argon2id_raises_iterations_at_the_memory_cap() {
+        let params = calibrate_kdf_with(TARGET, KdfAlgorithm::Argon2id, &limits(ARGON2_START_M_COST));
+        match params {
+            KdfParams::Argon2id(argon2) => assert_eq!(argon2.m_cost, ARGON2_START_M_COST),
+            _ => panic!("Should be Argon2id")
+        }
+        assert_takes_about_target(&params)
+    }
+
+    #[test]
+    fn pbkdf2_reaches_the_target() {
+        let params = calibrate_kdf_with(TARGET, KdfAlgorithm::Pbkdf2Sha256, &limits(0));
+        match params {
+            KdfParams::Pbkdf2Sha256 { iterations } => assert!(iterations >= PBKDF2_START_ITERATIONS),
+            _ => panic!("Should be PBKDF2")
+        }
+        assert_takes_about_target(&params)
+    }
+
+    #[test]
+    fn calibration_time_is_bounded() {
+        let limits = CalibrationLimits { max_m_cost: 1024, max_calibration_time: Duration::from_millis(50), runs: 1 };
+        let start = Instant::now();
+        let params = calibrate_kdf_with(Duration::from_secs(60), KdfAlgorithm::Argon2id, &limits);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        match params {
+            KdfParams::Argon2id(argon2) => assert!(argon2.m_cost <= 1024),
+            _ => panic!("Should be Argon2id")
+        }
+    }
+}
@@ -0,0 +1,547 @@
+//! Key files that say what they hold and detect truncation.
+//!
+//! A key file is UTF-8 text, one `name: value` field per line after the
+//! magic line:
+//!
+//! ```text
+//! SAES-KEY-FILE
+//! version: 1
+//! created-at: 1700000000
+//! fingerprint: 1f0e6d5c4b3a2918
+//! label: billing service
+//! comment: rotated after the 2024 audit
+//! key: MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=
+//! sha256: <64 hex digits>
+//! ```
+//!
+//! `created-at` is in Unix seconds, `fingerprint` is `Key::fingerprint`,
+//! `label` and `comment` are optional, and the last line is the SHA-256 of
+//! every byte before it. `KeyFile::load` checks, in this order, the magic,
+//! the version, the checksum and the key material, each failing with its
+//! own error. `KeyFile::load_or_legacy` also accepts the bare base64 line
+//! or raw 32 bytes of older key files.
+//!
+//! With the `password` feature, `KeyFile::save_protected` writes a
+//! `protected: passphrase` line before the key, whose value is then a
+//! `password` envelope of the key bytes (KDF id, parameters and salt
+//! included) instead of its base64. `KeyFile::is_protected` tells whether
+//! to prompt for a passphrase; the unprotected loaders fail on such a file
+//! with `PassphraseRequiredError`.
+//!
+//! The whole file is a secret, as is what `encode` returns; what is read
+//! is wiped once decoded.
+
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{error, fmt};
+
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "password")]
+use crate::password::{decrypt_with_password, encrypt_with_password_and_kdf, KdfParams, PasswordError};
+use crate::secure_buffer::wipe;
+use crate::Key;
+
+pub const KEY_FILE_MAGIC: &str = "SAES-KEY-FILE";
+
+pub const KEY_FILE_VERSION: u32 = 1;
+
+const PROTECTED_VALUE: &str = "passphrase";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyMeta {
+    pub created_at: SystemTime,
+    /// A single line.
+    pub label: Option<String>,
+    /// A single line.
+    pub comment: Option<String>
+}
+
+impl KeyMeta {
+    pub fn new(created_at: SystemTime) -> KeyMeta {
+        KeyMeta { created_at, label: None, comment: None }
+    }
+}
+
+#[derive(Debug)]
+pub enum KeyFileError {
+    KeyFileIoError(io::Error),
+    /// Doesn't start with `KEY_FILE_MAGIC`: not a key file, or a legacy one.
+    WrongMagicError,
+    /// Holds the version found.
+    UnsupportedVersionError(String),
+    /// Truncated or altered since it was written.
+    ChecksumMismatchError,
+    /// A missing, duplicate or unknown field, or a multi-line label or comment.
+    MalformedKeyFileError,
+    /// Not the base64 of 32 bytes, or not matching the fingerprint.
+    BadKeyMaterialError,
+    /// Passphrase-protected, to load with `KeyFile::load_protected`.
+    PassphraseRequiredError,
+    /// Given to `KeyFile::load_protected` but not protected, to load with `KeyFile::load`.
+    NotProtectedError,
+    /// The checksum holds, so the file is intact: the passphrase is wrong.
+    WrongPassphraseError
+}
+
+impl fmt::Display for KeyFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyFileError::KeyFileIoError(e) => write!(f, "{}", e),
+            KeyFileError::WrongMagicError => write!(f, "Not a key file"),
+            KeyFileError::UnsupportedVersionError(version) => write!(f, "Unsupported key file version {}", version),
+            KeyFileError::ChecksumMismatchError => write!(f, "Key file checksum mismatch, truncated or altered"),
+            KeyFileError::MalformedKeyFileError => write!(f, "Malformed key file"),
+            KeyFileError::BadKeyMaterialError => write!(f, "Invalid key in key file"),
+            KeyFileError::PassphraseRequiredError => write!(f, "Key file is passphrase-protected, load it with KeyFile::load_protected"),
+            KeyFileError::NotProtectedError => write!(f, "Key file is not passphrase-protected, load it with KeyFile::load"),
+            KeyFileError::WrongPassphraseError => write!(f, "Wrong key file passphrase"),
+        }
+    }
+}
+
+impl error::Error for KeyFileError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            KeyFileError::KeyFileIoError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for KeyFileError {
+    fn from(e: io::Error) -> KeyFileError {
+        KeyFileError::KeyFileIoError(e)
+    }
+}
+
+fn checksum(body: &str) -> String {
+    Sha256::digest(body.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn is_single_line(value: &Option<String>) -> bool {
+    value.as_ref().is_none_or(|value| !value.contains('\n') && !value.contains('\r'))
+}
+
+fn write_new<P: AsRef<Path>>(path: P, mut text: String) -> Result<(), KeyFileError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let result = options.open(path).and_then(|mut file| file.write_all(text.as_bytes()).and_then(|_| file.sync_all()));
+    // Safe: the zeros are valid UTF-8, and the string is dropped right after.
+    wipe(unsafe { text.as_bytes_mut() });
+    Ok(result?)
+}
+
+/// The fields of a key file whose magic, version and checksum are valid.
+struct Fields<'a> {
+    created_at: SystemTime,
+    fingerprint: &'a str,
+    label: Option<&'a str>,
+    comment: Option<&'a str>,
+    protected: bool,
+    key: &'a str
+}
+
+impl<'a> Fields<'a> {
+    fn parse(text: &'a str) -> Result<Fields<'a>, KeyFileError> {
+        let rest = match text.strip_prefix(KEY_FILE_MAGIC).and_then(|rest| rest.strip_prefix('\n')) {
+            Some(rest) => rest,
+            None => return Err(KeyFileError::WrongMagicError)
+        };
+        let version = rest.lines().next().and_then(|line| line.strip_prefix("version: "));
+        match version {
+            Some(version) if version == KEY_FILE_VERSION.to_string() => {},
+            Some(version) => return Err(KeyFileError::UnsupportedVersionError(String::from(version))),
+            None => return Err(KeyFileError::MalformedKeyFileError)
+        }
+        let (body, sum) = match text.trim_end_matches('\n').rfind('\n') {
+            Some(end) => (&text[..end + 1], &text[end + 1..]),
+            None => return Err(KeyFileError::ChecksumMismatchError)
+        };
+        if sum.strip_prefix("sha256: ").map(|sum| sum.trim_end_matches('\n')) != Some(&checksum(body)[..]) {
+            return Err(KeyFileError::ChecksumMismatchError);
+        }
+
+        let (mut created_at, mut fingerprint, mut label, mut comment, mut protected, mut key) = (None, None, None, None, None, None);
+        for line in body.lines().skip(2) {
+            let (name, value) = match line.split_once(": ") {
+                Some(field) => field,
+                None => return Err(KeyFileError::MalformedKeyFileError)
+            };
+            let field = match name {
+                "created-at" => &mut created_at,
+                "fingerprint" => &mut fingerprint,
+                "label" => &mut label,
+                "comment" => &mut comment,
+                "protected" => &mut protected,
+                "key" => &mut key,
+                _ => return Err(KeyFileError::MalformedKeyFileError)
+            };
+            if field.replace(value).is_some() {
+                return Err(KeyFileError::MalformedKeyFileError);
+            }
+        }
+        let created_at = match created_at.map(str::parse::<u64>) {
+            Some(Ok(seconds)) => UNIX_EPOCH + Duration::from_secs(seconds),
+            _ => return Err(KeyFileError::MalformedKeyFileError)
+        };
+        let protected = match protected {
+            None => false,
+            Some(PROTECTED_VALUE) => true,
+            Some(_) => return Err(KeyFileError::MalformedKeyFileError)
+        };
+        match (fingerprint, key) {
+            (Some(fingerprint), Some(key)) => Ok(Fields { created_at, fingerprint, label, comment, protected, key }),
+            _ => Err(KeyFileError::MalformedKeyFileError)
+        }
+    }
+
+    fn check_fingerprint(self, key: Key) -> Result<(Key, KeyMeta), KeyFileError> {
+        if key.fingerprint() != self.fingerprint {
+            return Err(KeyFileError::BadKeyMaterialError);
+        }
+        Ok((key, KeyMeta { created_at: self.created_at, label: self.label.map(String::from), comment: self.comment.map(String::from) }))
+    }
+}
+
+pub struct KeyFile;
+
+impl KeyFile {
+    /// The key file text for `key`, see the module docs.
+    pub fn encode(key: &Key, meta: &KeyMeta) -> Result<String, KeyFileError> {
+        let mut base64_key = base64::encode(&key.u8_array);
+        let text = KeyFile::assemble(key, meta, false, &base64_key);
+        // Safe: the zeros are valid UTF-8, and the string is dropped right after.
+        wipe(unsafe { base64_key.as_bytes_mut() });
+        text
+    }
+
+    fn assemble(key: &Key, meta: &KeyMeta, protected: bool, key_field: &str) -> Result<String, KeyFileError> {
+        if !is_single_line(&meta.label) || !is_single_line(&meta.comment) {
+            return Err(KeyFileError::MalformedKeyFileError);
+        }
+        let created_at = meta.created_at.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+        let mut body = format!("{}\nversion: {}\ncreated-at: {}\nfingerprint: {}\n", KEY_FILE_MAGIC, KEY_FILE_VERSION, created_at, key.fingerprint());
+        if let Some(label) = &meta.label {
+            body.push_str(&format!("label: {}\n", label));
+        }
+        if let Some(comment) = &meta.comment {
+            body.push_str(&format!("comment: {}\n", comment));
+        }
+        if protected {
+            body.push_str(&format!("protected: {}\n", PROTECTED_VALUE));
+        }
+        body.push_str(&format!("key: {}\n", key_field));
+        let sum = checksum(&body);
+        body.push_str(&format!("sha256: {}\n", sum));
+        Ok(body)
+    }
+
+    /// Parses the output of `encode`. A passphrase-protected file fails
+    /// with `PassphraseRequiredError`.
+    pub fn decode(text: &str) -> Result<(Key, KeyMeta), KeyFileError> {
+        let fields = Fields::parse(text)?;
+        if fields.protected {
+            return Err(KeyFileError::PassphraseRequiredError);
+        }
+        let key = Key::try_from(fields.key).map_err(|_| KeyFileError::BadKeyMaterialError)?;
+        fields.check_fingerprint(key)
+    }
+
+    /// Whether `text` is a key file needing a passphrase, so callers can
+    /// prompt for one only when needed.
+    pub fn is_protected_text(text: &str) -> Result<bool, KeyFileError> {
+        Fields::parse(text).map(|fields| fields.protected)
+    }
+
+    /// Same as `is_protected_text`, reading `path`.
+    pub fn is_protected<P: AsRef<Path>>(path: P) -> Result<bool, KeyFileError> {
+        let mut content = fs::read(path)?;
+        let result = match std::str::from_utf8(&content) {
+            Ok(text) => KeyFile::is_protected_text(text),
+            Err(_) => Err(KeyFileError::WrongMagicError)
+        };
+        wipe(&mut content);
+        result
+    }
+
+    /// Writes the key file, readable by the owner only on Unix. Fails if
+    /// `path` exists: a key file is never overwritten.
+    pub fn save<P: AsRef<Path>>(path: P, key: &Key, meta: &KeyMeta) -> Result<(), KeyFileError> {
+        write_new(path, KeyFile::encode(key, meta)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<(Key, KeyMeta), KeyFileError> {
+        let mut content = fs::read(path)?;
+        let result = match std::str::from_utf8(&content) {
+            Ok(text) => KeyFile::decode(text),
+            Err(_) => Err(KeyFileError::WrongMagicError)
+        };
+        wipe(&mut content);
+        result
+    }
+
+    /// Like `load`, also accepting a file holding only the base64 key
+    /// (surrounding whitespace ignored) or the raw 32 bytes, which has no
+    /// metadata.
+    pub fn load_or_legacy<P: AsRef<Path>>(path: P) -> Result<(Key, Option<KeyMeta>), KeyFileError> {
+        let mut content = fs::read(path)?;
+        let result = KeyFile::decode_or_legacy(&content);
+        wipe(&mut content);
+        result
+    }
+
+    pub(crate) fn decode_or_legacy(content: &[u8]) -> Result<(Key, Option<KeyMeta>), KeyFileError> {
+        if content.starts_with(KEY_FILE_MAGIC.as_bytes()) {
+            return match std::str::from_utf8(content) {
+                Ok(text) => KeyFile::decode(text).map(|(key, meta)| (key, Some(meta))),
+                Err(_) => Err(KeyFileError::MalformedKeyFileError)
+            };
+        }
+        if content.len() == 32 {
+            let mut u8_array = [0u8; 32];
+            u8_array.copy_from_slice(content);
+            return Ok((Key { u8_array }, None));
+        }
+        match std::str::from_utf8(content).map(|text| Key::try_from(text.trim())) {
+            Ok(Ok(key)) => Ok((key, None)),
+            _ => Err(KeyFileError::BadKeyMaterialError)
+        }
+    }
+}
+
+#[cfg(feature = "password")]
+impl KeyFile {
+    /// Same as `encode`, with the key encrypted under `passphrase` as a
+    /// `password` envelope, which holds the salt and `kdf_params`.
+    pub fn encode_protected(key: &Key, meta: &KeyMeta, passphrase: &str, kdf_params: &KdfParams) -> Result<String, KeyFileError> {
+        let envelope = encrypt_with_password_and_kdf(passphrase, &key.u8_array, kdf_params).map_err(|_| KeyFileError::BadKeyMaterialError)?;
+        KeyFile::assemble(key, meta, true, &envelope)
+    }
+
+    /// Parses the output of `encode_protected`. A wrong passphrase fails
+    /// with `WrongPassphraseError`, a file that isn't protected with
+    /// `NotProtectedError`.
+    pub fn decode_protected(text: &str, passphrase: &str) -> Result<(Key, KeyMeta), KeyFileError> {
+        let fields = Fields::parse(text)?;
+        if !fields.protected {
+            return Err(KeyFileError::NotProtectedError);
+        }
+        let mut plaintext = match decrypt_with_password(passphrase, fields.key) {
+            Ok(plaintext) => plaintext,
+            Err(PasswordError::PasswordDecryptionError(_)) => return Err(KeyFileError::WrongPassphraseError),
+            Err(_) => return Err(KeyFileError::BadKeyMaterialError)
+        };
+        let key = match <[u8; 32]>::try_from(&plaintext[..]) {
+            Ok(u8_array) => Ok(Key { u8_array }),
+            Err(_) => Err(KeyFileError::BadKeyMaterialError)
+        };
+        wipe(&mut plaintext);
+        fields.check_fingerprint(key?)
+    }
+
+    /// Same as `save`, with the key protected by `passphrase`.
+    pub fn save_protected<P: AsRef<Path>>(path: P, key: &Key, meta: &KeyMeta, passphrase: &str, kdf_params: &KdfParams) -> Result<(), KeyFileError> {
+        write_new(path, KeyFile::encode_protected(key, meta, passphrase, kdf_params)?)
+    }
+
+    pub fn load_protected<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<(Key, KeyMeta), KeyFileError> {
+        let mut content = fs::read(path)?;
+        let result = match std::str::from_utf8(&content) {
+            Ok(text) => KeyFile::decode_protected(text, passphrase),
+            Err(_) => Err(KeyFileError::WrongMagicError)
+        };
+        wipe(&mut content);
+        result
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        crate::test_path(&format!("key-file-{}", name))
+    }
+
+    fn meta() -> KeyMeta {
+        KeyMeta { created_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000), label: Some(String::from("billing")), comment: None }
+    }
+
+    fn decode_error(text: &str) -> String {
+        match KeyFile::decode(text) {
+            Ok(_) => panic!("Should err"),
+            Err(e) => format!("{:?}", e)
+        }
+    }
+
+    #[test]
+    fn round_trips_through_a_file() {
+        let path = temp_path("round-trip");
+        let _ = fs::remove_file(&path);
+        KeyFile::save(&path, &Key { u8_array: KEY }, &meta()).unwrap();
+        let (key, loaded) = KeyFile::load(&path).unwrap();
+        assert_eq!((key.u8_array, loaded), (KEY, meta()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600)
+        }
+        match KeyFile::save(&path, &Key { u8_array: KEY }, &meta()) {
+            Ok(_) => assert!(false, "Should err KeyFileIoError"),
+            Err(e) => match e {
+                KeyFileError::KeyFileIoError(e) => assert_eq!(e.kind(), io::ErrorKind::AlreadyExists),
+                _ => assert!(false, "Should err KeyFileIoError, got {}", e)
+            }
+        }
+        fs::remove_file(&path).unwrap()
+    }
+
+    #[test]
+    fn every_bit_flip_is_detected() {
+        let text = KeyFile::encode(&Key { u8_array: KEY }, &meta()).unwrap();
+        for index in 0..text.len() {
+            for bit in 0..8 {
+                let mut bytes = text.clone().into_bytes();
+                bytes[index] ^= 1 << bit;
+                if let Ok(flipped) = String::from_utf8(bytes) {
+                    assert!(KeyFile::decode(&flipped).is_err(), "byte {} bit {}", index, bit)
+                }
+            }
+        }
+        let body_index = text.find("label").unwrap();
+        let mut bytes = text.clone().into_bytes();
+        bytes[body_index] ^= 0x20;
+        assert_eq!(decode_error(&String::from_utf8(bytes).unwrap()), "ChecksumMismatchError");
+        assert_eq!(decode_error(&text[..text.len() - 10]), "ChecksumMismatchError")
+    }
+
+    #[test]
+    fn failures_have_distinct_errors() {
+        let text = KeyFile::encode(&Key { u8_array: KEY }, &meta()).unwrap();
+        assert_eq!(decode_error("MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=\n"), "WrongMagicError");
+        assert_eq!(decode_error(&text.replace("version: 1", "version: 2")), "UnsupportedVersionError(\"2\")");
+
+        let rechecksummed = |body: String| format!("{}sha256: {}\n", body, checksum(&body));
+        let body = &text[..text.rfind("sha256: ").unwrap()];
+        assert_eq!(decode_error(&rechecksummed(body.replace("MDEy", "MDEz"))), "BadKeyMaterialError");
+        assert_eq!(decode_error(&rechecksummed(body.replace("key: MDEy", "key: !DEy"))), "BadKeyMaterialError");
+        assert_eq!(decode_error(&rechecksummed(body.replace("label", "owner"))), "MalformedKeyFileError");
+        assert_eq!(decode_error(&rechecksummed(format!("{}label: twice\n", body))), "MalformedKeyFileError");
+
+        let multi_line = KeyMeta { comment: Some(String::from("two\nlines")), ..meta() };
+        match KeyFile::encode(&Key { u8_array: KEY }, &multi_line) {
+            Ok(_) => assert!(false, "Should err MalformedKeyFileError"),
+            Err(e) => match e {
+                KeyFileError::MalformedKeyFileError => assert!(true),
+                _ => assert!(false, "Should err MalformedKeyFileError, got {}", e)
+            }
+        }
+    }
+
+    #[cfg(feature = "password")]
+    const TEST_KDF_PARAMS: KdfParams = KdfParams::Argon2id(crate::password::PasswordParams { m_cost: 64, t_cost: 1, p_cost: 1 });
+
+    #[cfg(feature = "password")]
+    #[test]
+    fn protected_files_round_trip() {
+        let path = temp_path("protected");
+        let _ = fs::remove_file(&path);
+        KeyFile::save_protected(&path, &Key { u8_array: KEY }, &meta(), "correct horse", &TEST_KDF_PARAMS).unwrap();
+        assert!(KeyFile::is_protected(&path).unwrap());
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.contains("protected: passphrase\n") && !text.contains("MDEyMzQ1Njc4OTAx"), "{}", text);
+        let (key, loaded) = KeyFile::load_protected(&path, "correct horse").unwrap();
+        assert_eq!((key.u8_array, loaded), (KEY, meta()));
+        fs::remove_file(&path).unwrap();
+
+        let unprotected = KeyFile::encode(&Key { u8_array: KEY }, &meta()).unwrap();
+        assert!(!KeyFile::is_protected_text(&unprotected).unwrap());
+        match KeyFile::decode_protected(&unprotected, "correct horse") {
+            Ok(_) => assert!(false, "Should err NotProtectedError"),
+            Err(e) => match e {
+                KeyFileError::NotProtectedError => assert!(true),
+                _ => assert!(false, "Should err NotProtectedError, got {}", e)
+            }
+        }
+    }
+
+    #[cfg(feature = "password")]
+    #[test]
+    fn wrong_passphrase_is_not_corruption() {
+        let text = KeyFile::encode_protected(&Key { u8_array: KEY }, &meta(), "correct horse", &TEST_KDF_PARAMS).unwrap();
+        match KeyFile::decode_protected(&text, "battery staple") {
+            Ok(_) => assert!(false, "Should err WrongPassphraseError"),
+            Err(e) => match e {
+                KeyFileError::WrongPassphraseError => assert!(true),
+                _ => assert!(false, "Should err WrongPassphraseError, got {}", e)
+            }
+        }
+        let index = text.find("key: ").unwrap() + 20;
+        let mut bytes = text.into_bytes();
+        bytes[index] ^= 1;
+        match KeyFile::decode_protected(&String::from_utf8(bytes).unwrap(), "correct horse") {
+            Ok(_) => assert!(false, "Should err ChecksumMismatchError"),
+            Err(e) => match e {
+                KeyFileError::ChecksumMismatchError => assert!(true),
+                _ => assert!(false, "Should err ChecksumMismatchError, got {}", e)
+            }
+        }
+    }
+
+    #[cfg(feature = "password")]
+    #[test]
+    fn unprotected_loaders_ask_for_a_passphrase() {
+        let path = temp_path("protected-unprotected-loader");
+        let _ = fs::remove_file(&path);
+        KeyFile::save_protected(&path, &Key { u8_array: KEY }, &meta(), "correct horse", &TEST_KDF_PARAMS).unwrap();
+        for result in [KeyFile::load(&path).map(|_| ()), KeyFile::load_or_legacy(&path).map(|_| ())].iter() {
+            match result {
+                Ok(_) => assert!(false, "Should err PassphraseRequiredError"),
+                Err(e) => match e {
+                    KeyFileError::PassphraseRequiredError => assert!(e.to_string().contains("KeyFile::load_protected")),
+                    _ => assert!(false, "Should err PassphraseRequiredError, got {}", e)
+                }
+            }
+        }
+        fs::remove_file(&path).unwrap()
+    }
+
+    #[test]
+    fn legacy_files_load_without_metadata() {
+        let base64_path = temp_path("legacy-base64");
+        fs::write(&base64_path, "MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=\n").unwrap();
+        let raw_path = temp_path("legacy-raw");
+        fs::write(&raw_path, KEY).unwrap();
+        for path in [&base64_path, &raw_path].iter() {
+            let (key, meta) = KeyFile::load_or_legacy(path).unwrap();
+            assert_eq!((key.u8_array, meta), (KEY, None));
+            match KeyFile::load(path) {
+                Ok(_) => assert!(false, "Should err WrongMagicError"),
+                Err(e) => match e {
+                    KeyFileError::WrongMagicError => assert!(true),
+                    _ => assert!(false, "Should err WrongMagicError, got {}", e)
+                }
+            }
+            fs::remove_file(path).unwrap()
+        }
+
+        let (key, loaded) = KeyFile::decode_or_legacy(KeyFile::encode(&Key { u8_array: KEY }, &meta()).unwrap().as_bytes()).unwrap();
+        assert_eq!((key.u8_array, loaded), (KEY, Some(meta())));
+        match KeyFile::decode_or_legacy(b"not a key") {
+            Ok(_) => assert!(false, "Should err BadKeyMaterialError"),
+            Err(e) => match e {
+                KeyFileError::BadKeyMaterialError => assert!(true),
+                _ => assert!(false, "Should err BadKeyMaterialError, got {}", e)
+            }
+        }
+    }
+}
@@ -0,0 +1,538 @@
+//! A set of named keys with one primary used for new encryptions.
+//!
+//! Every key carries `KeyMetadata`. A primary past its `not_after` can no
+//! longer encrypt, but any enabled key, expired or not, still decrypts:
+//! this is how rotation retires a key without stranding existing data.
+//!
+//! With the `json` feature the ring round-trips through JSON, e.g. for
+//! config files. The key bytes are written base64-encoded under
+//! `sensitive_key_base64`; treat the whole document as a secret.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::{error, fmt};
+
+use crate::failure_policy::FailurePolicy;
+use crate::provider::{KeyProvider, ProviderError};
+use crate::report::{DecryptReport, ReportingDecrypt};
+use crate::{decrypt_bytes, decrypt_bytes_with_aad, encrypt_bytes, DecryptionError, EncryptedAndIv, EncryptionError, Key};
+
+/// Source of the current time, injectable for tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyMetadata {
+    pub label: String,
+    pub created_at: SystemTime,
+    /// Last instant at which the key may be used for new encryptions.
+    pub not_after: Option<SystemTime>,
+    /// Disabled keys neither encrypt nor decrypt.
+    pub enabled: bool
+}
+
+impl KeyMetadata {
+    /// Metadata for an enabled key that never expires.
+    pub fn new(label: &str, created_at: SystemTime) -> KeyMetadata {
+        KeyMetadata {
+            label: String::from(label),
+            created_at,
+            not_after: None,
+            enabled: true
+        }
+    }
+}
+
+struct KeyEntry {
+    key: Key,
+    metadata: KeyMetadata
+}
+
+#[derive(Debug, Clone)]
+pub enum KeyRingError {
+    UnknownKeyError,
+    DisabledKeyError,
+    /// The primary key is past its `not_after` and may only decrypt.
+    PrimaryKeyExpired,
+    KeyRingEncryptionError(EncryptionError),
+    KeyRingDecryptionError(DecryptionError),
+    #[cfg(feature = "json")]
+    InvalidKeyRingJsonError
+}
+
+impl fmt::Display for KeyRingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyRingError::UnknownKeyError => write!(f, "No such key in the key ring"),
+            KeyRingError::DisabledKeyError => write!(f, "The key is disabled"),
+            KeyRingError::PrimaryKeyExpired => write!(f, "The primary key has expired for encryption"),
+            KeyRingError::KeyRingEncryptionError(e) => write!(f, "{}", e),
+            KeyRingError::KeyRingDecryptionError(e) => write!(f, "{}", e),
+            #[cfg(feature = "json")]
+            KeyRingError::InvalidKeyRingJsonError => write!(f, "Invalid key ring JSON"),
+        }
+    }
+}
+
+impl error::Error for KeyRingError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            KeyRingError::KeyRingEncryptionError(e) => Some(e),
+            KeyRingError::KeyRingDecryptionError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// Output of `KeyRing::encrypt`: the ciphertext and the id of the key that produced it.
+pub struct KeyRingEncrypted {
+    pub key_id: String,
+    pub encrypted_and_iv: EncryptedAndIv
+}
+
+pub struct KeyRing {
+    entries: BTreeMap<String, KeyEntry>,
+    primary_id: String,
+    clock: Arc<dyn Clock>,
+    failure_policy: Option<Arc<FailurePolicy>>
+}
+
+impl KeyRing {
+    pub fn new(primary_id: &str, primary: Key, metadata: KeyMetadata) -> KeyRing {
+        let mut entries = BTreeMap::new();
+        entries.insert(String::from(primary_id), KeyEntry { key: primary, metadata });
+        KeyRing {
+            entries,
+            primary_id: String::from(primary_id),
+            clock: Arc::new(SystemClock),
+            failure_policy: None
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> KeyRing {
+        self.clock = clock;
+        self
+    }
+
+    /// Throttles failing decryptions, per the context id given to
+    /// `decrypt_for`; `decrypt` counts under the empty context id. Unknown
+    /// and disabled keys aren't counted.
+    pub fn with_failure_policy(mut self, failure_policy: Arc<FailurePolicy>) -> KeyRing {
+        self.failure_policy = Some(failure_policy);
+        self
+    }
+
+    /// Adds a key, replacing any key with the same id.
+    pub fn add(&mut self, id: &str, key: Key, metadata: KeyMetadata) {
+        self.entries.insert(String::from(id), KeyEntry { key, metadata });
+    }
+
+    pub fn set_primary(&mut self, id: &str) -> Result<(), KeyRingError> {
+        if !self.entries.contains_key(id) {
+            return Err(KeyRingError::UnknownKeyError);
+        }
+        self.primary_id = String::from(id);
+        Ok(())
+    }
+
+    pub fn primary_id(&self) -> &str {
+        &self.primary_id
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(|id| &id[..])
+    }
+
+    pub fn metadata(&self, id: &str) -> Option<&KeyMetadata> {
+        self.entries.get(id).map(|entry| &entry.metadata)
+    }
+
+    pub fn metadata_mut(&mut self, id: &str) -> Option<&mut KeyMetadata> {
+        self.entries.get_mut(id).map(|entry| &mut entry.metadata)
+    }
+
+    /// Encrypts with the primary key, provided it's enabled and not expired.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<KeyRingEncrypted, KeyRingError> {
+        let entry = match self.entries.get(&self.primary_id) {
+            Some(value) => value,
+            None => return Err(KeyRingError::UnknownKeyError)
+        };
+        if !entry.metadata.enabled {
+            return Err(KeyRingError::DisabledKeyError);
+        }
+        if let Some(not_after) = entry.metadata.not_after {
+            if self.clock.now() > not_after {
+                return Err(KeyRingError::PrimaryKeyExpired);
+            }
+        }
+        match encrypt_bytes(&entry.key, plaintext) {
+            Ok(encrypted_and_iv) => Ok(KeyRingEncrypted {
+                key_id: self.primary_id.clone(),
+                encrypted_and_iv
+            }),
+            Err(e) => Err(KeyRingError::KeyRingEncryptionError(e))
+        }
+    }
+
+    /// Decrypts with the key named by `encrypted.key_id`, which must be
+    /// enabled. Not throttled: the failure policy only applies to `decrypt_for`.
+    pub fn decrypt(&self, encrypted: &KeyRingEncrypted) -> Result<Vec<u8>, KeyRingError> {
+        self.decrypt_as(None, encrypted)
+    }
+
+    /// Like `decrypt`, but fails with `DecryptionError::RateLimited` while
+    /// `context_id` is over the failure policy's threshold.
+    pub fn decrypt_for(&self, context_id: &str, encrypted: &KeyRingEncrypted) -> Result<Vec<u8>, KeyRingError> {
+        self.decrypt_as(Some(context_id), encrypted)
+    }
+
+    fn decrypt_as(&self, context_id: Option<&str>, encrypted: &KeyRingEncrypted) -> Result<Vec<u8>, KeyRingError> {
+        let entry = match self.entries.get(&encrypted.key_id) {
+            Some(value) => value,
+            None => return Err(KeyRingError::UnknownKeyError)
+        };
+        if !entry.metadata.enabled {
+            return Err(KeyRingError::DisabledKeyError);
+        }
+        let decrypt = || decrypt_bytes(&entry.key, &encrypted.encrypted_and_iv);
+        match (&self.failure_policy, context_id) {
+            (Some(failure_policy), Some(context_id)) => failure_policy.guard(context_id, &entry.key, decrypt),
+            _ => decrypt()
+        }.map_err(KeyRingError::KeyRingDecryptionError)
+    }
+}
+
+/// Hands out enabled keys, expired ones included: providers are for decryption.
+impl KeyProvider for KeyRing {
+    fn key_for(&self, key_id: &str) -> Result<Key, ProviderError> {
+        match self.entries.get(key_id) {
+            Some(entry) if entry.metadata.enabled => Ok(Key { u8_array: entry.key.u8_array }),
+            Some(_) => Err(ProviderError::KeyUnavailableError(String::from(key_id))),
+            None => Err(ProviderError::UnknownKeyIdError(String::from(key_id)))
+        }
+    }
+}
+
+/// Tries the named key, then every other enabled key in id order. Not
+/// throttled, like `KeyRing::decrypt`: there is no context id to count
+/// failures under. An unknown or disabled named key is reported as such
+/// only if no other key is enabled.
+impl ReportingDecrypt for KeyRing {
+    type Input = KeyRingEncrypted;
+    type Error = KeyRingError;
+
+    fn decrypt_with_report(&self, input: &KeyRingEncrypted, aad: &[u8]) -> Result<(Vec<u8>, DecryptReport), KeyRingError> {
+        let mut candidates: Vec<(&str, &Key)> = Vec::new();
+        let named_error = match self.entries.get(&input.key_id) {
+            Some(entry) if entry.metadata.enabled => {
+                candidates.push((&input.key_id, &entry.key));
+                None
+            },
+            Some(_) => Some(KeyRingError::DisabledKeyError),
+            None => Some(KeyRingError::UnknownKeyError)
+        };
+        candidates.extend(self.entries.iter()
+            .filter(|(id, entry)| **id != input.key_id && entry.metadata.enabled)
+            .map(|(id, entry)| (&id[..], &entry.key)));
+        if let Some(e) = named_error {
+            if candidates.is_empty() {
+                return Err(e);
+            }
+        }
+
+        let mut last_error = DecryptionError::GenericDecryptionError;
+        for (id, key) in candidates.iter() {
+            match decrypt_bytes_with_aad(key, &input.encrypted_and_iv, aad) {
+                Ok(plaintext) => {
+                    let mut report = DecryptReport::new(key, &input.encrypted_and_iv, aad, &plaintext);
+                    report.key_id = Some(String::from(*id));
+                    if *id != input.key_id {
+                        report.fallback_from = Some(input.key_id.clone());
+                    }
+                    return Ok((plaintext, report));
+                },
+                Err(e) => last_error = e
+            }
+        }
+        Err(KeyRingError::KeyRingDecryptionError(last_error))
+    }
+}
+
+#[cfg(feature = "json")]
+mod json {
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{KeyEntry, KeyMetadata, KeyRing, KeyRingError, SystemClock};
+    use crate::Key;
+
+    #[derive(Serialize, Deserialize)]
+    struct JsonKey {
+        id: String,
+        label: String,
+        /// Unix seconds.
+        created_at: u64,
+        not_after: Option<u64>,
+        enabled: bool,
+        sensitive_key_base64: String
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct JsonKeyRing {
+        primary: String,
+        keys: Vec<JsonKey>
+    }
+
+    fn to_unix(time: SystemTime) -> u64 {
+        time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+    }
+
+    fn from_unix(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    impl KeyRing {
+        /// Serializes the whole ring, key bytes included.
+        pub fn to_json(&self) -> String {
+            let key_ring = JsonKeyRing {
+                primary: self.primary_id.clone(),
+                keys: self.entries.iter().map(|(id, entry)| JsonKey {
+                    id: id.clone(),
+                    label: entry.metadata.label.clone(),
+                    created_at: to_unix(entry.metadata.created_at),
+                    not_after: entry.metadata.not_after.map(to_unix),
+                    enabled: entry.metadata.enabled,
+                    sensitive_key_base64: base64::encode(&entry.key.u8_array)
+                }).collect()
+            };
+            serde_json::to_string_pretty(&key_ring).expect("serializing plain values can't fail")
+        }
+
+        pub fn from_json(json: &str) -> Result<KeyRing, KeyRingError> {
+            let key_ring: JsonKeyRing = serde_json::from_str(json).map_err(|_| KeyRingError::InvalidKeyRingJsonError)?;
+            let mut entries = BTreeMap::new();
+            for json_key in key_ring.keys {
+                let key = Key::try_from(&json_key.sensitive_key_base64[..]).map_err(|_| KeyRingError::InvalidKeyRingJsonError)?;
+                let metadata = KeyMetadata {
+                    label: json_key.label,
+                    created_at: from_unix(json_key.created_at),
+                    not_after: json_key.not_after.map(from_unix),
+                    enabled: json_key.enabled
+                };
+                entries.insert(json_key.id, KeyEntry { key, metadata });
+            }
+            if !entries.contains_key(&key_ring.primary) {
+                return Err(KeyRingError::UnknownKeyError);
+            }
+            Ok(KeyRing {
+                entries,
+                primary_id: key_ring.primary,
+                clock: Arc::new(SystemClock),
+                failure_policy: None
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    struct ManualClock(Mutex<SystemTime>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn ninety_day_ring(clock: Arc<ManualClock>) -> KeyRing {
+        let created_at = UNIX_EPOCH + 1000 * DAY;
+        let mut metadata = KeyMetadata::new("2024 primary", created_at);
+        metadata.not_after = Some(created_at + 90 * DAY);
+        KeyRing::new("k1", Key::generate(), metadata).with_clock(clock)
+    }
+
+    #[test]
+    fn expired_primary_still_decrypts_but_no_longer_encrypts() {
+        let clock = Arc::new(ManualClock(Mutex::new(UNIX_EPOCH + 1089 * DAY)));
+        let key_ring = ninety_day_ring(clock.clone());
+        let encrypted = key_ring.encrypt(b"This is a text.").unwrap();
+
+        *clock.0.lock().unwrap() = UNIX_EPOCH + 1091 * DAY;
+        match key_ring.encrypt(b"This is a text.") {
+            Ok(_) => assert!(false, "Should err PrimaryKeyExpired"),
+            Err(e) => match e {
+                KeyRingError::PrimaryKeyExpired => assert!(true),
+                _ => assert!(false, "Should err PrimaryKeyExpired")
+            }
+        }
+        assert_eq!(key_ring.decrypt(&encrypted).unwrap(), b"This is a text.");
+
+        // Without a context id, failures are neither refused nor counted.
+        let key_ring = KeyRing::new("k1", Key::generate(), KeyMetadata::new("k1", SystemTime::now()))
+            .with_failure_policy(Arc::new(FailurePolicy::new(1, Duration::from_secs(60))));
+        let mut tampered = key_ring.encrypt(b"This is a text.").unwrap();
+        tampered.encrypted_and_iv.encrypted.u8_vec[0] ^= 1;
+        assert!(key_ring.decrypt(&tampered).is_err());
+        assert!(key_ring.decrypt(&tampered).is_err());
+        assert!(key_ring.decrypt_for("", &key_ring.encrypt(b"x").unwrap()).is_ok())
+    }
+
+    #[test]
+    fn rotation_keeps_old_values_readable() {
+        let mut key_ring = KeyRing::new("k1", Key::generate(), KeyMetadata::new("old", SystemTime::now()));
+        let old = key_ring.encrypt(b"old value").unwrap();
+        key_ring.add("k2", Key::generate(), KeyMetadata::new("new", SystemTime::now()));
+        key_ring.set_primary("k2").unwrap();
+        let new = key_ring.encrypt(b"new value").unwrap();
+        assert_eq!(new.key_id, "k2");
+        assert_eq!(key_ring.decrypt(&old).unwrap(), b"old value");
+        assert_eq!(key_ring.decrypt(&new).unwrap(), b"new value")
+    }
+
+    #[test]
+    fn disabled_key_no_longer_decrypts() {
+        let mut key_ring = KeyRing::new("k1", Key::generate(), KeyMetadata::new("old", SystemTime::now()));
+        let encrypted = key_ring.encrypt(b"This is a text.").unwrap();
+        key_ring.metadata_mut("k1").unwrap().enabled = false;
+        match key_ring.decrypt(&encrypted) {
+            Ok(_) => assert!(false, "Should err DisabledKeyError"),
+            Err(e) => match e {
+                KeyRingError::DisabledKeyError => assert!(true),
+                _ => assert!(false, "Should err DisabledKeyError")
+            }
+        }
+    }
+
+    #[test]
+    fn failure_policy_counts_decryption_failures_only() {
+        let key_ring = KeyRing::new("k1", Key::generate(), KeyMetadata::new("k1", SystemTime::now()))
+            .with_failure_policy(Arc::new(FailurePolicy::new(1, Duration::from_secs(60))));
+        let encrypted = key_ring.encrypt(b"This is a text.").unwrap();
+        let unknown = KeyRingEncrypted { key_id: String::from("k9"), encrypted_and_iv: key_ring.encrypt(b"x").unwrap().encrypted_and_iv };
+        assert!(key_ring.decrypt_for("client", &unknown).is_err());
+        assert_eq!(key_ring.decrypt_for("client", &encrypted).unwrap(), b"This is a text.");
+
+        let mut tampered = key_ring.encrypt(b"This is a text.").unwrap();
+        tampered.encrypted_and_iv.encrypted.u8_vec[0] ^= 1;
+        assert!(key_ring.decrypt_for("client", &tampered).is_err());
+        match key_ring.decrypt_for("client", &encrypted) {
+            Ok(_) => assert!(false, "Should err RateLimited"),
+            Err(e) => match e {
+                KeyRingError::KeyRingDecryptionError(DecryptionError::RateLimited) => assert!(true),
+                _ => assert!(false, "Should err RateLimited")
+            }
+        }
+        assert_eq!(key_ring.decrypt(&encrypted).unwrap(), b"This is a text.");
+
+        // Without a context id, failures are neither refused nor counted.
+        let key_ring = KeyRing::new("k1", Key::generate(), KeyMetadata::new("k1", SystemTime::now()))
+            .with_failure_policy(Arc::new(FailurePolicy::new(1, Duration::from_secs(60))));
+        let mut tampered = key_ring.encrypt(b"This is a text.").unwrap();
+        tampered.encrypted_and_iv.encrypted.u8_vec[0] ^= 1;
+        assert!(key_ring.decrypt(&tampered).is_err());
+        assert!(key_ring.decrypt(&tampered).is_err());
+        assert!(key_ring.decrypt_for("", &key_ring.encrypt(b"x").unwrap()).is_ok())
+    }
+
+    #[test]
+    fn report_names_the_key_and_any_fallback() {
+        use crate::report::decrypt_with_report;
+
+        let (k1, k2) = (Key::generate(), Key::generate());
+        let (k1_fingerprint, k2_fingerprint) = (k1.fingerprint(), k2.fingerprint());
+        let mut key_ring = KeyRing::new("k1", k1, KeyMetadata::new("old", SystemTime::now()));
+        let old = key_ring.encrypt(b"old value").unwrap();
+        key_ring.add("k2", k2, KeyMetadata::new("new", SystemTime::now()));
+        key_ring.set_primary("k2").unwrap();
+        let new = key_ring.encrypt(b"new value").unwrap();
+
+        let (plaintext, report) = decrypt_with_report(&key_ring, &old).unwrap();
+        assert_eq!(plaintext, b"old value");
+        assert_eq!((report.key_id.as_deref(), report.fallback_from, report.key_fingerprint), (Some("k1"), None, k1_fingerprint));
+        assert_eq!((report.algorithm, report.format_version, report.envelope_key_id, report.kcv_present), ("AES-256-GCM", crate::FORMAT_VERSION, None, false));
+        assert_eq!((report.aad_len, report.aad_digest_present, report.ciphertext_len, report.plaintext_len), (0, false, 9 + 16, 9));
+
+        let mislabelled = KeyRingEncrypted { key_id: String::from("k1"), encrypted_and_iv: new.encrypted_and_iv };
+        let (plaintext, report) = decrypt_with_report(&key_ring, &mislabelled).unwrap();
+        assert_eq!(plaintext, b"new value");
+        assert_eq!((report.key_id.as_deref(), report.fallback_from.as_deref(), &report.key_fingerprint[..]), (Some("k2"), Some("k1"), &k2_fingerprint[..]));
+        match key_ring.decrypt(&mislabelled) {
+            Ok(_) => assert!(false, "Should err KeyRingDecryptionError"),
+            Err(e) => match e {
+                KeyRingError::KeyRingDecryptionError(DecryptionError::GenericDecryptionError) => assert!(true),
+                _ => assert!(false, "Should err KeyRingDecryptionError")
+            }
+        }
+
+        let unknown = KeyRingEncrypted { key_id: String::from("k9"), encrypted_and_iv: old.encrypted_and_iv };
+        let (plaintext, report) = decrypt_with_report(&key_ring, &unknown).unwrap();
+        assert_eq!(plaintext, b"old value");
+        assert_eq!((report.key_id.as_deref(), report.fallback_from.as_deref()), (Some("k1"), Some("k9")));
+        match key_ring.decrypt(&unknown) {
+            Ok(_) => assert!(false, "Should err UnknownKeyError"),
+            Err(e) => match e {
+                KeyRingError::UnknownKeyError => assert!(true),
+                _ => assert!(false, "Should err UnknownKeyError")
+            }
+        }
+    }
+
+    #[test]
+    fn report_fallback_skips_disabled_keys() {
+        let mut key_ring = KeyRing::new("k1", Key::generate(), KeyMetadata::new("old", SystemTime::now()));
+        let encrypted = key_ring.encrypt(b"This is a text.").unwrap();
+        key_ring.metadata_mut("k1").unwrap().enabled = false;
+        match crate::report::decrypt_with_report(&key_ring, &encrypted) {
+            Ok(_) => assert!(false, "Should err DisabledKeyError"),
+            Err(e) => match e {
+                KeyRingError::DisabledKeyError => assert!(true),
+                _ => assert!(false, "Should err DisabledKeyError")
+            }
+        }
+
+        key_ring.add("k2", Key::generate(), KeyMetadata::new("new", SystemTime::now()));
+        match crate::report::decrypt_with_report(&key_ring, &encrypted) {
+            Ok(_) => assert!(false, "Should err KeyRingDecryptionError"),
+            Err(e) => match e {
+                KeyRingError::KeyRingDecryptionError(DecryptionError::GenericDecryptionError) => assert!(true),
+                _ => assert!(false, "Should err KeyRingDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn json_round_trips_metadata_and_keys() {
+        let clock = Arc::new(ManualClock(Mutex::new(UNIX_EPOCH + 1000 * DAY)));
+        let key_ring = ninety_day_ring(clock);
+        let encrypted = key_ring.encrypt(b"This is a text.").unwrap();
+        let json = key_ring.to_json();
+        assert!(json.contains("sensitive_key_base64"));
+
+        let parsed = KeyRing::from_json(&json).unwrap();
+        assert_eq!(parsed.primary_id(), "k1");
+        assert_eq!(parsed.metadata("k1"), key_ring.metadata("k1"));
+        assert_eq!(parsed.decrypt(&encrypted).unwrap(), b"This is a text.")
+    }
+}
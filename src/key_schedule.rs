@@ -0,0 +1,358 @@
+//! Keys rotated on a calendar.
+//!
+//! A `KeySchedule` lists key ids with their activation times. At any time
+//! the key activated last encrypts, and it plus the `previous_keys` (2 by
+//! default) activated before it decrypt. Older keys are retired: what they
+//! encrypted fails with `KeyRetired`, even though the key may still exist.
+//!
+//! The schedule only holds ids; the keys come from a `KeyProvider`, such
+//! as a `KeyRing`, or from the pairs given to `from_keys`. With the `json`
+//! feature the schedule round-trips through JSON without any key bytes,
+//! and is read back against the provider holding the keys.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::{error, fmt};
+
+use crate::key_ring::{Clock, KeyRingEncrypted, SystemClock};
+use crate::provider::{KeyProvider, ProviderError};
+use crate::{decrypt_bytes, encrypt_bytes, DecryptionError, EncryptionError, Key};
+
+pub const DEFAULT_PREVIOUS_KEYS: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledKey {
+    pub key_id: String,
+    pub activation: SystemTime
+}
+
+#[derive(Debug, Clone)]
+pub enum KeyScheduleError {
+    /// No key is active yet.
+    NoActiveKeyError,
+    UnknownScheduledKeyError(String),
+    /// The key's activation time is still ahead, e.g. the encrypting host's
+    /// clock runs fast.
+    KeyNotYetActive(String),
+    /// The key is older than the keys the schedule decrypts with.
+    KeyRetired(String),
+    ScheduleProviderError(ProviderError),
+    ScheduleEncryptionError(EncryptionError),
+    ScheduleDecryptionError(DecryptionError),
+    #[cfg(feature = "json")]
+    InvalidScheduleJsonError
+}
+
+impl fmt::Display for KeyScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyScheduleError::NoActiveKeyError => write!(f, "No key of the schedule is active yet"),
+            KeyScheduleError::UnknownScheduledKeyError(key_id) => write!(f, "Key {} isn't in the schedule", key_id),
+            KeyScheduleError::KeyNotYetActive(key_id) => write!(f, "Key {} isn't active yet", key_id),
+            KeyScheduleError::KeyRetired(key_id) => write!(f, "Key {} has been retired", key_id),
+            KeyScheduleError::ScheduleProviderError(e) => write!(f, "{}", e),
+            KeyScheduleError::ScheduleEncryptionError(e) => write!(f, "{}", e),
+            KeyScheduleError::ScheduleDecryptionError(e) => write!(f, "{}", e),
+            #[cfg(feature = "json")]
+            KeyScheduleError::InvalidScheduleJsonError => write!(f, "Invalid key schedule JSON"),
+        }
+    }
+}
+
+impl error::Error for KeyScheduleError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            KeyScheduleError::ScheduleProviderError(e) => Some(e),
+            KeyScheduleError::ScheduleEncryptionError(e) => Some(e),
+            KeyScheduleError::ScheduleDecryptionError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// The keys given to `from_keys`.
+struct ScheduleKeys(BTreeMap<String, Key>);
+
+impl KeyProvider for ScheduleKeys {
+    fn key_for(&self, key_id: &str) -> Result<Key, ProviderError> {
+        match self.0.get(key_id) {
+            Some(key) => Ok(Key { u8_array: key.u8_array }),
+            None => Err(ProviderError::UnknownKeyIdError(String::from(key_id)))
+        }
+    }
+}
+
+pub struct KeySchedule {
+    keys: Arc<dyn KeyProvider + Send + Sync>,
+    /// Sorted by activation.
+    entries: Vec<ScheduledKey>,
+    previous_keys: usize,
+    clock: Arc<dyn Clock>
+}
+
+impl KeySchedule {
+    /// An empty schedule of keys held by `keys`.
+    pub fn new(keys: Arc<dyn KeyProvider + Send + Sync>) -> KeySchedule {
+        KeySchedule { keys, entries: Vec::new(), previous_keys: DEFAULT_PREVIOUS_KEYS, clock: Arc::new(SystemClock) }
+    }
+
+    /// A schedule holding its own keys, each with its id and activation time.
+    pub fn from_keys<I: IntoIterator<Item = (String, Key, SystemTime)>>(keys: I) -> KeySchedule {
+        let mut by_id = BTreeMap::new();
+        let mut activations = Vec::new();
+        for (key_id, key, activation) in keys {
+            activations.push((key_id.clone(), activation));
+            by_id.insert(key_id, key);
+        }
+        let mut key_schedule = KeySchedule::new(Arc::new(ScheduleKeys(by_id)));
+        for (key_id, activation) in activations {
+            key_schedule.add(&key_id, activation);
+        }
+        key_schedule
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> KeySchedule {
+        self.clock = clock;
+        self
+    }
+
+    /// How many keys activated before the current one still decrypt.
+    pub fn with_previous_keys(mut self, previous_keys: usize) -> KeySchedule {
+        self.previous_keys = previous_keys;
+        self
+    }
+
+    /// Schedules `key_id`, replacing its previous activation time.
+    pub fn add(&mut self, key_id: &str, activation: SystemTime) {
+        self.entries.retain(|entry| entry.key_id != key_id);
+        let index = self.entries.partition_point(|entry| entry.activation <= activation);
+        self.entries.insert(index, ScheduledKey { key_id: String::from(key_id), activation });
+    }
+
+    /// Sorted by activation.
+    pub fn entries(&self) -> &[ScheduledKey] {
+        &self.entries
+    }
+
+    fn active_index(&self, time: SystemTime) -> Option<usize> {
+        self.entries.partition_point(|entry| entry.activation <= time).checked_sub(1)
+    }
+
+    pub fn encrypting_key_at(&self, time: SystemTime) -> Option<&str> {
+        self.active_index(time).map(|index| &self.entries[index].key_id[..])
+    }
+
+    /// The encrypting key first, then the previous keys, newest first.
+    pub fn decrypting_keys_at(&self, time: SystemTime) -> Vec<&str> {
+        match self.active_index(time) {
+            Some(index) => self.entries[index.saturating_sub(self.previous_keys)..=index].iter().rev().map(|entry| &entry.key_id[..]).collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Encrypts with the key active now, stamping its id.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<KeyRingEncrypted, KeyScheduleError> {
+        let key_id = match self.encrypting_key_at(self.clock.now()) {
+            Some(key_id) => key_id,
+            None => return Err(KeyScheduleError::NoActiveKeyError)
+        };
+        let key = self.keys.key_for(key_id).map_err(KeyScheduleError::ScheduleProviderError)?;
+        match encrypt_bytes(&key, plaintext) {
+            Ok(encrypted_and_iv) => Ok(KeyRingEncrypted { key_id: String::from(key_id), encrypted_and_iv }),
+            Err(e) => Err(KeyScheduleError::ScheduleEncryptionError(e))
+        }
+    }
+
+    /// Decrypts with the key named by `encrypted.key_id`, provided the
+    /// schedule decrypts with it now.
+    pub fn decrypt(&self, encrypted: &KeyRingEncrypted) -> Result<Vec<u8>, KeyScheduleError> {
+        let key_id = &encrypted.key_id;
+        let position = match self.entries.iter().position(|entry| entry.key_id == *key_id) {
+            Some(position) => position,
+            None => return Err(KeyScheduleError::UnknownScheduledKeyError(key_id.clone()))
+        };
+        match self.active_index(self.clock.now()) {
+            Some(index) if position > index => return Err(KeyScheduleError::KeyNotYetActive(key_id.clone())),
+            Some(index) if index - position > self.previous_keys => return Err(KeyScheduleError::KeyRetired(key_id.clone())),
+            Some(_) => (),
+            None => return Err(KeyScheduleError::KeyNotYetActive(key_id.clone()))
+        }
+        let key = self.keys.key_for(key_id).map_err(KeyScheduleError::ScheduleProviderError)?;
+        decrypt_bytes(&key, &encrypted.encrypted_and_iv).map_err(KeyScheduleError::ScheduleDecryptionError)
+    }
+}
+
+#[cfg(feature = "json")]
+mod json {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{KeySchedule, KeyScheduleError};
+    use crate::provider::KeyProvider;
+
+    #[derive(Serialize, Deserialize)]
+    struct JsonScheduledKey {
+        key_id: String,
+        /// Unix seconds.
+        activation: u64
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct JsonKeySchedule {
+        previous_keys: usize,
+        keys: Vec<JsonScheduledKey>
+    }
+
+    fn to_unix(time: SystemTime) -> u64 {
+        time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+    }
+
+    impl KeySchedule {
+        /// The ids, activation times and `previous_keys`; no key bytes.
+        pub fn to_json(&self) -> String {
+            let key_schedule = JsonKeySchedule {
+                previous_keys: self.previous_keys,
+                keys: self.entries.iter().map(|entry| JsonScheduledKey { key_id: entry.key_id.clone(), activation: to_unix(entry.activation) }).collect()
+            };
+            serde_json::to_string_pretty(&key_schedule).expect("serializing plain values can't fail")
+        }
+
+        /// Reads `to_json`, taking the keys from `keys`, e.g. a `KeyRing`
+        /// with the same ids.
+        pub fn from_json(json: &str, keys: Arc<dyn KeyProvider + Send + Sync>) -> Result<KeySchedule, KeyScheduleError> {
+            let key_schedule: JsonKeySchedule = serde_json::from_str(json).map_err(|_| KeyScheduleError::InvalidScheduleJsonError)?;
+            let mut parsed = KeySchedule::new(keys).with_previous_keys(key_schedule.previous_keys);
+            for entry in key_schedule.keys {
+                parsed.add(&entry.key_id, UNIX_EPOCH + Duration::from_secs(entry.activation));
+            }
+            Ok(parsed)
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    struct ManualClock(Mutex<SystemTime>);
+
+    impl ManualClock {
+        fn set_day(&self, day: u32) {
+            *self.0.lock().unwrap() = UNIX_EPOCH + day * DAY;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    /// Keys activated on days 100, 130 and 160.
+    fn quarterly(clock: Arc<ManualClock>) -> KeySchedule {
+        KeySchedule::from_keys(vec![
+            (String::from("k2"), Key { u8_array: [2u8; 32] }, UNIX_EPOCH + 130 * DAY),
+            (String::from("k1"), Key { u8_array: [1u8; 32] }, UNIX_EPOCH + 100 * DAY),
+            (String::from("k3"), Key { u8_array: [3u8; 32] }, UNIX_EPOCH + 160 * DAY)
+        ]).with_clock(clock).with_previous_keys(1)
+    }
+
+    fn assert_retired(result: Result<Vec<u8>, KeyScheduleError>) {
+        match result {
+            Ok(_) => assert!(false, "Should err KeyRetired"),
+            Err(e) => match e {
+                KeyScheduleError::KeyRetired(_) => assert!(true),
+                _ => assert!(false, "Should err KeyRetired, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn acceptance_follows_the_clock_across_two_rotations() {
+        let clock = Arc::new(ManualClock(Mutex::new(UNIX_EPOCH)));
+        let key_schedule = quarterly(clock.clone());
+        match key_schedule.encrypt(b"too early") {
+            Err(KeyScheduleError::NoActiveKeyError) => assert!(true),
+            _ => assert!(false, "Should err NoActiveKeyError")
+        }
+
+        clock.set_day(110);
+        let first = key_schedule.encrypt(b"first").unwrap();
+        assert_eq!(first.key_id, "k1");
+        assert_eq!(key_schedule.decrypt(&first).unwrap(), b"first");
+
+        clock.set_day(140);
+        let second = key_schedule.encrypt(b"second").unwrap();
+        assert_eq!(second.key_id, "k2");
+        assert_eq!(key_schedule.decrypting_keys_at(clock.now()), vec!["k2", "k1"]);
+        assert_eq!(key_schedule.decrypt(&first).unwrap(), b"first");
+
+        clock.set_day(170);
+        assert_eq!(key_schedule.encrypt(b"third").unwrap().key_id, "k3");
+        assert_eq!(key_schedule.decrypt(&second).unwrap(), b"second");
+        assert_retired(key_schedule.decrypt(&first))
+    }
+
+    #[test]
+    fn default_window_is_current_plus_two() {
+        let clock = Arc::new(ManualClock(Mutex::new(UNIX_EPOCH)));
+        let mut key_schedule = quarterly(clock.clone()).with_previous_keys(DEFAULT_PREVIOUS_KEYS);
+        key_schedule.add("k4", UNIX_EPOCH + 190 * DAY);
+        assert_eq!(key_schedule.encrypting_key_at(UNIX_EPOCH + 99 * DAY), None);
+        assert_eq!(key_schedule.decrypting_keys_at(UNIX_EPOCH + 165 * DAY), vec!["k3", "k2", "k1"]);
+        assert_eq!(key_schedule.decrypting_keys_at(UNIX_EPOCH + 195 * DAY), vec!["k4", "k3", "k2"]);
+        assert_eq!(key_schedule.encrypting_key_at(UNIX_EPOCH + 190 * DAY), Some("k4"))
+    }
+
+    #[test]
+    fn unknown_and_future_keys_fail() {
+        let clock = Arc::new(ManualClock(Mutex::new(UNIX_EPOCH + 170 * DAY)));
+        let key_schedule = quarterly(clock.clone());
+        let mut encrypted = key_schedule.encrypt(b"This is a text.").unwrap();
+        clock.set_day(140);
+        match key_schedule.decrypt(&encrypted) {
+            Err(KeyScheduleError::KeyNotYetActive(key_id)) => assert_eq!(key_id, "k3"),
+            _ => assert!(false, "Should err KeyNotYetActive")
+        }
+        encrypted.key_id = String::from("k9");
+        match key_schedule.decrypt(&encrypted) {
+            Err(KeyScheduleError::UnknownScheduledKeyError(_)) => assert!(true),
+            _ => assert!(false, "Should err UnknownScheduledKeyError")
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn json_references_keys_of_a_ring_by_id() {
+        use crate::key_ring::{KeyMetadata, KeyRing};
+
+        let clock = Arc::new(ManualClock(Mutex::new(UNIX_EPOCH + 110 * DAY)));
+        let key_schedule = quarterly(clock.clone());
+        let old = key_schedule.encrypt(b"old").unwrap();
+        clock.set_day(140);
+        let encrypted = key_schedule.encrypt(b"This is a text.").unwrap();
+        let json = key_schedule.to_json();
+        assert!(!json.contains("u8_array") && !json.contains("sensitive"));
+
+        let mut ring = KeyRing::new("k1", Key { u8_array: [1u8; 32] }, KeyMetadata::new("k1", UNIX_EPOCH));
+        ring.add("k2", Key { u8_array: [2u8; 32] }, KeyMetadata::new("k2", UNIX_EPOCH));
+        let parsed = KeySchedule::from_json(&json, Arc::new(ring)).unwrap().with_clock(clock.clone());
+        assert_eq!(parsed.entries(), key_schedule.entries());
+        assert_eq!(parsed.decrypt(&encrypted).unwrap(), b"This is a text.");
+
+        clock.set_day(170);
+        assert_retired(parsed.decrypt(&old));
+        match parsed.encrypt(b"k3 isn't in the ring") {
+            Err(KeyScheduleError::ScheduleProviderError(ProviderError::UnknownKeyIdError(_))) => assert!(true),
+            _ => assert!(false, "Should err ScheduleProviderError")
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Lenient parsing of base64 pasted by operators, from terminals, chat
+//! messages or YAML.
+//!
+//! Opt-in through the `try_from_lenient` constructors; `TryFrom<&str>`
+//! stays strict. The lenient form trims and drops ASCII whitespace (line
+//! wraps included), strips one pair of matching surrounding quotes (`"`,
+//! `'` or a backtick), accepts the URL-safe alphabet and adds missing
+//! padding. A string that mixes both alphabets, or whose padding doesn't
+//! match its length, is still rejected: there is no single reading of it.
+
+use std::convert::TryInto;
+
+use base64::DecodeError;
+
+use crate::{Encrypted, InvalidEncryptedError, InvalidIvError, InvalidKeyError, Iv, Key};
+
+/// Decodes `text` with the leniencies listed in the module docs.
+pub fn decode_lenient_base64(text: &str) -> Result<Vec<u8>, DecodeError> {
+    let mut trimmed = text.trim_matches(|c: char| c.is_ascii_whitespace());
+    for quote in ['"', '\'', '`'].iter() {
+        if trimmed.len() >= 2 && trimmed.starts_with(*quote) && trimmed.ends_with(*quote) {
+            trimmed = trimmed[1..trimmed.len() - 1].trim_matches(|c: char| c.is_ascii_whitespace());
+            break;
+        }
+    }
+    let compact: Vec<u8> = trimmed.bytes().filter(|byte| !byte.is_ascii_whitespace()).collect();
+
+    let mut url_safe = None;
+    let mut standard = None;
+    for (offset, byte) in compact.iter().enumerate() {
+        match byte {
+            b'-' | b'_' => url_safe = url_safe.or(Some(offset)),
+            b'+' | b'/' => standard = standard.or(Some(offset)),
+            _ => {}
+        }
+    }
+    if let (Some(url_safe), Some(standard)) = (url_safe, standard) {
+        let offset = url_safe.max(standard);
+        return Err(DecodeError::InvalidByte(offset, compact[offset]));
+    }
+
+    let body_len = compact.iter().rposition(|byte| *byte != b'=').map_or(0, |last| last + 1);
+    let padding = compact.len() - body_len;
+    if padding > 2 || (padding > 0 && !compact.len().is_multiple_of(4)) {
+        return Err(DecodeError::InvalidByte(body_len, b'='));
+    }
+    let body: Vec<u8> = compact[..body_len].iter().map(|byte| match byte {
+        b'-' => b'+',
+        b'_' => b'/',
+        other => *other
+    }).collect();
+    base64::decode_config(&body, base64::STANDARD_NO_PAD)
+}
+
+impl Key {
+    /// Like `Key::try_from(&str)`, see the module docs for what is tolerated.
+    pub fn try_from_lenient(text: &str) -> Result<Key, InvalidKeyError> {
+        let bytes = decode_lenient_base64(text).map_err(InvalidKeyError::InvalidKeyBase64Error)?;
+        match bytes.as_slice().try_into() {
+            Ok(u8_array) => Ok(Key { u8_array }),
+            Err(_) => Err(InvalidKeyError::InvalidKeySizeError)
+        }
+    }
+}
+
+impl Iv {
+    pub fn try_from_lenient(text: &str) -> Result<Iv, InvalidIvError> {
+        let bytes = decode_lenient_base64(text).map_err(InvalidIvError::InvalidIvBase64Error)?;
+        match bytes.as_slice().try_into() {
+            Ok(u8_array) => Ok(Iv { u8_array }),
+            Err(_) => Err(InvalidIvError::InvalidIvSizeError)
+        }
+    }
+}
+
+impl Encrypted {
+    pub fn try_from_lenient(text: &str) -> Result<Encrypted, InvalidEncryptedError> {
+        match decode_lenient_base64(text) {
+            Ok(u8_vec) if u8_vec.is_empty() => Err(InvalidEncryptedError::EmptyEncryptedError),
+            Ok(u8_vec) => Ok(Encrypted { u8_vec }),
+            Err(e) => Err(InvalidEncryptedError::InvalidEncryptedBase64Error(e))
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    const KEY_BASE64: &str = "MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=";
+    /// Bytes whose base64 has both `+` and `/`.
+    const BYTES: [u8; 7] = [0xfb, 0xff, 0xbf, 0x00, 0x01, 0x02, 0x03];
+
+    #[test]
+    fn each_mangling_is_tolerated() {
+        for text in [
+            "MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=\n",
+            "  MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=\r\n",
+            "\"MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=\"",
+            "'MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE='",
+            "`MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=`",
+            "MDEyMzQ1Njc4OTAxMjM0NTY3\nODkwMTIzNDU2Nzg5MDE="
+        ].iter() {
+            assert_eq!(Key::try_from_lenient(text).unwrap().u8_array, *b"01234567890123456789012345678901", "for {:?}", text);
+            assert!(Key::try_from(*text).is_err(), "strict parsing should reject {:?}", text)
+        }
+        assert_eq!(Key::try_from_lenient("MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE").unwrap().u8_array, *b"01234567890123456789012345678901");
+        assert_eq!(Encrypted::try_from_lenient("-_-_AAECAw").unwrap().u8_vec, BYTES);
+        assert_eq!(base64::encode(&BYTES), "+/+/AAECAw==")
+    }
+
+    #[test]
+    fn combined_manglings_are_tolerated() {
+        assert_eq!(Encrypted::try_from_lenient(" \"-_-_\n AAEC\r\nAw\" \n").unwrap().u8_vec, BYTES);
+        assert_eq!(Key::try_from_lenient("'MDEyMzQ1Njc4OTAx\nMjM0NTY3ODkwMTIz\nNDU2Nzg5MDE'\n").unwrap().u8_array, *b"01234567890123456789012345678901");
+        assert_eq!(Iv::try_from_lenient("\"MDEyMzQ1Njc4OTAx\"\n").unwrap().u8_array, *b"012345678901");
+        assert_eq!(Key::try_from_lenient(KEY_BASE64).unwrap().u8_array, *b"01234567890123456789012345678901")
+    }
+
+    #[test]
+    fn ambiguous_or_corrupt_input_still_fails() {
+        for text in [
+            "+_//AAECAw==",
+            "+///AAECAw=",
+            "+///AAECAw===",
+            "+///AA=ECAw==",
+            "\"+///AAECAw==",
+            "+///AAE*CAw==",
+            "+///AAECA"
+        ].iter() {
+            match Encrypted::try_from_lenient(text) {
+                Ok(_) => assert!(false, "Should err InvalidEncryptedBase64Error for {:?}", text),
+                Err(e) => match e {
+                    InvalidEncryptedError::InvalidEncryptedBase64Error(_) => assert!(true),
+                    _ => assert!(false, "Should err InvalidEncryptedBase64Error for {:?}", text)
+                }
+            }
+        }
+        for text in ["", " \n", "\"\""].iter() {
+            match Encrypted::try_from_lenient(text) {
+                Ok(_) => assert!(false, "Should err EmptyEncryptedError for {:?}", text),
+                Err(e) => match e {
+                    InvalidEncryptedError::EmptyEncryptedError => assert!(true),
+                    _ => assert!(false, "Should err EmptyEncryptedError for {:?}, got {}", text, e)
+                }
+            }
+        }
+        match Key::try_from_lenient("\"MDEyMzQ1Njc4OTAx\"") {
+            Ok(_) => assert!(false, "Should err InvalidKeySizeError"),
+            Err(e) => match e {
+                InvalidKeyError::InvalidKeySizeError => assert!(true),
+                _ => assert!(false, "Should err InvalidKeySizeError")
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! HMAC-SHA256 tags, for values that need tamper evidence but not secrecy.
+//!
+//! The MAC key is HMAC-SHA256(key, "simple-aes256-gcm mac key"), not the
+//! `Key` bytes themselves, so one key can serve both AES-GCM and `sign`.
+//! `verify` compares in constant time.
+
+use std::convert::{TryFrom, TryInto};
+use std::{error, fmt};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+#[cfg(feature = "base64")]
+use crate::encoding::{Encoding, InvalidEncodingError};
+use crate::Key;
+
+pub const MAC_TAG_LEN: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct Tag {
+    pub u8_array: [u8; MAC_TAG_LEN]
+}
+
+#[derive(Debug, Clone)]
+pub enum MacError {
+    /// The tag doesn't match the data under this key.
+    InvalidMacError
+}
+
+impl fmt::Display for MacError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MacError::InvalidMacError => write!(f, "Invalid MAC"),
+        }
+    }
+}
+
+impl error::Error for MacError {}
+
+#[derive(Debug, Clone)]
+pub enum InvalidTagError {
+    InvalidTagSizeError,
+    #[cfg(feature = "base64")]
+    InvalidTagBase64Error(base64::DecodeError),
+    #[cfg(feature = "base64")]
+    InvalidTagEncodingError(InvalidEncodingError)
+}
+
+impl fmt::Display for InvalidTagError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidTagError::InvalidTagSizeError => write!(f, "Please provide a 32-byte tag"),
+            #[cfg(feature = "base64")]
+            InvalidTagError::InvalidTagBase64Error(_) => write!(f, "Please provide a valid base64"),
+            #[cfg(feature = "base64")]
+            InvalidTagError::InvalidTagEncodingError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for InvalidTagError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            InvalidTagError::InvalidTagSizeError => None,
+            #[cfg(feature = "base64")]
+            InvalidTagError::InvalidTagBase64Error(e) => Some(e),
+            #[cfg(feature = "base64")]
+            InvalidTagError::InvalidTagEncodingError(e) => Some(e),
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for Tag {
+    type Error = InvalidTagError;
+    fn try_from(bytes: &[u8]) -> Result<Tag, InvalidTagError> {
+        match bytes.try_into() {
+            Ok(u8_array) => Ok(Tag { u8_array }),
+            Err(_) => Err(InvalidTagError::InvalidTagSizeError)
+        }
+    }
+}
+
+#[cfg(feature = "base64")]
+impl TryFrom<&str> for Tag {
+    type Error = InvalidTagError;
+    fn try_from(base64_tag: &str) -> Result<Tag, InvalidTagError> {
+        let bytes = base64::decode(base64_tag).map_err(InvalidTagError::InvalidTagBase64Error)?;
+        Tag::try_from(bytes.as_slice())
+    }
+}
+
+#[cfg(feature = "base64")]
+impl From<&Tag> for String {
+    fn from(tag: &Tag) -> String {
+        base64::encode(&tag.u8_array)
+    }
+}
+
+#[cfg(feature = "base64")]
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from(self))
+    }
+}
+
+#[cfg(feature = "base64")]
+impl Tag {
+    pub fn to_hex(&self) -> String {
+        Encoding::Hex.encode(&self.u8_array)
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Tag, InvalidTagError> {
+        let bytes = Encoding::Hex.decode(hex).map_err(InvalidTagError::InvalidTagEncodingError)?;
+        Tag::try_from(bytes.as_slice())
+    }
+}
+
+fn mac_key(key: &Key) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key.u8_array).expect("HMAC accepts any key length");
+    mac.update(b"simple-aes256-gcm mac key");
+    mac.finalize().into_bytes().into()
+}
+
+fn keyed(key: &Key, data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&mac_key(key)).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac
+}
+
+pub fn sign(key: &Key, data: &[u8]) -> Tag {
+    Tag { u8_array: keyed(key, data).finalize().into_bytes().into() }
+}
+
+pub fn verify(key: &Key, data: &[u8], tag: &Tag) -> Result<(), MacError> {
+    keyed(key, data).verify_slice(&tag.u8_array).map_err(|_| MacError::InvalidMacError)
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+    const TAG_HEX: &str = "dc77b9e46ca0532654e901efc1a41525642c6911d2f10b178ae947bef6697fd1";
+
+    #[test]
+    fn matches_the_known_answer() {
+        let tag = sign(&Key { u8_array: KEY }, b"This is a text.");
+        assert_eq!(tag.u8_array.iter().map(|byte| format!("{:02x}", byte)).collect::<String>(), TAG_HEX);
+        assert_eq!(
+            mac_key(&Key { u8_array: KEY }).iter().map(|byte| format!("{:02x}", byte)).collect::<String>(),
+            "3654d904d521ed06e9e9113ada2cff20e3f362f12d79145d9656b0fe6bc3fbc8"
+        )
+    }
+
+    #[test]
+    #[cfg(feature = "base64")]
+    fn string_forms_round_trip() {
+        let tag = sign(&Key { u8_array: KEY }, b"This is a text.");
+        assert_eq!(tag.to_string(), "3He55GygUyZU6QHvwaQVJWQsaRHS8QsXiulHvvZpf9E=");
+        assert_eq!(tag.to_hex(), TAG_HEX);
+        assert_eq!(Tag::try_from("3He55GygUyZU6QHvwaQVJWQsaRHS8QsXiulHvvZpf9E=").unwrap().u8_array, tag.u8_array);
+        assert_eq!(Tag::from_hex(&TAG_HEX.to_uppercase()).unwrap().u8_array, tag.u8_array);
+        match Tag::from_hex(&TAG_HEX[2..]) {
+            Err(InvalidTagError::InvalidTagSizeError) => assert!(true),
+            _ => assert!(false, "Should err InvalidTagSizeError")
+        }
+    }
+
+    #[test]
+    fn verify_accepts_only_the_signed_data_and_key() {
+        let tag = sign(&Key { u8_array: KEY }, b"This is a text.");
+        assert!(verify(&Key { u8_array: KEY }, b"This is a text.", &tag).is_ok());
+        let mut tampered = tag.clone();
+        tampered.u8_array[31] ^= 1;
+        for (key, data, tag) in [(KEY, &b"This is a text!"[..], &tag), ([1u8; 32], &b"This is a text."[..], &tag), (KEY, &b"This is a text."[..], &tampered)].iter() {
+            match verify(&Key { u8_array: *key }, data, tag) {
+                Ok(_) => assert!(false, "Should err InvalidMacError"),
+                Err(e) => match e {
+                    MacError::InvalidMacError => assert!(true)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mac_key_is_not_the_aes_key() {
+        assert_ne!(mac_key(&Key { u8_array: KEY }), KEY);
+        let raw = <Hmac<Sha256> as Mac>::new_from_slice(&KEY).unwrap().chain_update(b"This is a text.").finalize().into_bytes();
+        assert_ne!(sign(&Key { u8_array: KEY }, b"This is a text.").u8_array, <[u8; 32]>::from(raw))
+    }
+}
@@ -0,0 +1,107 @@
+//! BIP39 mnemonic backups of keys, enabled with the `mnemonic` feature.
+//!
+//! A 32-byte key maps to 24 words from the English BIP39 wordlist, the last
+//! word carrying an 8-bit checksum. The wordlist is compiled in.
+
+use std::{error, fmt};
+
+use bip39::{Language, Mnemonic};
+
+use crate::Key;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidMnemonicError {
+    /// The word at this zero-based index isn't in the wordlist.
+    InvalidMnemonicWordError(usize),
+    /// A key mnemonic has exactly 24 words.
+    InvalidMnemonicWordCountError(usize),
+    /// Every word is valid but the checksum doesn't match, e.g. two words swapped.
+    InvalidMnemonicChecksumError
+}
+
+impl fmt::Display for InvalidMnemonicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidMnemonicError::InvalidMnemonicWordError(index) => write!(f, "Unknown word #{} in the mnemonic", index + 1),
+            InvalidMnemonicError::InvalidMnemonicWordCountError(count) => write!(f, "Please provide 24 words, got {}", count),
+            InvalidMnemonicError::InvalidMnemonicChecksumError => write!(f, "Invalid mnemonic checksum"),
+        }
+    }
+}
+
+impl error::Error for InvalidMnemonicError {}
+
+impl Key {
+    /// The 24-word English BIP39 phrase encoding this key.
+    pub fn to_mnemonic(&self) -> String {
+        Mnemonic::from_entropy_in(Language::English, &self.u8_array)
+            .expect("32 bytes is a valid BIP39 entropy length")
+            .to_string()
+    }
+
+    /// Parses a phrase from `to_mnemonic`, ignoring case and extra whitespace.
+    pub fn from_mnemonic(phrase: &str) -> Result<Key, InvalidMnemonicError> {
+        let words: Vec<String> = phrase.split_whitespace().map(|word| word.to_lowercase()).collect();
+        if words.len() != 24 {
+            return Err(InvalidMnemonicError::InvalidMnemonicWordCountError(words.len()));
+        }
+        let mnemonic = match Mnemonic::parse_in_normalized(Language::English, &words.join(" ")) {
+            Ok(value) => value,
+            Err(bip39::Error::UnknownWord(index)) => return Err(InvalidMnemonicError::InvalidMnemonicWordError(index)),
+            Err(bip39::Error::BadWordCount(count)) => return Err(InvalidMnemonicError::InvalidMnemonicWordCountError(count)),
+            Err(_) => return Err(InvalidMnemonicError::InvalidMnemonicChecksumError)
+        };
+        let mut u8_array = [0u8; 32];
+        u8_array.copy_from_slice(&mnemonic.to_entropy());
+        Ok(Key { u8_array })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    // BIP39 reference vector for 32 zero bytes.
+    const ZERO_KEY_MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon art";
+
+    #[test]
+    fn to_mnemonic_matches_reference_vector() {
+        assert_eq!(Key { u8_array: [0u8; 32] }.to_mnemonic(), ZERO_KEY_MNEMONIC)
+    }
+
+    #[test]
+    fn mnemonic_round_trips() {
+        let key = Key { u8_array: *b"01234567890123456789012345678901" };
+        let phrase = key.to_mnemonic();
+        assert_eq!(phrase.split(' ').count(), 24);
+        assert_eq!(Key::from_mnemonic(&phrase).unwrap().u8_array, key.u8_array)
+    }
+
+    #[test]
+    fn from_mnemonic_tolerates_case_and_whitespace() {
+        let key = Key { u8_array: *b"01234567890123456789012345678901" };
+        let phrase = key.to_mnemonic().split(' ').map(|word| word.to_uppercase()).collect::<Vec<_>>().join(" \n\t ");
+        assert_eq!(Key::from_mnemonic(&format!("  {}  ", phrase)).unwrap().u8_array, key.u8_array)
+    }
+
+    #[test]
+    fn swapped_words_fail_the_checksum() {
+        let phrase = Key { u8_array: *b"01234567890123456789012345678901" }.to_mnemonic();
+        let mut words: Vec<&str> = phrase.split(' ').collect();
+        words.swap(0, 1);
+        match Key::from_mnemonic(&words.join(" ")) {
+            Ok(_) => assert!(false, "Should err InvalidMnemonicChecksumError"),
+            Err(e) => match e {
+                InvalidMnemonicError::InvalidMnemonicChecksumError => assert!(true),
+                _ => assert!(false, "Should err InvalidMnemonicChecksumError")
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_word_reports_its_index() {
+        let phrase = ZERO_KEY_MNEMONIC.replacen("abandon abandon abandon", "abandon abandon abandonn", 1);
+        assert_eq!(Key::from_mnemonic(&phrase).err(), Some(InvalidMnemonicError::InvalidMnemonicWordError(2)))
+    }
+}
@@ -0,0 +1,350 @@
+//! Encrypt once, decrypt with any of several named keys.
+//!
+//! `seal_for_many` encrypts the payload under a random data key (DEK) and
+//! wraps that DEK separately under each recipient's key. Every slot is
+//! independent: dropping one with `remove_recipient` leaves the others and
+//! the payload untouched.
+//!
+//! Binary form, all integers big-endian:
+//!
+//! ```text
+//! version (1) | slot count (2) | slots | payload iv (12) | payload ciphertext and tag
+//! slot = name length (2) | UTF-8 name | iv (12) | wrapped DEK and tag (48)
+//! ```
+//!
+//! With the `json` feature the same fields are available as JSON, byte
+//! strings being base64-encoded.
+
+use std::convert::{TryFrom, TryInto};
+use std::{error, fmt};
+
+use crate::{decrypt_bytes, encrypt_bytes, DecryptionError, Encrypted, EncryptedAndIv, EncryptionError, Iv, Key, IV_LEN, TAG_LEN};
+
+const VERSION: u8 = 1;
+const WRAPPED_KEY_LEN: usize = 32 + TAG_LEN;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipientSlot {
+    pub name: String,
+    pub iv: [u8; IV_LEN],
+    pub wrapped_key: Vec<u8>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiRecipientEnvelope {
+    pub recipients: Vec<RecipientSlot>,
+    pub iv: [u8; IV_LEN],
+    pub ciphertext: Vec<u8>
+}
+
+#[derive(Debug, Clone)]
+pub enum MultiRecipientError {
+    /// No slot carries this recipient name.
+    UnknownRecipientError,
+    /// The key doesn't unwrap the recipient's slot.
+    WrongRecipientKeyError,
+    /// The payload failed authentication after the DEK was unwrapped.
+    PayloadDecryptionError(DecryptionError),
+    /// Malformed binary or JSON envelope.
+    InvalidEnvelopeError,
+    /// More recipients than the binary form's 16-bit count holds.
+    TooManyRecipientsError(usize),
+    /// A recipient name longer than the binary form's 16-bit length holds, in bytes.
+    RecipientNameTooLongError(usize)
+}
+
+impl fmt::Display for MultiRecipientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MultiRecipientError::UnknownRecipientError => write!(f, "No slot for this recipient"),
+            MultiRecipientError::WrongRecipientKeyError => write!(f, "The key doesn't match the recipient's slot"),
+            MultiRecipientError::PayloadDecryptionError(e) => write!(f, "{}", e),
+            MultiRecipientError::InvalidEnvelopeError => write!(f, "Invalid multi-recipient envelope"),
+            MultiRecipientError::TooManyRecipientsError(count) => write!(f, "{} recipients, at most {} fit in an envelope", count, u16::MAX),
+            MultiRecipientError::RecipientNameTooLongError(len) => write!(f, "Recipient name of {} bytes, at most {} fit in an envelope", len, u16::MAX),
+        }
+    }
+}
+
+impl error::Error for MultiRecipientError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            MultiRecipientError::PayloadDecryptionError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// Encrypts `plaintext` once and wraps its data key for every `(name, key)` recipient.
+pub fn seal_for_many(recipient_keys: &[(&str, &Key)], plaintext: &[u8]) -> Result<MultiRecipientEnvelope, EncryptionError> {
+    let dek = Key::generate();
+    let mut recipients = Vec::with_capacity(recipient_keys.len());
+    for (name, key) in recipient_keys {
+        let wrapped = encrypt_bytes(key, &dek.u8_array)?;
+        recipients.push(RecipientSlot {
+            name: String::from(*name),
+            iv: wrapped.iv.u8_array,
+            wrapped_key: wrapped.encrypted.u8_vec
+        });
+    }
+    let payload = encrypt_bytes(&dek, plaintext)?;
+    Ok(MultiRecipientEnvelope {
+        recipients,
+        iv: payload.iv.u8_array,
+        ciphertext: payload.encrypted.u8_vec
+    })
+}
+
+/// Unwraps `recipient_name`'s slot with `key` and decrypts the payload.
+pub fn open(recipient_name: &str, key: &Key, envelope: &MultiRecipientEnvelope) -> Result<Vec<u8>, MultiRecipientError> {
+    let slot = match envelope.recipients.iter().find(|slot| slot.name == recipient_name) {
+        Some(value) => value,
+        None => return Err(MultiRecipientError::UnknownRecipientError)
+    };
+    let wrapped = EncryptedAndIv::from_parts(Iv { u8_array: slot.iv }, Encrypted { u8_vec: slot.wrapped_key.clone() });
+    let dek = match decrypt_bytes(key, &wrapped).ok().and_then(|bytes| bytes.as_slice().try_into().ok()) {
+        Some(u8_array) => Key { u8_array },
+        None => return Err(MultiRecipientError::WrongRecipientKeyError)
+    };
+    let payload = EncryptedAndIv::from_parts(Iv { u8_array: envelope.iv }, Encrypted { u8_vec: envelope.ciphertext.clone() });
+    decrypt_bytes(&dek, &payload).map_err(MultiRecipientError::PayloadDecryptionError)
+}
+
+impl MultiRecipientEnvelope {
+    /// Drops `name`'s slot, returning whether there was one.
+    pub fn remove_recipient(&mut self, name: &str) -> bool {
+        let before = self.recipients.len();
+        self.recipients.retain(|slot| slot.name != name);
+        self.recipients.len() != before
+    }
+
+    /// Fails if there are more than `u16::MAX` recipients or a name is
+    /// longer than `u16::MAX` bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MultiRecipientError> {
+        let count = u16::try_from(self.recipients.len()).map_err(|_| MultiRecipientError::TooManyRecipientsError(self.recipients.len()))?;
+        let mut bytes = vec![VERSION];
+        bytes.extend_from_slice(&count.to_be_bytes());
+        for slot in &self.recipients {
+            let name_len = u16::try_from(slot.name.len()).map_err(|_| MultiRecipientError::RecipientNameTooLongError(slot.name.len()))?;
+            bytes.extend_from_slice(&name_len.to_be_bytes());
+            bytes.extend_from_slice(slot.name.as_bytes());
+            bytes.extend_from_slice(&slot.iv);
+            bytes.extend_from_slice(&slot.wrapped_key);
+        }
+        bytes.extend_from_slice(&self.iv);
+        bytes.extend_from_slice(&self.ciphertext);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<MultiRecipientEnvelope, MultiRecipientError> {
+        let mut reader = Reader { bytes };
+        if reader.take(1)? != [VERSION] {
+            return Err(MultiRecipientError::InvalidEnvelopeError);
+        }
+        let count = reader.take_u16()?;
+        let mut recipients = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let name_len = reader.take_u16()? as usize;
+            let name = match std::str::from_utf8(reader.take(name_len)?) {
+                Ok(value) => String::from(value),
+                Err(_) => return Err(MultiRecipientError::InvalidEnvelopeError)
+            };
+            let iv = reader.take_iv()?;
+            recipients.push(RecipientSlot { name, iv, wrapped_key: reader.take(WRAPPED_KEY_LEN)?.to_vec() });
+        }
+        let iv = reader.take_iv()?;
+        Ok(MultiRecipientEnvelope { recipients, iv, ciphertext: reader.bytes.to_vec() })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8]
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MultiRecipientError> {
+        if self.bytes.len() < len {
+            return Err(MultiRecipientError::InvalidEnvelopeError);
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn take_u16(&mut self) -> Result<u16, MultiRecipientError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn take_iv(&mut self) -> Result<[u8; IV_LEN], MultiRecipientError> {
+        Ok(self.take(IV_LEN)?.try_into().unwrap())
+    }
+}
+
+#[cfg(feature = "json")]
+mod json {
+    use std::convert::TryInto;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{MultiRecipientEnvelope, MultiRecipientError, RecipientSlot, VERSION};
+    use crate::IV_LEN;
+
+    #[derive(Serialize, Deserialize)]
+    struct JsonSlot {
+        name: String,
+        iv: String,
+        wrapped_key: String
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct JsonEnvelope {
+        version: u8,
+        recipients: Vec<JsonSlot>,
+        iv: String,
+        ciphertext: String
+    }
+
+    fn decode_iv(value: &str) -> Result<[u8; IV_LEN], MultiRecipientError> {
+        match base64::decode(value).ok().and_then(|bytes| bytes.as_slice().try_into().ok()) {
+            Some(iv) => Ok(iv),
+            None => Err(MultiRecipientError::InvalidEnvelopeError)
+        }
+    }
+
+    fn decode(value: &str) -> Result<Vec<u8>, MultiRecipientError> {
+        base64::decode(value).map_err(|_| MultiRecipientError::InvalidEnvelopeError)
+    }
+
+    impl MultiRecipientEnvelope {
+        pub fn to_json(&self) -> String {
+            let envelope = JsonEnvelope {
+                version: VERSION,
+                recipients: self.recipients.iter().map(|slot| JsonSlot {
+                    name: slot.name.clone(),
+                    iv: base64::encode(&slot.iv),
+                    wrapped_key: base64::encode(&slot.wrapped_key)
+                }).collect(),
+                iv: base64::encode(&self.iv),
+                ciphertext: base64::encode(&self.ciphertext)
+            };
+            serde_json::to_string(&envelope).expect("serializing plain strings can't fail")
+        }
+
+        pub fn from_json(json: &str) -> Result<MultiRecipientEnvelope, MultiRecipientError> {
+            let envelope: JsonEnvelope = serde_json::from_str(json).map_err(|_| MultiRecipientError::InvalidEnvelopeError)?;
+            if envelope.version != VERSION {
+                return Err(MultiRecipientError::InvalidEnvelopeError);
+            }
+            let mut recipients = Vec::with_capacity(envelope.recipients.len());
+            for slot in envelope.recipients {
+                recipients.push(RecipientSlot { iv: decode_iv(&slot.iv)?, wrapped_key: decode(&slot.wrapped_key)?, name: slot.name });
+            }
+            Ok(MultiRecipientEnvelope { recipients, iv: decode_iv(&envelope.iv)?, ciphertext: decode(&envelope.ciphertext)? })
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    fn three_recipients() -> (Key, Key, Key, MultiRecipientEnvelope) {
+        let (billing, search, archive) = (Key::generate(), Key::generate(), Key::generate());
+        let envelope = seal_for_many(&[("billing", &billing), ("search", &search), ("archive", &archive)], b"This is a text.").unwrap();
+        (billing, search, archive, envelope)
+    }
+
+    #[test]
+    fn every_recipient_can_open() {
+        let (billing, search, archive, envelope) = three_recipients();
+        assert_eq!(open("billing", &billing, &envelope).unwrap(), b"This is a text.");
+        assert_eq!(open("search", &search, &envelope).unwrap(), b"This is a text.");
+        assert_eq!(open("archive", &archive, &envelope).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn revoked_recipient_can_no_longer_open() {
+        let (billing, search, archive, mut envelope) = three_recipients();
+        assert!(envelope.remove_recipient("search"));
+        match open("search", &search, &envelope) {
+            Ok(_) => assert!(false, "Should err UnknownRecipientError"),
+            Err(e) => match e {
+                MultiRecipientError::UnknownRecipientError => assert!(true),
+                _ => assert!(false, "Should err UnknownRecipientError")
+            }
+        }
+        assert_eq!(open("billing", &billing, &envelope).unwrap(), b"This is a text.");
+        assert_eq!(open("archive", &archive, &envelope).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        let (_, _, _, envelope) = three_recipients();
+        match open("billing", &Key::generate(), &envelope) {
+            Ok(_) => assert!(false, "Should err WrongRecipientKeyError"),
+            Err(e) => match e {
+                MultiRecipientError::WrongRecipientKeyError => assert!(true),
+                _ => assert!(false, "Should err WrongRecipientKeyError")
+            }
+        }
+    }
+
+    #[test]
+    fn binary_form_round_trips() {
+        let (billing, _, _, envelope) = three_recipients();
+        let parsed = MultiRecipientEnvelope::from_bytes(&envelope.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed, envelope);
+        assert_eq!(open("billing", &billing, &parsed).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn truncated_binary_form_is_rejected() {
+        let (_, _, _, envelope) = three_recipients();
+        let bytes = envelope.to_bytes().unwrap();
+        match MultiRecipientEnvelope::from_bytes(&bytes[..40]) {
+            Ok(_) => assert!(false, "Should err InvalidEnvelopeError"),
+            Err(e) => match e {
+                MultiRecipientError::InvalidEnvelopeError => assert!(true),
+                _ => assert!(false, "Should err InvalidEnvelopeError")
+            }
+        }
+    }
+
+    #[test]
+    fn binary_form_limits_are_enforced() {
+        let (billing, _, _, mut envelope) = three_recipients();
+        let slot = envelope.recipients[0].clone();
+        envelope.recipients = vec![slot.clone(); u16::MAX as usize];
+        let parsed = MultiRecipientEnvelope::from_bytes(&envelope.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.recipients.len(), u16::MAX as usize);
+        assert_eq!(open("billing", &billing, &parsed).unwrap(), b"This is a text.");
+        envelope.recipients.push(slot.clone());
+        match envelope.to_bytes() {
+            Ok(_) => assert!(false, "Should err TooManyRecipientsError"),
+            Err(e) => match e {
+                MultiRecipientError::TooManyRecipientsError(65_536) => assert!(true),
+                _ => assert!(false, "Should err TooManyRecipientsError, got {}", e)
+            }
+        }
+        envelope.recipients = vec![RecipientSlot { name: "n".repeat(u16::MAX as usize), ..slot.clone() }];
+        assert_eq!(MultiRecipientEnvelope::from_bytes(&envelope.to_bytes().unwrap()).unwrap(), envelope);
+        envelope.recipients = vec![RecipientSlot { name: "n".repeat(u16::MAX as usize + 1), ..slot }];
+        match envelope.to_bytes() {
+            Ok(_) => assert!(false, "Should err RecipientNameTooLongError"),
+            Err(e) => match e {
+                MultiRecipientError::RecipientNameTooLongError(65_536) => assert!(true),
+                _ => assert!(false, "Should err RecipientNameTooLongError, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn json_form_round_trips() {
+        let (_, _, archive, envelope) = three_recipients();
+        let parsed = MultiRecipientEnvelope::from_json(&envelope.to_json()).unwrap();
+        assert_eq!(parsed, envelope);
+        assert_eq!(open("archive", &archive, &parsed).unwrap(), b"This is a text.")
+    }
+}
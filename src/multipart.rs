@@ -0,0 +1,262 @@
+//! Splitting plaintexts too large for one message into encrypted parts.
+//!
+//! Every part is an envelope of its own, with a fresh iv, whose AAD binds
+//! the message id, the part index and the part count. A part can't be
+//! moved to another position or message, and a message can't be cut short
+//! by dropping parts or changing the count: the remaining parts no longer
+//! authenticate against what `decrypt_multipart` expects.
+//!
+//! `EncryptedPart::to_bytes` is `message id (16) || index (4) || total (4)
+//! || iv (12) || ciphertext and tag`, integers big-endian, so parts can
+//! travel separately and be put back together with `MultipartEncrypted::from_parts`.
+
+use std::convert::TryInto;
+use std::{error, fmt};
+
+use crate::{decrypt_bytes_with_aad, DecryptionError, Encrypted, EncryptedAndIv, EncryptionError, Iv, Key, IV_LEN, TAG_LEN};
+#[cfg(feature = "rng")]
+use crate::encrypt_bytes_with_aad;
+
+pub const MESSAGE_ID_LEN: usize = 16;
+
+/// Bytes of `EncryptedPart::to_bytes` before the ciphertext.
+pub const PART_HEADER_LEN: usize = MESSAGE_ID_LEN + 4 + 4 + IV_LEN;
+
+const AAD_PREFIX: &[u8] = b"simple-aes256-gcm multipart:";
+
+#[derive(Debug, Clone)]
+pub enum MultipartError {
+    /// The part size leaves no room for plaintext, or the message needs more than `u32::MAX` parts.
+    InvalidPartSizeError,
+    MultipartEncryptionError(EncryptionError),
+    /// No parts, or not as many as the parts say.
+    PartCountError,
+    /// A part isn't at its index, or belongs to another message.
+    PartOrderError,
+    PartDecryptionError(u32, DecryptionError),
+    /// `EncryptedPart::from_bytes` got fewer bytes than a header and a tag.
+    InvalidPartError
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MultipartError::InvalidPartSizeError => write!(f, "Invalid multipart part size"),
+            MultipartError::MultipartEncryptionError(e) => write!(f, "{}", e),
+            MultipartError::PartCountError => write!(f, "Wrong number of parts"),
+            MultipartError::PartOrderError => write!(f, "Parts out of order or from another message"),
+            MultipartError::PartDecryptionError(index, e) => write!(f, "Part {}: {}", index, e),
+            MultipartError::InvalidPartError => write!(f, "Invalid part"),
+        }
+    }
+}
+
+impl error::Error for MultipartError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            MultipartError::MultipartEncryptionError(e) => Some(e),
+            MultipartError::PartDecryptionError(_, e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+pub struct EncryptedPart {
+    pub message_id: [u8; MESSAGE_ID_LEN],
+    pub index: u32,
+    pub total: u32,
+    pub encrypted_and_iv: EncryptedAndIv
+}
+
+impl EncryptedPart {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PART_HEADER_LEN + self.encrypted_and_iv.encrypted.u8_vec.len());
+        bytes.extend_from_slice(&self.message_id);
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.total.to_be_bytes());
+        bytes.extend_from_slice(&self.encrypted_and_iv.iv.u8_array);
+        bytes.extend_from_slice(&self.encrypted_and_iv.encrypted.u8_vec);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<EncryptedPart, MultipartError> {
+        if bytes.len() < PART_HEADER_LEN + TAG_LEN {
+            return Err(MultipartError::InvalidPartError);
+        }
+        let (message_id, rest) = bytes.split_at(MESSAGE_ID_LEN);
+        let (index, rest) = rest.split_at(4);
+        let (total, rest) = rest.split_at(4);
+        let (iv, encrypted) = rest.split_at(IV_LEN);
+        Ok(EncryptedPart {
+            message_id: message_id.try_into().unwrap(),
+            index: u32::from_be_bytes(index.try_into().unwrap()),
+            total: u32::from_be_bytes(total.try_into().unwrap()),
+            encrypted_and_iv: EncryptedAndIv::from_parts(Iv { u8_array: iv.try_into().unwrap() }, Encrypted { u8_vec: encrypted.to_vec() })
+        })
+    }
+}
+
+pub struct MultipartEncrypted {
+    pub parts: Vec<EncryptedPart>
+}
+
+impl MultipartEncrypted {
+    /// Puts parts received in any order back in index order. Whether they
+    /// are all there is checked by `decrypt_multipart`.
+    pub fn from_parts(mut parts: Vec<EncryptedPart>) -> MultipartEncrypted {
+        parts.sort_by_key(|part| part.index);
+        MultipartEncrypted { parts }
+    }
+}
+
+fn part_aad(message_id: &[u8; MESSAGE_ID_LEN], index: u32, total: u32) -> Vec<u8> {
+    let mut aad = AAD_PREFIX.to_vec();
+    aad.extend_from_slice(message_id);
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.extend_from_slice(&total.to_be_bytes());
+    aad
+}
+
+/// Splits `plaintext` into parts whose `Encrypted` (ciphertext and tag)
+/// is at most `max_part_ciphertext_len` bytes. An empty plaintext gives one empty part.
+#[cfg(feature = "rng")]
+pub fn encrypt_multipart(key: &Key, plaintext: &[u8], max_part_ciphertext_len: usize) -> Result<MultipartEncrypted, MultipartError> {
+    if max_part_ciphertext_len <= TAG_LEN {
+        return Err(MultipartError::InvalidPartSizeError);
+    }
+    let part_len = max_part_ciphertext_len - TAG_LEN;
+    let total: u32 = plaintext.len().div_ceil(part_len).max(1).try_into().map_err(|_| MultipartError::InvalidPartSizeError)?;
+    let message_id: [u8; MESSAGE_ID_LEN] = rand::random();
+    let mut parts = Vec::with_capacity(total as usize);
+    for index in 0..total {
+        let start = index as usize * part_len;
+        let chunk = &plaintext[start..plaintext.len().min(start + part_len)];
+        let encrypted_and_iv = encrypt_bytes_with_aad(key, chunk, &part_aad(&message_id, index, total)).map_err(MultipartError::MultipartEncryptionError)?;
+        parts.push(EncryptedPart { message_id, index, total, encrypted_and_iv });
+    }
+    Ok(MultipartEncrypted { parts })
+}
+
+/// Checks that every part is present, in order and of the same message,
+/// then decrypts and concatenates them.
+pub fn decrypt_multipart(key: &Key, multipart: &MultipartEncrypted) -> Result<Vec<u8>, MultipartError> {
+    let first = multipart.parts.first().ok_or(MultipartError::PartCountError)?;
+    let (message_id, total) = (first.message_id, first.total);
+    if multipart.parts.len() as u64 != total as u64 {
+        return Err(MultipartError::PartCountError);
+    }
+    let mut plaintext = Vec::new();
+    for (position, part) in multipart.parts.iter().enumerate() {
+        let index = position as u32;
+        if part.index != index || part.total != total || part.message_id != message_id {
+            return Err(MultipartError::PartOrderError);
+        }
+        let chunk = decrypt_bytes_with_aad(key, &part.encrypted_and_iv, &part_aad(&message_id, index, total))
+            .map_err(|e| MultipartError::PartDecryptionError(index, e))?;
+        plaintext.extend(chunk);
+    }
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+#[cfg(feature = "rng")]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    fn plaintext(len: usize) -> Vec<u8> {
+        (0..len).map(|index| index as u8).collect()
+    }
+
+    #[test]
+    fn just_over_one_part_round_trips() {
+        let key = Key { u8_array: KEY };
+        let multipart = encrypt_multipart(&key, &plaintext(101), 100 + TAG_LEN).unwrap();
+        assert_eq!(multipart.parts.len(), 2);
+        assert_eq!(multipart.parts[0].encrypted_and_iv.encrypted.u8_vec.len(), 100 + TAG_LEN);
+        assert_eq!(multipart.parts[1].encrypted_and_iv.encrypted.u8_vec.len(), 1 + TAG_LEN);
+        assert_eq!(decrypt_multipart(&key, &multipart).unwrap(), plaintext(101))
+    }
+
+    #[test]
+    fn single_part_and_empty_plaintext() {
+        let key = Key { u8_array: KEY };
+        for len in [0, 1, 100].iter() {
+            let multipart = encrypt_multipart(&key, &plaintext(*len), 100 + TAG_LEN).unwrap();
+            assert_eq!(multipart.parts.len(), 1);
+            assert_eq!(decrypt_multipart(&key, &multipart).unwrap(), plaintext(*len))
+        }
+    }
+
+    #[test]
+    fn parts_travel_separately() {
+        let key = Key { u8_array: KEY };
+        let multipart = encrypt_multipart(&key, &plaintext(350), 116).unwrap();
+        let mut received: Vec<Vec<u8>> = multipart.parts.iter().map(EncryptedPart::to_bytes).collect();
+        received.reverse();
+        let parts = received.iter().map(|bytes| EncryptedPart::from_bytes(bytes).unwrap()).collect();
+        assert_eq!(decrypt_multipart(&key, &MultipartEncrypted::from_parts(parts)).unwrap(), plaintext(350))
+    }
+
+    #[test]
+    fn reordered_parts_fail() {
+        let key = Key { u8_array: KEY };
+        let mut multipart = encrypt_multipart(&key, &plaintext(350), 116).unwrap();
+        multipart.parts.swap(0, 1);
+        match decrypt_multipart(&key, &multipart) {
+            Ok(_) => assert!(false, "Should err PartOrderError"),
+            Err(e) => match e {
+                MultipartError::PartOrderError => assert!(true),
+                _ => assert!(false, "Should err PartOrderError")
+            }
+        }
+        // Rewriting the indexes to match doesn't help, they are authenticated.
+        multipart.parts[0].index = 0;
+        multipart.parts[1].index = 1;
+        match decrypt_multipart(&key, &multipart) {
+            Ok(_) => assert!(false, "Should err PartDecryptionError"),
+            Err(e) => match e {
+                MultipartError::PartDecryptionError(0, _) => assert!(true),
+                _ => assert!(false, "Should err PartDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    fn dropped_part_fails() {
+        let key = Key { u8_array: KEY };
+        let mut multipart = encrypt_multipart(&key, &plaintext(350), 116).unwrap();
+        multipart.parts.pop();
+        match decrypt_multipart(&key, &multipart) {
+            Ok(_) => assert!(false, "Should err PartCountError"),
+            Err(e) => match e {
+                MultipartError::PartCountError => assert!(true),
+                _ => assert!(false, "Should err PartCountError")
+            }
+        }
+        // Nor does lowering the count.
+        for part in multipart.parts.iter_mut() {
+            part.total = 3;
+        }
+        match decrypt_multipart(&key, &multipart) {
+            Ok(_) => assert!(false, "Should err PartDecryptionError"),
+            Err(e) => match e {
+                MultipartError::PartDecryptionError(_, _) => assert!(true),
+                _ => assert!(false, "Should err PartDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    fn part_size_must_leave_room_for_plaintext() {
+        match encrypt_multipart(&Key { u8_array: KEY }, b"This is a text.", TAG_LEN) {
+            Ok(_) => assert!(false, "Should err InvalidPartSizeError"),
+            Err(e) => match e {
+                MultipartError::InvalidPartSizeError => assert!(true),
+                _ => assert!(false, "Should err InvalidPartSizeError")
+            }
+        }
+    }
+}
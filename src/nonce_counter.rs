@@ -0,0 +1,359 @@
+//! Deterministic ivs (`Iv::from_parts(prefix, counter)`) whose counter
+//! survives restarts and crashes.
+//!
+//! The counter lives in a small text file:
+//!
+//! ```text
+//! simple-aes256-gcm nonce counter v1
+//! fingerprint <Key::fingerprint()>
+//! reserved <first counter not handed out yet>
+//! clean <next counter>
+//! ```
+//!
+//! Opening reserves a block of counters ahead, and so does using the last
+//! of a block: the file is only rewritten (to a temporary file, synced,
+//! then renamed over it) once per block. Every counter below `reserved`
+//! may have been used. The `clean` line is only written on a clean close
+//! or drop; a file without one was left by a crash, and counting resumes
+//! at `reserved`, skipping the rest of the block that was in use.
+//!
+//! A file written for another key is refused. An exclusive lock on a
+//! `.lock` file next to it keeps two counters, in this process or
+//! another, from sharing it; the lock goes away with the process, so a
+//! crash never leaves it behind. Where files can't be locked, e.g. on
+//! WASI, `open` fails with a `CounterIoError` of kind `Unsupported` rather
+//! than returning an unlocked counter.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{error, fmt};
+
+use crate::{Iv, Key};
+
+pub const COUNTER_FILE_HEADER: &str = "simple-aes256-gcm nonce counter v1";
+
+/// Counters reserved per write by `open`.
+pub const DEFAULT_BLOCK_SIZE: u64 = 1024;
+
+#[derive(Debug)]
+pub enum NonceCounterError {
+    CounterIoError(io::Error),
+    /// Another counter holds the lock on this file.
+    CounterLockedError,
+    /// The file was written for the key with this fingerprint.
+    CounterKeyMismatchError(String),
+    InvalidCounterFileError,
+    /// Every counter value has been handed out: rotate the key.
+    CounterExhaustedError
+}
+
+impl fmt::Display for NonceCounterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NonceCounterError::CounterIoError(e) => write!(f, "{}", e),
+            NonceCounterError::CounterLockedError => write!(f, "The nonce counter file is in use"),
+            NonceCounterError::CounterKeyMismatchError(fingerprint) => write!(f, "The nonce counter file belongs to key {}", fingerprint),
+            NonceCounterError::InvalidCounterFileError => write!(f, "Invalid nonce counter file"),
+            NonceCounterError::CounterExhaustedError => write!(f, "Nonce counter exhausted, rotate the key"),
+        }
+    }
+}
+
+impl error::Error for NonceCounterError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            NonceCounterError::CounterIoError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for NonceCounterError {
+    fn from(e: io::Error) -> NonceCounterError {
+        NonceCounterError::CounterIoError(e)
+    }
+}
+
+struct CounterFile {
+    fingerprint: String,
+    reserved: u64,
+    clean: Option<u64>
+}
+
+impl CounterFile {
+    fn parse(text: &str) -> Option<CounterFile> {
+        let mut lines = text.lines();
+        if lines.next()? != COUNTER_FILE_HEADER {
+            return None;
+        }
+        let fingerprint = String::from(lines.next()?.strip_prefix("fingerprint ")?);
+        let reserved = lines.next()?.strip_prefix("reserved ")?.parse().ok()?;
+        let clean = match lines.next() {
+            Some(line) => Some(line.strip_prefix("clean ")?.parse().ok()?),
+            None => None
+        };
+        match lines.next() {
+            Some(_) => None,
+            None => Some(CounterFile { fingerprint, reserved, clean })
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut text = format!("{}\nfingerprint {}\nreserved {}\n", COUNTER_FILE_HEADER, self.fingerprint, self.reserved);
+        if let Some(next) = self.clean {
+            text.push_str(&format!("clean {}\n", next));
+        }
+        text
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn write_durably(path: &Path, text: &str) -> io::Result<()> {
+    let temp_path = with_suffix(path, ".tmp");
+    let mut temp = File::create(&temp_path)?;
+    temp.write_all(text.as_bytes())?;
+    temp.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    // The rename itself must reach the disk too.
+    #[cfg(unix)]
+    {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            File::open(parent)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+struct State {
+    next: u64,
+    reserved: u64
+}
+
+pub struct PersistentNonceCounter {
+    path: PathBuf,
+    fingerprint: String,
+    prefix: [u8; 4],
+    block_size: u64,
+    state: Mutex<State>,
+    /// Held, never read: the lock is released when it is dropped.
+    _lock: File,
+    /// Set once the clean shutdown was written (or must not be), so that
+    /// `Drop` doesn't write it again.
+    closed: bool
+}
+
+impl PersistentNonceCounter {
+    /// `open_with(path, key, [0; 4], DEFAULT_BLOCK_SIZE)`.
+    pub fn open<P: AsRef<Path>>(path: P, key: &Key) -> Result<PersistentNonceCounter, NonceCounterError> {
+        PersistentNonceCounter::open_with(path, key, [0; 4], DEFAULT_BLOCK_SIZE)
+    }
+
+    /// Creates the file for `key` if it doesn't exist, and reserves the
+    /// first block of `block_size` counters (at least 1). Ivs start with
+    /// `prefix`; changing it between runs never makes an iv repeat.
+    pub fn open_with<P: AsRef<Path>>(path: P, key: &Key, prefix: [u8; 4], block_size: u64) -> Result<PersistentNonceCounter, NonceCounterError> {
+        let path = path.as_ref().to_path_buf();
+        let lock = OpenOptions::new().create(true).truncate(false).write(true).open(with_suffix(&path, ".lock"))?;
+        match lock.try_lock() {
+            Ok(()) => {},
+            Err(fs::TryLockError::WouldBlock) => return Err(NonceCounterError::CounterLockedError),
+            Err(fs::TryLockError::Error(e)) => return Err(NonceCounterError::CounterIoError(e))
+        }
+        let fingerprint = key.fingerprint();
+        let next = match fs::read_to_string(&path) {
+            Ok(text) => {
+                let file = CounterFile::parse(&text).ok_or(NonceCounterError::InvalidCounterFileError)?;
+                if file.fingerprint != fingerprint {
+                    return Err(NonceCounterError::CounterKeyMismatchError(file.fingerprint));
+                }
+                match file.clean {
+                    Some(next) if next <= file.reserved => next,
+                    Some(_) => return Err(NonceCounterError::InvalidCounterFileError),
+                    None => file.reserved
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(NonceCounterError::CounterIoError(e))
+        };
+        let mut counter = PersistentNonceCounter {
+            path,
+            fingerprint,
+            prefix,
+            block_size: block_size.max(1),
+            state: Mutex::new(State { next, reserved: next }),
+            _lock: lock,
+            closed: false
+        };
+        let reserved = {
+            let mut state = counter.state.lock().unwrap_or_else(|e| e.into_inner());
+            counter.reserve(&mut state)
+        };
+        if let Err(e) = reserved {
+            // Nothing was handed out: leave the file as it was.
+            counter.closed = true;
+            return Err(NonceCounterError::from(e));
+        }
+        Ok(counter)
+    }
+
+    /// Marks the next block as possibly used, before handing any of it out.
+    fn reserve(&self, state: &mut State) -> io::Result<()> {
+        let reserved = state.next.saturating_add(self.block_size);
+        write_durably(&self.path, &CounterFile { fingerprint: self.fingerprint.clone(), reserved, clean: None }.render())?;
+        state.reserved = reserved;
+        Ok(())
+    }
+
+    /// The next iv, never returned before for this file.
+    pub fn next_iv(&self) -> Result<Iv, NonceCounterError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.next == u64::MAX {
+            return Err(NonceCounterError::CounterExhaustedError);
+        }
+        if state.next == state.reserved {
+            self.reserve(&mut state)?;
+        }
+        let iv = Iv::from_parts(self.prefix, state.next);
+        state.next += 1;
+        Ok(iv)
+    }
+
+    /// Records a clean shutdown, so the next `open` resumes right after the
+    /// last iv handed out. Dropping does the same, ignoring errors; after
+    /// `close`, even a failed one, it writes nothing.
+    pub fn close(mut self) -> Result<(), NonceCounterError> {
+        self.closed = true;
+        Ok(self.persist_clean()?)
+    }
+
+    fn persist_clean(&self) -> io::Result<()> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        write_durably(&self.path, &CounterFile { fingerprint: self.fingerprint.clone(), reserved: state.reserved, clean: Some(state.next) }.render())
+    }
+
+    /// Drops without recording a clean shutdown, as a crash would.
+    #[cfg(all(test, not(target_os = "wasi")))]
+    fn crash(mut self) {
+        self.closed = true;
+    }
+}
+
+impl Drop for PersistentNonceCounter {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = self.persist_clean();
+        }
+    }
+}
+
+// Every test opens a counter, which WASI's missing file locks refuse.
+#[cfg(all(test, not(target_os = "wasi")))]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    fn counter_path(name: &str) -> PathBuf {
+        let path = crate::test_path(&format!("nonce-{}", name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn take(counter: &PersistentNonceCounter, n: usize) -> Vec<u64> {
+        (0..n).map(|_| counter.next_iv().unwrap().counter()).collect()
+    }
+
+    #[test]
+    fn restarts_after_crashes_never_repeat_an_iv() {
+        let path = counter_path("crash");
+        let key = Key { u8_array: KEY };
+        let mut seen = HashSet::new();
+        for _ in 0..3 {
+            let counter = PersistentNonceCounter::open_with(&path, &key, *b"node", 4).unwrap();
+            for value in take(&counter, 6) {
+                assert!(seen.insert(value), "counter {} repeated", value);
+            }
+            counter.crash();
+        }
+        // Each run used 6 of the 8 counters it reserved, and the next run
+        // skipped the rest.
+        let counter = PersistentNonceCounter::open_with(&path, &key, *b"node", 4).unwrap();
+        let iv = counter.next_iv().unwrap();
+        assert_eq!((iv.prefix(), iv.counter()), (*b"node", 24));
+
+        // A clean close resumes right after the last one.
+        counter.close().unwrap();
+        let counter = PersistentNonceCounter::open_with(&path, &key, *b"node", 4).unwrap();
+        assert_eq!(take(&counter, 2), vec![25, 26]);
+        drop(counter);
+        assert_eq!(PersistentNonceCounter::open(&path, &key).unwrap().next_iv().unwrap().counter(), 27);
+        fs::remove_file(&path).unwrap()
+    }
+
+    #[test]
+    fn a_second_open_of_the_same_file_is_rejected() {
+        let path = counter_path("locked");
+        let key = Key { u8_array: KEY };
+        let counter = PersistentNonceCounter::open(&path, &key).unwrap();
+        match PersistentNonceCounter::open(&path, &key) {
+            Ok(_) => assert!(false, "Should err CounterLockedError"),
+            Err(e) => match e {
+                NonceCounterError::CounterLockedError => assert!(true),
+                _ => assert!(false, "Should err CounterLockedError, got {}", e)
+            }
+        }
+        counter.crash();
+        assert!(PersistentNonceCounter::open(&path, &key).is_ok());
+        fs::remove_file(&path).unwrap()
+    }
+
+    #[test]
+    fn another_keys_file_is_refused() {
+        let path = counter_path("other-key");
+        PersistentNonceCounter::open(&path, &Key { u8_array: KEY }).unwrap().close().unwrap();
+        match PersistentNonceCounter::open(&path, &Key { u8_array: [0u8; 32] }) {
+            Ok(_) => assert!(false, "Should err CounterKeyMismatchError"),
+            Err(e) => match e {
+                NonceCounterError::CounterKeyMismatchError(fingerprint) => assert_eq!(fingerprint, Key { u8_array: KEY }.fingerprint()),
+                _ => assert!(false, "Should err CounterKeyMismatchError, got {}", e)
+            }
+        }
+        fs::write(&path, "simple-aes256-gcm nonce counter v1\nreserved 4\n").unwrap();
+        match PersistentNonceCounter::open(&path, &Key { u8_array: KEY }) {
+            Err(NonceCounterError::InvalidCounterFileError) => assert!(true),
+            _ => assert!(false, "Should err InvalidCounterFileError")
+        }
+        fs::remove_file(&path).unwrap()
+    }
+
+    #[test]
+    fn exhaustion_fails_cleanly_and_persists() {
+        let path = counter_path("exhausted");
+        let key = Key { u8_array: KEY };
+        let file = CounterFile { fingerprint: key.fingerprint(), reserved: u64::MAX - 2, clean: None };
+        fs::write(&path, file.render()).unwrap();
+        let counter = PersistentNonceCounter::open_with(&path, &key, [0; 4], 16).unwrap();
+        assert_eq!(take(&counter, 2), vec![u64::MAX - 2, u64::MAX - 1]);
+        for _ in 0..2 {
+            match counter.next_iv() {
+                Ok(_) => assert!(false, "Should err CounterExhaustedError"),
+                Err(e) => match e {
+                    NonceCounterError::CounterExhaustedError => assert!(true),
+                    _ => assert!(false, "Should err CounterExhaustedError, got {}", e)
+                }
+            }
+        }
+        counter.close().unwrap();
+        assert!(PersistentNonceCounter::open(&path, &key).unwrap().next_iv().is_err());
+        fs::remove_file(&path).unwrap()
+    }
+}
@@ -0,0 +1,133 @@
+//! Key storage in the OS keychain, enabled with the `os-keyring` feature.
+//!
+//! Keys are stored in their base64 form under a `(service, account)` pair:
+//! macOS Keychain, Windows Credential Manager, or the Linux kernel keyring.
+
+use std::convert::TryFrom;
+use std::{error, fmt};
+
+use crate::{InvalidKeyError, Key};
+
+#[derive(Debug)]
+pub enum KeyringError {
+    /// No key is stored for this service and account.
+    NoEntryKeyringError,
+    /// The stored value isn't a valid base64, 32-byte key.
+    InvalidStoredKeyError(InvalidKeyError),
+    /// The platform keychain failed or is unavailable.
+    PlatformKeyringError(keyring::Error)
+}
+
+impl From<keyring::Error> for KeyringError {
+    fn from(e: keyring::Error) -> Self {
+        match e {
+            keyring::Error::NoEntry => KeyringError::NoEntryKeyringError,
+            e => KeyringError::PlatformKeyringError(e)
+        }
+    }
+}
+
+impl fmt::Display for KeyringError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyringError::NoEntryKeyringError => write!(f, "No key found in the keyring"),
+            KeyringError::InvalidStoredKeyError(_) => write!(f, "The keyring entry isn't a valid key"),
+            KeyringError::PlatformKeyringError(_) => write!(f, "Keyring error"),
+        }
+    }
+}
+
+impl error::Error for KeyringError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            KeyringError::NoEntryKeyringError => None,
+            KeyringError::InvalidStoredKeyError(e) => Some(e),
+            KeyringError::PlatformKeyringError(e) => Some(e),
+        }
+    }
+}
+
+impl Key {
+    /// Stores the key, base64-encoded, in the OS keychain.
+    pub fn save_to_keyring(&self, service: &str, account: &str) -> Result<(), KeyringError> {
+        self.save_to_entry(&keyring::Entry::new(service, account)?)
+    }
+
+    /// Loads a key stored with `save_to_keyring`.
+    pub fn load_from_keyring(service: &str, account: &str) -> Result<Key, KeyringError> {
+        Key::load_from_entry(&keyring::Entry::new(service, account)?)
+    }
+
+    /// Removes a key stored with `save_to_keyring`.
+    pub fn delete_from_keyring(service: &str, account: &str) -> Result<(), KeyringError> {
+        Ok(keyring::Entry::new(service, account)?.delete_credential()?)
+    }
+
+    fn save_to_entry(&self, entry: &keyring::Entry) -> Result<(), KeyringError> {
+        Ok(entry.set_password(&base64::encode(&self.u8_array))?)
+    }
+
+    fn load_from_entry(entry: &keyring::Entry) -> Result<Key, KeyringError> {
+        Key::try_from(entry.get_password()?).map_err(KeyringError::InvalidStoredKeyError)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use keyring::mock::MockCredential;
+
+    // Mock credentials aren't shared between `Entry` values, so the tests
+    // go through a single entry instead of the `(service, account)` API.
+    fn mock_entry() -> keyring::Entry {
+        keyring::set_default_credential_builder(keyring::mock::default_credential_builder());
+        keyring::Entry::new("simple-aes256-gcm", "tests").unwrap()
+    }
+
+    #[test]
+    fn save_then_load_round_trips() {
+        let entry = mock_entry();
+        let key = Key { u8_array: [7u8; 32] };
+        key.save_to_entry(&entry).unwrap();
+        assert_eq!(Key::load_from_entry(&entry).unwrap().u8_array, key.u8_array)
+    }
+
+    #[test]
+    fn load_missing_entry_fails_with_no_entry() {
+        match Key::load_from_entry(&mock_entry()) {
+            Ok(_) => assert!(false, "Should err NoEntryKeyringError"),
+            Err(e) => match e {
+                KeyringError::NoEntryKeyringError => assert!(true),
+                _ => assert!(false, "Should err NoEntryKeyringError")
+            }
+        }
+    }
+
+    #[test]
+    fn load_invalid_stored_value_fails() {
+        let entry = mock_entry();
+        entry.set_password("not a key").unwrap();
+        match Key::load_from_entry(&entry) {
+            Ok(_) => assert!(false, "Should err InvalidStoredKeyError"),
+            Err(e) => match e {
+                KeyringError::InvalidStoredKeyError(_) => assert!(true),
+                _ => assert!(false, "Should err InvalidStoredKeyError")
+            }
+        }
+    }
+
+    #[test]
+    fn platform_failure_is_not_no_entry() {
+        let entry = mock_entry();
+        let mock: &MockCredential = entry.get_credential().downcast_ref().unwrap();
+        mock.set_error(keyring::Error::PlatformFailure(Box::new(fmt::Error)));
+        match Key::load_from_entry(&entry) {
+            Ok(_) => assert!(false, "Should err PlatformKeyringError"),
+            Err(e) => match e {
+                KeyringError::PlatformKeyringError(_) => assert!(true),
+                _ => assert!(false, "Should err PlatformKeyringError")
+            }
+        }
+    }
+}
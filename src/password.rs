@@ -0,0 +1,444 @@
+//! Self-contained password-encrypted messages, enabled with the `password` feature.
+//!
+//! `encrypt_with_password` returns one base64 envelope holding everything
+//! needed to decrypt it except the password:
+//!
+//! ```text
+//! version (1) | kdf id (1) | m_cost (4, BE) | t_cost (4, BE) | p_cost (4, BE) | salt (16) | iv (12) | ciphertext and tag
+//! ```
+//!
+//! Version 1 knows two kdf ids deriving the 32-byte key: 1, Argon2id
+//! (v0x13), and 2, PBKDF2-HMAC-SHA256 with `t_cost` iterations, `m_cost`
+//! and `p_cost` 0. `kdf_calibration` picks either's parameters for a host.
+//!
+//! Version 2 inserts a normalization id after the version byte: the
+//! `PasswordNormalization` applied to the password before derivation, 0
+//! for none, 1 for NFC and 2 for NFKD. Without it, the same passphrase
+//! typed on two systems can reach the KDF as different bytes, e.g. `é` as
+//! one precomposed code point or as `e` and a combining accent, and fail
+//! to decrypt. New envelopes use NFC unless told otherwise; those asking
+//! for no normalization are written as version 1, which is read back
+//! without any.
+
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::{error, fmt};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use sha2::Sha256;
+use unicode_normalization::{is_nfc, is_nfkd, UnicodeNormalization};
+
+use crate::kdf_cache::KdfCache;
+use crate::{decrypt_bytes, encrypt_bytes_with_iv, DecryptionError, Encrypted, EncryptedAndIv, EncryptionError, Iv, Key, IV_LEN, TAG_LEN};
+
+const VERSION: u8 = 1;
+const VERSION_NORMALIZED: u8 = 2;
+pub(crate) const KDF_ARGON2ID: u8 = 1;
+pub(crate) const KDF_PBKDF2_SHA256: u8 = 2;
+const SALT_LEN: usize = 16;
+/// From the kdf id to the salt included.
+const KDF_FIELDS_LEN: usize = 1 + 3 * 4 + SALT_LEN;
+
+/// Envelopes asking for more than this are rejected before running the KDF.
+pub const MAX_M_COST: u32 = 1024 * 1024;
+pub const MAX_T_COST: u32 = 16;
+pub const MAX_P_COST: u32 = 16;
+pub const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+/// Argon2id cost parameters: memory in KiB, iterations and lanes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasswordParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32
+}
+
+impl Default for PasswordParams {
+    /// The OWASP recommended minimum: 19 MiB, 2 iterations, 1 lane.
+    fn default() -> Self {
+        PasswordParams { m_cost: 19 * 1024, t_cost: 2, p_cost: 1 }
+    }
+}
+
+/// The KDF of an envelope and its parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdfParams {
+    Argon2id(PasswordParams),
+    Pbkdf2Sha256 { iterations: u32 }
+}
+
+impl KdfParams {
+    /// The kdf id and the `m_cost`, `t_cost` and `p_cost` fields of the envelope.
+    pub(crate) fn to_fields(self) -> (u8, u32, u32, u32) {
+        match self {
+            KdfParams::Argon2id(params) => (KDF_ARGON2ID, params.m_cost, params.t_cost, params.p_cost),
+            KdfParams::Pbkdf2Sha256 { iterations } => (KDF_PBKDF2_SHA256, 0, iterations, 0)
+        }
+    }
+
+    fn from_fields(kdf_id: u8, m_cost: u32, t_cost: u32, p_cost: u32) -> Result<KdfParams, PasswordError> {
+        match kdf_id {
+            KDF_ARGON2ID => Ok(KdfParams::Argon2id(PasswordParams { m_cost, t_cost, p_cost })),
+            KDF_PBKDF2_SHA256 if m_cost == 0 && p_cost == 0 => Ok(KdfParams::Pbkdf2Sha256 { iterations: t_cost }),
+            KDF_PBKDF2_SHA256 => Err(PasswordError::InvalidKdfParamsError),
+            _ => Err(PasswordError::UnknownKdfError(kdf_id))
+        }
+    }
+}
+
+impl From<PasswordParams> for KdfParams {
+    fn from(params: PasswordParams) -> KdfParams {
+        KdfParams::Argon2id(params)
+    }
+}
+
+/// How a password is normalized before key derivation, recorded in the envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PasswordNormalization {
+    /// The password's bytes as given, as in version 1 envelopes.
+    None,
+    /// Canonical composition: precomposed and decomposed accents match.
+    /// The default for new envelopes.
+    #[default]
+    Nfc,
+    /// Compatibility decomposition: also matches compatibility characters
+    /// with their plain forms, e.g. the `ﬁ` ligature and `fi`, or
+    /// full-width and ASCII digits.
+    Nfkd
+}
+
+impl PasswordNormalization {
+    pub fn apply(self, password: &str) -> Cow<'_, str> {
+        match self {
+            PasswordNormalization::Nfc if !is_nfc(password) => Cow::Owned(password.nfc().collect()),
+            PasswordNormalization::Nfkd if !is_nfkd(password) => Cow::Owned(password.nfkd().collect()),
+            _ => Cow::Borrowed(password)
+        }
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            PasswordNormalization::None => 0,
+            PasswordNormalization::Nfc => 1,
+            PasswordNormalization::Nfkd => 2
+        }
+    }
+
+    fn from_id(id: u8) -> Result<PasswordNormalization, PasswordError> {
+        match id {
+            0 => Ok(PasswordNormalization::None),
+            1 => Ok(PasswordNormalization::Nfc),
+            2 => Ok(PasswordNormalization::Nfkd),
+            _ => Err(PasswordError::UnknownNormalizationError(id))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PasswordError {
+    InvalidEnvelopeBase64Error(base64::DecodeError),
+    /// Truncated envelope or unknown version.
+    InvalidEnvelopeError,
+    UnknownKdfError(u8),
+    UnknownNormalizationError(u8),
+    /// KDF parameters above the `MAX_*` caps or refused by Argon2.
+    InvalidKdfParamsError,
+    PasswordEncryptionError(EncryptionError),
+    /// Wrong password or tampered envelope.
+    PasswordDecryptionError(DecryptionError)
+}
+
+impl fmt::Display for PasswordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PasswordError::InvalidEnvelopeBase64Error(_) => write!(f, "Please provide a valid base64"),
+            PasswordError::InvalidEnvelopeError => write!(f, "Invalid password envelope"),
+            PasswordError::UnknownKdfError(id) => write!(f, "Unknown KDF id {}", id),
+            PasswordError::UnknownNormalizationError(id) => write!(f, "Unknown password normalization id {}", id),
+            PasswordError::InvalidKdfParamsError => write!(f, "Invalid or unsafe KDF parameters"),
+            PasswordError::PasswordEncryptionError(e) => write!(f, "{}", e),
+            PasswordError::PasswordDecryptionError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for PasswordError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            PasswordError::InvalidEnvelopeBase64Error(e) => Some(e),
+            PasswordError::PasswordEncryptionError(e) => Some(e),
+            PasswordError::PasswordDecryptionError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+pub(crate) fn derive_key(password: &str, salt: &[u8], params: &PasswordParams) -> Result<Key, PasswordError> {
+    if params.m_cost > MAX_M_COST || params.t_cost > MAX_T_COST || params.p_cost > MAX_P_COST {
+        return Err(PasswordError::InvalidKdfParamsError);
+    }
+    let argon2_params = match Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32)) {
+        Ok(value) => value,
+        Err(_) => return Err(PasswordError::InvalidKdfParamsError)
+    };
+    let mut u8_array = [0u8; 32];
+    match Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params).hash_password_into(password.as_bytes(), salt, &mut u8_array) {
+        Ok(_) => Ok(Key { u8_array }),
+        Err(_) => Err(PasswordError::InvalidKdfParamsError)
+    }
+}
+
+pub(crate) fn derive_kdf_key(password: &str, salt: &[u8], params: &KdfParams) -> Result<Key, PasswordError> {
+    match params {
+        KdfParams::Argon2id(params) => derive_key(password, salt, params),
+        KdfParams::Pbkdf2Sha256 { iterations } => {
+            if *iterations == 0 || *iterations > MAX_PBKDF2_ITERATIONS {
+                return Err(PasswordError::InvalidKdfParamsError);
+            }
+            let mut u8_array = [0u8; 32];
+            pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, *iterations, &mut u8_array);
+            Ok(Key { u8_array })
+        }
+    }
+}
+
+impl Key {
+    /// Argon2id of `password` and `salt` (at least 8 bytes), the key
+    /// derivation of password envelopes. See `kdf_cache` to avoid
+    /// repeating it. The password isn't normalized: pass it through
+    /// `PasswordNormalization::apply` first to match envelopes.
+    pub fn from_password(password: &str, salt: &[u8], params: &PasswordParams) -> Result<Key, PasswordError> {
+        derive_key(password, salt, params)
+    }
+}
+
+fn seal(password: &str, plaintext: &[u8], params: &KdfParams, normalization: PasswordNormalization, salt: [u8; SALT_LEN], iv: Iv) -> Result<String, PasswordError> {
+    let key = derive_kdf_key(&normalization.apply(password), &salt, params)?;
+    let encrypted_and_iv = encrypt_bytes_with_iv(&key, iv, plaintext).map_err(PasswordError::PasswordEncryptionError)?;
+    let (kdf_id, m_cost, t_cost, p_cost) = params.to_fields();
+    let mut envelope = Vec::with_capacity(2 + KDF_FIELDS_LEN + IV_LEN + encrypted_and_iv.encrypted.u8_vec.len());
+    if normalization == PasswordNormalization::None {
+        envelope.push(VERSION);
+    } else {
+        envelope.push(VERSION_NORMALIZED);
+        envelope.push(normalization.id());
+    }
+    envelope.push(kdf_id);
+    envelope.extend_from_slice(&m_cost.to_be_bytes());
+    envelope.extend_from_slice(&t_cost.to_be_bytes());
+    envelope.extend_from_slice(&p_cost.to_be_bytes());
+    envelope.extend_from_slice(&salt);
+    envelope.extend_from_slice(&encrypted_and_iv.iv.u8_array);
+    envelope.extend_from_slice(&encrypted_and_iv.encrypted.u8_vec);
+    Ok(base64::encode(&envelope))
+}
+
+/// Encrypts `plaintext` under a key derived from `password` with the
+/// default `PasswordParams`, after NFC normalization.
+pub fn encrypt_with_password(password: &str, plaintext: &[u8]) -> Result<String, PasswordError> {
+    encrypt_with_password_and_params(password, plaintext, &PasswordParams::default())
+}
+
+/// Same as `encrypt_with_password`, with explicit Argon2id cost parameters.
+pub fn encrypt_with_password_and_params(password: &str, plaintext: &[u8], params: &PasswordParams) -> Result<String, PasswordError> {
+    encrypt_with_password_and_kdf(password, plaintext, &KdfParams::Argon2id(*params))
+}
+
+/// Same as `encrypt_with_password`, with any KDF, such as the parameters
+/// returned by `kdf_calibration::calibrate_kdf`.
+pub fn encrypt_with_password_and_kdf(password: &str, plaintext: &[u8], params: &KdfParams) -> Result<String, PasswordError> {
+    encrypt_with_password_normalized(password, plaintext, params, PasswordNormalization::default())
+}
+
+/// Same as `encrypt_with_password_and_kdf`, with an explicit normalization.
+pub fn encrypt_with_password_normalized(password: &str, plaintext: &[u8], params: &KdfParams, normalization: PasswordNormalization) -> Result<String, PasswordError> {
+    seal(password, plaintext, params, normalization, rand::random::<[u8; SALT_LEN]>(), Iv::generate())
+}
+
+/// Decrypts an envelope produced by `encrypt_with_password`, reading the KDF
+/// parameters, salt and normalization back from it.
+pub fn decrypt_with_password(password: &str, envelope: &str) -> Result<Vec<u8>, PasswordError> {
+    open(password, envelope, derive_kdf_key)
+}
+
+/// Same as `decrypt_with_password`, deriving the key through `cache`.
+pub fn decrypt_with_password_cached(cache: &KdfCache, password: &str, envelope: &str) -> Result<Vec<u8>, PasswordError> {
+    open(password, envelope, |password, salt, params| cache.get_or_derive_kdf(password, salt, params))
+}
+
+fn open<F>(password: &str, envelope: &str, derive: F) -> Result<Vec<u8>, PasswordError>
+where F: FnOnce(&str, &[u8], &KdfParams) -> Result<Key, PasswordError> {
+    let envelope = base64::decode(envelope).map_err(PasswordError::InvalidEnvelopeBase64Error)?;
+    let (normalization, fields) = match envelope.first() {
+        Some(&VERSION) => (PasswordNormalization::None, &envelope[1..]),
+        Some(&VERSION_NORMALIZED) if envelope.len() > 1 => (PasswordNormalization::from_id(envelope[1])?, &envelope[2..]),
+        _ => return Err(PasswordError::InvalidEnvelopeError)
+    };
+    if fields.len() < KDF_FIELDS_LEN + IV_LEN + TAG_LEN {
+        return Err(PasswordError::InvalidEnvelopeError);
+    }
+    let read_u32 = |offset: usize| u32::from_be_bytes(fields[offset..offset + 4].try_into().unwrap());
+    let params = KdfParams::from_fields(fields[0], read_u32(1), read_u32(5), read_u32(9))?;
+    let key = derive(&normalization.apply(password), &fields[13..KDF_FIELDS_LEN], &params)?;
+    let encrypted_and_iv = EncryptedAndIv::from_parts(Iv { u8_array: fields[KDF_FIELDS_LEN..KDF_FIELDS_LEN + IV_LEN].try_into().unwrap() }, Encrypted { u8_vec: fields[KDF_FIELDS_LEN + IV_LEN..].to_vec() });
+    decrypt_bytes(&key, &encrypted_and_iv).map_err(PasswordError::PasswordDecryptionError)
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    // Small costs to keep debug-build tests fast.
+    const TEST_PARAMS: PasswordParams = PasswordParams { m_cost: 64, t_cost: 1, p_cost: 1 };
+
+    // Produced independently with Python's `cryptography` (Argon2id
+    // m=64 t=1 p=1, salt "0123456789abcdef", iv "012345678901").
+    const FIXTURE: &str = "AQEAAABAAAAAAQAAAAEwMTIzNDU2Nzg5YWJjZGVmMDEyMzQ1Njc4OTAxuhSo6Pudbh91XIEkvH6pBzXH13jzSX7TREwXDCyjLA==";
+
+    #[test]
+    fn encrypt_then_decrypt_with_password_is_iso() {
+        let envelope = encrypt_with_password("correct horse", b"This is a text.").unwrap();
+        assert_eq!(decrypt_with_password("correct horse", &envelope).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn wrong_password_fails_with_decryption_error() {
+        let envelope = encrypt_with_password_and_params("correct horse", b"This is a text.", &TEST_PARAMS).unwrap();
+        match decrypt_with_password("battery staple", &envelope) {
+            Ok(_) => assert!(false, "Should err PasswordDecryptionError"),
+            Err(e) => match e {
+                PasswordError::PasswordDecryptionError(DecryptionError::GenericDecryptionError) => assert!(true),
+                _ => assert!(false, "Should err PasswordDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    fn fixture_envelope_is_pinned() {
+        let salt = *b"0123456789abcdef";
+        let iv = Iv { u8_array: *b"012345678901" };
+        assert_eq!(seal("correct horse", b"This is a text.", &KdfParams::Argon2id(TEST_PARAMS), PasswordNormalization::None, salt, iv).unwrap(), FIXTURE);
+        assert_eq!(decrypt_with_password("correct horse", FIXTURE).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn cached_decryption_derives_once() {
+        let cache = KdfCache::new(4);
+        for _ in 0..2 {
+            assert_eq!(decrypt_with_password_cached(&cache, "correct horse", FIXTURE).unwrap(), b"This is a text.");
+        }
+        assert_eq!(cache.len(), 1);
+        match decrypt_with_password_cached(&cache, "battery staple", FIXTURE) {
+            Ok(_) => assert!(false, "Should err PasswordDecryptionError"),
+            Err(e) => match e {
+                PasswordError::PasswordDecryptionError(_) => assert!(true),
+                _ => assert!(false, "Should err PasswordDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    fn pbkdf2_envelopes_round_trip() {
+        let params = KdfParams::Pbkdf2Sha256 { iterations: 1000 };
+        let envelope = encrypt_with_password_and_kdf("correct horse", b"This is a text.", &params).unwrap();
+        let decoded = base64::decode(&envelope).unwrap();
+        assert_eq!(decoded[..2], [VERSION_NORMALIZED, 1]);
+        assert_eq!(decoded[2..15], [KDF_PBKDF2_SHA256, 0, 0, 0, 0, 0, 0, 0x03, 0xe8, 0, 0, 0, 0]);
+        assert_eq!(decrypt_with_password("correct horse", &envelope).unwrap(), b"This is a text.");
+        assert!(decrypt_with_password("battery staple", &envelope).is_err());
+
+        let mut with_memory = decoded;
+        with_memory[6] = 1;
+        match decrypt_with_password("correct horse", &base64::encode(&with_memory)) {
+            Ok(_) => assert!(false, "Should err InvalidKdfParamsError"),
+            Err(e) => match e {
+                PasswordError::InvalidKdfParamsError => assert!(true),
+                _ => assert!(false, "Should err InvalidKdfParamsError")
+            }
+        }
+    }
+
+    #[test]
+    fn nfc_matches_composed_and_decomposed_passphrases() {
+        let (composed, decomposed) = ("caf\u{e9} cr\u{e8}me", "cafe\u{301} cre\u{300}me");
+        assert_ne!(composed.as_bytes(), decomposed.as_bytes());
+        let params = KdfParams::Argon2id(TEST_PARAMS);
+        for (sealed_with, opened_with) in [(composed, decomposed), (decomposed, composed)].iter() {
+            let envelope = encrypt_with_password_and_kdf(sealed_with, b"This is a text.", &params).unwrap();
+            assert_eq!(decrypt_with_password(opened_with, &envelope).unwrap(), b"This is a text.");
+
+            let envelope = encrypt_with_password_normalized(sealed_with, b"This is a text.", &params, PasswordNormalization::None).unwrap();
+            assert_eq!(base64::decode(&envelope).unwrap()[0], VERSION);
+            assert_eq!(decrypt_with_password(sealed_with, &envelope).unwrap(), b"This is a text.");
+            match decrypt_with_password(opened_with, &envelope) {
+                Ok(_) => assert!(false, "Should err PasswordDecryptionError"),
+                Err(e) => match e {
+                    PasswordError::PasswordDecryptionError(_) => assert!(true),
+                    _ => assert!(false, "Should err PasswordDecryptionError, got {}", e)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nfkd_also_matches_compatibility_characters() {
+        let params = KdfParams::Argon2id(TEST_PARAMS);
+        let envelope = encrypt_with_password_normalized("\u{fb01}sh \u{ff11}\u{ff12}", b"This is a text.", &params, PasswordNormalization::Nfkd).unwrap();
+        assert_eq!(base64::decode(&envelope).unwrap()[..2], [VERSION_NORMALIZED, 2]);
+        assert_eq!(decrypt_with_password("fish 12", &envelope).unwrap(), b"This is a text.");
+        let envelope = encrypt_with_password_and_kdf("\u{fb01}sh \u{ff11}\u{ff12}", b"This is a text.", &params).unwrap();
+        assert!(decrypt_with_password("fish 12", &envelope).is_err(), "NFC keeps compatibility characters")
+    }
+
+    #[test]
+    fn unknown_normalization_is_rejected() {
+        let envelope = encrypt_with_password_and_kdf("correct horse", b"This is a text.", &KdfParams::Argon2id(TEST_PARAMS)).unwrap();
+        let mut envelope = base64::decode(&envelope).unwrap();
+        envelope[1] = 9;
+        match decrypt_with_password("correct horse", &base64::encode(&envelope)) {
+            Ok(_) => assert!(false, "Should err UnknownNormalizationError"),
+            Err(e) => match e {
+                PasswordError::UnknownNormalizationError(9) => assert!(true),
+                _ => assert!(false, "Should err UnknownNormalizationError, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_kdf_is_rejected() {
+        let mut envelope = base64::decode(FIXTURE).unwrap();
+        envelope[1] = 42;
+        match decrypt_with_password("correct horse", &base64::encode(&envelope)) {
+            Ok(_) => assert!(false, "Should err UnknownKdfError"),
+            Err(e) => match e {
+                PasswordError::UnknownKdfError(42) => assert!(true),
+                _ => assert!(false, "Should err UnknownKdfError")
+            }
+        }
+    }
+
+    #[test]
+    fn params_above_caps_are_rejected() {
+        let mut envelope = base64::decode(FIXTURE).unwrap();
+        envelope[2..6].copy_from_slice(&(MAX_M_COST + 1).to_be_bytes());
+        match decrypt_with_password("correct horse", &base64::encode(&envelope)) {
+            Ok(_) => assert!(false, "Should err InvalidKdfParamsError"),
+            Err(e) => match e {
+                PasswordError::InvalidKdfParamsError => assert!(true),
+                _ => assert!(false, "Should err InvalidKdfParamsError")
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_envelope_is_rejected() {
+        match decrypt_with_password("correct horse", &FIXTURE[..40]) {
+            Ok(_) => assert!(false, "Should err InvalidEnvelopeError"),
+            Err(e) => match e {
+                PasswordError::InvalidEnvelopeError => assert!(true),
+                _ => assert!(false, "Should err InvalidEnvelopeError")
+            }
+        }
+    }
+}
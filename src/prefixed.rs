@@ -0,0 +1,156 @@
+//! Strings in the shape of Vault transit ciphertexts, `<prefix>:v<N>:<base64>`.
+//!
+//! The body is standard, padded base64 of `iv || ciphertext and tag`, like
+//! a token's. The version is only carried, not interpreted: it is meant to
+//! select the key, for example a `KeyRing` id, before decrypting. Prefixes
+//! can't contain `:`.
+
+use std::convert::TryInto;
+use std::{error, fmt};
+
+use crate::{Encrypted, EncryptedAndIv, Iv, IV_LEN, TAG_LEN};
+
+#[derive(Debug, Clone)]
+pub enum PrefixedStringError {
+    /// Fewer than three `:`-separated segments.
+    MissingSegmentError,
+    /// The first segment isn't the expected prefix.
+    UnknownPrefixError(String),
+    /// The second segment isn't `v` followed by a decimal `u32`.
+    InvalidVersionError,
+    InvalidPrefixedBase64Error(base64::DecodeError),
+    /// Shorter than an iv and a tag.
+    InvalidPrefixedSizeError
+}
+
+impl fmt::Display for PrefixedStringError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrefixedStringError::MissingSegmentError => write!(f, "Expected <prefix>:v<version>:<base64>"),
+            PrefixedStringError::UnknownPrefixError(prefix) => write!(f, "Unknown prefix {}", prefix),
+            PrefixedStringError::InvalidVersionError => write!(f, "Invalid version segment, expected v<number>"),
+            PrefixedStringError::InvalidPrefixedBase64Error(_) => write!(f, "Please provide a valid base64"),
+            PrefixedStringError::InvalidPrefixedSizeError => write!(f, "Ciphertext too short"),
+        }
+    }
+}
+
+impl error::Error for PrefixedStringError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            PrefixedStringError::InvalidPrefixedBase64Error(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// E.g. `to_prefixed_string("vault", 1, &encrypted_and_iv)` is `vault:v1:...`.
+/// A key check value in the envelope isn't written.
+pub fn to_prefixed_string(prefix: &str, version: u32, encrypted_and_iv: &EncryptedAndIv) -> String {
+    let mut bytes = encrypted_and_iv.iv.u8_array.to_vec();
+    bytes.extend_from_slice(&encrypted_and_iv.encrypted.u8_vec);
+    format!("{}:v{}:{}", prefix, version, base64::encode(&bytes))
+}
+
+/// Parses a string written by `to_prefixed_string` with `expected_prefix`,
+/// returning its version and envelope.
+pub fn from_prefixed_string(expected_prefix: &str, text: &str) -> Result<(u32, EncryptedAndIv), PrefixedStringError> {
+    let mut segments = text.splitn(3, ':');
+    let (prefix, version, body) = match (segments.next(), segments.next(), segments.next()) {
+        (Some(prefix), Some(version), Some(body)) => (prefix, version, body),
+        _ => return Err(PrefixedStringError::MissingSegmentError)
+    };
+    if prefix != expected_prefix {
+        return Err(PrefixedStringError::UnknownPrefixError(String::from(prefix)));
+    }
+    let version = match version.strip_prefix('v') {
+        Some(digits) if !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit()) => {
+            digits.parse().map_err(|_| PrefixedStringError::InvalidVersionError)?
+        },
+        _ => return Err(PrefixedStringError::InvalidVersionError)
+    };
+    let bytes = base64::decode(body).map_err(PrefixedStringError::InvalidPrefixedBase64Error)?;
+    if bytes.len() < IV_LEN + TAG_LEN {
+        return Err(PrefixedStringError::InvalidPrefixedSizeError);
+    }
+    Ok((version, EncryptedAndIv::from_parts(Iv { u8_array: bytes[..IV_LEN].try_into().unwrap() }, Encrypted { u8_vec: bytes[IV_LEN..].to_vec() })))
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_bytes, encrypt_bytes_with_iv, Key};
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    fn sample() -> EncryptedAndIv {
+        encrypt_bytes_with_iv(&Key { u8_array: KEY }, Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap()
+    }
+
+    #[test]
+    fn round_trips_with_version() {
+        let text = to_prefixed_string("vault", 12, &sample());
+        assert_eq!(text, "vault:v12:MDEyMzQ1Njc4OTAxJOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==");
+        let (version, encrypted_and_iv) = from_prefixed_string("vault", &text).unwrap();
+        assert_eq!(version, 12);
+        assert_eq!(decrypt_bytes(&Key { u8_array: KEY }, &encrypted_and_iv).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn version_selects_the_key_ring_key() {
+        use crate::key_ring::{KeyMetadata, KeyRing, KeyRingEncrypted};
+        use std::time::SystemTime;
+
+        let mut ring = KeyRing::new("1", Key { u8_array: [1u8; 32] }, KeyMetadata::new("first", SystemTime::now()));
+        let old = ring.encrypt(b"old value").unwrap();
+        ring.add("2", Key { u8_array: [2u8; 32] }, KeyMetadata::new("second", SystemTime::now()));
+        ring.set_primary("2").unwrap();
+        let new = ring.encrypt(b"new value").unwrap();
+
+        for (stored, expected) in [(old, &b"old value"[..]), (new, &b"new value"[..])].iter() {
+            let text = to_prefixed_string("vault", stored.key_id.parse().unwrap(), &stored.encrypted_and_iv);
+            let (version, encrypted_and_iv) = from_prefixed_string("vault", &text).unwrap();
+            let parsed = KeyRingEncrypted { key_id: version.to_string(), encrypted_and_iv };
+            assert_eq!(ring.decrypt(&parsed).unwrap(), *expected)
+        }
+    }
+
+    #[test]
+    fn other_prefix_is_rejected() {
+        let text = to_prefixed_string("vault", 1, &sample());
+        match from_prefixed_string("kms", &text) {
+            Ok(_) => assert!(false, "Should err UnknownPrefixError"),
+            Err(e) => match e {
+                PrefixedStringError::UnknownPrefixError(prefix) => assert_eq!(prefix, "vault"),
+                _ => assert!(false, "Should err UnknownPrefixError")
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_segments_are_rejected() {
+        let body = "MDEyMzQ1Njc4OTAxJOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==";
+        for text in ["vault:v1", "vault"].iter() {
+            match from_prefixed_string("vault", text) {
+                Err(PrefixedStringError::MissingSegmentError) => assert!(true),
+                _ => assert!(false, "Should err MissingSegmentError for {}", text)
+            }
+        }
+        for version in ["1", "v", "v-1", "v+1", "v99999999999", "V1"].iter() {
+            match from_prefixed_string("vault", &format!("vault:{}:{}", version, body)) {
+                Err(PrefixedStringError::InvalidVersionError) => assert!(true),
+                _ => assert!(false, "Should err InvalidVersionError for {}", version)
+            }
+        }
+        match from_prefixed_string("vault", "vault:v1:not base64!") {
+            Err(PrefixedStringError::InvalidPrefixedBase64Error(_)) => assert!(true),
+            _ => assert!(false, "Should err InvalidPrefixedBase64Error")
+        }
+        match from_prefixed_string("vault", "vault:v1:MDEyMzQ1Njc4OTAx") {
+            Err(PrefixedStringError::InvalidPrefixedSizeError) => assert!(true),
+            _ => assert!(false, "Should err InvalidPrefixedSizeError")
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Resolving keys by id at decryption time, e.g. from a remote KMS.
+//!
+//! `decrypt_with_provider` keeps the two ways it can fail apart: the
+//! provider couldn't produce a key (`KeyProviderError`), or the key it
+//! produced doesn't decrypt the envelope (`ProviderDecryptionError`).
+//!
+//! With the `tokio` feature, `AsyncKeyProvider` is the same for providers
+//! that fetch keys asynchronously, and `SpawnBlocking` runs a blocking
+//! `KeyProvider` on tokio's blocking thread pool.
+//!
+//! `caching_provider::CachingKeyProvider` caches what either kind resolves.
+
+use std::{error, fmt};
+#[cfg(feature = "tokio")]
+use std::future::Future;
+#[cfg(feature = "tokio")]
+use std::sync::Arc;
+
+use crate::{decrypt_bytes, DecryptionError, EncryptedAndIv, Key};
+
+#[derive(Debug, Clone)]
+pub enum ProviderError {
+    UnknownKeyIdError(String),
+    /// The key exists but the provider can't hand it out, e.g. disabled or revoked.
+    KeyUnavailableError(String),
+    /// The provider itself failed, e.g. the KMS is unreachable. Holds a description.
+    ProviderBackendError(String)
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProviderError::UnknownKeyIdError(key_id) => write!(f, "Unknown key id {}", key_id),
+            ProviderError::KeyUnavailableError(key_id) => write!(f, "Key {} is unavailable", key_id),
+            ProviderError::ProviderBackendError(description) => write!(f, "Key provider failed: {}", description),
+        }
+    }
+}
+
+impl error::Error for ProviderError {}
+
+#[derive(Debug, Clone)]
+pub enum ProviderDecryptError {
+    KeyProviderError(ProviderError),
+    ProviderDecryptionError(DecryptionError)
+}
+
+impl fmt::Display for ProviderDecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProviderDecryptError::KeyProviderError(e) => write!(f, "{}", e),
+            ProviderDecryptError::ProviderDecryptionError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for ProviderDecryptError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ProviderDecryptError::KeyProviderError(e) => Some(e),
+            ProviderDecryptError::ProviderDecryptionError(e) => Some(e),
+        }
+    }
+}
+
+pub trait KeyProvider {
+    fn key_for(&self, key_id: &str) -> Result<Key, ProviderError>;
+}
+
+pub fn decrypt_with_provider<P: KeyProvider + ?Sized>(provider: &P, key_id: &str, encrypted_and_iv: &EncryptedAndIv) -> Result<Vec<u8>, ProviderDecryptError> {
+    let key = provider.key_for(key_id).map_err(ProviderDecryptError::KeyProviderError)?;
+    decrypt_bytes(&key, encrypted_and_iv).map_err(ProviderDecryptError::ProviderDecryptionError)
+}
+
+#[cfg(feature = "tokio")]
+pub trait AsyncKeyProvider {
+    fn key_for(&self, key_id: &str) -> impl Future<Output = Result<Key, ProviderError>> + Send;
+}
+
+#[cfg(feature = "tokio")]
+pub async fn decrypt_with_async_provider<P: AsyncKeyProvider + ?Sized>(provider: &P, key_id: &str, encrypted_and_iv: &EncryptedAndIv) -> Result<Vec<u8>, ProviderDecryptError> {
+    let key = provider.key_for(key_id).await.map_err(ProviderDecryptError::KeyProviderError)?;
+    decrypt_bytes(&key, encrypted_and_iv).map_err(ProviderDecryptError::ProviderDecryptionError)
+}
+
+/// Makes a blocking `KeyProvider` usable as an `AsyncKeyProvider`, each
+/// lookup running on `tokio::task::spawn_blocking`.
+#[cfg(feature = "tokio")]
+pub struct SpawnBlocking<P>(pub Arc<P>);
+
+#[cfg(feature = "tokio")]
+impl<P: KeyProvider + Send + Sync + 'static> AsyncKeyProvider for SpawnBlocking<P> {
+    fn key_for(&self, key_id: &str) -> impl Future<Output = Result<Key, ProviderError>> + Send {
+        let provider = Arc::clone(&self.0);
+        let key_id = String::from(key_id);
+        async move {
+            match tokio::task::spawn_blocking(move || provider.key_for(&key_id)).await {
+                Ok(result) => result,
+                Err(e) => Err(ProviderError::ProviderBackendError(e.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::{encrypt_bytes_with_iv, Iv};
+
+    struct InMemoryProvider {
+        keys: HashMap<String, [u8; 32]>
+    }
+
+    impl KeyProvider for InMemoryProvider {
+        fn key_for(&self, key_id: &str) -> Result<Key, ProviderError> {
+            match self.keys.get(key_id) {
+                Some(u8_array) => Ok(Key { u8_array: *u8_array }),
+                None => Err(ProviderError::UnknownKeyIdError(String::from(key_id)))
+            }
+        }
+    }
+
+    fn provider() -> InMemoryProvider {
+        let mut keys = HashMap::new();
+        keys.insert(String::from("tenant-1"), [1u8; 32]);
+        keys.insert(String::from("tenant-2"), [2u8; 32]);
+        InMemoryProvider { keys }
+    }
+
+    fn sample() -> EncryptedAndIv {
+        encrypt_bytes_with_iv(&Key { u8_array: [1u8; 32] }, Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap()
+    }
+
+    #[test]
+    fn resolves_the_key_by_id() {
+        assert_eq!(decrypt_with_provider(&provider(), "tenant-1", &sample()).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn provider_and_decryption_failures_are_distinct() {
+        match decrypt_with_provider(&provider(), "tenant-3", &sample()) {
+            Ok(_) => assert!(false, "Should err KeyProviderError"),
+            Err(e) => match e {
+                ProviderDecryptError::KeyProviderError(ProviderError::UnknownKeyIdError(key_id)) => assert_eq!(key_id, "tenant-3"),
+                _ => assert!(false, "Should err KeyProviderError")
+            }
+        }
+        match decrypt_with_provider(&provider(), "tenant-2", &sample()) {
+            Ok(_) => assert!(false, "Should err ProviderDecryptionError"),
+            Err(e) => match e {
+                ProviderDecryptError::ProviderDecryptionError(DecryptionError::GenericDecryptionError) => assert!(true),
+                _ => assert!(false, "Should err ProviderDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn key_ring_is_a_provider() {
+        use crate::key_ring::{KeyMetadata, KeyRing};
+        use std::time::SystemTime;
+
+        let mut ring = KeyRing::new("tenant-1", Key { u8_array: [1u8; 32] }, KeyMetadata::new("first", SystemTime::now()));
+        ring.add("tenant-2", Key { u8_array: [2u8; 32] }, KeyMetadata::new("second", SystemTime::now()));
+        assert_eq!(decrypt_with_provider(&ring, "tenant-1", &sample()).unwrap(), b"This is a text.");
+        ring.metadata_mut("tenant-1").unwrap().enabled = false;
+        match decrypt_with_provider(&ring, "tenant-1", &sample()) {
+            Err(ProviderDecryptError::KeyProviderError(ProviderError::KeyUnavailableError(_))) => assert!(true),
+            _ => assert!(false, "Should err KeyUnavailableError")
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    struct RemoteProvider;
+
+    #[cfg(feature = "tokio")]
+    impl AsyncKeyProvider for RemoteProvider {
+        fn key_for(&self, key_id: &str) -> impl Future<Output = Result<Key, ProviderError>> + Send {
+            let key_id = String::from(key_id);
+            async move {
+                tokio::task::yield_now().await;
+                match key_id.as_str() {
+                    "tenant-1" => Ok(Key { u8_array: [1u8; 32] }),
+                    "offline" => Err(ProviderError::ProviderBackendError(String::from("connection refused"))),
+                    _ => Err(ProviderError::UnknownKeyIdError(key_id))
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio")]
+    async fn async_provider_under_tokio() {
+        assert_eq!(decrypt_with_async_provider(&RemoteProvider, "tenant-1", &sample()).await.unwrap(), b"This is a text.");
+        match decrypt_with_async_provider(&RemoteProvider, "offline", &sample()).await {
+            Err(ProviderDecryptError::KeyProviderError(ProviderError::ProviderBackendError(_))) => assert!(true),
+            _ => assert!(false, "Should err ProviderBackendError")
+        }
+        let blocking = SpawnBlocking(Arc::new(provider()));
+        assert_eq!(decrypt_with_async_provider(&blocking, "tenant-1", &sample()).await.unwrap(), b"This is a text.");
+        match decrypt_with_async_provider(&blocking, "tenant-2", &sample()).await {
+            Err(ProviderDecryptError::ProviderDecryptionError(_)) => assert!(true),
+            _ => assert!(false, "Should err ProviderDecryptionError")
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Values encrypted at rest in Redis.
+//!
+//! `EncryptedRedis` wraps any `redis::ConnectionLike` (a `Connection`, a
+//! `Client`, a cluster connection). `set` stores `iv || ciphertext and tag`,
+//! with a fresh iv, and `get` decrypts it back. The Redis key name is
+//! authenticated as associated data (`"simple-aes256-gcm redis:" || name`):
+//! a value copied to another key by someone with access to the cache no
+//! longer decrypts. A value that doesn't decrypt, whether corrupted,
+//! written by something else or moved, is a `RedisDecryptionError`, never
+//! a miss.
+//!
+//! Async connections (`aio::ConnectionManager`) can store and read
+//! `seal_value` and `open_value` themselves, the same format.
+
+use std::time::Duration;
+use std::{error, fmt};
+
+use redis::ConnectionLike;
+
+use crate::{decrypt_bytes_with_aad, encrypt_bytes_with_aad, DecryptionError, Encrypted, EncryptedAndIv, EncryptionError, Iv, Key, IV_LEN, TAG_LEN};
+
+const KEY_AAD_PREFIX: &str = "simple-aes256-gcm redis:";
+
+#[derive(Debug)]
+pub enum EncryptedRedisError {
+    RedisCommandError(redis::RedisError),
+    RedisEncryptionError(EncryptionError),
+    /// The stored value is corrupted, foreign, or was written under another key name.
+    RedisDecryptionError(DecryptionError)
+}
+
+impl fmt::Display for EncryptedRedisError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncryptedRedisError::RedisCommandError(e) => write!(f, "{}", e),
+            EncryptedRedisError::RedisEncryptionError(e) => write!(f, "{}", e),
+            EncryptedRedisError::RedisDecryptionError(e) => write!(f, "Could not decrypt the cached value: {}", e),
+        }
+    }
+}
+
+impl error::Error for EncryptedRedisError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            EncryptedRedisError::RedisCommandError(e) => Some(e),
+            EncryptedRedisError::RedisEncryptionError(e) => Some(e),
+            EncryptedRedisError::RedisDecryptionError(e) => Some(e),
+        }
+    }
+}
+
+impl From<redis::RedisError> for EncryptedRedisError {
+    fn from(e: redis::RedisError) -> EncryptedRedisError {
+        EncryptedRedisError::RedisCommandError(e)
+    }
+}
+
+fn key_aad(name: &str) -> Vec<u8> {
+    format!("{}{}", KEY_AAD_PREFIX, name).into_bytes()
+}
+
+/// The value `EncryptedRedis::set` stores under `name`.
+pub fn seal_value(key: &Key, name: &str, plaintext: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    let encrypted_and_iv = encrypt_bytes_with_aad(key, plaintext, &key_aad(name))?;
+    let mut value = Vec::with_capacity(IV_LEN + encrypted_and_iv.encrypted.u8_vec.len());
+    value.extend_from_slice(&encrypted_and_iv.iv.u8_array);
+    value.extend_from_slice(&encrypted_and_iv.encrypted.u8_vec);
+    Ok(value)
+}
+
+/// Decrypts a value stored under `name` by `seal_value`.
+pub fn open_value(key: &Key, name: &str, value: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+    if value.len() < IV_LEN + TAG_LEN {
+        return Err(DecryptionError::GenericDecryptionError);
+    }
+    let mut u8_array = [0u8; IV_LEN];
+    u8_array.copy_from_slice(&value[..IV_LEN]);
+    let encrypted_and_iv = EncryptedAndIv::from_parts(Iv { u8_array }, Encrypted { u8_vec: value[IV_LEN..].to_vec() });
+    decrypt_bytes_with_aad(key, &encrypted_and_iv, &key_aad(name))
+}
+
+/// Wiped on drop.
+struct OwnedKey(Key);
+
+impl Drop for OwnedKey {
+    fn drop(&mut self) {
+        crate::secure_buffer::wipe(&mut self.0.u8_array);
+    }
+}
+
+pub struct EncryptedRedis<C: ConnectionLike> {
+    key: OwnedKey,
+    connection: C
+}
+
+impl<C: ConnectionLike> EncryptedRedis<C> {
+    pub fn new(key: &Key, connection: C) -> EncryptedRedis<C> {
+        EncryptedRedis { key: OwnedKey(Key { u8_array: key.u8_array }), connection }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.connection
+    }
+
+    /// `SET name <encrypted value> [PX ttl]`, replacing any previous value
+    /// and expiry. A `ttl` below a millisecond is rejected by Redis.
+    pub fn set(&mut self, name: &str, plaintext: &[u8], ttl: Option<Duration>) -> Result<(), EncryptedRedisError> {
+        let value = seal_value(&self.key.0, name, plaintext).map_err(EncryptedRedisError::RedisEncryptionError)?;
+        let mut command = redis::cmd("SET");
+        command.arg(name).arg(value);
+        if let Some(ttl) = ttl {
+            command.arg("PX").arg(ttl.as_millis() as u64);
+        }
+        Ok(command.exec(&mut self.connection)?)
+    }
+
+    /// `None` only when `name` doesn't exist.
+    pub fn get(&mut self, name: &str) -> Result<Option<Vec<u8>>, EncryptedRedisError> {
+        let value: Option<Vec<u8>> = redis::cmd("GET").arg(name).query(&mut self.connection)?;
+        match value {
+            Some(value) => match open_value(&self.key.0, name, &value) {
+                Ok(plaintext) => Ok(Some(plaintext)),
+                Err(e) => Err(EncryptedRedisError::RedisDecryptionError(e))
+            },
+            None => Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use redis::{Arg, Cmd, ErrorKind, RedisError, RedisResult, Value};
+    use std::collections::HashMap;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    /// Answers SET and GET from a map, recording every command.
+    #[derive(Default)]
+    struct MockConnection {
+        values: HashMap<Vec<u8>, Vec<u8>>,
+        commands: Vec<Vec<Vec<u8>>>
+    }
+
+    impl ConnectionLike for MockConnection {
+        fn req_command(&mut self, cmd: &Cmd) -> RedisResult<Value> {
+            let args: Vec<Vec<u8>> = cmd.args_iter().filter_map(|arg| match arg {
+                Arg::Simple(bytes) => Some(bytes.to_vec()),
+                Arg::Cursor => None
+            }).collect();
+            self.commands.push(args.clone());
+            match &args[0][..] {
+                b"SET" => {
+                    self.values.insert(args[1].clone(), args[2].clone());
+                    Ok(Value::Okay)
+                },
+                b"GET" => Ok(self.values.get(&args[1]).map_or(Value::Nil, |value| Value::BulkString(value.clone()))),
+                _ => Err(RedisError::from((ErrorKind::ClientError, "not mocked")))
+            }
+        }
+
+        fn req_packed_command(&mut self, _: &[u8]) -> RedisResult<Value> {
+            Err(RedisError::from((ErrorKind::ClientError, "not mocked")))
+        }
+
+        fn req_packed_commands(&mut self, _: &[u8], _: usize, _: usize) -> RedisResult<Vec<Value>> {
+            Err(RedisError::from((ErrorKind::ClientError, "not mocked")))
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+
+        fn check_connection(&mut self) -> bool {
+            true
+        }
+
+        fn is_open(&self) -> bool {
+            true
+        }
+    }
+
+    fn cache() -> EncryptedRedis<MockConnection> {
+        EncryptedRedis::new(&Key { u8_array: KEY }, MockConnection::default())
+    }
+
+    fn assert_decryption_error(result: Result<Option<Vec<u8>>, EncryptedRedisError>) {
+        match result {
+            Ok(_) => assert!(false, "Should err RedisDecryptionError"),
+            Err(e) => match e {
+                EncryptedRedisError::RedisDecryptionError(_) => assert!(true),
+                _ => assert!(false, "Should err RedisDecryptionError, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn values_round_trip_encrypted_at_rest() {
+        let mut cache = cache();
+        cache.set("user:1:email", b"a@example.com", Some(Duration::from_secs(60))).unwrap();
+        assert_eq!(cache.get("user:1:email").unwrap().unwrap(), b"a@example.com");
+        assert_eq!(cache.get("user:2:email").unwrap(), None);
+
+        let connection = cache.into_inner();
+        let stored = &connection.values[&b"user:1:email".to_vec()];
+        assert_eq!(stored.len(), IV_LEN + 13 + TAG_LEN);
+        assert!(!stored.windows(13).any(|window| window == b"a@example.com"));
+        assert_eq!(connection.commands[0][3..], [b"PX".to_vec(), b"60000".to_vec()])
+    }
+
+    #[test]
+    fn fresh_iv_per_write() {
+        let mut cache = cache();
+        cache.set("k", b"same", None).unwrap();
+        let first = cache.connection.values[&b"k".to_vec()].clone();
+        cache.set("k", b"same", None).unwrap();
+        assert_ne!(cache.connection.values[&b"k".to_vec()], first);
+        assert_eq!(cache.connection.commands[0].len(), 3)
+    }
+
+    #[test]
+    fn moved_corrupted_or_foreign_values_are_errors_not_misses() {
+        let mut cache = cache();
+        cache.set("user:1:email", b"a@example.com", None).unwrap();
+        let stored = cache.connection.values[&b"user:1:email".to_vec()].clone();
+
+        cache.connection.values.insert(b"user:2:email".to_vec(), stored.clone());
+        assert_decryption_error(cache.get("user:2:email"));
+
+        let mut corrupted = stored.clone();
+        corrupted[IV_LEN] ^= 1;
+        cache.connection.values.insert(b"user:1:email".to_vec(), corrupted);
+        assert_decryption_error(cache.get("user:1:email"));
+
+        cache.connection.values.insert(b"user:1:email".to_vec(), b"plain".to_vec());
+        assert_decryption_error(cache.get("user:1:email"));
+
+        let mut other_key = EncryptedRedis::new(&Key { u8_array: [0u8; 32] }, MockConnection::default());
+        other_key.connection.values.insert(b"user:1:email".to_vec(), stored);
+        assert_decryption_error(other_key.get("user:1:email"))
+    }
+
+    #[test]
+    fn seal_and_open_match_the_wrapper() {
+        let mut cache = cache();
+        cache.set("k", b"value", None).unwrap();
+        assert_eq!(open_value(&Key { u8_array: KEY }, "k", &cache.connection.values[&b"k".to_vec()]).unwrap(), b"value");
+        let sealed = seal_value(&Key { u8_array: KEY }, "k", b"other").unwrap();
+        cache.connection.values.insert(b"k".to_vec(), sealed);
+        assert_eq!(cache.get("k").unwrap().unwrap(), b"other")
+    }
+}
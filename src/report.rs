@@ -0,0 +1,151 @@
+//! Decrypting and reporting what produced the value, e.g. to schedule
+//! migrations of data of mixed ages.
+//!
+//! `decrypt_with_report` takes a `Key` and an `EncryptedAndIv`, or a
+//! `KeyRing` and a `KeyRingEncrypted`. A ring first tries the key the value
+//! names, then, if that key is unknown, disabled or doesn't decrypt, every
+//! other enabled key in id order; `KeyRing::decrypt` never falls back. The
+//! report only holds non-secret facts: lengths, versions, ids and the key
+//! fingerprint, never key bytes, ivs, associated data or plaintext.
+
+use crate::{decrypt_bytes_with_aad, DecryptionError, EncryptedAndIv, Key};
+
+/// The only cipher envelopes are produced with, for `DecryptReport::algorithm`.
+pub const ALGORITHM: &str = "AES-256-GCM";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecryptReport {
+    pub algorithm: &'static str,
+    /// `EncryptedAndIv::format_version`.
+    pub format_version: u8,
+    /// `Key::fingerprint` of the key that decrypted.
+    pub key_fingerprint: String,
+    /// Ring id of the key that decrypted; `None` for a `Key`.
+    pub key_id: Option<String>,
+    /// The key id the value named, when another key decrypted it.
+    pub fallback_from: Option<String>,
+    /// `EncryptedAndIv::key_id`.
+    pub envelope_key_id: Option<[u8; 4]>,
+    pub kcv_present: bool,
+    /// Length of the associated data decrypted with, 0 if none.
+    pub aad_len: usize,
+    /// Whether the envelope records an `aad_digest`.
+    pub aad_digest_present: bool,
+    /// Ciphertext and tag.
+    pub ciphertext_len: usize,
+    pub plaintext_len: usize
+}
+
+impl DecryptReport {
+    pub(crate) fn new(key: &Key, encrypted_and_iv: &EncryptedAndIv, aad: &[u8], plaintext: &[u8]) -> DecryptReport {
+        DecryptReport {
+            algorithm: ALGORITHM,
+            format_version: encrypted_and_iv.format_version,
+            key_fingerprint: key.fingerprint(),
+            key_id: None,
+            fallback_from: None,
+            envelope_key_id: encrypted_and_iv.key_id,
+            kcv_present: encrypted_and_iv.kcv.is_some(),
+            aad_len: aad.len(),
+            aad_digest_present: encrypted_and_iv.aad_digest.is_some(),
+            ciphertext_len: encrypted_and_iv.encrypted.u8_vec.len(),
+            plaintext_len: plaintext.len()
+        }
+    }
+}
+
+/// What `decrypt_with_report` decrypts with: a `Key`, or a `KeyRing`.
+pub trait ReportingDecrypt {
+    type Input;
+    type Error;
+
+    fn decrypt_with_report(&self, input: &Self::Input, aad: &[u8]) -> Result<(Vec<u8>, DecryptReport), Self::Error>;
+}
+
+impl ReportingDecrypt for Key {
+    type Input = EncryptedAndIv;
+    type Error = DecryptionError;
+
+    fn decrypt_with_report(&self, input: &EncryptedAndIv, aad: &[u8]) -> Result<(Vec<u8>, DecryptReport), DecryptionError> {
+        let plaintext = decrypt_bytes_with_aad(self, input, aad)?;
+        let report = DecryptReport::new(self, input, aad, &plaintext);
+        Ok((plaintext, report))
+    }
+}
+
+/// Same as `decrypt_bytes`, also returning a `DecryptReport`.
+pub fn decrypt_with_report<K: ReportingDecrypt + ?Sized>(keys: &K, input: &K::Input) -> Result<(Vec<u8>, DecryptReport), K::Error> {
+    keys.decrypt_with_report(input, &[])
+}
+
+/// Same as `decrypt_bytes_with_aad`, also returning a `DecryptReport`.
+pub fn decrypt_with_report_and_aad<K: ReportingDecrypt + ?Sized>(keys: &K, input: &K::Input, aad: &[u8]) -> Result<(Vec<u8>, DecryptReport), K::Error> {
+    keys.decrypt_with_report(input, aad)
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::{aad_digest, decrypt_bytes, encrypt_bytes_with_iv, encrypt_bytes_with_iv_and_aad, Iv, FORMAT_VERSION};
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    fn key() -> Key {
+        Key { u8_array: KEY }
+    }
+
+    #[test]
+    fn reports_every_field_for_both_format_versions() {
+        let current = encrypt_bytes_with_iv(&key(), Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap();
+        let (plaintext, report) = decrypt_with_report(&key(), &current).unwrap();
+        assert_eq!(plaintext, b"This is a text.");
+        assert_eq!(report, DecryptReport {
+            algorithm: "AES-256-GCM",
+            format_version: FORMAT_VERSION,
+            key_fingerprint: key().fingerprint(),
+            key_id: None,
+            fallback_from: None,
+            envelope_key_id: None,
+            kcv_present: false,
+            aad_len: 0,
+            aad_digest_present: false,
+            ciphertext_len: 15 + 16,
+            plaintext_len: 15
+        });
+
+        let legacy = encrypt_bytes_with_iv_and_aad(&key(), Iv { u8_array: *b"012345678901" }, b"Old", b"session").unwrap()
+            .with_format_version(0)
+            .with_key_id(*b"k001")
+            .with_kcv(key().check_value())
+            .with_aad_digest(aad_digest(b"session"));
+        let (plaintext, report) = decrypt_with_report_and_aad(&key(), &legacy, b"session").unwrap();
+        assert_eq!(plaintext, b"Old");
+        assert_eq!(report, DecryptReport {
+            algorithm: "AES-256-GCM",
+            format_version: 0,
+            key_fingerprint: key().fingerprint(),
+            key_id: None,
+            fallback_from: None,
+            envelope_key_id: Some(*b"k001"),
+            kcv_present: true,
+            aad_len: 7,
+            aad_digest_present: true,
+            ciphertext_len: 3 + 16,
+            plaintext_len: 3
+        });
+        assert_eq!(decrypt_bytes(&key(), &current).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn failures_are_those_of_decrypt() {
+        let encrypted_and_iv = encrypt_bytes_with_iv(&key(), Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap();
+        match decrypt_with_report(&Key { u8_array: [0u8; 32] }, &encrypted_and_iv) {
+            Ok(_) => assert!(false, "Should err DecryptionError::GenericDecryptionError"),
+            Err(e) => match e {
+                DecryptionError::GenericDecryptionError => assert!(true),
+                _ => assert!(false, "Should err DecryptionError::GenericDecryptionError, got {}", e)
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Re-encrypting stored values under a new key.
+//!
+//! `rotate_all` never stops at a failed item: every item gets a result,
+//! and the report counts them. Items whose key check value (see
+//! `encrypt_with_kcv`) already matches the new key are passed through
+//! untouched and counted as skipped, so an interrupted rotation can simply
+//! be run again.
+
+use std::{error, fmt};
+
+use crate::{decrypt_bytes, encrypt_bytes, DecryptionError, EncryptedAndIv, EncryptionError, Key};
+
+#[derive(Debug, Clone)]
+pub enum ReencryptError {
+    /// Wrong old key, or a corrupted value.
+    ReencryptDecryptionError(DecryptionError),
+    ReencryptEncryptionError(EncryptionError)
+}
+
+impl fmt::Display for ReencryptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReencryptError::ReencryptDecryptionError(e) => write!(f, "Re-encryption failed: {}", e),
+            ReencryptError::ReencryptEncryptionError(e) => write!(f, "Re-encryption failed: {}", e),
+        }
+    }
+}
+
+impl error::Error for ReencryptError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ReencryptError::ReencryptDecryptionError(e) => Some(e),
+            ReencryptError::ReencryptEncryptionError(e) => Some(e),
+        }
+    }
+}
+
+/// Decrypts with `old` and encrypts with `new` under a fresh iv. A key
+/// check value in the input is replaced by the new key's.
+pub fn reencrypt(old: &Key, new: &Key, encrypted_and_iv: &EncryptedAndIv) -> Result<EncryptedAndIv, ReencryptError> {
+    let plaintext = decrypt_bytes(old, encrypted_and_iv).map_err(ReencryptError::ReencryptDecryptionError)?;
+    let mut reencrypted = encrypt_bytes(new, &plaintext).map_err(ReencryptError::ReencryptEncryptionError)?;
+    if encrypted_and_iv.kcv.is_some() {
+        reencrypted.kcv = Some(new.check_value());
+    }
+    Ok(reencrypted)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RotationSummary {
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Already under the new key according to their key check value.
+    pub skipped: usize
+}
+
+impl RotationSummary {
+    pub fn processed(&self) -> usize {
+        self.succeeded + self.failed + self.skipped
+    }
+}
+
+pub struct RotationReport<Id> {
+    /// One entry per input item, in input order. Skipped items are `Ok`
+    /// with the original value.
+    pub results: Vec<(Id, Result<EncryptedAndIv, ReencryptError>)>,
+    pub summary: RotationSummary
+}
+
+pub fn rotate_all<I, Id>(old: &Key, new: &Key, items: I) -> RotationReport<Id>
+where I: IntoIterator<Item = (Id, EncryptedAndIv)> {
+    rotate_all_with_progress(old, new, items, 0, |_| {})
+}
+
+/// Like `rotate_all`, calling `progress` after every `every` items
+/// (never when `every` is 0).
+pub fn rotate_all_with_progress<I, Id, F>(old: &Key, new: &Key, items: I, every: usize, mut progress: F) -> RotationReport<Id>
+where I: IntoIterator<Item = (Id, EncryptedAndIv)>, F: FnMut(&RotationSummary) {
+    let new_kcv = new.check_value();
+    let mut report = RotationReport { results: Vec::new(), summary: RotationSummary::default() };
+    for (id, encrypted_and_iv) in items {
+        let result = if encrypted_and_iv.kcv == Some(new_kcv) {
+            report.summary.skipped += 1;
+            Ok(encrypted_and_iv)
+        } else {
+            let result = reencrypt(old, new, &encrypted_and_iv);
+            match result {
+                Ok(_) => report.summary.succeeded += 1,
+                Err(_) => report.summary.failed += 1
+            }
+            result
+        };
+        report.results.push((id, result));
+        if every != 0 && report.summary.processed() % every == 0 {
+            progress(&report.summary);
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::{decrypt, encrypt, encrypt_with_kcv, Decrypted};
+
+    #[test]
+    fn rotate_all_reports_failures_and_skips_without_aborting() {
+        let old = Key { u8_array: [1u8; 32] };
+        let new = Key { u8_array: [2u8; 32] };
+        let mut corrupted = encrypt(&old, &Decrypted::from("corrupted")).unwrap();
+        corrupted.encrypted.u8_vec[0] ^= 1;
+        let items = vec![
+            ("a", encrypt(&old, &Decrypted::from("first")).unwrap()),
+            ("b", corrupted),
+            ("c", encrypt_with_kcv(&new, &Decrypted::from("already rotated")).unwrap()),
+            ("d", encrypt_with_kcv(&old, &Decrypted::from("last")).unwrap())
+        ];
+
+        let mut calls = Vec::new();
+        let report = rotate_all_with_progress(&old, &new, items, 2, |summary| calls.push(*summary));
+        assert_eq!(report.summary, RotationSummary { succeeded: 2, failed: 1, skipped: 1 });
+        assert_eq!(calls, vec![
+            RotationSummary { succeeded: 1, failed: 1, skipped: 0 },
+            RotationSummary { succeeded: 2, failed: 1, skipped: 1 }
+        ]);
+
+        let mut results = report.results.into_iter();
+        let (id, a) = results.next().unwrap();
+        assert_eq!((id, decrypt(&new, a.unwrap()).unwrap()), ("a", String::from("first")));
+        match results.next().unwrap() {
+            ("b", Err(ReencryptError::ReencryptDecryptionError(DecryptionError::GenericDecryptionError))) => assert!(true),
+            _ => assert!(false, "Should err ReencryptDecryptionError for b")
+        }
+        let (id, c) = results.next().unwrap();
+        assert_eq!((id, decrypt(&new, c.unwrap()).unwrap()), ("c", String::from("already rotated")));
+        let (id, d) = results.next().unwrap();
+        let d = d.unwrap();
+        assert_eq!(d.kcv, Some(new.check_value()));
+        assert_eq!((id, decrypt(&new, d).unwrap()), ("d", String::from("last")))
+    }
+
+    #[test]
+    fn reencrypt_with_wrong_old_key_fails() {
+        let encrypted_and_iv = encrypt_with_kcv(&Key { u8_array: [1u8; 32] }, &Decrypted::from("value")).unwrap();
+        match reencrypt(&Key { u8_array: [3u8; 32] }, &Key { u8_array: [2u8; 32] }, &encrypted_and_iv) {
+            Ok(_) => assert!(false, "Should err ReencryptDecryptionError"),
+            Err(e) => match e {
+                ReencryptError::ReencryptDecryptionError(DecryptionError::ProbableWrongKey) => assert!(true),
+                _ => assert!(false, "Should err ReencryptDecryptionError")
+            }
+        }
+    }
+}
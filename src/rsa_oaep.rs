@@ -0,0 +1,409 @@
+//! Encrypt for a recipient that only holds an RSA key pair, e.g. a Java or
+//! .NET service whose keys live in a keystore.
+//!
+//! `seal_for_rsa` encrypts the payload with AES-256-GCM under a random data
+//! key (DEK) and wraps that DEK with RSA-OAEP: SHA-256 as the hash and as
+//! the MGF1 hash, no label. Java's `RSA/ECB/OAEPWithSHA-256AndMGF1Padding`
+//! uses SHA-1 for MGF1 by default, so Java callers must pass
+//! `new OAEPParameterSpec("SHA-256", "MGF1", MGF1ParameterSpec.SHA256, PSource.PSpecified.DEFAULT)`;
+//! .NET's `RSAEncryptionPadding.OaepSHA256` and
+//! `openssl pkeyutl -pkeyopt rsa_padding_mode:oaep -pkeyopt rsa_oaep_md:sha256 -pkeyopt rsa_mgf1_md:sha256`
+//! match as they are. The payload has no AAD, and its tag is the last 16
+//! bytes of the ciphertext.
+//!
+//! Recipient keys are accepted as SubjectPublicKeyInfo DER or PEM
+//! (`PUBLIC KEY`) or PKCS#1 PEM (`RSA PUBLIC KEY`), with a modulus of at
+//! least 2048 bits. Private keys are PKCS#8 (`PRIVATE KEY`) or PKCS#1
+//! (`RSA PRIVATE KEY`) PEM; encrypted PEM isn't supported.
+//!
+//! The RSA decryption in the `rsa` crate isn't constant-time
+//! (RUSTSEC-2023-0071): don't run `open_with_rsa` where an attacker can
+//! submit envelopes and time the answers.
+//!
+//! Binary form, all integers big-endian:
+//!
+//! ```text
+//! version (1) | wrapped DEK length (2) | wrapped DEK | payload iv (12) | payload ciphertext and tag
+//! ```
+//!
+//! With the `json` feature the same fields are available as JSON, byte
+//! strings being base64-encoded, along with `"alg": "RSA-OAEP-256"` and
+//! `"enc": "A256GCM"`.
+
+use std::convert::{TryFrom, TryInto};
+use std::{error, fmt};
+
+use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey};
+use rsa::pkcs8::der::pem;
+use rsa::pkcs8::spki::SubjectPublicKeyInfoRef;
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, PrivateKeyInfo};
+use rsa::rand_core::OsRng;
+use rsa::traits::PublicKeyParts;
+use rsa::{Oaep, RsaPrivateKey, RsaPublicKey};
+use sha2::Sha256;
+
+use crate::secure_buffer::wipe;
+use crate::{decrypt_bytes, encrypt_bytes, DecryptionError, Encrypted, EncryptedAndIv, Iv, Key};
+
+const VERSION: u8 = 1;
+
+/// Smallest recipient modulus `seal_for_rsa` accepts.
+pub const MIN_MODULUS_BITS: usize = 2048;
+
+#[derive(Debug, Clone)]
+pub enum RsaError {
+    /// Neither DER nor PEM of a supported structure.
+    InvalidRsaKeyError,
+    /// A well-formed key or PEM block that isn't the expected kind of RSA key, e.g. an EC key.
+    WrongKeyTypeError(String),
+    /// The recipient's modulus is shorter than `MIN_MODULUS_BITS`.
+    RsaModulusTooSmallError(usize),
+    /// Wrapping the DEK or encrypting the payload failed.
+    RsaEncryptionError,
+    /// The private key doesn't unwrap the envelope's DEK.
+    KeyUnwrapError,
+    /// The payload failed authentication after the DEK was unwrapped.
+    PayloadDecryptionError(DecryptionError),
+    /// Malformed binary or JSON envelope.
+    InvalidEnvelopeError
+}
+
+impl fmt::Display for RsaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RsaError::InvalidRsaKeyError => write!(f, "Invalid RSA key, expected DER or PEM"),
+            RsaError::WrongKeyTypeError(found) => write!(f, "Expected an RSA key, got {}", found),
+            RsaError::RsaModulusTooSmallError(bits) => write!(f, "RSA modulus of {} bits, at least {} required", bits, MIN_MODULUS_BITS),
+            RsaError::RsaEncryptionError => write!(f, "RSA-OAEP encryption failed"),
+            RsaError::KeyUnwrapError => write!(f, "The private key doesn't unwrap this envelope"),
+            RsaError::PayloadDecryptionError(e) => write!(f, "{}", e),
+            RsaError::InvalidEnvelopeError => write!(f, "Invalid RSA envelope"),
+        }
+    }
+}
+
+impl error::Error for RsaError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RsaError::PayloadDecryptionError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RsaEnvelope {
+    pub wrapped_key: Vec<u8>,
+    pub iv: [u8; 12],
+    pub ciphertext: Vec<u8>
+}
+
+/// An RSA public key checked to be usable as a recipient.
+#[derive(Debug, Clone)]
+pub struct RsaRecipientKey {
+    public_key: RsaPublicKey
+}
+
+impl RsaRecipientKey {
+    /// Parses SubjectPublicKeyInfo DER or PEM, or PKCS#1 PEM.
+    pub fn from_der_or_pem(bytes: &[u8]) -> Result<RsaRecipientKey, RsaError> {
+        let public_key = if is_pem(bytes) {
+            let (label, der) = pem::decode_vec(bytes).map_err(|_| RsaError::InvalidRsaKeyError)?;
+            match label {
+                "PUBLIC KEY" => spki_public_key(&der)?,
+                "RSA PUBLIC KEY" => RsaPublicKey::from_pkcs1_der(&der).map_err(|_| RsaError::InvalidRsaKeyError)?,
+                other => return Err(RsaError::WrongKeyTypeError(String::from(other)))
+            }
+        } else {
+            spki_public_key(bytes)?
+        };
+        let bits = public_key.n().bits();
+        if bits < MIN_MODULUS_BITS {
+            return Err(RsaError::RsaModulusTooSmallError(bits));
+        }
+        Ok(RsaRecipientKey { public_key })
+    }
+
+    /// Modulus length in bits.
+    pub fn bits(&self) -> usize {
+        self.public_key.n().bits()
+    }
+}
+
+fn is_pem(bytes: &[u8]) -> bool {
+    bytes.iter().position(|byte| !byte.is_ascii_whitespace()).is_some_and(|start| bytes[start..].starts_with(b"-----BEGIN "))
+}
+
+fn spki_public_key(der: &[u8]) -> Result<RsaPublicKey, RsaError> {
+    let info = SubjectPublicKeyInfoRef::try_from(der).map_err(|_| RsaError::InvalidRsaKeyError)?;
+    if info.algorithm.oid != rsa::pkcs1::ALGORITHM_OID {
+        return Err(RsaError::WrongKeyTypeError(format!("a public key with algorithm {}", info.algorithm.oid)));
+    }
+    RsaPublicKey::from_public_key_der(der).map_err(|_| RsaError::InvalidRsaKeyError)
+}
+
+fn private_key(private_key_pem: &str) -> Result<RsaPrivateKey, RsaError> {
+    let (label, der) = pem::decode_vec(private_key_pem.as_bytes()).map_err(|_| RsaError::InvalidRsaKeyError)?;
+    let result = match label {
+        "PRIVATE KEY" => {
+            let info = PrivateKeyInfo::try_from(der.as_slice()).map_err(|_| RsaError::InvalidRsaKeyError)?;
+            if info.algorithm.oid != rsa::pkcs1::ALGORITHM_OID {
+                Err(RsaError::WrongKeyTypeError(format!("a private key with algorithm {}", info.algorithm.oid)))
+            } else {
+                RsaPrivateKey::from_pkcs8_der(&der).map_err(|_| RsaError::InvalidRsaKeyError)
+            }
+        },
+        "RSA PRIVATE KEY" => RsaPrivateKey::from_pkcs1_der(&der).map_err(|_| RsaError::InvalidRsaKeyError),
+        other => Err(RsaError::WrongKeyTypeError(String::from(other)))
+    };
+    let mut der = der;
+    wipe(&mut der);
+    result
+}
+
+/// Encrypts `plaintext` under a fresh data key wrapped for the RSA key in
+/// `recipient_spki_der_or_pem`.
+pub fn seal_for_rsa(recipient_spki_der_or_pem: &[u8], plaintext: &[u8]) -> Result<RsaEnvelope, RsaError> {
+    seal_for_rsa_key(&RsaRecipientKey::from_der_or_pem(recipient_spki_der_or_pem)?, plaintext)
+}
+
+/// `seal_for_rsa` with an already parsed recipient, for sealing many messages.
+pub fn seal_for_rsa_key(recipient: &RsaRecipientKey, plaintext: &[u8]) -> Result<RsaEnvelope, RsaError> {
+    let dek = Key::generate();
+    let wrapped_key = recipient.public_key.encrypt(&mut OsRng, Oaep::new::<Sha256>(), &dek.u8_array).map_err(|_| RsaError::RsaEncryptionError)?;
+    let payload = encrypt_bytes(&dek, plaintext).map_err(|_| RsaError::RsaEncryptionError)?;
+    Ok(RsaEnvelope {
+        wrapped_key,
+        iv: payload.iv.u8_array,
+        ciphertext: payload.encrypted.u8_vec
+    })
+}
+
+/// Unwraps the envelope's data key with `private_key_pem` and decrypts the payload.
+pub fn open_with_rsa(private_key_pem: &str, envelope: &RsaEnvelope) -> Result<Vec<u8>, RsaError> {
+    let private_key = private_key(private_key_pem)?;
+    let mut unwrapped = private_key.decrypt(Oaep::new::<Sha256>(), &envelope.wrapped_key).map_err(|_| RsaError::KeyUnwrapError)?;
+    let dek = unwrapped.as_slice().try_into().ok().map(|u8_array| Key { u8_array });
+    wipe(&mut unwrapped);
+    let dek = match dek {
+        Some(value) => value,
+        None => return Err(RsaError::KeyUnwrapError)
+    };
+    let payload = EncryptedAndIv::from_parts(Iv { u8_array: envelope.iv }, Encrypted { u8_vec: envelope.ciphertext.clone() });
+    decrypt_bytes(&dek, &payload).map_err(RsaError::PayloadDecryptionError)
+}
+
+impl RsaEnvelope {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![VERSION];
+        bytes.extend_from_slice(&(self.wrapped_key.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.wrapped_key);
+        bytes.extend_from_slice(&self.iv);
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<RsaEnvelope, RsaError> {
+        let mut reader = Reader { bytes };
+        if reader.take(1)? != [VERSION] {
+            return Err(RsaError::InvalidEnvelopeError);
+        }
+        let wrapped_len = reader.take_u16()? as usize;
+        let wrapped_key = reader.take(wrapped_len)?.to_vec();
+        let iv = reader.take_iv()?;
+        Ok(RsaEnvelope { wrapped_key, iv, ciphertext: reader.bytes.to_vec() })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8]
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], RsaError> {
+        if self.bytes.len() < len {
+            return Err(RsaError::InvalidEnvelopeError);
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn take_u16(&mut self) -> Result<u16, RsaError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn take_iv(&mut self) -> Result<[u8; 12], RsaError> {
+        Ok(self.take(12)?.try_into().unwrap())
+    }
+}
+
+#[cfg(feature = "json")]
+mod json {
+    use std::convert::TryInto;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{RsaEnvelope, RsaError, VERSION};
+
+    const ALG: &str = "RSA-OAEP-256";
+    const ENC: &str = "A256GCM";
+
+    #[derive(Serialize, Deserialize)]
+    struct JsonEnvelope {
+        version: u8,
+        alg: String,
+        enc: String,
+        wrapped_key: String,
+        iv: String,
+        ciphertext: String
+    }
+
+    fn decode(value: &str) -> Result<Vec<u8>, RsaError> {
+        base64::decode(value).map_err(|_| RsaError::InvalidEnvelopeError)
+    }
+
+    impl RsaEnvelope {
+        pub fn to_json(&self) -> String {
+            let envelope = JsonEnvelope {
+                version: VERSION,
+                alg: String::from(ALG),
+                enc: String::from(ENC),
+                wrapped_key: base64::encode(&self.wrapped_key),
+                iv: base64::encode(&self.iv),
+                ciphertext: base64::encode(&self.ciphertext)
+            };
+            serde_json::to_string(&envelope).expect("serializing plain strings can't fail")
+        }
+
+        pub fn from_json(json: &str) -> Result<RsaEnvelope, RsaError> {
+            let envelope: JsonEnvelope = serde_json::from_str(json).map_err(|_| RsaError::InvalidEnvelopeError)?;
+            if envelope.version != VERSION || envelope.alg != ALG || envelope.enc != ENC {
+                return Err(RsaError::InvalidEnvelopeError);
+            }
+            let iv = match decode(&envelope.iv)?.as_slice().try_into() {
+                Ok(value) => value,
+                Err(_) => return Err(RsaError::InvalidEnvelopeError)
+            };
+            Ok(RsaEnvelope { wrapped_key: decode(&envelope.wrapped_key)?, iv, ciphertext: decode(&envelope.ciphertext)? })
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+
+    const PRIVATE_PEM: &str = include_str!("../tests/fixtures/rsa_2048.pem");
+    const PRIVATE_PKCS1_PEM: &str = include_str!("../tests/fixtures/rsa_2048.pkcs1.pem");
+    const PUBLIC_PEM: &[u8] = include_bytes!("../tests/fixtures/rsa_2048.pub.pem");
+    const PUBLIC_DER: &[u8] = include_bytes!("../tests/fixtures/rsa_2048.pub.der");
+
+    #[test]
+    fn generated_keypair_round_trips() {
+        let private_key = RsaPrivateKey::new(&mut OsRng, 2048).unwrap();
+        let public_der = private_key.to_public_key().to_public_key_der().unwrap();
+        let private_pem = private_key.to_pkcs8_pem(LineEnding::LF).unwrap();
+        let envelope = seal_for_rsa(public_der.as_bytes(), b"This is a text.").unwrap();
+        assert_eq!(envelope.wrapped_key.len(), 256);
+        assert_eq!(open_with_rsa(&private_pem, &envelope).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn fixture_key_forms_round_trip() {
+        for public_key in [PUBLIC_PEM, PUBLIC_DER].iter() {
+            let envelope = seal_for_rsa(public_key, b"This is a text.").unwrap();
+            assert_eq!(open_with_rsa(PRIVATE_PEM, &envelope).unwrap(), b"This is a text.");
+            assert_eq!(open_with_rsa(PRIVATE_PKCS1_PEM, &envelope).unwrap(), b"This is a text.")
+        }
+    }
+
+    #[test]
+    fn opens_openssl_wrapped_fixture() {
+        // Data key wrapped with `openssl pkeyutl -encrypt -pkeyopt rsa_padding_mode:oaep
+        // -pkeyopt rsa_oaep_md:sha256 -pkeyopt rsa_mgf1_md:sha256`.
+        let envelope = RsaEnvelope::from_bytes(include_bytes!("../tests/fixtures/rsa_oaep_envelope.bin")).unwrap();
+        assert_eq!(open_with_rsa(PRIVATE_PEM, &envelope).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn undersized_modulus_is_rejected() {
+        match seal_for_rsa(include_bytes!("../tests/fixtures/rsa_1024.pub.pem"), b"This is a text.") {
+            Ok(_) => assert!(false, "Should err RsaModulusTooSmallError"),
+            Err(e) => match e {
+                RsaError::RsaModulusTooSmallError(1024) => assert!(true),
+                _ => assert!(false, "Should err RsaModulusTooSmallError, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_key_types_are_rejected() {
+        let ec_public = include_bytes!("../tests/fixtures/ec_p256.pub.pem");
+        let private_as_public = PRIVATE_PEM.as_bytes();
+        for bytes in [&ec_public[..], private_as_public].iter() {
+            match RsaRecipientKey::from_der_or_pem(bytes) {
+                Ok(_) => assert!(false, "Should err WrongKeyTypeError"),
+                Err(e) => match e {
+                    RsaError::WrongKeyTypeError(_) => assert!(true),
+                    _ => assert!(false, "Should err WrongKeyTypeError, got {}", e)
+                }
+            }
+        }
+        let envelope = seal_for_rsa(PUBLIC_PEM, b"This is a text.").unwrap();
+        match open_with_rsa(std::str::from_utf8(PUBLIC_PEM).unwrap(), &envelope) {
+            Ok(_) => assert!(false, "Should err WrongKeyTypeError"),
+            Err(e) => match e {
+                RsaError::WrongKeyTypeError(_) => assert!(true),
+                _ => assert!(false, "Should err WrongKeyTypeError, got {}", e)
+            }
+        }
+        match RsaRecipientKey::from_der_or_pem(b"not a key") {
+            Ok(_) => assert!(false, "Should err InvalidRsaKeyError"),
+            Err(e) => match e {
+                RsaError::InvalidRsaKeyError => assert!(true),
+                _ => assert!(false, "Should err InvalidRsaKeyError, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_wrapped_key_fails() {
+        let mut envelope = seal_for_rsa(PUBLIC_PEM, b"This is a text.").unwrap();
+        envelope.wrapped_key[10] ^= 1;
+        match open_with_rsa(PRIVATE_PEM, &envelope) {
+            Ok(_) => assert!(false, "Should err KeyUnwrapError"),
+            Err(e) => match e {
+                RsaError::KeyUnwrapError => assert!(true),
+                _ => assert!(false, "Should err KeyUnwrapError, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn binary_form_round_trips() {
+        let envelope = seal_for_rsa(PUBLIC_PEM, b"This is a text.").unwrap();
+        let bytes = envelope.to_bytes();
+        assert_eq!(RsaEnvelope::from_bytes(&bytes).unwrap(), envelope);
+        match RsaEnvelope::from_bytes(&bytes[..100]) {
+            Ok(_) => assert!(false, "Should err InvalidEnvelopeError"),
+            Err(e) => match e {
+                RsaError::InvalidEnvelopeError => assert!(true),
+                _ => assert!(false, "Should err InvalidEnvelopeError, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn json_form_round_trips() {
+        let envelope = seal_for_rsa(PUBLIC_PEM, b"This is a text.").unwrap();
+        let json = envelope.to_json();
+        assert!(json.contains("\"alg\":\"RSA-OAEP-256\""));
+        let parsed = RsaEnvelope::from_json(&json).unwrap();
+        assert_eq!(parsed, envelope);
+        assert_eq!(open_with_rsa(PRIVATE_PEM, &parsed).unwrap(), b"This is a text.")
+    }
+}
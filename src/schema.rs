@@ -0,0 +1,152 @@
+//! `JsonSchema` for the envelope types, describing their serde form (see
+//! `serialization`) exactly: what validates against the schema
+//! deserializes, and the other way around.
+
+use std::borrow::Cow;
+
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+
+use crate::{Encrypted, EncryptedAndIv, Iv};
+
+/// Canonical, padded standard base64: unused trailing bits must be zero.
+const CANONICAL_BASE64_PATTERN: &str = "^(?:[A-Za-z0-9+/]{4})*(?:[A-Za-z0-9+/][AQgw]==|[A-Za-z0-9+/]{2}[AEIMQUYcgkosw048]=)?$";
+
+impl JsonSchema for Iv {
+    fn schema_name() -> Cow<'static, str> {
+        "Iv".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "12-byte iv, base64",
+            "type": "string",
+            "contentEncoding": "base64",
+            "pattern": "^[A-Za-z0-9+/]{16}$",
+            "minLength": 16,
+            "maxLength": 16
+        })
+    }
+}
+
+impl JsonSchema for Encrypted {
+    fn schema_name() -> Cow<'static, str> {
+        "Encrypted".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "Ciphertext and 16-byte tag, base64",
+            "type": "string",
+            "contentEncoding": "base64",
+            "pattern": CANONICAL_BASE64_PATTERN,
+            "minLength": 24
+        })
+    }
+}
+
+impl JsonSchema for EncryptedAndIv {
+    fn schema_name() -> Cow<'static, str> {
+        "EncryptedAndIv".into()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "object",
+            "properties": {
+                "iv": generator.subschema_for::<Iv>(),
+                "encrypted": generator.subschema_for::<Encrypted>(),
+                "kcv": {
+                    "description": "3-byte key check value, base64",
+                    "type": "string",
+                    "contentEncoding": "base64",
+                    "pattern": "^[A-Za-z0-9+/]{4}$",
+                    "minLength": 4,
+                    "maxLength": 4
+                },
+                "key_id": {
+                    "description": "4-byte key id, base64",
+                    "type": "string",
+                    "contentEncoding": "base64",
+                    "pattern": "^[A-Za-z0-9+/]{5}[AQgw]==$",
+                    "minLength": 8,
+                    "maxLength": 8
+                },
+                "aad_digest": {
+                    "description": "8-byte aad digest, base64",
+                    "type": "string",
+                    "contentEncoding": "base64",
+                    "pattern": "^[A-Za-z0-9+/]{10}[AEIMQUYcgkosw048]=$",
+                    "minLength": 12,
+                    "maxLength": 12
+                },
+                "format_version": {
+                    "description": "Left out when 1",
+                    "type": "integer",
+                    "minimum": 0,
+                    "maximum": 255,
+                    "not": { "const": 1 }
+                }
+            },
+            "required": ["iv", "encrypted"],
+            "additionalProperties": false
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encrypt_bytes_with_iv, Key};
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    fn schema() -> serde_json::Value {
+        serde_json::to_value(schemars::schema_for!(EncryptedAndIv)).unwrap()
+    }
+
+    #[test]
+    fn schema_is_pinned() {
+        let golden: serde_json::Value = serde_json::from_str(include_str!("../tests/golden/encrypted_and_iv.schema.json")).unwrap();
+        assert_eq!(schema(), golden)
+    }
+
+    #[test]
+    fn serialized_values_validate() {
+        let validator = jsonschema::validator_for(&schema()).unwrap();
+        let key = Key { u8_array: KEY };
+        for plaintext in [&b""[..], b"a", b"ab", b"This is a text.", &[0u8; 100]].iter() {
+            let mut encrypted_and_iv = encrypt_bytes_with_iv(&key, Iv { u8_array: *b"012345678901" }, plaintext).unwrap();
+            assert!(validator.is_valid(&serde_json::to_value(&encrypted_and_iv).unwrap()));
+            encrypted_and_iv.kcv = Some(key.check_value());
+            assert!(validator.is_valid(&serde_json::to_value(&encrypted_and_iv).unwrap()));
+            let encrypted_and_iv = encrypted_and_iv.with_key_id(*b"k001").with_aad_digest(crate::aad_digest(plaintext)).with_format_version(0);
+            assert!(validator.is_valid(&serde_json::to_value(&encrypted_and_iv).unwrap()))
+        }
+    }
+
+    #[test]
+    fn schema_and_deserialization_agree() {
+        let validator = jsonschema::validator_for(&schema()).unwrap();
+        for (json, valid) in [
+            (r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ=="}"#, true),
+            (r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==","kcv":"vJBB"}"#, true),
+            (r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==","key_id":"azAwMQ==","aad_digest":"UhKINodaEVc=","format_version":2}"#, true),
+            (r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==","format_version":0}"#, true),
+            (r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==","key_id":"azAwMR=="}"#, false),
+            (r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==","aad_digest":"UhKINodaEV="}"#, false),
+            (r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==","format_version":1}"#, false),
+            (r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==","format_version":256}"#, false),
+            (r#"{"iv":"MDEyMzQ1Njc4OTA","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ=="}"#, false),
+            (r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ"}"#, false),
+            (r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWR=="}"#, false),
+            (r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc"}"#, false),
+            (r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==","tag":"x"}"#, false),
+            (r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==","kcv":"vJA="}"#, false),
+            (r#"{"encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ=="}"#, false)
+        ].iter() {
+            let value: serde_json::Value = serde_json::from_str(json).unwrap();
+            assert_eq!(validator.is_valid(&value), *valid, "schema for {}", json);
+            assert_eq!(serde_json::from_value::<EncryptedAndIv>(value).is_ok(), *valid, "serde for {}", json)
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Plaintext held in locked, wiped memory.
+//!
+//! `decrypt_into_secure` decrypts in place, into the one allocation the
+//! returned `SecureBuffer` owns: the plaintext is never in an ordinary
+//! `Vec` or `String`, and the buffer is zeroed when dropped. With the
+//! `mlock` feature on Unix, its pages are also locked so they can't be
+//! swapped out; locking is best effort (it fails beyond `RLIMIT_MEMLOCK`),
+//! see `is_locked`. Without it, the buffer is only wiped.
+//!
+//! Copies made through `as_slice` or `as_str` by the caller are out of
+//! this crate's reach.
+
+use std::{fmt, ptr, sync::atomic};
+
+use aead::{generic_array::GenericArray, Aead};
+
+use crate::backend::aes256_gcm;
+use crate::{instrument, DecryptionError, EncryptedAndIv, Key};
+
+/// Zeroes `buffer` in a way the optimizer can't elide.
+pub(crate) fn wipe(buffer: &mut [u8]) {
+    for byte in buffer.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    atomic::compiler_fence(atomic::Ordering::SeqCst);
+}
+
+/// Deliberately not `Clone`; `Debug` only shows the length.
+pub struct SecureBuffer {
+    bytes: Box<[u8]>,
+    locked: bool
+}
+
+impl SecureBuffer {
+    fn from_slice(bytes: &[u8]) -> SecureBuffer {
+        let mut buffer = SecureBuffer { bytes: bytes.to_vec().into_boxed_slice(), locked: false };
+        buffer.lock();
+        buffer
+    }
+
+    #[cfg(all(feature = "mlock", unix))]
+    fn lock(&mut self) {
+        if !self.bytes.is_empty() {
+            self.locked = unsafe { libc::mlock(self.bytes.as_ptr() as *const libc::c_void, self.bytes.len()) } == 0;
+        }
+    }
+
+    #[cfg(not(all(feature = "mlock", unix)))]
+    fn lock(&mut self) {}
+
+    #[cfg(all(feature = "mlock", unix))]
+    fn unlock(&mut self) {
+        if self.locked {
+            unsafe { libc::munlock(self.bytes.as_ptr() as *const libc::c_void, self.bytes.len()) };
+        }
+    }
+
+    #[cfg(not(all(feature = "mlock", unix)))]
+    fn unlock(&mut self) {}
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn as_str(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.bytes)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Whether the pages are locked in memory, only ever with the `mlock` feature.
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+impl AsRef<[u8]> for SecureBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl fmt::Debug for SecureBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecureBuffer {{ len: {}, locked: {} }}", self.bytes.len(), self.locked)
+    }
+}
+
+impl Drop for SecureBuffer {
+    fn drop(&mut self) {
+        wipe(&mut self.bytes);
+        self.unlock();
+    }
+}
+
+/// Same as `decrypt_bytes`, into a `SecureBuffer`.
+pub fn decrypt_into_secure(key: &Key, encrypted_and_iv: &EncryptedAndIv) -> Result<SecureBuffer, DecryptionError> {
+    instrument::decrypt(key, encrypted_and_iv.encrypted.u8_vec.len(), || {
+        if let Some(kcv) = encrypted_and_iv.kcv {
+            if kcv != key.check_value() {
+                return Err(DecryptionError::ProbableWrongKey);
+            }
+        }
+        let tag = encrypted_and_iv.encrypted.tag().ok_or(DecryptionError::GenericDecryptionError)?;
+        // Starts out as the ciphertext, decrypted in place once the tag checks out.
+        let mut buffer = SecureBuffer::from_slice(encrypted_and_iv.encrypted.ciphertext_body());
+        match aes256_gcm(key).decrypt_in_place_detached(GenericArray::from_slice(&encrypted_and_iv.iv.u8_array), &[], &mut buffer.bytes, GenericArray::from_slice(tag)) {
+            Ok(()) => Ok(buffer),
+            Err(_) => Err(DecryptionError::GenericDecryptionError)
+        }
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::test_util::{encrypt_deterministic_for_tests, test_key};
+
+    fn encrypt(plaintext: &[u8]) -> EncryptedAndIv {
+        encrypt_deterministic_for_tests(&test_key(), 0, plaintext).unwrap()
+    }
+
+    #[test]
+    fn round_trips_through_the_secure_path() {
+        match decrypt_into_secure(&test_key(), &encrypt(b"This is a text.")) {
+            Ok(buffer) => {
+                assert_eq!(buffer.as_slice(), b"This is a text.");
+                assert_eq!(buffer.as_str().unwrap(), "This is a text.");
+                assert_eq!(format!("{:?}", buffer), format!("SecureBuffer {{ len: 15, locked: {} }}", buffer.is_locked()))
+            },
+            Err(_) => assert!(false, "Should decrypt")
+        }
+        match decrypt_into_secure(&test_key(), &encrypt(b"")) {
+            Ok(buffer) => assert!(buffer.is_empty()),
+            Err(_) => assert!(false, "Should decrypt")
+        }
+    }
+
+    #[test]
+    fn as_str_fails_on_binary_data() {
+        match decrypt_into_secure(&test_key(), &encrypt(&[0xff, 0xfe, 0x00])) {
+            Ok(buffer) => {
+                assert_eq!(buffer.as_slice(), [0xff, 0xfe, 0x00]);
+                assert!(buffer.as_str().is_err())
+            },
+            Err(_) => assert!(false, "Should decrypt")
+        }
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        match decrypt_into_secure(&Key { u8_array: [1u8; 32] }, &encrypt(b"This is a text.")) {
+            Ok(_) => assert!(false, "Should err GenericDecryptionError"),
+            Err(e) => match e {
+                DecryptionError::GenericDecryptionError => assert!(true),
+                _ => assert!(false, "Should err GenericDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(all(feature = "mlock", unix)))]
+    fn fallback_only_wipes() {
+        match decrypt_into_secure(&test_key(), &encrypt(b"This is a text.")) {
+            Ok(buffer) => assert!(!buffer.is_locked()),
+            Err(_) => assert!(false, "Should decrypt")
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Known-answer and round-trip checks to run on a deployed build.
+//!
+//! `self_test()` needs no key or network. It runs the AES-256 test cases
+//! 13 to 16 of the GCM specification (McGrew and Viega, the vectors NIST
+//! uses) through encryption and decryption, checks that a flipped tag bit
+//! is rejected, and, with `rng`, round-trips a random payload, also as a
+//! token when `base64` is enabled.
+
+use std::convert::TryInto;
+
+use crate::{decrypt_bytes_with_aad, encrypt_bytes_with_iv_and_aad, Encrypted, EncryptedAndIv, Iv, Key};
+
+#[derive(Debug, Clone)]
+pub struct SelfTestCheck {
+    pub name: &'static str,
+    pub passed: bool
+}
+
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    pub checks: Vec<SelfTestCheck>
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+
+    pub fn failed(&self) -> Vec<&'static str> {
+        self.checks.iter().filter(|check| !check.passed).map(|check| check.name).collect()
+    }
+}
+
+/// Hex fields, `ciphertext` ending with the tag.
+#[derive(Clone, Copy)]
+struct Vector {
+    name: &'static str,
+    key: &'static str,
+    iv: &'static str,
+    aad: &'static str,
+    plaintext: &'static str,
+    ciphertext: &'static str
+}
+
+const VECTORS: [Vector; 4] = [
+    Vector {
+        name: "gcm test case 13",
+        key: "0000000000000000000000000000000000000000000000000000000000000000",
+        iv: "000000000000000000000000",
+        aad: "",
+        plaintext: "",
+        ciphertext: "530f8afbc74536b9a963b4f1c4cb738b"
+    },
+    Vector {
+        name: "gcm test case 14",
+        key: "0000000000000000000000000000000000000000000000000000000000000000",
+        iv: "000000000000000000000000",
+        aad: "",
+        plaintext: "00000000000000000000000000000000",
+        ciphertext: "cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919"
+    },
+    Vector {
+        name: "gcm test case 15",
+        key: "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308",
+        iv: "cafebabefacedbaddecaf888",
+        aad: "",
+        plaintext: "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a721c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255",
+        ciphertext: "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662898015adb094dac5d93471bdec1a502270e3cc6c"
+    },
+    Vector {
+        name: "gcm test case 16",
+        key: "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308",
+        iv: "cafebabefacedbaddecaf888",
+        aad: "feedfacedeadbeeffeedfacedeadbeefabaddad2",
+        plaintext: "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a721c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+        ciphertext: "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f66276fc6ece0f4e1768cddf8853bb2d551b"
+    }
+];
+
+fn unhex(hex: &str) -> Vec<u8> {
+    (0..hex.len()).step_by(2).map(|index| u8::from_str_radix(&hex[index..index + 2], 16).expect("vectors are valid hex")).collect()
+}
+
+fn check_vector(vector: &Vector) -> bool {
+    let key = Key { u8_array: unhex(vector.key).as_slice().try_into().expect("vector keys are 32 bytes") };
+    let iv: [u8; 12] = unhex(vector.iv).as_slice().try_into().expect("vector ivs are 12 bytes");
+    let (aad, plaintext, ciphertext) = (unhex(vector.aad), unhex(vector.plaintext), unhex(vector.ciphertext));
+    let encrypted = match encrypt_bytes_with_iv_and_aad(&key, Iv { u8_array: iv }, &plaintext, &aad) {
+        Ok(encrypted_and_iv) => encrypted_and_iv.encrypted.u8_vec,
+        Err(_) => return false
+    };
+    let mut tampered = ciphertext.clone();
+    *tampered.last_mut().expect("ciphertexts end with a tag") ^= 1;
+    let decrypt = |u8_vec: Vec<u8>| decrypt_bytes_with_aad(&key, &EncryptedAndIv::from_parts(Iv { u8_array: iv }, Encrypted { u8_vec }), &aad);
+    encrypted == ciphertext
+        && decrypt(ciphertext).ok().as_ref() == Some(&plaintext)
+        && decrypt(tampered).is_err()
+}
+
+fn run_vectors(vectors: &[Vector]) -> Vec<SelfTestCheck> {
+    vectors.iter().map(|vector| SelfTestCheck { name: vector.name, passed: check_vector(vector) }).collect()
+}
+
+#[cfg(feature = "rng")]
+fn check_round_trip() -> bool {
+    let key = Key::generate();
+    let plaintext: Vec<u8> = (0..1000).map(|_| rand::random::<u8>()).collect();
+    match crate::encrypt_bytes(&key, &plaintext) {
+        Ok(encrypted_and_iv) => crate::decrypt_bytes(&key, &encrypted_and_iv).ok() == Some(plaintext),
+        Err(_) => false
+    }
+}
+
+#[cfg(all(feature = "rng", feature = "base64"))]
+fn check_token_round_trip() -> bool {
+    let key = Key::generate();
+    let plaintext: Vec<u8> = (0..100).map(|_| rand::random::<u8>()).collect();
+    match crate::token::encrypt_to_token(&key, &plaintext) {
+        Ok(token) => crate::token::decrypt_token(&key, &token).ok() == Some(plaintext),
+        Err(_) => false
+    }
+}
+
+pub fn self_test() -> SelfTestReport {
+    #[allow(unused_mut)]
+    let mut checks = run_vectors(&VECTORS);
+    #[cfg(feature = "rng")]
+    checks.push(SelfTestCheck { name: "random round trip", passed: check_round_trip() });
+    #[cfg(all(feature = "rng", feature = "base64"))]
+    checks.push(SelfTestCheck { name: "token round trip", passed: check_token_round_trip() });
+    SelfTestReport { checks }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn healthy_build_passes() {
+        let report = self_test();
+        assert!(report.passed(), "failed: {:?}", report.failed());
+        assert!(report.checks.len() >= VECTORS.len())
+    }
+
+    #[test]
+    fn a_wrong_vector_is_reported() {
+        let wrong = Vector {
+            name: "wrong tag",
+            ciphertext: "530f8afbc74536b9a963b4f1c4cb738c",
+            ..VECTORS[0]
+        };
+        let checks = run_vectors(&[VECTORS[0], wrong, VECTORS[1]]);
+        assert_eq!(checks.iter().map(|check| check.passed).collect::<Vec<_>>(), vec![true, false, true]);
+        let report = SelfTestReport { checks };
+        assert!(!report.passed());
+        assert_eq!(report.failed(), vec!["wrong tag"])
+    }
+}
@@ -0,0 +1,312 @@
+//! Replay protection with authenticated sequence numbers.
+//!
+//! A `SequencedEncryptor` numbers the messages of a channel from 0 and
+//! writes each as:
+//!
+//! `sequence number (8, big-endian) || iv (12) || ciphertext and tag`
+//!
+//! The sequence number travels in clear but is authenticated, with the
+//! channel id, as associated data:
+//! `"simple-aes256-gcm sequenced" || channel id length (4, big-endian) || channel id || sequence number (8, big-endian)`.
+//! A message can't be renumbered, nor moved to another channel.
+//!
+//! A `SequencedDecryptor` keeps the highest sequence number accepted and,
+//! as DTLS does, a bitmap of which of the `window` numbers below it were
+//! accepted too. Messages may arrive out of order within the window; once
+//! authenticated, one already accepted fails with
+//! `DecryptionError::Replayed`, and one below the window with
+//! `DecryptionError::OutOfWindow`.
+//!
+//! Both sides' state must outlive restarts, or a restarted decryptor
+//! accepts everything again: persist `SequencedEncryptor::next_sequence` and
+//! `SequencedDecryptor::state`, and restore them with `resume`. The
+//! encryptor uses random ivs, so resuming it from a stale sequence number
+//! only gets its messages rejected, never reuses an iv.
+
+use std::convert::TryInto;
+
+use crate::{instrument, open, DecryptionError, Encrypted, EncryptedAndIv, Iv, Key, IV_LEN, TAG_LEN};
+#[cfg(feature = "rng")]
+use crate::{encrypt_bytes_with_aad, EncryptionError};
+
+const AAD_LABEL: &[u8] = b"simple-aes256-gcm sequenced";
+
+/// The largest window, the width of the bitmap.
+pub const MAX_WINDOW: u32 = 64;
+
+pub const DEFAULT_WINDOW: u32 = 64;
+
+fn sequence_aad(channel_id: &str, sequence: u64) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(AAD_LABEL.len() + 4 + channel_id.len() + 8);
+    aad.extend_from_slice(AAD_LABEL);
+    aad.extend_from_slice(&(channel_id.len() as u32).to_be_bytes());
+    aad.extend_from_slice(channel_id.as_bytes());
+    aad.extend_from_slice(&sequence.to_be_bytes());
+    aad
+}
+
+/// Wiped on drop.
+struct OwnedKey(Key);
+
+impl Drop for OwnedKey {
+    fn drop(&mut self) {
+        crate::secure_buffer::wipe(&mut self.0.u8_array);
+    }
+}
+
+#[cfg(feature = "rng")]
+pub struct SequencedEncryptor {
+    key: OwnedKey,
+    channel_id: String,
+    next_sequence: u64
+}
+
+#[cfg(feature = "rng")]
+impl SequencedEncryptor {
+    pub fn new(key: &Key, channel_id: &str) -> SequencedEncryptor {
+        SequencedEncryptor::resume(key, channel_id, 0)
+    }
+
+    /// Continues a channel from a persisted `next_sequence`.
+    pub fn resume(key: &Key, channel_id: &str, next_sequence: u64) -> SequencedEncryptor {
+        SequencedEncryptor { key: OwnedKey(Key { u8_array: key.u8_array }), channel_id: String::from(channel_id), next_sequence }
+    }
+
+    /// The number of the next message, the state to persist.
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    /// Fails once `u64::MAX` messages have been sent.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let sequence = self.next_sequence;
+        if sequence == u64::MAX {
+            return Err(EncryptionError::GenericEncryptionError);
+        }
+        let encrypted_and_iv = encrypt_bytes_with_aad(&self.key.0, plaintext, &sequence_aad(&self.channel_id, sequence))?;
+        self.next_sequence += 1;
+        let mut message = Vec::with_capacity(8 + IV_LEN + encrypted_and_iv.encrypted.u8_vec.len());
+        message.extend_from_slice(&sequence.to_be_bytes());
+        message.extend_from_slice(&encrypted_and_iv.iv.u8_array);
+        message.extend_from_slice(&encrypted_and_iv.encrypted.u8_vec);
+        Ok(message)
+    }
+}
+
+/// What a `SequencedDecryptor` has accepted, for persistence.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayWindowState {
+    /// The highest sequence number accepted plus one, 0 before the first message.
+    pub next: u64,
+    /// Bit `i` is set when `next - 1 - i` was accepted.
+    pub bitmap: u64
+}
+
+impl ReplayWindowState {
+    /// `next` then `bitmap`, both big-endian.
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&self.next.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.bitmap.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; 16]) -> ReplayWindowState {
+        ReplayWindowState {
+            next: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            bitmap: u64::from_be_bytes(bytes[8..].try_into().unwrap())
+        }
+    }
+}
+
+pub struct SequencedDecryptor {
+    key: OwnedKey,
+    channel_id: String,
+    window: u32,
+    state: ReplayWindowState
+}
+
+impl SequencedDecryptor {
+    /// With the `DEFAULT_WINDOW`.
+    pub fn new(key: &Key, channel_id: &str) -> SequencedDecryptor {
+        SequencedDecryptor::with_window(key, channel_id, DEFAULT_WINDOW)
+    }
+
+    /// Accepts messages up to `window - 1` numbers below the highest one
+    /// accepted. `window` is clamped to 1..=`MAX_WINDOW`; 1 allows no
+    /// reordering at all.
+    pub fn with_window(key: &Key, channel_id: &str, window: u32) -> SequencedDecryptor {
+        SequencedDecryptor::resume(key, channel_id, window, ReplayWindowState::default())
+    }
+
+    /// Continues from a persisted `state`.
+    pub fn resume(key: &Key, channel_id: &str, window: u32, state: ReplayWindowState) -> SequencedDecryptor {
+        SequencedDecryptor {
+            key: OwnedKey(Key { u8_array: key.u8_array }),
+            channel_id: String::from(channel_id),
+            window: window.clamp(1, MAX_WINDOW),
+            state
+        }
+    }
+
+    pub fn state(&self) -> ReplayWindowState {
+        self.state
+    }
+
+    /// The sequence number of `message`, read without authenticating it.
+    pub fn sequence_of(message: &[u8]) -> Option<u64> {
+        message.get(..8).map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Authenticates `message`, then checks its sequence number against the
+    /// window. The state only changes when the plaintext is returned.
+    ///
+    /// Audited as one decryption, whose failure class is `"replayed"` or
+    /// `"out_of_window"` when the window rejects an authentic message.
+    pub fn decrypt(&mut self, message: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        let key = &self.key.0;
+        let channel_id = &self.channel_id;
+        let window = self.window;
+        let state = &mut self.state;
+        instrument::decrypt(key, message.len(), || {
+            if message.len() < 8 + IV_LEN + TAG_LEN {
+                return Err(DecryptionError::GenericDecryptionError);
+            }
+            let sequence = u64::from_be_bytes(message[..8].try_into().unwrap());
+            let iv = Iv { u8_array: message[8..8 + IV_LEN].try_into().unwrap() };
+            let encrypted_and_iv = EncryptedAndIv::from_parts(iv, Encrypted { u8_vec: message[8 + IV_LEN..].to_vec() });
+            let plaintext = open(key, &encrypted_and_iv, &sequence_aad(channel_id, sequence))?;
+            accept(state, window, sequence)?;
+            Ok(plaintext)
+        })
+    }
+}
+
+fn accept(state: &mut ReplayWindowState, window: u32, sequence: u64) -> Result<(), DecryptionError> {
+    if sequence >= state.next {
+        let shift = sequence - state.next + 1;
+        state.bitmap = if shift >= 64 { 0 } else { state.bitmap << shift };
+        state.bitmap |= 1;
+        state.next = sequence + 1;
+        return Ok(());
+    }
+    let offset = state.next - 1 - sequence;
+    if offset >= u64::from(window) {
+        return Err(DecryptionError::OutOfWindow);
+    }
+    if state.bitmap & (1 << offset) != 0 {
+        return Err(DecryptionError::Replayed);
+    }
+    state.bitmap |= 1 << offset;
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(feature = "rng")]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    fn channel() -> (SequencedEncryptor, SequencedDecryptor) {
+        (SequencedEncryptor::new(&Key { u8_array: KEY }, "device-7"), SequencedDecryptor::with_window(&Key { u8_array: KEY }, "device-7", 8))
+    }
+
+    fn assert_rejected(result: Result<Vec<u8>, DecryptionError>, replayed: bool) {
+        match result {
+            Ok(_) => assert!(false, "Should err Replayed or OutOfWindow"),
+            Err(e) => match e {
+                DecryptionError::Replayed => assert!(replayed, "Should err OutOfWindow"),
+                DecryptionError::OutOfWindow => assert!(!replayed, "Should err Replayed"),
+                _ => assert!(false, "Should err Replayed or OutOfWindow, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn in_order_messages_are_accepted() {
+        let (mut encryptor, mut decryptor) = channel();
+        for i in 0..20u8 {
+            let message = encryptor.encrypt(&[i]).unwrap();
+            assert_eq!(SequencedDecryptor::sequence_of(&message), Some(u64::from(i)));
+            assert_eq!(decryptor.decrypt(&message).unwrap(), [i]);
+        }
+        assert_eq!(encryptor.next_sequence(), 20);
+        assert_eq!(decryptor.state().next, 20)
+    }
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let (mut encryptor, mut decryptor) = channel();
+        let first = encryptor.encrypt(b"one").unwrap();
+        let second = encryptor.encrypt(b"two").unwrap();
+        decryptor.decrypt(&first).unwrap();
+        assert_rejected(decryptor.decrypt(&first), true);
+        decryptor.decrypt(&second).unwrap();
+        assert_rejected(decryptor.decrypt(&second), true);
+        assert_rejected(decryptor.decrypt(&first), true)
+    }
+
+    #[test]
+    fn reordering_within_the_window_is_accepted() {
+        let (mut encryptor, mut decryptor) = channel();
+        let messages: Vec<Vec<u8>> = (0..8u8).map(|i| encryptor.encrypt(&[i]).unwrap()).collect();
+        for i in [7, 3, 0, 6, 1, 2, 5, 4].iter() {
+            assert_eq!(decryptor.decrypt(&messages[*i]).unwrap(), [*i as u8]);
+        }
+        assert_eq!(decryptor.state().bitmap, 0xff);
+        assert_rejected(decryptor.decrypt(&messages[4]), true)
+    }
+
+    #[test]
+    fn messages_below_the_window_are_rejected() {
+        let (mut encryptor, mut decryptor) = channel();
+        let stale = encryptor.encrypt(b"stale").unwrap();
+        let messages: Vec<Vec<u8>> = (0..8).map(|_| encryptor.encrypt(b"fresh").unwrap()).collect();
+        decryptor.decrypt(&messages[7]).unwrap();
+        decryptor.decrypt(&messages[0]).unwrap();
+        assert_rejected(decryptor.decrypt(&stale), false);
+
+        // A jump of more than the bitmap's width clears it.
+        let far = SequencedEncryptor::resume(&Key { u8_array: KEY }, "device-7", 1000).encrypt(b"far").unwrap();
+        decryptor.decrypt(&far).unwrap();
+        assert_eq!(decryptor.state(), ReplayWindowState { next: 1001, bitmap: 1 });
+        assert_rejected(decryptor.decrypt(&messages[1]), false)
+    }
+
+    #[test]
+    fn sequence_numbers_and_channels_are_authenticated() {
+        let (mut encryptor, mut decryptor) = channel();
+        let mut renumbered = encryptor.encrypt(b"one").unwrap();
+        renumbered[7] = 5;
+        match decryptor.decrypt(&renumbered) {
+            Err(DecryptionError::GenericDecryptionError) => assert!(true),
+            _ => assert!(false, "Should err GenericDecryptionError")
+        }
+        let message = encryptor.encrypt(b"two").unwrap();
+        match SequencedDecryptor::new(&Key { u8_array: KEY }, "device-8").decrypt(&message) {
+            Err(DecryptionError::GenericDecryptionError) => assert!(true),
+            _ => assert!(false, "Should err GenericDecryptionError")
+        }
+        assert_eq!(decryptor.state(), ReplayWindowState::default())
+    }
+
+    #[test]
+    fn state_survives_a_restart() {
+        let (mut encryptor, mut decryptor) = channel();
+        let messages: Vec<Vec<u8>> = (0..4).map(|_| encryptor.encrypt(b"message").unwrap()).collect();
+        decryptor.decrypt(&messages[3]).unwrap();
+        decryptor.decrypt(&messages[1]).unwrap();
+        let saved = decryptor.state().to_bytes();
+        let next_sequence = encryptor.next_sequence();
+        drop((encryptor, decryptor));
+
+        let mut decryptor = SequencedDecryptor::resume(&Key { u8_array: KEY }, "device-7", 8, ReplayWindowState::from_bytes(&saved));
+        assert_rejected(decryptor.decrypt(&messages[3]), true);
+        assert_rejected(decryptor.decrypt(&messages[1]), true);
+        decryptor.decrypt(&messages[2]).unwrap();
+        let mut encryptor = SequencedEncryptor::resume(&Key { u8_array: KEY }, "device-7", next_sequence);
+        assert_eq!(decryptor.decrypt(&encryptor.encrypt(b"after").unwrap()).unwrap(), b"after")
+    }
+}
@@ -0,0 +1,185 @@
+//! Serde support for the envelope types.
+//!
+//! `Iv` and `Encrypted` are standard, padded base64 strings, and
+//! `EncryptedAndIv` is `{"iv": ..., "encrypted": ..., "kcv": ...,
+//! "key_id": ..., "aad_digest": ..., "format_version": ...}`, with each
+//! optional field left out when absent and `format_version` left out when
+//! it is `FORMAT_VERSION`, so older JSON still parses, with no metadata.
+//! Deserialization only accepts the exact form
+//! serialization produces (canonical base64, an iv of 12 bytes, a
+//! ciphertext at least as long as a tag, no unknown fields), which is
+//! also what the `schemars` schemas describe.
+
+use std::convert::TryInto;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+use crate::{Encrypted, EncryptedAndIv, Iv, FORMAT_VERSION, TAG_LEN};
+
+fn decode_canonical<E: de::Error>(text: &str) -> Result<Vec<u8>, E> {
+    match base64::decode(text) {
+        Ok(bytes) if base64::encode(&bytes) == text => Ok(bytes),
+        _ => Err(E::custom("expected canonical, padded base64"))
+    }
+}
+
+fn decode_optional_array<E: de::Error, const N: usize>(text: Option<String>, expected: &str) -> Result<Option<[u8; N]>, E> {
+    match text {
+        Some(text) => {
+            let bytes = decode_canonical::<E>(&text)?;
+            match bytes.as_slice().try_into() {
+                Ok(array) => Ok(Some(array)),
+                Err(_) => Err(de::Error::invalid_length(bytes.len(), &expected))
+            }
+        },
+        None => Ok(None)
+    }
+}
+
+impl Serialize for Iv {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&String::from(self))
+    }
+}
+
+impl<'de> Deserialize<'de> for Iv {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Iv, D::Error> {
+        let bytes = decode_canonical::<D::Error>(&String::deserialize(deserializer)?)?;
+        match bytes.as_slice().try_into() {
+            Ok(u8_array) => Ok(Iv { u8_array }),
+            Err(_) => Err(de::Error::invalid_length(bytes.len(), &"a 12-byte iv"))
+        }
+    }
+}
+
+impl Serialize for Encrypted {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&String::from(self))
+    }
+}
+
+impl<'de> Deserialize<'de> for Encrypted {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Encrypted, D::Error> {
+        let u8_vec = decode_canonical::<D::Error>(&String::deserialize(deserializer)?)?;
+        if u8_vec.len() < TAG_LEN {
+            return Err(de::Error::invalid_length(u8_vec.len(), &"a ciphertext of at least 16 bytes"));
+        }
+        Ok(Encrypted { u8_vec })
+    }
+}
+
+#[derive(serde::Serialize)]
+struct SerializedEncryptedAndIv<'a> {
+    iv: &'a Iv,
+    encrypted: &'a Encrypted,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kcv: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aad_digest: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format_version: Option<u8>
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeserializedEncryptedAndIv {
+    iv: Iv,
+    encrypted: Encrypted,
+    #[serde(default)]
+    kcv: Option<String>,
+    #[serde(default)]
+    key_id: Option<String>,
+    #[serde(default)]
+    aad_digest: Option<String>,
+    #[serde(default)]
+    format_version: Option<u8>
+}
+
+impl Serialize for EncryptedAndIv {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedEncryptedAndIv {
+            iv: &self.iv,
+            encrypted: &self.encrypted,
+            kcv: self.kcv.map(|kcv| base64::encode(&kcv)),
+            key_id: self.key_id.map(|key_id| base64::encode(&key_id)),
+            aad_digest: self.aad_digest.map(|aad_digest| base64::encode(&aad_digest)),
+            format_version: Some(self.format_version).filter(|&version| version != FORMAT_VERSION)
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for EncryptedAndIv {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<EncryptedAndIv, D::Error> {
+        let deserialized = DeserializedEncryptedAndIv::deserialize(deserializer)?;
+        let format_version = match deserialized.format_version {
+            Some(FORMAT_VERSION) => return Err(de::Error::custom("format_version 1 is left out")),
+            Some(version) => version,
+            None => FORMAT_VERSION
+        };
+        Ok(EncryptedAndIv {
+            kcv: decode_optional_array::<D::Error, 3>(deserialized.kcv, "a 3-byte key check value")?,
+            key_id: decode_optional_array::<D::Error, 4>(deserialized.key_id, "a 4-byte key id")?,
+            aad_digest: decode_optional_array::<D::Error, 8>(deserialized.aad_digest, "an 8-byte aad digest")?,
+            format_version,
+            ..EncryptedAndIv::from_parts(deserialized.iv, deserialized.encrypted)
+        })
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+    use crate::{encrypt_bytes_with_iv, Key};
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    #[test]
+    fn encrypted_and_iv_json_is_pinned_and_round_trips() {
+        let mut encrypted_and_iv = encrypt_bytes_with_iv(&Key { u8_array: KEY }, Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap();
+        let json = serde_json::to_string(&encrypted_and_iv).unwrap();
+        assert_eq!(json, r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ=="}"#);
+        let parsed: EncryptedAndIv = serde_json::from_str(&json).unwrap();
+        assert_eq!((parsed.iv.u8_array, parsed.encrypted.u8_vec, parsed.kcv), (encrypted_and_iv.iv.u8_array, encrypted_and_iv.encrypted.u8_vec.clone(), None));
+
+        encrypted_and_iv.kcv = Some([188, 144, 65]);
+        let json = serde_json::to_string(&encrypted_and_iv).unwrap();
+        assert!(json.ends_with(r#","kcv":"vJBB"}"#));
+        assert_eq!(serde_json::from_str::<EncryptedAndIv>(&json).unwrap().kcv, Some([188, 144, 65]))
+    }
+
+    #[test]
+    fn metadata_round_trips_and_legacy_json_has_none() {
+        let encrypted_and_iv = encrypt_bytes_with_iv(&Key { u8_array: KEY }, Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap();
+        let legacy: EncryptedAndIv = serde_json::from_str(&serde_json::to_string(&encrypted_and_iv).unwrap()).unwrap();
+        assert_eq!((legacy.key_id(), legacy.aad_digest(), legacy.format_version()), (None, None, FORMAT_VERSION));
+
+        let (iv, encrypted) = encrypted_and_iv.into_parts();
+        let with_metadata = EncryptedAndIv::from_parts(iv, encrypted).with_key_id(*b"k001").with_aad_digest(crate::aad_digest(b"context")).with_format_version(2);
+        let json = serde_json::to_string(&with_metadata).unwrap();
+        assert!(json.ends_with(r#","key_id":"azAwMQ==","aad_digest":"UhKINodaEVc=","format_version":2}"#), "{}", json);
+        let parsed: EncryptedAndIv = serde_json::from_str(&json).unwrap();
+        assert_eq!((parsed.key_id(), parsed.aad_digest(), parsed.format_version()), (Some(*b"k001"), with_metadata.aad_digest(), 2));
+        assert_eq!(parsed.aad_matches(b"context"), Some(true));
+        assert_eq!(parsed.aad_matches(b"other"), Some(false));
+        assert_eq!(legacy.aad_matches(b"context"), None)
+    }
+
+    #[test]
+    fn non_canonical_or_short_values_are_rejected() {
+        for json in [
+            r#"{"iv":"MDEyMzQ1Njc4OTA","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ=="}"#,
+            r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ"}"#,
+            r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc"}"#,
+            r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==","tag":"x"}"#,
+            r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==","kcv":"vJA="}"#,
+            r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==","key_id":"azAw"}"#,
+            r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==","aad_digest":"UhKINodaEVc"}"#,
+            r#"{"iv":"MDEyMzQ1Njc4OTAx","encrypted":"JOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==","format_version":1}"#
+        ].iter() {
+            assert!(serde_json::from_str::<EncryptedAndIv>(json).is_err(), "Should err for {}", json)
+        }
+    }
+}
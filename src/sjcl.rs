@@ -0,0 +1,329 @@
+//! Reading and writing the JSON format of the Stanford JavaScript Crypto
+//! Library (`sjcl.encrypt`), in GCM mode with 256-bit keys.
+//!
+//! ```text
+//! {"iv":"..","v":1,"iter":10000,"ks":256,"ts":128,"mode":"gcm","adata":"","cipher":"aes","salt":"..","ct":".."}
+//! ```
+//!
+//! `iv`, `salt`, `adata` and `ct` are base64; `ct` is the ciphertext
+//! followed by the tag, truncated to `ts` bits. With a password, the key
+//! is PBKDF2-HMAC-SHA256 over `salt` with `iter` iterations; with a key,
+//! `salt` and `iter` are unused. SJCL uses 16-byte ivs (8 to 16 bytes are
+//! accepted), which AES-GCM turns into its initial counter with GHASH, so
+//! this module has its own GCM on top of the `aes` and `ghash` crates
+//! rather than going through `Aes256Gcm`, which only takes 12-byte ivs.
+//!
+//! Both functions are audited like the one-shot ones, with the
+//! fingerprint of the (derived) key; malformed or unsupported JSON is
+//! rejected before that.
+//!
+//! This is for interoperability with data and clients that already use
+//! SJCL; new data should use the crate's own envelopes.
+
+use std::{error, fmt};
+
+use aead::generic_array::GenericArray;
+use aes::block_cipher_trait::BlockCipher;
+use aes::Aes256;
+use ghash::universal_hash::UniversalHash;
+use ghash::GHash;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crate::audit::{self, AuditOperation};
+use crate::Key;
+
+/// `iter` written by `encrypt_sjcl`, SJCL's default.
+pub const SJCL_ITERATIONS: u32 = 10_000;
+
+pub enum SjclSecret<'a> {
+    Password(&'a str),
+    Key(&'a Key)
+}
+
+#[derive(Debug, Clone)]
+pub enum SjclError {
+    InvalidSjclJsonError,
+    /// Another mode, cipher, key size, tag size or version, or an iv outside 8 to 16 bytes.
+    UnsupportedSjclParametersError,
+    /// A password was given for a value without a salt.
+    MissingSjclSaltError,
+    /// Wrong password or key, or a tampered value.
+    SjclDecryptionError
+}
+
+impl fmt::Display for SjclError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SjclError::InvalidSjclJsonError => write!(f, "Invalid SJCL JSON"),
+            SjclError::UnsupportedSjclParametersError => write!(f, "Unsupported SJCL parameters, only AES-256-GCM is supported"),
+            SjclError::MissingSjclSaltError => write!(f, "SJCL value without a salt"),
+            SjclError::SjclDecryptionError => write!(f, "SJCL decryption error"),
+        }
+    }
+}
+
+impl error::Error for SjclError {}
+
+fn default_version() -> u32 { 1 }
+fn default_iterations() -> u32 { SJCL_ITERATIONS }
+fn default_key_size() -> u32 { 128 }
+fn default_tag_size() -> u32 { 64 }
+fn default_mode() -> String { String::from("ccm") }
+fn default_cipher() -> String { String::from("aes") }
+
+/// Field order and defaults are SJCL's.
+#[derive(Serialize, Deserialize)]
+struct SjclJson {
+    iv: String,
+    #[serde(default = "default_version")]
+    v: u32,
+    #[serde(default = "default_iterations")]
+    iter: u32,
+    #[serde(default = "default_key_size")]
+    ks: u32,
+    #[serde(default = "default_tag_size")]
+    ts: u32,
+    #[serde(default = "default_mode")]
+    mode: String,
+    #[serde(default)]
+    adata: String,
+    #[serde(default = "default_cipher")]
+    cipher: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    salt: Option<String>,
+    ct: String
+}
+
+/// SJCL's base64 decoder ignores whitespace and padding.
+fn decode_base64(text: &str) -> Result<Vec<u8>, SjclError> {
+    let stripped: String = text.chars().filter(|c| !c.is_whitespace() && *c != '=').collect();
+    base64::decode_config(&stripped, base64::STANDARD_NO_PAD).map_err(|_| SjclError::InvalidSjclJsonError)
+}
+
+fn derive_key(secret: &SjclSecret, salt: Option<&[u8]>, iterations: u32) -> Result<[u8; 32], SjclError> {
+    match secret {
+        SjclSecret::Key(key) => Ok(key.u8_array),
+        SjclSecret::Password(password) => {
+            let salt = salt.ok_or(SjclError::MissingSjclSaltError)?;
+            if iterations == 0 {
+                return Err(SjclError::UnsupportedSjclParametersError);
+            }
+            let mut key = [0u8; 32];
+            pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut key);
+            Ok(key)
+        }
+    }
+}
+
+/// AES-256-GCM with any iv length (SP 800-38D).
+struct Gcm {
+    cipher: Aes256,
+    hash_key: GenericArray<u8, aead::generic_array::typenum::U16>
+}
+
+impl Gcm {
+    fn new(key: &[u8; 32]) -> Gcm {
+        let cipher = Aes256::new(GenericArray::from_slice(key));
+        let mut hash_key = GenericArray::default();
+        cipher.encrypt_block(&mut hash_key);
+        Gcm { cipher, hash_key }
+    }
+
+    fn initial_counter(&self, iv: &[u8]) -> [u8; 16] {
+        let mut counter = [0u8; 16];
+        if iv.len() == 12 {
+            counter[..12].copy_from_slice(iv);
+            counter[15] = 1;
+            return counter;
+        }
+        let mut ghash = GHash::new(&self.hash_key);
+        ghash.update_padded(iv);
+        let mut lengths = [0u8; 16];
+        lengths[8..].copy_from_slice(&(iv.len() as u64 * 8).to_be_bytes());
+        ghash.update_block(GenericArray::from_slice(&lengths));
+        counter.copy_from_slice(&ghash.result().into_bytes());
+        counter
+    }
+
+    /// XORs the keystream starting at `counter + 1` into `data`.
+    fn apply_keystream(&self, counter: &[u8; 16], data: &mut [u8]) {
+        let mut block_counter = u32::from_be_bytes([counter[12], counter[13], counter[14], counter[15]]);
+        for chunk in data.chunks_mut(16) {
+            block_counter = block_counter.wrapping_add(1);
+            let mut block = GenericArray::clone_from_slice(counter);
+            block[12..].copy_from_slice(&block_counter.to_be_bytes());
+            self.cipher.encrypt_block(&mut block);
+            chunk.iter_mut().zip(block.iter()).for_each(|(byte, pad)| *byte ^= pad);
+        }
+    }
+
+    fn tag(&self, counter: &[u8; 16], adata: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+        let mut ghash = GHash::new(&self.hash_key);
+        ghash.update_padded(adata);
+        ghash.update_padded(ciphertext);
+        let mut lengths = [0u8; 16];
+        lengths[..8].copy_from_slice(&(adata.len() as u64 * 8).to_be_bytes());
+        lengths[8..].copy_from_slice(&(ciphertext.len() as u64 * 8).to_be_bytes());
+        ghash.update_block(GenericArray::from_slice(&lengths));
+        let mut tag = [0u8; 16];
+        tag.copy_from_slice(&ghash.result().into_bytes());
+        let mut mask = GenericArray::clone_from_slice(counter);
+        self.cipher.encrypt_block(&mut mask);
+        tag.iter_mut().zip(mask.iter()).for_each(|(byte, pad)| *byte ^= pad);
+        tag
+    }
+}
+
+/// Decrypts a value written by `sjcl.encrypt` (or `encrypt_sjcl`),
+/// checking its `adata`.
+pub fn decrypt_sjcl(secret: SjclSecret, json: &str) -> Result<Vec<u8>, SjclError> {
+    let parsed: SjclJson = serde_json::from_str(json).map_err(|_| SjclError::InvalidSjclJsonError)?;
+    if parsed.v != 1 || parsed.mode != "gcm" || parsed.cipher != "aes" || parsed.ks != 256 || ![64, 96, 128].contains(&parsed.ts) {
+        return Err(SjclError::UnsupportedSjclParametersError);
+    }
+    let iv = decode_base64(&parsed.iv)?;
+    if iv.len() < 8 || iv.len() > 16 {
+        return Err(SjclError::UnsupportedSjclParametersError);
+    }
+    let adata = decode_base64(&parsed.adata)?;
+    let salt = match &parsed.salt {
+        Some(salt) => Some(decode_base64(salt)?),
+        None => None
+    };
+    let ciphertext = decode_base64(&parsed.ct)?;
+    let key = Key { u8_array: derive_key(&secret, salt.as_deref(), parsed.iter)? };
+    let ciphertext_len = ciphertext.len();
+    let result = open(&key, &iv, &adata, ciphertext, parsed.ts as usize / 8);
+    audit::record(AuditOperation::Decrypt, &key, ciphertext_len, result.as_ref().err().map(|_| "authentication"));
+    result
+}
+
+/// Checks the `tag_len` bytes at the end of `ciphertext`, then decrypts the rest.
+fn open(key: &Key, iv: &[u8], adata: &[u8], mut ciphertext: Vec<u8>, tag_len: usize) -> Result<Vec<u8>, SjclError> {
+    if ciphertext.len() < tag_len {
+        return Err(SjclError::SjclDecryptionError);
+    }
+    let tag = ciphertext.split_off(ciphertext.len() - tag_len);
+
+    let gcm = Gcm::new(&key.u8_array);
+    let counter = gcm.initial_counter(iv);
+    if gcm.tag(&counter, adata, &ciphertext)[..tag_len].ct_eq(&tag).unwrap_u8() != 1 {
+        return Err(SjclError::SjclDecryptionError);
+    }
+    gcm.apply_keystream(&counter, &mut ciphertext);
+    Ok(ciphertext)
+}
+
+/// Encrypts to the JSON `sjcl.decrypt` reads: AES-256-GCM, a 16-byte iv,
+/// a 128-bit tag and, with a password, an 8-byte salt and `SJCL_ITERATIONS`.
+#[cfg(feature = "rng")]
+pub fn encrypt_sjcl(secret: SjclSecret, plaintext: &[u8], adata: &[u8]) -> String {
+    let iv: [u8; 16] = rand::random();
+    let salt = match secret {
+        SjclSecret::Password(_) => Some(rand::random::<[u8; 8]>()),
+        SjclSecret::Key(_) => None
+    };
+    let key = Key { u8_array: derive_key(&secret, salt.as_ref().map(|salt| &salt[..]), SJCL_ITERATIONS).expect("there is a salt for passwords") };
+    let gcm = Gcm::new(&key.u8_array);
+    let counter = gcm.initial_counter(&iv);
+    let mut ciphertext = plaintext.to_vec();
+    gcm.apply_keystream(&counter, &mut ciphertext);
+    let tag = gcm.tag(&counter, adata, &ciphertext);
+    ciphertext.extend_from_slice(&tag);
+    audit::record(AuditOperation::Encrypt, &key, plaintext.len(), None);
+    serde_json::to_string(&SjclJson {
+        iv: base64::encode(&iv),
+        v: 1,
+        iter: SJCL_ITERATIONS,
+        ks: 256,
+        ts: 128,
+        mode: String::from("gcm"),
+        adata: base64::encode(adata),
+        cipher: String::from("aes"),
+        salt: salt.map(|salt| base64::encode(&salt)),
+        ct: base64::encode(&ciphertext)
+    }).expect("serializing strings and numbers can't fail")
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "correct horse battery staple";
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    /// Values in the form `sjcl.encrypt(password, plaintext, {mode: "gcm", ks: 256, ts: ..., iter: ..., adata: ...})`
+    /// returns, computed with Python's `cryptography` (PBKDF2-HMAC-SHA256, GCM with a 16-, 12- or 8-byte iv,
+    /// tag truncated to `ts`), independently of this module. Replace them with SJCL output when at hand.
+    fn fixture(name: &str) -> String {
+        let fixtures: serde_json::Value = serde_json::from_str(include_str!("../tests/fixtures/sjcl.json")).unwrap();
+        fixtures[name].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn decrypts_password_fixtures() {
+        assert_eq!(decrypt_sjcl(SjclSecret::Password(PASSWORD), &fixture("password")).unwrap(), b"This is a text.");
+        assert_eq!(decrypt_sjcl(SjclSecret::Password(PASSWORD), &fixture("password_adata")).unwrap(), br#"{"id":42}"#);
+        assert_eq!(decrypt_sjcl(SjclSecret::Password(PASSWORD), &fixture("password_short_tag_short_iv")).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn decrypts_key_fixtures() {
+        let key = Key { u8_array: KEY };
+        assert_eq!(decrypt_sjcl(SjclSecret::Key(&key), &fixture("key_iv96")).unwrap(), b"This is a text.");
+        assert_eq!(decrypt_sjcl(SjclSecret::Key(&key), &fixture("key_empty")).unwrap(), b"")
+    }
+
+    #[test]
+    fn wrong_password_fails() {
+        match decrypt_sjcl(SjclSecret::Password("Tr0ub4dor&3"), &fixture("password")) {
+            Ok(_) => assert!(false, "Should err SjclDecryptionError"),
+            Err(e) => match e {
+                SjclError::SjclDecryptionError => assert!(true),
+                _ => assert!(false, "Should err SjclDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    fn altered_adata_fails() {
+        let json = fixture("password_adata").replace("dXNlcjo0Mg==", "dXNlcjo0Mw==");
+        match decrypt_sjcl(SjclSecret::Password(PASSWORD), &json) {
+            Ok(_) => assert!(false, "Should err SjclDecryptionError"),
+            Err(e) => match e {
+                SjclError::SjclDecryptionError => assert!(true),
+                _ => assert!(false, "Should err SjclDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported_parameters_are_rejected() {
+        for (from, to) in [(r#""mode":"gcm""#, r#""mode":"ccm""#), (r#""ks":256"#, r#""ks":128"#), (r#""ts":128"#, r#""ts":32"#)].iter() {
+            match decrypt_sjcl(SjclSecret::Password(PASSWORD), &fixture("password").replace(from, to)) {
+                Ok(_) => assert!(false, "Should err UnsupportedSjclParametersError"),
+                Err(e) => match e {
+                    SjclError::UnsupportedSjclParametersError => assert!(true),
+                    _ => assert!(false, "Should err UnsupportedSjclParametersError")
+                }
+            }
+        }
+        match decrypt_sjcl(SjclSecret::Password(PASSWORD), &fixture("key_empty")) {
+            Err(SjclError::MissingSjclSaltError) => assert!(true),
+            _ => assert!(false, "Should err MissingSjclSaltError")
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn encrypt_round_trips() {
+        let json = encrypt_sjcl(SjclSecret::Password(PASSWORD), b"This is a text.", b"user:42");
+        assert!(json.starts_with(r#"{"iv":""#) && json.contains(r#","v":1,"iter":10000,"ks":256,"ts":128,"mode":"gcm","adata":"dXNlcjo0Mg==","cipher":"aes","salt":""#));
+        assert_eq!(decrypt_sjcl(SjclSecret::Password(PASSWORD), &json).unwrap(), b"This is a text.");
+        let key = Key { u8_array: KEY };
+        assert_eq!(decrypt_sjcl(SjclSecret::Key(&key), &encrypt_sjcl(SjclSecret::Key(&key), b"", b"")).unwrap(), b"")
+    }
+}
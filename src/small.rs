@@ -0,0 +1,200 @@
+//! Encryption of small messages without heap allocation.
+//!
+//! `encrypt_small` keeps the ciphertext of up to `SMALL_MESSAGE_LEN` bytes
+//! of plaintext inline, next to the iv, and spills to an `EncryptedAndIv`
+//! above that. `decrypt_small` writes the plaintext into a caller buffer,
+//! e.g. an array on the stack. The bytes are those of the crate-root
+//! functions: under the same iv, `encrypt_small_with_iv` and
+//! `encrypt_bytes_with_iv` produce the same ciphertext, and either output
+//! decrypts with the other API.
+//!
+//! Only a spilled encryption allocates, plus whatever an audit sink or a
+//! `tracing` subscriber does when recording the operation, and the labels
+//! of the `metrics` feature.
+
+use aead::generic_array::GenericArray;
+use aead::Aead;
+
+use crate::backend::aes256_gcm;
+use crate::instrument::{self, CiphertextLen};
+use crate::secure_buffer::wipe;
+use crate::{encrypt_bytes_with_iv, DecryptionError, Encrypted, EncryptedAndIv, EncryptionError, Iv, Key, TAG_LEN};
+
+/// Largest plaintext kept inline, in bytes.
+pub const SMALL_MESSAGE_LEN: usize = 256;
+
+pub struct InlineEncrypted {
+    iv: Iv,
+    len: usize,
+    bytes: [u8; SMALL_MESSAGE_LEN + TAG_LEN]
+}
+
+// Inline is large by design: boxing it would be the allocation avoided.
+#[allow(clippy::large_enum_variant)]
+pub enum SmallEncrypted {
+    /// At most `SMALL_MESSAGE_LEN` bytes of plaintext.
+    Inline(InlineEncrypted),
+    Spilled(EncryptedAndIv)
+}
+
+impl SmallEncrypted {
+    pub fn iv(&self) -> &Iv {
+        match self {
+            SmallEncrypted::Inline(inline) => &inline.iv,
+            SmallEncrypted::Spilled(encrypted_and_iv) => &encrypted_and_iv.iv
+        }
+    }
+
+    /// Ciphertext followed by the tag, the layout of `Encrypted`.
+    pub fn ciphertext(&self) -> &[u8] {
+        match self {
+            SmallEncrypted::Inline(inline) => &inline.bytes[..inline.len],
+            SmallEncrypted::Spilled(encrypted_and_iv) => &encrypted_and_iv.encrypted.u8_vec
+        }
+    }
+
+    /// The buffer length `decrypt_small` needs.
+    pub fn plaintext_len(&self) -> usize {
+        self.ciphertext().len().saturating_sub(TAG_LEN)
+    }
+
+    /// The envelope of the crate-root functions; allocates if inline.
+    pub fn into_encrypted_and_iv(self) -> EncryptedAndIv {
+        match self {
+            SmallEncrypted::Inline(inline) => {
+                let encrypted = Encrypted { u8_vec: inline.bytes[..inline.len].to_vec() };
+                EncryptedAndIv::from_parts(inline.iv, encrypted)
+            },
+            SmallEncrypted::Spilled(encrypted_and_iv) => encrypted_and_iv
+        }
+    }
+}
+
+impl CiphertextLen for SmallEncrypted {
+    fn ciphertext_len(&self) -> usize {
+        self.ciphertext().len()
+    }
+}
+
+/// Encrypts with a freshly generated iv.
+#[cfg(feature = "rng")]
+pub fn encrypt_small(key: &Key, plaintext: &[u8]) -> Result<SmallEncrypted, EncryptionError> {
+    encrypt_small_with_iv(key, Iv::generate(), plaintext)
+}
+
+/// Encrypts with a caller-supplied iv. The iv must never be reused with the same key.
+pub fn encrypt_small_with_iv(key: &Key, iv: Iv, plaintext: &[u8]) -> Result<SmallEncrypted, EncryptionError> {
+    if plaintext.len() > SMALL_MESSAGE_LEN {
+        return encrypt_bytes_with_iv(key, iv, plaintext).map(SmallEncrypted::Spilled);
+    }
+    instrument::encrypt(key, plaintext.len(), || {
+        let mut bytes = [0u8; SMALL_MESSAGE_LEN + TAG_LEN];
+        let (body, tag) = bytes[..plaintext.len() + TAG_LEN].split_at_mut(plaintext.len());
+        body.copy_from_slice(plaintext);
+        match aes256_gcm(key).encrypt_in_place_detached(&iv.to_nonce(), &[], body) {
+            Ok(computed) => tag.copy_from_slice(&computed),
+            Err(_) => return Err(EncryptionError::GenericEncryptionError)
+        }
+        Ok(SmallEncrypted::Inline(InlineEncrypted { iv, len: plaintext.len() + TAG_LEN, bytes }))
+    })
+}
+
+/// Decrypts into the start of `out`, returning the plaintext.
+///
+/// On failure the part of `out` that was written is wiped.
+///
+/// # Panics
+///
+/// If `out` is shorter than `encrypted.plaintext_len()`.
+pub fn decrypt_small<'a>(key: &Key, encrypted: &SmallEncrypted, out: &'a mut [u8]) -> Result<&'a [u8], DecryptionError> {
+    if let SmallEncrypted::Spilled(encrypted_and_iv) = encrypted {
+        if let Some(kcv) = encrypted_and_iv.kcv {
+            if kcv != key.check_value() {
+                return Err(DecryptionError::ProbableWrongKey);
+            }
+        }
+    }
+    let ciphertext = encrypted.ciphertext();
+    instrument::decrypt(key, ciphertext.len(), move || {
+        if ciphertext.len() < TAG_LEN {
+            return Err(DecryptionError::GenericDecryptionError);
+        }
+        let (body, tag) = ciphertext.split_at(ciphertext.len() - TAG_LEN);
+        let plaintext = &mut out[..body.len()];
+        plaintext.copy_from_slice(body);
+        match aes256_gcm(key).decrypt_in_place_detached(&encrypted.iv().to_nonce(), &[], plaintext, GenericArray::from_slice(tag)) {
+            Ok(()) => Ok(&*plaintext),
+            Err(_) => {
+                wipe(plaintext);
+                Err(DecryptionError::GenericDecryptionError)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::decrypt_bytes;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+    const IV: [u8; 12] = *b"012345678901";
+
+    fn key() -> Key {
+        Key { u8_array: KEY }
+    }
+
+    #[test]
+    fn matches_the_normal_path_under_a_fixed_iv() {
+        for len in [0, 1, 15, 16, 100, SMALL_MESSAGE_LEN, SMALL_MESSAGE_LEN + 1, 1000].iter() {
+            let plaintext = vec![0x5au8; *len];
+            let small = encrypt_small_with_iv(&key(), Iv { u8_array: IV }, &plaintext).unwrap();
+            let expected = encrypt_bytes_with_iv(&key(), Iv { u8_array: IV }, &plaintext).unwrap();
+            assert_eq!(small.ciphertext(), &expected.encrypted.u8_vec[..], "{} bytes", len);
+            assert_eq!(small.plaintext_len(), *len);
+
+            let mut out = [0u8; 1024];
+            assert_eq!(decrypt_small(&key(), &small, &mut out).unwrap(), &plaintext[..]);
+            assert_eq!(decrypt_small(&key(), &SmallEncrypted::Spilled(expected), &mut out).unwrap(), &plaintext[..]);
+            assert_eq!(decrypt_bytes(&key(), &small.into_encrypted_and_iv()).unwrap(), plaintext)
+        }
+    }
+
+    #[test]
+    fn spills_above_small_message_len() {
+        match encrypt_small_with_iv(&key(), Iv { u8_array: IV }, &[1u8; SMALL_MESSAGE_LEN]).unwrap() {
+            SmallEncrypted::Inline(inline) => assert_eq!(inline.len, SMALL_MESSAGE_LEN + TAG_LEN),
+            SmallEncrypted::Spilled(_) => assert!(false, "Should stay inline")
+        }
+        match encrypt_small_with_iv(&key(), Iv { u8_array: IV }, &[1u8; SMALL_MESSAGE_LEN + 1]).unwrap() {
+            SmallEncrypted::Inline(_) => assert!(false, "Should spill"),
+            SmallEncrypted::Spilled(encrypted_and_iv) => assert_eq!(encrypted_and_iv.encrypted.u8_vec.len(), SMALL_MESSAGE_LEN + 1 + TAG_LEN)
+        }
+    }
+
+    #[test]
+    fn tampered_ciphertext_fails_and_wipes_the_output() {
+        let mut small = encrypt_small_with_iv(&key(), Iv { u8_array: IV }, b"This is a text.").unwrap();
+        if let SmallEncrypted::Inline(inline) = &mut small {
+            inline.bytes[0] ^= 1;
+        }
+        let mut out = [0xffu8; 32];
+        match decrypt_small(&key(), &small, &mut out) {
+            Ok(_) => assert!(false, "Should err DecryptionError::GenericDecryptionError"),
+            Err(e) => match e {
+                DecryptionError::GenericDecryptionError => assert!(true),
+                _ => assert!(false, "Should err DecryptionError::GenericDecryptionError, got {}", e)
+            }
+        }
+        assert_eq!(&out[..15], &[0u8; 15]);
+        assert_eq!(&out[15..], &[0xffu8; 17])
+    }
+
+    #[test]
+    #[should_panic]
+    fn short_output_buffer_panics() {
+        let small = encrypt_small_with_iv(&key(), Iv { u8_array: IV }, b"This is a text.").unwrap();
+        let _ = decrypt_small(&key(), &small, &mut [0u8; 8]);
+    }
+}
@@ -0,0 +1,125 @@
+//! proptest strategies for the envelope types.
+//!
+//! `any_envelope` generates envelopes that parse but don't decrypt, with
+//! the same edge cases as the `arbitrary` impls; `roundtrippable_envelope`
+//! generates ones that decrypt under the given key, with their plaintext.
+//!
+//! Strategies need `Debug` values, so this feature also adds `Debug` for
+//! the four types. `Key`'s only shows the fingerprint.
+
+use std::fmt;
+
+use proptest::collection::vec;
+use proptest::option;
+use proptest::prelude::*;
+
+use crate::{encrypt_bytes_with_iv, Encrypted, EncryptedAndIv, Iv, Key, TAG_LEN};
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key {{ fingerprint: {} }}", self.fingerprint())
+    }
+}
+
+impl fmt::Debug for Iv {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Iv({})", hex(&self.u8_array))
+    }
+}
+
+impl fmt::Debug for Encrypted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Encrypted({})", hex(&self.u8_vec))
+    }
+}
+
+impl fmt::Debug for EncryptedAndIv {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EncryptedAndIv")
+            .field("encrypted", &self.encrypted)
+            .field("iv", &self.iv)
+            .field("kcv", &self.kcv)
+            .field("key_id", &self.key_id)
+            .field("format_version", &self.format_version)
+            .field("aad_digest", &self.aad_digest)
+            .finish()
+    }
+}
+
+pub fn any_key() -> impl Strategy<Value = Key> {
+    any::<[u8; 32]>().prop_map(|u8_array| Key { u8_array })
+}
+
+/// All-zero ivs are generated about one time in eight.
+pub fn any_iv() -> impl Strategy<Value = Iv> {
+    prop_oneof![
+        1 => Just([0u8; 12]),
+        7 => any::<[u8; 12]>()
+    ].prop_map(|u8_array| Iv { u8_array })
+}
+
+/// At least a tag, a bare tag about one time in eight.
+pub fn any_encrypted() -> impl Strategy<Value = Encrypted> {
+    prop_oneof![
+        1 => vec(any::<u8>(), TAG_LEN),
+        7 => vec(any::<u8>(), TAG_LEN..TAG_LEN + 256)
+    ].prop_map(|u8_vec| Encrypted { u8_vec })
+}
+
+pub fn any_envelope() -> impl Strategy<Value = EncryptedAndIv> {
+    (any_encrypted(), any_iv(), option::of(any::<[u8; 3]>()), option::of(any::<[u8; 4]>()), any::<u8>(), option::of(any::<[u8; 8]>()))
+        .prop_map(|(encrypted, iv, kcv, key_id, format_version, aad_digest)| EncryptedAndIv { kcv, key_id, format_version, aad_digest, ..EncryptedAndIv::from_parts(iv, encrypted) })
+}
+
+/// Plaintexts of up to 1 KiB and envelopes that decrypt them under `key`,
+/// half of them carrying its check value.
+pub fn roundtrippable_envelope(key: &Key) -> impl Strategy<Value = (Vec<u8>, EncryptedAndIv)> {
+    let u8_array = key.u8_array;
+    (vec(any::<u8>(), 0..1024), any_iv(), any::<bool>()).prop_map(move |(plaintext, iv, with_kcv)| {
+        let key = Key { u8_array };
+        let mut encrypted_and_iv = encrypt_bytes_with_iv(&key, iv, &plaintext).expect("encryption with a given iv can't fail");
+        if with_kcv {
+            encrypted_and_iv.kcv = Some(key.check_value());
+        }
+        (plaintext, encrypted_and_iv)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "base64")]
+    use std::convert::TryFrom;
+    use crate::decrypt_bytes;
+
+    proptest! {
+        #[test]
+        fn decrypt_of_encrypt_is_identity(key in any_key(), iv in any_iv(), plaintext in vec(any::<u8>(), 0..2048)) {
+            let encrypted_and_iv = encrypt_bytes_with_iv(&key, iv, &plaintext).unwrap();
+            prop_assert_eq!(decrypt_bytes(&key, &encrypted_and_iv).unwrap(), plaintext);
+        }
+
+        #[test]
+        fn roundtrippable_envelopes_decrypt(pair in any::<[u8; 32]>().prop_flat_map(|key| (roundtrippable_envelope(&Key { u8_array: key }), Just(key)))) {
+            let ((plaintext, encrypted_and_iv), key) = pair;
+            let key = Key { u8_array: key };
+            prop_assert_eq!(decrypt_bytes(&key, &encrypted_and_iv).unwrap(), plaintext);
+        }
+
+        #[test]
+        #[cfg(feature = "base64")]
+        fn arbitrary_envelopes_round_trip_through_strings(envelope in any_envelope()) {
+            let iv = Iv::try_from(String::from(&envelope.iv).as_str()).unwrap();
+            prop_assert_eq!(iv.u8_array, envelope.iv.u8_array);
+            let encrypted = Encrypted::try_from(envelope.encrypted.to_string().as_str()).unwrap();
+            prop_assert_eq!(&encrypted.u8_vec, &envelope.encrypted.u8_vec);
+            let parsed = EncryptedAndIv::from_token(&envelope.to_token()).unwrap();
+            prop_assert_eq!(parsed.iv.u8_array, envelope.iv.u8_array);
+            prop_assert_eq!(parsed.encrypted.u8_vec, envelope.encrypted.u8_vec);
+        }
+    }
+}
@@ -0,0 +1,528 @@
+//! Chunked encryption of streams too large to hold in memory.
+//!
+//! The format follows the STREAM construction (Hoang, Reyhanitabar, Rogaway,
+//! Vizár, 2015): a header, `version (1) || salt (16) || nonce prefix (7)`,
+//! then chunks of `CHUNK_SIZE` plaintext bytes, each sealed with its own tag
+//! under the nonce `prefix (7) || chunk counter (4, big-endian) || last flag (1)`,
+//! and with the header as associated data. Only the final chunk, which can
+//! be shorter or even empty, has the last flag set.
+//!
+//! As in Tink's streaming AEAD, the chunks aren't sealed under the key
+//! itself but under a per-stream key, HKDF-SHA256 of the key with the
+//! random salt. Nonces then only have to be unique within a stream, which
+//! the counter guarantees, and the number of streams per key is bounded by
+//! salt collisions, around 2^64 streams, rather than by nonce prefix
+//! collisions, which a 7-byte random prefix alone would reach after about
+//! 2^28 streams.
+//!
+//! Because of the last flag, cutting a stream after a complete chunk is
+//! detected: `StreamDecryptor` fails with `StreamError::Truncated` instead
+//! of reaching a clean end of stream, as it does for a stream cut inside
+//! its header. A stream cut inside a chunk, however little of it is left,
+//! can't be told apart from a corrupted one and fails that chunk's
+//! authentication. In both cases the error replaces end of stream, also
+//! through `io::Read`, but chunks authenticated before it have already
+//! been returned.
+
+use std::io::{self, Read};
+#[cfg(feature = "rng")]
+use std::io::Write;
+use std::{error, fmt};
+
+use aead::{generic_array::GenericArray, Aead, Payload};
+use aes_gcm::Aes256Gcm;
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::backend::aes256_gcm;
+use crate::secure_buffer::wipe;
+use crate::instrument::StreamInstrument;
+use crate::{Key, TAG_LEN};
+
+/// Plaintext bytes per chunk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+pub const STREAM_VERSION: u8 = 1;
+
+const SALT_LEN: usize = 16;
+
+const PREFIX_LEN: usize = 7;
+
+pub(crate) const HEADER_LEN: usize = 1 + SALT_LEN + PREFIX_LEN;
+
+const STREAM_KEY_INFO: &[u8] = b"simple-aes256-gcm stream key";
+
+#[derive(Debug)]
+pub enum StreamError {
+    /// The stream ended before its final chunk.
+    Truncated,
+    /// Unknown version byte.
+    InvalidStreamHeaderError,
+    /// The chunk with this index is corrupted, or was encrypted with another key.
+    ChunkAuthenticationError(u32),
+    StreamIoError(io::Error)
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamError::Truncated => write!(f, "Stream truncated: the final chunk is missing"),
+            StreamError::InvalidStreamHeaderError => write!(f, "Invalid stream header"),
+            StreamError::ChunkAuthenticationError(index) => write!(f, "Could not authenticate chunk {}", index),
+            StreamError::StreamIoError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for StreamError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            StreamError::StreamIoError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for StreamError {
+    fn from(e: io::Error) -> StreamError {
+        StreamError::StreamIoError(e)
+    }
+}
+
+fn chunk_nonce(header: &[u8; HEADER_LEN], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..PREFIX_LEN].copy_from_slice(&header[1 + SALT_LEN..]);
+    nonce[PREFIX_LEN..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+/// The cipher of the stream whose header is `header`, under its own key.
+fn stream_cipher(key: &Key, header: &[u8; HEADER_LEN]) -> Aes256Gcm {
+    let mut stream_key = Key { u8_array: [0u8; 32] };
+    Hkdf::<Sha256>::new(Some(&header[1..1 + SALT_LEN]), &key.u8_array)
+        .expand(STREAM_KEY_INFO, &mut stream_key.u8_array)
+        .expect("32 bytes is a valid HKDF-SHA256 length");
+    let cipher = aes256_gcm(&stream_key);
+    wipe(&mut stream_key.u8_array);
+    cipher
+}
+
+/// Seals and opens the chunks of one stream, in order. Shared by the sync
+/// adapters and the ones of `async_stream`, so both write the same bytes.
+pub(crate) struct ChunkCipher {
+    cipher: Aes256Gcm,
+    header: [u8; HEADER_LEN],
+    counter: u32
+}
+
+impl ChunkCipher {
+    /// A fresh salt and nonce prefix.
+    #[cfg(feature = "rng")]
+    pub(crate) fn generate(key: &Key) -> ChunkCipher {
+        let mut header = [0u8; HEADER_LEN];
+        header[0] = STREAM_VERSION;
+        header[1..1 + SALT_LEN].copy_from_slice(&rand::random::<[u8; SALT_LEN]>());
+        header[1 + SALT_LEN..].copy_from_slice(&rand::random::<[u8; PREFIX_LEN]>());
+        ChunkCipher { cipher: stream_cipher(key, &header), header, counter: 0 }
+    }
+
+    /// Checks a header read from a stream.
+    pub(crate) fn from_header(key: &Key, header: &[u8]) -> Result<ChunkCipher, StreamError> {
+        if header.len() < HEADER_LEN {
+            return Err(StreamError::Truncated);
+        }
+        if header[0] != STREAM_VERSION {
+            return Err(StreamError::InvalidStreamHeaderError);
+        }
+        let mut header_array = [0u8; HEADER_LEN];
+        header_array.copy_from_slice(&header[..HEADER_LEN]);
+        Ok(ChunkCipher { cipher: stream_cipher(key, &header_array), header: header_array, counter: 0 })
+    }
+
+    #[cfg(feature = "rng")]
+    pub(crate) fn header(&self) -> &[u8; HEADER_LEN] {
+        &self.header
+    }
+
+    #[cfg(feature = "rng")]
+    pub(crate) fn seal(&mut self, plaintext: &[u8], last: bool) -> io::Result<Vec<u8>> {
+        let nonce = chunk_nonce(&self.header, self.counter, last);
+        let ciphertext = match self.cipher.encrypt(GenericArray::from_slice(&nonce), Payload { msg: plaintext, aad: &self.header }) {
+            Ok(value) => value,
+            Err(_) => return Err(io::Error::other("chunk encryption failed"))
+        };
+        self.counter = match self.counter.checked_add(1) {
+            Some(value) => value,
+            None => return Err(io::Error::other("stream too long"))
+        };
+        Ok(ciphertext)
+    }
+
+    fn try_open(&self, ciphertext: &[u8], last: bool) -> Option<Vec<u8>> {
+        let nonce = chunk_nonce(&self.header, self.counter, last);
+        self.cipher.decrypt(GenericArray::from_slice(&nonce), Payload { msg: ciphertext, aad: &self.header }).ok()
+    }
+
+    /// Opens the next chunk; `last` when nothing follows it in the stream.
+    pub(crate) fn open(&mut self, ciphertext: &[u8], last: bool) -> Result<Vec<u8>, StreamError> {
+        // Nothing after a chunk boundary: the stream ended without its
+        // final chunk. A fragment too short for a tag fails authentication.
+        if ciphertext.is_empty() {
+            return Err(StreamError::Truncated);
+        }
+        match self.try_open(ciphertext, last) {
+            Some(plaintext) if last => Ok(plaintext),
+            Some(plaintext) => {
+                self.counter = self.counter.checked_add(1).ok_or(StreamError::ChunkAuthenticationError(self.counter))?;
+                Ok(plaintext)
+            },
+            // A valid non-final chunk at the very end: the rest was cut off.
+            None if last && self.try_open(ciphertext, false).is_some() => Err(StreamError::Truncated),
+            None => Err(StreamError::ChunkAuthenticationError(self.counter))
+        }
+    }
+}
+
+/// Writes an encrypted stream. `finish` must be called: without the final
+/// chunk, the stream reads as truncated.
+#[cfg(feature = "rng")]
+pub struct StreamEncryptor<W: Write> {
+    chunks: ChunkCipher,
+    buffer: Vec<u8>,
+    writer: W,
+    instrument: StreamInstrument
+}
+
+#[cfg(feature = "rng")]
+impl<W: Write> StreamEncryptor<W> {
+    /// Writes the header, with a fresh salt and nonce prefix.
+    pub fn new(key: &Key, mut writer: W) -> io::Result<StreamEncryptor<W>> {
+        let chunks = ChunkCipher::generate(key);
+        writer.write_all(chunks.header())?;
+        Ok(StreamEncryptor {
+            chunks,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            writer,
+            instrument: StreamInstrument::new("encrypt", key)
+        })
+    }
+
+    fn write_chunk(&mut self, len: usize, last: bool) -> io::Result<()> {
+        let ciphertext = self.chunks.seal(&self.buffer[..len], last)?;
+        self.writer.write_all(&ciphertext)?;
+        self.buffer.drain(..len);
+        self.instrument.chunk();
+        Ok(())
+    }
+
+    /// Writes the final chunk and returns the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let len = self.buffer.len();
+        self.write_chunk(len, true)?;
+        self.writer.flush()?;
+        self.instrument.encrypted();
+        Ok(self.writer)
+    }
+}
+
+#[cfg(feature = "rng")]
+impl<W: Write> Write for StreamEncryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        self.instrument.add(buf.len());
+        // A full chunk is only written once more data follows: the final
+        // chunk, written by `finish`, must be the last one.
+        while self.buffer.len() > CHUNK_SIZE {
+            self.write_chunk(CHUNK_SIZE, false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads an encrypted stream, see the module docs for how truncation is reported.
+pub struct StreamDecryptor<R: Read> {
+    chunks: ChunkCipher,
+    lookahead: Option<u8>,
+    plaintext: Vec<u8>,
+    position: usize,
+    finished: bool,
+    failed: bool,
+    reader: R,
+    instrument: StreamInstrument
+}
+
+/// Reads until `buffer` is full or the reader is exhausted.
+fn read_up_to<R: Read>(reader: &mut R, buffer: &mut Vec<u8>, len: usize) -> io::Result<()> {
+    while buffer.len() < len {
+        let start = buffer.len();
+        buffer.resize(len, 0);
+        match reader.read(&mut buffer[start..]) {
+            Ok(0) => {
+                buffer.truncate(start);
+                return Ok(());
+            },
+            Ok(read) => buffer.truncate(start + read),
+            Err(e) => {
+                buffer.truncate(start);
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<R: Read> StreamDecryptor<R> {
+    /// Reads and checks the header.
+    pub fn new(key: &Key, mut reader: R) -> Result<StreamDecryptor<R>, StreamError> {
+        let mut instrument = StreamInstrument::new("decrypt", key);
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        let chunks = read_up_to(&mut reader, &mut header, HEADER_LEN).map_err(StreamError::from)
+            .and_then(|_| ChunkCipher::from_header(key, &header));
+        let chunks = match chunks {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                instrument.decrypted(Some(&e));
+                return Err(e);
+            }
+        };
+        Ok(StreamDecryptor {
+            chunks,
+            lookahead: None,
+            plaintext: Vec::new(),
+            position: 0,
+            finished: false,
+            failed: false,
+            reader,
+            instrument
+        })
+    }
+
+    /// Returns the next authenticated chunk, `None` after the final one.
+    pub fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, StreamError> {
+        let result = self.read_chunk();
+        match &result {
+            Err(e) => self.instrument.decrypted(Some(e)),
+            Ok(_) if self.finished => self.instrument.decrypted(None),
+            Ok(_) => {}
+        }
+        result
+    }
+
+    fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, StreamError> {
+        if self.finished {
+            return Ok(None);
+        }
+        let mut ciphertext = Vec::with_capacity(CHUNK_SIZE + TAG_LEN);
+        ciphertext.extend(self.lookahead.take());
+        read_up_to(&mut self.reader, &mut ciphertext, CHUNK_SIZE + TAG_LEN)?;
+        self.instrument.add(ciphertext.len());
+        let mut last = true;
+        if ciphertext.len() == CHUNK_SIZE + TAG_LEN {
+            let mut next = Vec::with_capacity(1);
+            read_up_to(&mut self.reader, &mut next, 1)?;
+            self.lookahead = next.first().copied();
+            last = self.lookahead.is_none();
+        }
+        let plaintext = self.chunks.open(&ciphertext, last)?;
+        self.instrument.chunk();
+        self.finished = last;
+        Ok(Some(plaintext))
+    }
+}
+
+impl<R: Read> Read for StreamDecryptor<R> {
+    /// Errors wrap a `StreamError`, reachable with `get_ref` and
+    /// `downcast_ref`. After one, every read fails: the caller never sees
+    /// a clean end of a damaged stream.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.failed {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "stream decryption already failed"));
+        }
+        while self.position == self.plaintext.len() {
+            match self.next_chunk() {
+                Ok(Some(plaintext)) => {
+                    self.plaintext = plaintext;
+                    self.position = 0;
+                },
+                Ok(None) => return Ok(0),
+                Err(StreamError::StreamIoError(e)) => return Err(e),
+                Err(e) => {
+                    self.failed = true;
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+            }
+        }
+        let len = buf.len().min(self.plaintext.len() - self.position);
+        buf[..len].copy_from_slice(&self.plaintext[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "rng")]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    fn encrypt_stream(plaintext: &[u8]) -> Vec<u8> {
+        let mut encryptor = StreamEncryptor::new(&Key { u8_array: KEY }, Vec::new()).unwrap();
+        for piece in plaintext.chunks(10_000) {
+            encryptor.write_all(piece).unwrap();
+        }
+        encryptor.finish().unwrap()
+    }
+
+    fn read_all(stream: &[u8]) -> Result<Vec<u8>, StreamError> {
+        let mut decryptor = StreamDecryptor::new(&Key { u8_array: KEY }, stream)?;
+        let mut plaintext = Vec::new();
+        while let Some(chunk) = decryptor.next_chunk()? {
+            plaintext.extend(chunk);
+        }
+        Ok(plaintext)
+    }
+
+    fn plaintext(len: usize) -> Vec<u8> {
+        (0..len).map(|index| (index % 251) as u8).collect()
+    }
+
+    #[test]
+    fn round_trips_at_and_around_chunk_boundaries() {
+        for len in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE].iter() {
+            let stream = encrypt_stream(&plaintext(*len));
+            assert_eq!(stream.len(), HEADER_LEN + len + (len / CHUNK_SIZE + if len % CHUNK_SIZE == 0 && *len != 0 { 0 } else { 1 }) * TAG_LEN);
+            assert_eq!(read_all(&stream).unwrap(), plaintext(*len));
+            let mut through_read = Vec::new();
+            StreamDecryptor::new(&Key { u8_array: KEY }, stream.as_slice()).unwrap().read_to_end(&mut through_read).unwrap();
+            assert_eq!(through_read, plaintext(*len))
+        }
+    }
+
+    #[test]
+    fn empty_plaintext_is_one_empty_final_chunk() {
+        let stream = encrypt_stream(b"");
+        assert_eq!(stream.len(), HEADER_LEN + TAG_LEN);
+        let mut decryptor = StreamDecryptor::new(&Key { u8_array: KEY }, stream.as_slice()).unwrap();
+        assert_eq!(decryptor.next_chunk().unwrap(), Some(Vec::new()));
+        assert_eq!(decryptor.next_chunk().unwrap(), None);
+        match read_all(&stream[..HEADER_LEN]) {
+            Err(StreamError::Truncated) => assert!(true),
+            _ => assert!(false, "Should err Truncated without the final chunk")
+        }
+        match StreamDecryptor::new(&Key { u8_array: KEY }, &b""[..]) {
+            Err(StreamError::Truncated) => assert!(true),
+            _ => assert!(false, "Should err Truncated for a zero-byte stream")
+        }
+    }
+
+    #[test]
+    fn cut_after_a_chunk_is_truncated() {
+        let stream = encrypt_stream(&plaintext(3 * CHUNK_SIZE + 100));
+        let chunk = CHUNK_SIZE + TAG_LEN;
+        for cut in [HEADER_LEN, HEADER_LEN + chunk, HEADER_LEN + 2 * chunk, HEADER_LEN + 3 * chunk].iter() {
+            match read_all(&stream[..*cut]) {
+                Ok(_) => assert!(false, "Should err Truncated at {}", cut),
+                Err(e) => match e {
+                    StreamError::Truncated => assert!(true),
+                    _ => assert!(false, "Should err Truncated at {}, got {}", cut, e)
+                }
+            }
+        }
+        match read_all(&stream[..HEADER_LEN - 1]) {
+            Err(StreamError::Truncated) => assert!(true),
+            _ => assert!(false, "Should err Truncated")
+        }
+    }
+
+    #[test]
+    fn cut_inside_a_chunk_fails_authentication() {
+        let stream = encrypt_stream(&plaintext(3 * CHUNK_SIZE + 100));
+        let chunk = CHUNK_SIZE + TAG_LEN;
+        let cuts = [
+            (HEADER_LEN + 100, 0),
+            (HEADER_LEN + 5, 0),
+            (HEADER_LEN + chunk + 5000, 1),
+            (HEADER_LEN + chunk + TAG_LEN - 1, 1),
+            (stream.len() - 1, 3),
+            (HEADER_LEN + 3 * chunk + TAG_LEN, 3),
+            (HEADER_LEN + 3 * chunk + 1, 3)
+        ];
+        for (cut, index) in cuts.iter() {
+            match read_all(&stream[..*cut]) {
+                Ok(_) => assert!(false, "Should err ChunkAuthenticationError at {}", cut),
+                Err(e) => match e {
+                    StreamError::ChunkAuthenticationError(value) => assert_eq!(value, *index),
+                    _ => assert!(false, "Should err ChunkAuthenticationError at {}, got {}", cut, e)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn read_adapter_never_reports_a_clean_end_of_a_truncated_stream() {
+        let stream = encrypt_stream(&plaintext(2 * CHUNK_SIZE + 100));
+        let cut = &stream[..HEADER_LEN + 2 * (CHUNK_SIZE + TAG_LEN)];
+        let mut decryptor = StreamDecryptor::new(&Key { u8_array: KEY }, cut).unwrap();
+        let mut plaintext_read = Vec::new();
+        let e = decryptor.read_to_end(&mut plaintext_read).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        match e.get_ref().and_then(|inner| inner.downcast_ref::<StreamError>()) {
+            Some(StreamError::Truncated) => assert!(true),
+            _ => assert!(false, "Should wrap StreamError::Truncated")
+        }
+        assert!(plaintext_read.len() <= 2 * CHUNK_SIZE);
+        assert!(decryptor.read(&mut [0u8; 16]).is_err())
+    }
+
+    #[test]
+    fn unfinished_encryptor_reads_as_truncated() {
+        let mut output = Vec::new();
+        {
+            let mut encryptor = StreamEncryptor::new(&Key { u8_array: KEY }, &mut output).unwrap();
+            encryptor.write_all(&plaintext(2 * CHUNK_SIZE)).unwrap();
+        }
+        match read_all(&output) {
+            Err(StreamError::Truncated) => assert!(true),
+            _ => assert!(false, "Should err Truncated")
+        }
+    }
+
+    #[test]
+    fn reordered_chunks_fail_authentication() {
+        let mut stream = encrypt_stream(&plaintext(3 * CHUNK_SIZE + 100));
+        let chunk = CHUNK_SIZE + TAG_LEN;
+        let (first, rest) = stream[HEADER_LEN..].split_at_mut(chunk);
+        first.swap_with_slice(&mut rest[..chunk]);
+        match read_all(&stream) {
+            Err(StreamError::ChunkAuthenticationError(0)) => assert!(true),
+            _ => assert!(false, "Should err ChunkAuthenticationError(0)")
+        }
+    }
+
+    #[test]
+    fn chunks_are_sealed_under_a_key_derived_from_the_salt() {
+        let stream = encrypt_stream(b"This is a text.");
+        let other = encrypt_stream(b"This is a text.");
+        assert_ne!(stream[1..1 + SALT_LEN], other[1..1 + SALT_LEN]);
+        let mut resalted = stream.clone();
+        resalted[1] ^= 1;
+        match read_all(&resalted) {
+            Err(StreamError::ChunkAuthenticationError(0)) => assert!(true),
+            _ => assert!(false, "Should err ChunkAuthenticationError(0)")
+        }
+        // The long-term key alone doesn't open the chunks.
+        let mut header = [0u8; HEADER_LEN];
+        header.copy_from_slice(&stream[..HEADER_LEN]);
+        let nonce = chunk_nonce(&header, 0, true);
+        assert!(aes256_gcm(&Key { u8_array: KEY }).decrypt(GenericArray::from_slice(&nonce), Payload { msg: &stream[HEADER_LEN..], aad: &header }).is_err())
+    }
+}
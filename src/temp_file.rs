@@ -0,0 +1,177 @@
+//! Temporary files whose contents are only ever on disk encrypted.
+//!
+//! The file is in the `stream` format, so `path()` can be handed to a
+//! process that only moves ciphertext around, and `persist_encrypted`
+//! keeps it readable with `StreamDecryptor`. It is created with a random
+//! name in `std::env::temp_dir()` (`TMPDIR` or `/tmp` on WASI, or
+//! `new_in`'s directory), readable by its owner only on Unix, and removed
+//! when the `EncryptedTempFile` is dropped, including while unwinding
+//! from a panic. A process that is killed or aborts leaves it behind, but
+//! encrypted.
+//!
+//! With the `tracing` feature, `write_all` and `read_all` are `temp_file`
+//! spans around the stream's own, see `instrument`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use zeroize::Zeroizing;
+
+use crate::stream::{StreamDecryptor, StreamEncryptor};
+use crate::Key;
+
+pub struct EncryptedTempFile {
+    key: Key,
+    file: File,
+    path: PathBuf,
+    persisted: bool
+}
+
+fn create(dir: &Path) -> io::Result<(File, PathBuf)> {
+    loop {
+        let path = dir.join(format!(".saes-{:032x}.tmp", rand::random::<u128>()));
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        match options.open(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+impl EncryptedTempFile {
+    pub fn new(key: &Key) -> io::Result<EncryptedTempFile> {
+        EncryptedTempFile::new_in(key, crate::temp_dir())
+    }
+
+    pub fn new_in<P: AsRef<Path>>(key: &Key, dir: P) -> io::Result<EncryptedTempFile> {
+        let (file, path) = create(dir.as_ref())?;
+        let mut temp_file = EncryptedTempFile { key: Key { u8_array: key.u8_array }, file, path, persisted: false };
+        temp_file.write_all(&[])?;
+        Ok(temp_file)
+    }
+
+    /// Replaces the contents with `data`, as a new stream with a fresh salt.
+    pub fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        #[cfg(feature = "tracing")]
+        let _entered = tracing::debug_span!("temp_file", operation = "write").entered();
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut encryptor = StreamEncryptor::new(&self.key, &mut self.file)?;
+        encryptor.write_all(data)?;
+        encryptor.finish()?;
+        self.file.sync_data()
+    }
+
+    /// Decrypts the contents. A damaged file fails with
+    /// `io::ErrorKind::InvalidData`, wrapping the `StreamError`.
+    pub fn read_all(&mut self) -> io::Result<Zeroizing<Vec<u8>>> {
+        #[cfg(feature = "tracing")]
+        let _entered = tracing::debug_span!("temp_file", operation = "read").entered();
+        self.file.seek(SeekFrom::Start(0))?;
+        let capacity = self.file.metadata()?.len() as usize;
+        let mut plaintext = Zeroizing::new(Vec::with_capacity(capacity));
+        let mut decryptor = StreamDecryptor::new(&self.key, &mut self.file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        loop {
+            match decryptor.next_chunk() {
+                Ok(Some(chunk)) => plaintext.extend_from_slice(&Zeroizing::new(chunk)),
+                Ok(None) => return Ok(plaintext),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+
+    /// The encrypted file, for processes that only need the ciphertext.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves the encrypted file to `path` instead of deleting it.
+    pub fn persist_encrypted<P: AsRef<Path>>(mut self, path: P) -> io::Result<()> {
+        if fs::rename(&self.path, path.as_ref()).is_err() {
+            // Across file systems: copy, then let drop remove the original.
+            fs::copy(&self.path, path.as_ref())?;
+            return Ok(());
+        }
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for EncryptedTempFile {
+    fn drop(&mut self) {
+        crate::secure_buffer::wipe(&mut self.key.u8_array);
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    #[test]
+    fn round_trips_and_replaces_contents() {
+        let mut temp_file = EncryptedTempFile::new(&Key { u8_array: KEY }).unwrap();
+        assert!(temp_file.read_all().unwrap().is_empty());
+        let data: Vec<u8> = (0..200_000u32).map(|index| index as u8).collect();
+        temp_file.write_all(&data).unwrap();
+        assert_eq!(*temp_file.read_all().unwrap(), data);
+        temp_file.write_all(b"This is a text.").unwrap();
+        assert_eq!(*temp_file.read_all().unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn contents_on_disk_are_encrypted() {
+        let mut temp_file = EncryptedTempFile::new(&Key { u8_array: KEY }).unwrap();
+        temp_file.write_all(b"This is a very secret text.").unwrap();
+        let on_disk = fs::read(temp_file.path()).unwrap();
+        assert!(!on_disk.windows(6).any(|window| window == b"secret"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(temp_file.path()).unwrap().permissions().mode() & 0o777, 0o600)
+        }
+        let mut decryptor = StreamDecryptor::new(&Key { u8_array: KEY }, on_disk.as_slice()).unwrap();
+        assert_eq!(decryptor.next_chunk().unwrap().unwrap(), b"This is a very secret text.")
+    }
+
+    #[test]
+    #[cfg(panic = "unwind")]
+    fn drop_removes_the_file_even_when_panicking() {
+        let temp_file = EncryptedTempFile::new(&Key { u8_array: KEY }).unwrap();
+        let path = temp_file.path().to_path_buf();
+        assert!(path.exists());
+        drop(temp_file);
+        assert!(!path.exists());
+
+        let path = std::panic::catch_unwind(|| {
+            let temp_file = EncryptedTempFile::new(&Key { u8_array: KEY }).unwrap();
+            let path = temp_file.path().to_path_buf();
+            std::panic::panic_any(path);
+        }).unwrap_err().downcast::<PathBuf>().unwrap();
+        assert!(!path.exists())
+    }
+
+    #[test]
+    fn persist_keeps_the_ciphertext() {
+        let mut temp_file = EncryptedTempFile::new(&Key { u8_array: KEY }).unwrap();
+        temp_file.write_all(b"This is a text.").unwrap();
+        let source = temp_file.path().to_path_buf();
+        let target = crate::test_path("persisted");
+        temp_file.persist_encrypted(&target).unwrap();
+        assert!(!source.exists());
+        let on_disk = fs::read(&target).unwrap();
+        fs::remove_file(&target).unwrap();
+        let mut decryptor = StreamDecryptor::new(&Key { u8_array: KEY }, on_disk.as_slice()).unwrap();
+        assert_eq!(decryptor.next_chunk().unwrap().unwrap(), b"This is a text.")
+    }
+}
@@ -0,0 +1,67 @@
+//! Fixed keys and ivs for tests of code built on this crate.
+//!
+//! **Never use any of this in production.** `TEST_KEY` is public, and
+//! `encrypt_deterministic_for_tests` makes iv reuse a matter of passing
+//! the same seed twice. The point is stable fixtures: the same inputs give
+//! the same ciphertext bytes in every release, which
+//! tests/golden/test_util.txt pins.
+//!
+//! Only available with the `test-util` feature, which no other feature
+//! enables; enable it under `[dev-dependencies]`.
+
+use crate::{encrypt_bytes_with_iv, EncryptedAndIv, EncryptionError, Iv, Key};
+
+/// The bytes of `test_key()`.
+pub const TEST_KEY: [u8; 32] = *b"simple-aes256-gcm test key 00000";
+
+/// Ivs from `test_iv` start with these 4 bytes.
+pub const TEST_IV_PREFIX: [u8; 4] = *b"test";
+
+pub fn test_key() -> Key {
+    Key { u8_array: TEST_KEY }
+}
+
+/// `TEST_IV_PREFIX` followed by `n` big-endian: distinct for distinct `n`.
+pub fn test_iv(n: u64) -> Iv {
+    Iv::from_parts(TEST_IV_PREFIX, n)
+}
+
+/// `encrypt_bytes_with_iv` with `test_iv(iv_seed)`.
+pub fn encrypt_deterministic_for_tests(key: &Key, iv_seed: u64, plaintext: &[u8]) -> Result<EncryptedAndIv, EncryptionError> {
+    encrypt_bytes_with_iv(key, test_iv(iv_seed), plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt_bytes;
+
+    const GOLDEN: &str = include_str!("../tests/golden/test_util.txt");
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn fixtures() -> String {
+        let mut lines = String::new();
+        for (iv_seed, plaintext) in [(0u64, &b""[..]), (1, b"This is a text."), (u64::MAX, &[0u8, 1, 2, 0xff][..])].iter() {
+            let encrypted_and_iv = encrypt_deterministic_for_tests(&test_key(), *iv_seed, plaintext).unwrap();
+            lines.push_str(&format!("{} {} {}\n", iv_seed, hex(&encrypted_and_iv.iv.u8_array), hex(&encrypted_and_iv.encrypted.u8_vec)));
+        }
+        lines
+    }
+
+    #[test]
+    fn fixtures_are_stable() {
+        assert_eq!(fixtures(), GOLDEN, "regenerate tests/golden/test_util.txt only for a deliberate format change")
+    }
+
+    #[test]
+    fn helpers_pin_exact_bytes() {
+        let encrypted_and_iv = encrypt_deterministic_for_tests(&test_key(), 1, b"This is a text.").unwrap();
+        assert_eq!(hex(&encrypted_and_iv.iv.u8_array), "746573740000000000000001");
+        assert_eq!(encrypted_and_iv.encrypted.u8_vec, encrypt_deterministic_for_tests(&test_key(), 1, b"This is a text.").unwrap().encrypted.u8_vec);
+        assert_ne!(encrypted_and_iv.encrypted.u8_vec, encrypt_deterministic_for_tests(&test_key(), 2, b"This is a text.").unwrap().encrypted.u8_vec);
+        assert_eq!(decrypt_bytes(&test_key(), &encrypted_and_iv).unwrap(), b"This is a text.")
+    }
+}
@@ -0,0 +1,448 @@
+//! Combined tokens: iv and ciphertext in one string.
+//!
+//! A token is `saes1:` followed by the standard, padded base64 of
+//! `iv (12) || ciphertext and tag`. The prefix names the format version so
+//! that tokens can be recognised inside larger documents.
+//!
+//! The `*_with` variants use another `Encoding`, each with its own prefix
+//! (`saes1u:`, `saes1x:`, `saes1b:`), so a token can't be mis-decoded under
+//! the wrong encoding. `from_token` and `decrypt_token` accept any of them.
+//!
+//! A token can end with a footer: `.` and the base64url (unpadded) of a
+//! UTF-8 string. The footer isn't encrypted, `read_footer` returns it
+//! without a key, but it is authenticated as associated data, so changing
+//! it makes decryption fail. `.` is in none of the encodings' alphabets.
+
+use std::convert::TryInto;
+use std::{error, fmt};
+
+use crate::encoding::{Encoding, InvalidEncodingError, ENCODINGS};
+use crate::{decrypt_bytes_with_aad, encrypted_len, DecryptionError, Encrypted, EncryptedAndIv, Iv, Key, IV_LEN, TAG_LEN};
+#[cfg(feature = "rng")]
+use crate::{encrypt_bytes, encrypt_bytes_with_aad, EncryptionError};
+
+/// Prefix of base64 tokens, see `Encoding::token_prefix` for the others.
+pub const TOKEN_PREFIX: &str = "saes1:";
+
+pub const FOOTER_SEPARATOR: char = '.';
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum InvalidTokenError {
+    /// The token doesn't start with the expected prefix.
+    InvalidTokenPrefixError,
+    InvalidTokenBase64Error(base64::DecodeError),
+    /// Invalid hex or base32 body.
+    InvalidTokenEncodingError(InvalidEncodingError),
+    /// Shorter than an iv and a tag.
+    InvalidTokenSizeError,
+    /// The footer isn't base64url of a UTF-8 string.
+    InvalidTokenFooterError,
+    /// The token is empty, or has nothing after its prefix.
+    EmptyTokenError
+}
+
+impl fmt::Display for InvalidTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidTokenError::InvalidTokenPrefixError => write!(f, "Please provide a token starting with {}", TOKEN_PREFIX),
+            InvalidTokenError::InvalidTokenBase64Error(_) => write!(f, "Please provide a valid base64"),
+            InvalidTokenError::InvalidTokenEncodingError(e) => write!(f, "{}", e),
+            InvalidTokenError::InvalidTokenSizeError => write!(f, "Token too short"),
+            InvalidTokenError::InvalidTokenFooterError => write!(f, "Invalid token footer"),
+            InvalidTokenError::EmptyTokenError => write!(f, "Please provide a non-empty token"),
+        }
+    }
+}
+
+impl error::Error for InvalidTokenError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            InvalidTokenError::InvalidTokenBase64Error(e) => Some(e),
+            InvalidTokenError::InvalidTokenEncodingError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum TokenError {
+    InvalidTokenError(InvalidTokenError),
+    TokenDecryptionError(DecryptionError)
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenError::InvalidTokenError(e) => write!(f, "{}", e),
+            TokenError::TokenDecryptionError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for TokenError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TokenError::InvalidTokenError(e) => Some(e),
+            TokenError::TokenDecryptionError(e) => Some(e),
+        }
+    }
+}
+
+impl EncryptedAndIv {
+    pub fn to_token(&self) -> String {
+        self.to_token_with(Encoding::Base64)
+    }
+
+    /// Parses a token in any encoding, recognised by its prefix.
+    pub fn from_token(token: &str) -> Result<EncryptedAndIv, InvalidTokenError> {
+        if token.is_empty() {
+            return Err(InvalidTokenError::EmptyTokenError);
+        }
+        match ENCODINGS.iter().find(|encoding| token.starts_with(encoding.token_prefix())) {
+            Some(encoding) => EncryptedAndIv::from_token_with(token, *encoding),
+            None => Err(InvalidTokenError::InvalidTokenPrefixError)
+        }
+    }
+
+    pub fn to_token_with(&self, encoding: Encoding) -> String {
+        let mut bytes = self.iv.u8_array.to_vec();
+        bytes.extend_from_slice(&self.encrypted.u8_vec);
+        format!("{}{}", encoding.token_prefix(), encoding.encode(&bytes))
+    }
+
+    /// Parses a token in `encoding` only; tokens in other encodings fail
+    /// with `InvalidTokenPrefixError`.
+    pub fn from_token_with(token: &str, encoding: Encoding) -> Result<EncryptedAndIv, InvalidTokenError> {
+        if token.is_empty() || token == encoding.token_prefix() {
+            return Err(InvalidTokenError::EmptyTokenError);
+        }
+        if !token.starts_with(encoding.token_prefix()) {
+            return Err(InvalidTokenError::InvalidTokenPrefixError);
+        }
+        let bytes = match encoding.decode(&token[encoding.token_prefix().len()..]) {
+            Ok(data) => data,
+            Err(InvalidEncodingError::InvalidBase64Error(e)) => return Err(InvalidTokenError::InvalidTokenBase64Error(e)),
+            Err(e) => return Err(InvalidTokenError::InvalidTokenEncodingError(e))
+        };
+        if bytes.len() < IV_LEN + TAG_LEN {
+            return Err(InvalidTokenError::InvalidTokenSizeError);
+        }
+        Ok(EncryptedAndIv::from_parts(Iv { u8_array: bytes[..IV_LEN].try_into().unwrap() }, Encrypted { u8_vec: bytes[IV_LEN..].to_vec() }))
+    }
+}
+
+/// Exact length of a token without footer for a plaintext of
+/// `plaintext_len` bytes, prefix included.
+pub fn token_len(plaintext_len: usize, encoding: Encoding) -> usize {
+    encoding.token_prefix().len() + encoding.encoded_len(IV_LEN + encrypted_len(plaintext_len))
+}
+
+/// Largest plaintext whose token (without footer) fits in `limit`
+/// characters, `None` if not even an empty one does.
+pub fn max_plaintext_for_token_len(limit: usize, encoding: Encoding) -> Option<usize> {
+    let encoded_limit = limit.checked_sub(encoding.token_prefix().len())?;
+    encoding.max_decoded_len(encoded_limit).checked_sub(IV_LEN + TAG_LEN)
+}
+
+impl Key {
+    /// Same as `encrypt_to_token(self, plaintext)`.
+    #[cfg(feature = "rng")]
+    pub fn encrypt_to_token(&self, plaintext: &[u8]) -> Result<String, EncryptionError> {
+        encrypt_to_token(self, plaintext)
+    }
+
+    /// Same as `decrypt_token(self, token)`.
+    pub fn decrypt_token(&self, token: &str) -> Result<Vec<u8>, TokenError> {
+        decrypt_token(self, token)
+    }
+}
+
+#[cfg(feature = "rng")]
+pub fn encrypt_to_token(key: &Key, plaintext: &[u8]) -> Result<String, EncryptionError> {
+    Ok(encrypt_bytes(key, plaintext)?.to_token())
+}
+
+#[cfg(feature = "rng")]
+pub fn encrypt_to_token_with(key: &Key, plaintext: &[u8], encoding: Encoding) -> Result<String, EncryptionError> {
+    Ok(encrypt_bytes(key, plaintext)?.to_token_with(encoding))
+}
+
+pub(crate) fn footer_aad(footer: &str) -> Vec<u8> {
+    let mut aad = b"simple-aes256-gcm token footer:".to_vec();
+    aad.extend_from_slice(footer.as_bytes());
+    aad
+}
+
+/// Splits off the footer, if any.
+fn split_footer(token: &str) -> Result<(&str, Option<String>), InvalidTokenError> {
+    let (body, encoded) = match token.find(FOOTER_SEPARATOR) {
+        Some(index) => (&token[..index], &token[index + 1..]),
+        None => return Ok((token, None))
+    };
+    let bytes = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).map_err(|_| InvalidTokenError::InvalidTokenFooterError)?;
+    match String::from_utf8(bytes) {
+        Ok(footer) => Ok((body, Some(footer))),
+        Err(_) => Err(InvalidTokenError::InvalidTokenFooterError)
+    }
+}
+
+/// Returns the footer without verifying it, `None` if the token has none.
+///
+/// Anyone can forge a footer: only trust it for routing and similar
+/// decisions that a later `decrypt_token` would catch.
+pub fn read_footer(token: &str) -> Result<Option<String>, InvalidTokenError> {
+    Ok(split_footer(token)?.1)
+}
+
+/// Encrypts to a token carrying `footer` in the clear, see the module docs.
+#[cfg(feature = "rng")]
+pub fn encrypt_to_token_with_footer(key: &Key, plaintext: &[u8], footer: &str) -> Result<String, EncryptionError> {
+    Ok(append_footer(encrypt_bytes_with_aad(key, plaintext, &footer_aad(footer))?.to_token(), footer))
+}
+
+#[cfg(feature = "rng")]
+pub(crate) fn append_footer(token: String, footer: &str) -> String {
+    format!("{}{}{}", token, FOOTER_SEPARATOR, base64::encode_config(footer, base64::URL_SAFE_NO_PAD))
+}
+
+/// The associated data a token with `footer`, if any, is encrypted with.
+pub(crate) fn token_aad(footer: Option<&str>) -> Vec<u8> {
+    footer.map(footer_aad).unwrap_or_default()
+}
+
+fn decrypt_parsed(key: &Key, encrypted_and_iv: EncryptedAndIv, footer: Option<String>) -> Result<Vec<u8>, TokenError> {
+    decrypt_bytes_with_aad(key, &encrypted_and_iv, &token_aad(footer.as_deref())).map_err(TokenError::TokenDecryptionError)
+}
+
+/// The envelope of a token in any encoding, and its footer.
+pub(crate) fn parse_token(token: &str) -> Result<(EncryptedAndIv, Option<String>), InvalidTokenError> {
+    let (body, footer) = split_footer(token)?;
+    Ok((EncryptedAndIv::from_token(body)?, footer))
+}
+
+/// The envelope of a token whose body must be in `encoding`, and its footer.
+pub(crate) fn parse_token_with(token: &str, encoding: Encoding) -> Result<(EncryptedAndIv, Option<String>), InvalidTokenError> {
+    let (body, footer) = split_footer(token)?;
+    Ok((EncryptedAndIv::from_token_with(body, encoding)?, footer))
+}
+
+/// Decrypts a token in any encoding, verifying its footer if it has one.
+pub fn decrypt_token(key: &Key, token: &str) -> Result<Vec<u8>, TokenError> {
+    let (encrypted_and_iv, footer) = parse_token(token).map_err(TokenError::InvalidTokenError)?;
+    decrypt_parsed(key, encrypted_and_iv, footer)
+}
+
+/// Decrypts a token that must be in `encoding`.
+pub fn decrypt_token_with(key: &Key, token: &str, encoding: Encoding) -> Result<Vec<u8>, TokenError> {
+    let (encrypted_and_iv, footer) = parse_token_with(token, encoding).map_err(TokenError::InvalidTokenError)?;
+    decrypt_parsed(key, encrypted_and_iv, footer)
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::encrypt_bytes_with_iv;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+
+    #[test]
+    fn token_is_pinned() {
+        let encrypted_and_iv = encrypt_bytes_with_iv(&Key { u8_array: KEY }, Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap();
+        assert_eq!(encrypted_and_iv.to_token(), "saes1:MDEyMzQ1Njc4OTAxJOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==")
+    }
+
+    #[test]
+    fn tokens_drop_metadata_and_parse_without_it() {
+        let (iv, encrypted) = encrypt_bytes_with_iv(&Key { u8_array: KEY }, Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap().into_parts();
+        let with_metadata = EncryptedAndIv::from_parts(iv, encrypted).with_kcv([188, 144, 65]).with_key_id(*b"k001").with_aad_digest([1; 8]).with_format_version(2);
+        assert_eq!(with_metadata.to_token(), "saes1:MDEyMzQ1Njc4OTAxJOehGYGcLFDCSuItDKFc4pcqzZ1e7xmBaEVLjMKyWQ==");
+        let parsed = EncryptedAndIv::from_token(&with_metadata.to_token()).unwrap();
+        assert_eq!((parsed.kcv(), parsed.key_id(), parsed.aad_digest(), parsed.format_version()), (None, None, None, crate::FORMAT_VERSION));
+        assert_eq!(parsed.encrypted().u8_vec, with_metadata.encrypted().u8_vec)
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn encrypt_to_token_then_decrypt_token_is_iso() {
+        let token = encrypt_to_token(&Key { u8_array: KEY }, b"This is a text.").unwrap();
+        assert_eq!(decrypt_token(&Key { u8_array: KEY }, &token).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn key_encrypt_to_token_then_key_decrypt_token_is_iso() {
+        let key = Key { u8_array: KEY };
+        let token = key.encrypt_to_token(b"This is a text.").unwrap();
+        assert_eq!(key.decrypt_token(&token).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn key_decrypt_token_errors() {
+        let key = Key { u8_array: KEY };
+        match key.decrypt_token("saes9:MDEyMzQ1Njc4OTAx") {
+            Ok(_) => assert!(false, "Should err InvalidTokenError"),
+            Err(e) => match e {
+                TokenError::InvalidTokenError(InvalidTokenError::InvalidTokenPrefixError) => assert!(true),
+                _ => assert!(false, "Should err InvalidTokenError")
+            }
+        }
+        let token = encrypt_bytes_with_iv(&key, Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap().to_token();
+        match (Key { u8_array: [0u8; 32] }).decrypt_token(&token) {
+            Ok(_) => assert!(false, "Should err TokenDecryptionError"),
+            Err(e) => match e {
+                TokenError::TokenDecryptionError(_) => assert!(true),
+                _ => assert!(false, "Should err TokenDecryptionError")
+            }
+        }
+    }
+
+    #[test]
+    fn token_round_trips_in_every_encoding() {
+        let key = Key { u8_array: KEY };
+        for encoding in ENCODINGS.iter() {
+            let token = encrypt_bytes_with_iv(&key, Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap().to_token_with(*encoding);
+            assert!(token.starts_with(encoding.token_prefix()));
+            assert_eq!(decrypt_token_with(&key, &token, *encoding).unwrap(), b"This is a text.");
+            assert_eq!(decrypt_token(&key, &token).unwrap(), b"This is a text.")
+        }
+    }
+
+    #[test]
+    fn token_len_matches_real_tokens() {
+        let key = Key { u8_array: KEY };
+        for encoding in ENCODINGS.iter() {
+            for plaintext_len in [0usize, 1, 100, 10_000].iter() {
+                let token = encrypt_bytes_with_iv(&key, Iv { u8_array: *b"012345678901" }, &vec![7u8; *plaintext_len]).unwrap().to_token_with(*encoding);
+                assert_eq!(token.len(), token_len(*plaintext_len, *encoding), "{:?} of {} bytes", encoding, plaintext_len);
+            }
+        }
+    }
+
+    #[test]
+    fn max_plaintext_for_token_len_is_the_inverse() {
+        for encoding in ENCODINGS.iter() {
+            assert_eq!(max_plaintext_for_token_len(token_len(0, *encoding) - 1, *encoding), None);
+            assert_eq!(max_plaintext_for_token_len(0, *encoding), None);
+            for plaintext_len in [0usize, 1, 100, 10_000].iter() {
+                for slack in 0..8 {
+                    let limit = token_len(*plaintext_len, *encoding) + slack;
+                    let max = max_plaintext_for_token_len(limit, *encoding).unwrap();
+                    assert!(max >= *plaintext_len);
+                    assert!(token_len(max, *encoding) <= limit && token_len(max + 1, *encoding) > limit, "{:?} in {}", encoding, limit)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn token_in_another_encoding_is_rejected() {
+        let encrypted_and_iv = encrypt_bytes_with_iv(&Key { u8_array: KEY }, Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap();
+        for encoding in ENCODINGS.iter() {
+            for other in ENCODINGS.iter().filter(|other| *other != encoding) {
+                match EncryptedAndIv::from_token_with(&encrypted_and_iv.to_token_with(*encoding), *other) {
+                    Ok(_) => assert!(false, "Should err InvalidTokenPrefixError"),
+                    Err(e) => match e {
+                        InvalidTokenError::InvalidTokenPrefixError => assert!(true),
+                        _ => assert!(false, "Should err InvalidTokenPrefixError")
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn footer_is_readable_without_the_key() {
+        let key = Key { u8_array: KEY };
+        let token = encrypt_to_token_with_footer(&key, b"This is a text.", "tenant=acme.eu/1").unwrap();
+        assert_eq!(token.matches(FOOTER_SEPARATOR).count(), 1);
+        assert_eq!(read_footer(&token).unwrap(), Some(String::from("tenant=acme.eu/1")));
+        assert_eq!(decrypt_token(&key, &token).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn modified_or_removed_footer_fails_decryption() {
+        let key = Key { u8_array: KEY };
+        let token = encrypt_to_token_with_footer(&key, b"This is a text.", "tenant=acme").unwrap();
+        let body = &token[..token.find(FOOTER_SEPARATOR).unwrap()];
+        let forged = format!("{}.{}", body, base64::encode_config("tenant=evil", base64::URL_SAFE_NO_PAD));
+        assert_eq!(read_footer(&forged).unwrap(), Some(String::from("tenant=evil")));
+        for token in [forged.as_str(), body].iter() {
+            match decrypt_token(&key, token) {
+                Ok(_) => assert!(false, "Should err TokenDecryptionError"),
+                Err(e) => match e {
+                    TokenError::TokenDecryptionError(_) => assert!(true),
+                    _ => assert!(false, "Should err TokenDecryptionError")
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn token_without_footer_still_parses() {
+        let token = encrypt_bytes_with_iv(&Key { u8_array: KEY }, Iv { u8_array: *b"012345678901" }, b"This is a text.").unwrap().to_token();
+        assert_eq!(read_footer(&token).unwrap(), None);
+        assert_eq!(decrypt_token(&Key { u8_array: KEY }, &token).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn invalid_footer_is_rejected() {
+        match read_footer("saes1:MDEyMzQ1Njc4OTAx.not base64!") {
+            Ok(_) => assert!(false, "Should err InvalidTokenFooterError"),
+            Err(e) => match e {
+                InvalidTokenError::InvalidTokenFooterError => assert!(true),
+                _ => assert!(false, "Should err InvalidTokenFooterError")
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_prefix_is_rejected() {
+        match EncryptedAndIv::from_token("saes9:MDEyMzQ1Njc4OTAx") {
+            Ok(_) => assert!(false, "Should err InvalidTokenPrefixError"),
+            Err(e) => match e {
+                InvalidTokenError::InvalidTokenPrefixError => assert!(true),
+                _ => assert!(false, "Should err InvalidTokenPrefixError")
+            }
+        }
+    }
+
+    #[test]
+    fn empty_tokens_are_rejected() {
+        for token in ["", TOKEN_PREFIX, Encoding::Hex.token_prefix()].iter() {
+            match EncryptedAndIv::from_token(token) {
+                Ok(_) => assert!(false, "Should err EmptyTokenError for {:?}", token),
+                Err(e) => match e {
+                    InvalidTokenError::EmptyTokenError => assert!(true),
+                    _ => assert!(false, "Should err EmptyTokenError for {:?}, got {}", token, e)
+                }
+            }
+        }
+        match decrypt_token(&Key { u8_array: KEY }, "") {
+            Err(TokenError::InvalidTokenError(InvalidTokenError::EmptyTokenError)) => assert!(true),
+            _ => assert!(false, "Should err EmptyTokenError")
+        }
+    }
+
+    #[test]
+    fn empty_plaintext_token_round_trips() {
+        let key = Key { u8_array: KEY };
+        let token = encrypt_bytes_with_iv(&key, Iv { u8_array: *b"012345678901" }, b"").unwrap().to_token();
+        assert_eq!(token.len(), token_len(0, Encoding::Base64));
+        assert_eq!(decrypt_token(&key, &token).unwrap(), Vec::<u8>::new())
+    }
+
+    #[test]
+    fn short_token_is_rejected() {
+        match EncryptedAndIv::from_token("saes1:MDEyMzQ1Njc4OTAx") {
+            Ok(_) => assert!(false, "Should err InvalidTokenSizeError"),
+            Err(e) => match e {
+                InvalidTokenError::InvalidTokenSizeError => assert!(true),
+                _ => assert!(false, "Should err InvalidTokenSizeError")
+            }
+        }
+    }
+}
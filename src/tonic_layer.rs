@@ -0,0 +1,493 @@
+//! Transparent encryption of gRPC messages between tonic clients and
+//! servers, enabled with the `tonic` feature.
+//!
+//! `EncryptionLayer` is a tower layer for both ends: on a client channel it
+//! encrypts requests and decrypts responses, on a `tonic::transport::Server`
+//! it does the reverse. Generated clients and servers are unchanged. Every
+//! gRPC message is replaced by an `EncryptedValue`:
+//!
+//! ```text
+//! message EncryptedValue {
+//!   string key_id = 1;
+//!   bytes iv = 2;
+//!   bytes ciphertext = 3; // ciphertext and tag
+//! }
+//! ```
+//!
+//! The associated data binds it to the method path, the direction and the
+//! message's position in the stream, so messages can't be moved between
+//! methods, from requests to responses, or reordered. The client sends the
+//! key id in the `x-saes-key-id` metadata; the server resolves it with its
+//! `KeyProvider` and answers under the same key. `key_for` is called while
+//! handling the request, so it should be fast: a `KeyRing` or a warm
+//! `caching_provider::CachingKeyProvider`.
+//!
+//! Failures become statuses, never panics: a missing, unknown or
+//! unavailable key id is `UNAUTHENTICATED`, a failing provider
+//! `UNAVAILABLE`, and a message that doesn't decrypt `INVALID_ARGUMENT`.
+//! Messages are transformed as they stream, one at a time; compressed
+//! messages are encrypted compressed.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::HeaderValue;
+use http_body::{Body as HttpBody, Frame};
+use prost::Message;
+use tonic::body::Body;
+use tonic::Status;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::provider::{KeyProvider, ProviderError};
+use crate::{decrypt_bytes_with_aad, encrypt_bytes_with_iv_and_aad, Encrypted, EncryptedAndIv, Iv, Key, IV_LEN};
+
+pub const KEY_ID_METADATA: &str = "x-saes-key-id";
+
+/// Largest incoming encrypted message, tonic's default decoding limit.
+pub const MAX_MESSAGE_LEN: usize = 4 * 1024 * 1024;
+
+const AAD_LABEL: &[u8] = b"simple-aes256-gcm grpc v1";
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Compressed flag and length.
+const FRAME_HEADER_LEN: usize = 5;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct EncryptedValue {
+    #[prost(string, tag = "1")]
+    pub key_id: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub iv: Vec<u8>,
+    /// Ciphertext and tag.
+    #[prost(bytes = "vec", tag = "3")]
+    pub ciphertext: Vec<u8>
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Request,
+    Response
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Seal,
+    Open
+}
+
+/// Encrypts or decrypts the messages of one direction of one call.
+struct MessageCipher {
+    key: Arc<Key>,
+    key_id: String,
+    path: String,
+    direction: Direction,
+    index: u64
+}
+
+impl MessageCipher {
+    fn aad(&self) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(AAD_LABEL.len() + self.path.len() + 11);
+        aad.extend_from_slice(AAD_LABEL);
+        aad.push(0);
+        aad.extend_from_slice(self.path.as_bytes());
+        aad.push(0);
+        aad.push(match self.direction {
+            Direction::Request => 0,
+            Direction::Response => 1
+        });
+        aad.extend_from_slice(&self.index.to_be_bytes());
+        aad
+    }
+
+    fn seal(&mut self, message: &[u8]) -> Result<Vec<u8>, Status> {
+        let encrypted_and_iv = encrypt_bytes_with_iv_and_aad(&self.key, Iv::generate(), message, &self.aad())
+            .map_err(|e| Status::internal(e.to_string()))?;
+        self.index += 1;
+        let value = EncryptedValue { key_id: self.key_id.clone(), iv: encrypted_and_iv.iv.u8_array.to_vec(), ciphertext: encrypted_and_iv.encrypted.u8_vec };
+        Ok(value.encode_to_vec())
+    }
+
+    fn open(&mut self, message: &[u8]) -> Result<Vec<u8>, Status> {
+        let value = EncryptedValue::decode(message).map_err(|_| Status::invalid_argument("Not an encrypted message"))?;
+        if value.key_id != self.key_id {
+            return Err(Status::invalid_argument("Encrypted message under another key id"));
+        }
+        if value.iv.len() != IV_LEN {
+            return Err(Status::invalid_argument("Not an encrypted message"));
+        }
+        let mut u8_array = [0u8; IV_LEN];
+        u8_array.copy_from_slice(&value.iv);
+        let encrypted_and_iv = EncryptedAndIv::from_parts(Iv { u8_array }, Encrypted { u8_vec: value.ciphertext });
+        let plaintext = decrypt_bytes_with_aad(&self.key, &encrypted_and_iv, &self.aad())
+            .map_err(|_| Status::invalid_argument("Encrypted message doesn't decrypt"))?;
+        self.index += 1;
+        Ok(plaintext)
+    }
+}
+
+/// A body whose gRPC messages are sealed or opened as they arrive.
+struct CipherBody<B> {
+    inner: Pin<Box<B>>,
+    buffer: BytesMut,
+    cipher: MessageCipher,
+    mode: Mode
+}
+
+impl<B> CipherBody<B> {
+    fn new(inner: B, cipher: MessageCipher, mode: Mode) -> CipherBody<B> {
+        CipherBody { inner: Box::pin(inner), buffer: BytesMut::new(), cipher, mode }
+    }
+
+    /// The next complete message, transformed and framed again.
+    fn next_message(&mut self) -> Option<Result<Bytes, Status>> {
+        if self.buffer.len() < FRAME_HEADER_LEN {
+            return None;
+        }
+        let compressed = self.buffer[0];
+        let len = u32::from_be_bytes([self.buffer[1], self.buffer[2], self.buffer[3], self.buffer[4]]) as usize;
+        if self.mode == Mode::Open && len > MAX_MESSAGE_LEN {
+            self.buffer.clear();
+            return Some(Err(Status::resource_exhausted("Encrypted message too large")));
+        }
+        if self.buffer.len() < FRAME_HEADER_LEN + len {
+            return None;
+        }
+        self.buffer.advance(FRAME_HEADER_LEN);
+        let message = self.buffer.split_to(len);
+        let transformed = match self.mode {
+            Mode::Seal => self.cipher.seal(&message),
+            Mode::Open => self.cipher.open(&message)
+        };
+        Some(transformed.map(|transformed| {
+            let mut frame = BytesMut::with_capacity(FRAME_HEADER_LEN + transformed.len());
+            frame.put_u8(compressed);
+            frame.put_u32(transformed.len() as u32);
+            frame.put_slice(&transformed);
+            frame.freeze()
+        }))
+    }
+}
+
+impl<B> HttpBody for CipherBody<B>
+where B: HttpBody<Data = Bytes>, B::Error: Into<BoxError> {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Status>>> {
+        let this = &mut *self;
+        loop {
+            if let Some(message) = this.next_message() {
+                return Poll::Ready(Some(message.map(Frame::data)));
+            }
+            let frame = match this.inner.as_mut().poll_frame(cx) {
+                Poll::Ready(frame) => frame,
+                Poll::Pending => return Poll::Pending
+            };
+            match frame {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => this.buffer.extend_from_slice(&data),
+                    Err(trailers) if this.buffer.is_empty() => return Poll::Ready(Some(Ok(trailers))),
+                    Err(_) => return Poll::Ready(Some(Err(Status::invalid_argument("Truncated gRPC message"))))
+                },
+                Some(Err(e)) => return Poll::Ready(Some(Err(Status::from_error(e.into())))),
+                None if this.buffer.is_empty() => return Poll::Ready(None),
+                None => {
+                    this.buffer.clear();
+                    return Poll::Ready(Some(Err(Status::invalid_argument("Truncated gRPC message"))));
+                }
+            }
+        }
+    }
+}
+
+fn provider_status(e: ProviderError) -> Status {
+    match e {
+        ProviderError::UnknownKeyIdError(_) | ProviderError::KeyUnavailableError(_) => Status::unauthenticated(e.to_string()),
+        ProviderError::ProviderBackendError(_) => Status::unavailable(e.to_string())
+    }
+}
+
+struct SingleKey {
+    key_id: String,
+    key: Key
+}
+
+impl KeyProvider for SingleKey {
+    fn key_for(&self, key_id: &str) -> Result<Key, ProviderError> {
+        if key_id == self.key_id {
+            Ok(Key { u8_array: self.key.u8_array })
+        } else {
+            Err(ProviderError::UnknownKeyIdError(String::from(key_id)))
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Role {
+    /// Encrypts under this key id.
+    Client(String),
+    Server
+}
+
+#[derive(Clone)]
+pub struct EncryptionLayer {
+    keys: Arc<dyn KeyProvider + Send + Sync>,
+    role: Role
+}
+
+impl EncryptionLayer {
+    /// For a client channel, encrypting under `key_id` as resolved by `keys`.
+    pub fn client(keys: Arc<dyn KeyProvider + Send + Sync>, key_id: &str) -> EncryptionLayer {
+        EncryptionLayer { keys, role: Role::Client(String::from(key_id)) }
+    }
+
+    /// For a server, resolving the key ids clients send with `keys`.
+    pub fn server(keys: Arc<dyn KeyProvider + Send + Sync>) -> EncryptionLayer {
+        EncryptionLayer { keys, role: Role::Server }
+    }
+
+    /// Same as `client`, with a single shared key.
+    pub fn client_with_key(key_id: &str, key: Key) -> EncryptionLayer {
+        EncryptionLayer::client(Arc::new(SingleKey { key_id: String::from(key_id), key }), key_id)
+    }
+
+    /// Same as `server`, accepting only `key_id`.
+    pub fn server_with_key(key_id: &str, key: Key) -> EncryptionLayer {
+        EncryptionLayer::server(Arc::new(SingleKey { key_id: String::from(key_id), key }))
+    }
+}
+
+impl<S> Layer<S> for EncryptionLayer {
+    type Service = EncryptionService<S>;
+
+    fn layer(&self, inner: S) -> EncryptionService<S> {
+        EncryptionService { inner, keys: Arc::clone(&self.keys), role: self.role.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct EncryptionService<S> {
+    inner: S,
+    keys: Arc<dyn KeyProvider + Send + Sync>,
+    role: Role
+}
+
+impl<S> EncryptionService<S> {
+    fn resolve<B>(&self, request: &http::Request<B>) -> Result<(String, Arc<Key>), Status> {
+        let key_id = match &self.role {
+            Role::Client(key_id) => key_id.clone(),
+            Role::Server => match request.headers().get(KEY_ID_METADATA).map(HeaderValue::to_str) {
+                Some(Ok(key_id)) => String::from(key_id),
+                _ => return Err(Status::unauthenticated(format!("Missing {} metadata", KEY_ID_METADATA)))
+            }
+        };
+        let key = self.keys.key_for(&key_id).map_err(provider_status)?;
+        Ok((key_id, Arc::new(key)))
+    }
+}
+
+impl<S, B, ResBody> Service<http::Request<B>> for EncryptionService<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    ResBody: HttpBody<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>
+{
+    type Response = http::Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<http::Response<Body>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let (key_id, key) = match self.resolve(&request) {
+            Ok(resolved) => resolved,
+            Err(status) => return Box::pin(async move { Ok(status.into_http()) })
+        };
+        let (mut parts, body) = request.into_parts();
+        let (request_mode, response_mode) = match self.role {
+            Role::Client(_) => {
+                match HeaderValue::from_str(&key_id) {
+                    Ok(value) => parts.headers.insert(KEY_ID_METADATA, value),
+                    Err(_) => return Box::pin(async move { Ok(Status::internal("Key id isn't valid metadata").into_http()) })
+                };
+                (Mode::Seal, Mode::Open)
+            },
+            Role::Server => (Mode::Open, Mode::Seal)
+        };
+        let path = String::from(parts.uri.path());
+        let request_cipher = MessageCipher { key: Arc::clone(&key), key_id: key_id.clone(), path: path.clone(), direction: Direction::Request, index: 0 };
+        let response_cipher = MessageCipher { key, key_id, path, direction: Direction::Response, index: 0 };
+        let request = http::Request::from_parts(parts, Body::new(CipherBody::new(body, request_cipher, request_mode)));
+
+        // The clone is ready only if `self.inner` was, so call the original.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let response = inner.call(request).await?;
+            Ok(response.map(|body| Body::new(CipherBody::new(body, response_cipher, response_mode))))
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use http::uri::PathAndQuery;
+    use tonic::client::GrpcService;
+    use tonic::server::UnaryService;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::{Channel, Server};
+    use tonic::{Code, Request, Response};
+    use tonic_prost::ProstCodec;
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+    const PATH: &str = "/test.Echo/Reverse";
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Echo {
+        #[prost(bytes = "vec", tag = "1")]
+        payload: Vec<u8>
+    }
+
+    struct Reverse;
+
+    impl UnaryService<Echo> for Reverse {
+        type Response = Echo;
+        type Future = std::future::Ready<Result<Response<Echo>, Status>>;
+
+        fn call(&mut self, request: Request<Echo>) -> Self::Future {
+            let mut payload = request.into_inner().payload;
+            payload.reverse();
+            std::future::ready(Ok(Response::new(Echo { payload })))
+        }
+    }
+
+    /// What a generated server does for one unary method.
+    #[derive(Clone)]
+    struct EchoServer;
+
+    impl Service<http::Request<Body>> for EchoServer {
+        type Response = http::Response<Body>;
+        type Error = Infallible;
+        type Future = Pin<Box<dyn Future<Output = Result<http::Response<Body>, Infallible>> + Send>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+            Box::pin(async move { Ok(tonic::server::Grpc::new(ProstCodec::<Echo, Echo>::default()).unary(Reverse, request).await) })
+        }
+    }
+
+    async fn serve(layer: EncryptionLayer) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Server::builder().layer(layer).serve_with_incoming(EchoServer, TcpIncoming::from(listener)));
+        addr
+    }
+
+    async fn channel(addr: SocketAddr) -> Channel {
+        Channel::from_shared(format!("http://{}", addr)).unwrap().connect().await.unwrap()
+    }
+
+    /// What a generated client does for one unary method.
+    async fn call<T, M1, M2>(service: T, metadata: Option<&str>, message: M1) -> Result<M2, Status>
+    where
+        T: GrpcService<Body>,
+        T::Error: Into<BoxError>,
+        T::ResponseBody: HttpBody<Data = Bytes> + Send + 'static,
+        <T::ResponseBody as HttpBody>::Error: Into<BoxError>,
+        M1: Message + Send + Sync + 'static,
+        M2: Message + Default + Send + Sync + 'static
+    {
+        let mut grpc = tonic::client::Grpc::new(service);
+        grpc.ready().await.map_err(|e| Status::unknown(e.into().to_string()))?;
+        let mut request = Request::new(message);
+        if let Some(key_id) = metadata {
+            request.metadata_mut().insert(KEY_ID_METADATA, key_id.parse().unwrap());
+        }
+        grpc.unary(request, PathAndQuery::from_static(PATH), ProstCodec::<M1, M2>::default()).await.map(Response::into_inner)
+    }
+
+    fn echo(payload: &[u8]) -> Echo {
+        Echo { payload: payload.to_vec() }
+    }
+
+    fn assert_code(result: Result<Echo, Status>, code: Code) {
+        match result {
+            Ok(_) => assert!(false, "Should err {:?}", code),
+            Err(status) => assert_eq!(status.code(), code, "{}", status)
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_through_both_layers() {
+        let addr = serve(EncryptionLayer::server_with_key("k1", Key { u8_array: KEY })).await;
+        let client = EncryptionLayer::client_with_key("k1", Key { u8_array: KEY }).layer(channel(addr).await);
+        let reply: Echo = call(client.clone(), None, echo(b"This is a text.")).await.unwrap();
+        assert_eq!(reply, echo(b".txet a si sihT"));
+        let reply: Echo = call(client, None, echo(b"")).await.unwrap();
+        assert_eq!(reply, echo(b""))
+    }
+
+    fn cipher(direction: Direction, index: u64) -> MessageCipher {
+        MessageCipher { key: Arc::new(Key { u8_array: KEY }), key_id: String::from("k1"), path: String::from(PATH), direction, index }
+    }
+
+    #[tokio::test]
+    async fn messages_are_encrypted_values_on_the_wire() {
+        let addr = serve(EncryptionLayer::server_with_key("k1", Key { u8_array: KEY })).await;
+        let sealed = cipher(Direction::Request, 0).seal(&echo(b"This is a text.").encode_to_vec()).unwrap();
+        let request = EncryptedValue::decode(&sealed[..]).unwrap();
+        let reply: EncryptedValue = call(channel(addr).await, Some("k1"), request).await.unwrap();
+        assert_eq!((reply.key_id.as_str(), reply.iv.len()), ("k1", IV_LEN));
+        let opened = cipher(Direction::Response, 0).open(&reply.encode_to_vec()).unwrap();
+        assert_eq!(Echo::decode(&opened[..]).unwrap(), echo(b".txet a si sihT"))
+    }
+
+    #[tokio::test]
+    async fn tampered_and_unauthenticated_requests_are_rejected() {
+        let addr = serve(EncryptionLayer::server_with_key("k1", Key { u8_array: KEY })).await;
+
+        let mut value = EncryptedValue::decode(&cipher(Direction::Request, 0).seal(&echo(b"This is a text.").encode_to_vec()).unwrap()[..]).unwrap();
+        value.ciphertext[0] ^= 1;
+        let reply: Result<EncryptedValue, Status> = call(channel(addr).await, Some("k1"), value).await;
+        match reply {
+            Ok(_) => assert!(false, "Should err InvalidArgument"),
+            Err(status) => assert_eq!((status.code(), status.message()), (Code::InvalidArgument, "Encrypted message doesn't decrypt"))
+        }
+
+        assert_code(call(channel(addr).await, None, echo(b"This is a text.")).await, Code::Unauthenticated);
+        let other_id = EncryptionLayer::client_with_key("k2", Key { u8_array: KEY }).layer(channel(addr).await);
+        assert_code(call(other_id, None, echo(b"This is a text.")).await, Code::Unauthenticated);
+        let other_key = EncryptionLayer::client_with_key("k1", Key { u8_array: [0u8; 32] }).layer(channel(addr).await);
+        assert_code(call(other_key, None, echo(b"This is a text.")).await, Code::InvalidArgument)
+    }
+
+    #[test]
+    fn messages_are_bound_to_their_position_and_direction() {
+        let sealed = cipher(Direction::Request, 0).seal(b"first").unwrap();
+        assert_eq!(cipher(Direction::Request, 0).open(&sealed).unwrap(), b"first");
+        for mut other in [cipher(Direction::Request, 1), cipher(Direction::Response, 0)] {
+            match other.open(&sealed) {
+                Ok(_) => assert!(false, "Should err InvalidArgument"),
+                Err(status) => assert_eq!(status.code(), Code::InvalidArgument)
+            }
+        }
+    }
+}
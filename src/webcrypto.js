@@ -0,0 +1,13 @@
+// Decrypts the output of `WebCryptoParts::to_json` with SubtleCrypto.
+// `key` is the 32-byte key in base64 or base64url, as `Key::try_from` reads it;
+// resolves to the plaintext as a Uint8Array.
+async function decryptWebCryptoParts(key, partsJson) {
+  const bytes = (text) => Uint8Array.from(atob(text.replace(/-/g, "+").replace(/_/g, "/")), (c) => c.charCodeAt(0));
+  const parts = JSON.parse(partsJson);
+  const cryptoKey = await crypto.subtle.importKey("raw", bytes(key), "AES-GCM", false, ["decrypt"]);
+  const params = { name: "AES-GCM", iv: bytes(parts.iv), tagLength: parts.tagLength };
+  if (parts.additionalData !== undefined) {
+    params.additionalData = bytes(parts.additionalData);
+  }
+  return new Uint8Array(await crypto.subtle.decrypt(params, cryptoKey, bytes(parts.ciphertext)));
+}
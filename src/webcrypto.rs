@@ -0,0 +1,226 @@
+//! The parameters `crypto.subtle.decrypt` takes, laid out for browsers.
+//!
+//! SubtleCrypto wants the iv and the additional data apart from the
+//! ciphertext, the tag appended to the ciphertext, and the tag length in
+//! bits. `to_webcrypto_parts` gives exactly these fields:
+//!
+//! ```text
+//! {"name":"AES-GCM","iv":"..","tagLength":128,"additionalData":"..","ciphertext":".."}
+//! ```
+//!
+//! byte strings being base64url without padding, and `additionalData`
+//! absent when there is none. `DECRYPT_JS` is a JavaScript function that
+//! imports the key and decrypts this JSON; the wasm-bindgen test in
+//! `tests/webcrypto.rs` runs it against ciphertexts from this crate, see
+//! `tests/wasm/run.sh`.
+
+use std::{error, fmt};
+
+use crate::{aad_digest, Encrypted, EncryptedAndIv, Iv, IV_LEN, TAG_LEN};
+
+/// Tag length in bits, the only one this crate writes or accepts.
+pub const TAG_LENGTH_BITS: u32 = 128;
+
+/// `async function decryptWebCryptoParts(key, partsJson)`, resolving to the
+/// plaintext as a `Uint8Array`. `key` is the key in base64 or base64url.
+pub const DECRYPT_JS: &str = include_str!("webcrypto.js");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebCryptoParts {
+    pub iv: [u8; IV_LEN],
+    /// Ciphertext followed by the tag, the `data` argument of `decrypt`.
+    pub ciphertext: Vec<u8>,
+    pub tag_length: u32,
+    pub additional_data: Option<Vec<u8>>
+}
+
+#[derive(Debug, Clone)]
+pub enum WebCryptoError {
+    /// A tag length other than 128 bits.
+    UnsupportedTagLengthError(u32),
+    /// Fewer bytes than a tag.
+    CiphertextTooShortError(usize),
+    /// Malformed JSON, base64url or iv.
+    InvalidWebCryptoJsonError
+}
+
+impl fmt::Display for WebCryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebCryptoError::UnsupportedTagLengthError(bits) => write!(f, "Unsupported tag length: {} bits, expected {}", bits, TAG_LENGTH_BITS),
+            WebCryptoError::CiphertextTooShortError(len) => write!(f, "Ciphertext of {} bytes can't hold a {}-byte tag", len, TAG_LEN),
+            WebCryptoError::InvalidWebCryptoJsonError => write!(f, "Invalid WebCrypto parameters JSON"),
+        }
+    }
+}
+
+impl error::Error for WebCryptoError {}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+impl WebCryptoParts {
+    pub fn iv_base64url(&self) -> String {
+        base64url(&self.iv)
+    }
+
+    pub fn ciphertext_base64url(&self) -> String {
+        base64url(&self.ciphertext)
+    }
+
+    pub fn additional_data_base64url(&self) -> Option<String> {
+        self.additional_data.as_deref().map(base64url)
+    }
+}
+
+/// The SubtleCrypto fields of `encrypted_and_iv`, without additional data.
+pub fn to_webcrypto_parts(encrypted_and_iv: &EncryptedAndIv) -> WebCryptoParts {
+    WebCryptoParts {
+        iv: encrypted_and_iv.iv.u8_array,
+        ciphertext: encrypted_and_iv.encrypted.u8_vec.clone(),
+        tag_length: TAG_LENGTH_BITS,
+        additional_data: None
+    }
+}
+
+/// Same as `to_webcrypto_parts`, for a value encrypted with `aad` (`encrypt_bytes_with_aad`).
+pub fn to_webcrypto_parts_with_aad(encrypted_and_iv: &EncryptedAndIv, aad: &[u8]) -> WebCryptoParts {
+    WebCryptoParts { additional_data: Some(aad.to_vec()), ..to_webcrypto_parts(encrypted_and_iv) }
+}
+
+/// Reads `parts` back; decrypt the result with `decrypt_bytes_with_aad`
+/// and `parts.additional_data`, whose `aad_digest` is recorded.
+pub fn from_webcrypto_parts(parts: &WebCryptoParts) -> Result<EncryptedAndIv, WebCryptoError> {
+    if parts.tag_length != TAG_LENGTH_BITS {
+        return Err(WebCryptoError::UnsupportedTagLengthError(parts.tag_length));
+    }
+    if parts.ciphertext.len() < TAG_LEN {
+        return Err(WebCryptoError::CiphertextTooShortError(parts.ciphertext.len()));
+    }
+    let encrypted_and_iv = EncryptedAndIv::from_parts(Iv { u8_array: parts.iv }, Encrypted { u8_vec: parts.ciphertext.clone() });
+    Ok(match &parts.additional_data {
+        Some(aad) => encrypted_and_iv.with_aad_digest(aad_digest(aad)),
+        None => encrypted_and_iv
+    })
+}
+
+#[cfg(feature = "json")]
+mod json {
+    use std::convert::TryInto;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{WebCryptoError, WebCryptoParts};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct JsonParts {
+        name: String,
+        iv: String,
+        tag_length: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        additional_data: Option<String>,
+        ciphertext: String
+    }
+
+    fn decode(value: &str) -> Result<Vec<u8>, WebCryptoError> {
+        base64::decode_config(value, base64::URL_SAFE_NO_PAD).map_err(|_| WebCryptoError::InvalidWebCryptoJsonError)
+    }
+
+    impl WebCryptoParts {
+        pub fn to_json(&self) -> String {
+            let parts = JsonParts {
+                name: String::from("AES-GCM"),
+                iv: self.iv_base64url(),
+                tag_length: self.tag_length,
+                additional_data: self.additional_data_base64url(),
+                ciphertext: self.ciphertext_base64url()
+            };
+            serde_json::to_string(&parts).expect("serializing plain strings can't fail")
+        }
+
+        pub fn from_json(json: &str) -> Result<WebCryptoParts, WebCryptoError> {
+            let parts: JsonParts = serde_json::from_str(json).map_err(|_| WebCryptoError::InvalidWebCryptoJsonError)?;
+            if parts.name != "AES-GCM" {
+                return Err(WebCryptoError::InvalidWebCryptoJsonError);
+            }
+            Ok(WebCryptoParts {
+                iv: decode(&parts.iv)?.as_slice().try_into().map_err(|_| WebCryptoError::InvalidWebCryptoJsonError)?,
+                ciphertext: decode(&parts.ciphertext)?,
+                tag_length: parts.tag_length,
+                additional_data: parts.additional_data.as_deref().map(decode).transpose()?
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_bytes, decrypt_bytes_with_aad, encrypt_bytes_with_iv, encrypt_bytes_with_iv_and_aad, Key};
+
+    const KEY: [u8; 32] = *b"01234567890123456789012345678901";
+    const IV: [u8; 12] = *b"012345678901";
+
+    #[test]
+    fn parts_round_trip() {
+        let key = Key { u8_array: KEY };
+        let encrypted_and_iv = encrypt_bytes_with_iv(&key, Iv { u8_array: IV }, b"This is a text.").unwrap();
+        let parts = to_webcrypto_parts(&encrypted_and_iv);
+        assert_eq!((parts.iv, parts.tag_length, parts.ciphertext.len()), (IV, 128, 15 + TAG_LEN));
+        assert_eq!(decrypt_bytes(&key, &from_webcrypto_parts(&parts).unwrap()).unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn additional_data_is_carried() {
+        let key = Key { u8_array: KEY };
+        let encrypted_and_iv = encrypt_bytes_with_iv_and_aad(&key, Iv { u8_array: IV }, b"This is a text.", b"user-42").unwrap();
+        let parts = to_webcrypto_parts_with_aad(&encrypted_and_iv, b"user-42");
+        assert_eq!(parts.additional_data_base64url().unwrap(), "dXNlci00Mg");
+        let parsed = from_webcrypto_parts(&parts).unwrap();
+        assert_eq!(parsed.aad_matches(b"user-42"), Some(true));
+        assert_eq!(decrypt_bytes_with_aad(&key, &parsed, b"user-42").unwrap(), b"This is a text.")
+    }
+
+    #[test]
+    fn rejects_other_tag_lengths_and_short_ciphertexts() {
+        let parts = WebCryptoParts { iv: IV, ciphertext: vec![0u8; 32], tag_length: 96, additional_data: None };
+        match from_webcrypto_parts(&parts) {
+            Ok(_) => assert!(false, "Should err UnsupportedTagLengthError"),
+            Err(e) => match e {
+                WebCryptoError::UnsupportedTagLengthError(96) => assert!(true),
+                _ => assert!(false, "Should err UnsupportedTagLengthError, got {}", e)
+            }
+        }
+        let parts = WebCryptoParts { ciphertext: vec![0u8; TAG_LEN - 1], tag_length: 128, ..parts };
+        match from_webcrypto_parts(&parts) {
+            Ok(_) => assert!(false, "Should err CiphertextTooShortError"),
+            Err(e) => match e {
+                WebCryptoError::CiphertextTooShortError(15) => assert!(true),
+                _ => assert!(false, "Should err CiphertextTooShortError, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn json_has_the_subtle_crypto_field_names() {
+        let key = Key { u8_array: KEY };
+        let encrypted_and_iv = encrypt_bytes_with_iv(&key, Iv { u8_array: IV }, b"This is a text.").unwrap();
+        let parts = to_webcrypto_parts(&encrypted_and_iv);
+        let json = parts.to_json();
+        assert!(json.starts_with("{\"name\":\"AES-GCM\",\"iv\":\"MDEyMzQ1Njc4OTAx\",\"tagLength\":128,\"ciphertext\":\""), "{}", json);
+        assert_eq!(WebCryptoParts::from_json(&json).unwrap(), parts);
+        let with_aad = to_webcrypto_parts_with_aad(&encrypted_and_iv, b"user-42");
+        assert_eq!(WebCryptoParts::from_json(&with_aad.to_json()).unwrap(), with_aad);
+        match WebCryptoParts::from_json(&json.replace("MDEyMzQ1Njc4OTAx", "MDEy")) {
+            Ok(_) => assert!(false, "Should err InvalidWebCryptoJsonError"),
+            Err(e) => match e {
+                WebCryptoError::InvalidWebCryptoJsonError => assert!(true),
+                _ => assert!(false, "Should err InvalidWebCryptoJsonError, got {}", e)
+            }
+        }
+    }
+}
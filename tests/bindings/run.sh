@@ -0,0 +1,35 @@
+#!/bin/sh
+# Generates the UniFFI bindings and runs the foreign-language smoke tests.
+#
+# Usage: tests/bindings/run.sh [python|kotlin|swift]...   (default: python)
+set -e
+
+cd "$(dirname "$0")/../.."
+cargo rustc --lib --features uniffi --crate-type cdylib
+out_dir=target/bindings
+library=target/debug/libsimple_aes256_gcm.so
+[ -f "$library" ] || library=target/debug/libsimple_aes256_gcm.dylib
+
+[ "$#" -gt 0 ] || set -- python
+for language in "$@"; do
+    cargo run --features uniffi-cli --bin uniffi-bindgen -- \
+        generate --library "$library" --language "$language" --out-dir "$out_dir/$language" --no-format
+    cp "$library" "$out_dir/$language/"
+    case "$language" in
+        python)
+            PYTHONPATH="$out_dir/python" python3 tests/bindings/test_simple_aes256_gcm.py ;;
+        kotlin)
+            kotlinc -classpath "$JNA_JAR" "$out_dir/kotlin/uniffi/simple_aes256_gcm/simple_aes256_gcm.kt" \
+                -d "$out_dir/kotlin/simple_aes256_gcm.jar"
+            kotlinc -classpath "$JNA_JAR:$out_dir/kotlin/simple_aes256_gcm.jar" -J-ea \
+                -Djna.library.path="$out_dir/kotlin" -script tests/bindings/test_simple_aes256_gcm.kts ;;
+        swift)
+            swiftc -module-name simple_aes256_gcm -emit-library -o "$out_dir/swift/libsimple_aes256_gcm_bindings.so" \
+                -I "$out_dir/swift" -Xcc -fmodule-map-file="$out_dir/swift/simple_aes256_gcmFFI.modulemap" \
+                -L "$out_dir/swift" -lsimple_aes256_gcm "$out_dir/swift/simple_aes256_gcm.swift" -emit-module
+            swiftc -I "$out_dir/swift" -L "$out_dir/swift" -lsimple_aes256_gcm_bindings -lsimple_aes256_gcm \
+                -Xcc -fmodule-map-file="$out_dir/swift/simple_aes256_gcmFFI.modulemap" \
+                tests/bindings/test_simple_aes256_gcm.swift -o "$out_dir/swift/test" && "$out_dir/swift/test" ;;
+    esac
+    echo "$language bindings: ok"
+done
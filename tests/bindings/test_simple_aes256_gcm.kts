@@ -0,0 +1,24 @@
+import uniffi.simple_aes256_gcm.*
+
+val key = Key.fromBase64("MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=")
+assert(key.toBase64() == "MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=")
+
+val encryptedAndIv = encrypt(key, "This is a text.")
+assert(encryptedAndIv.iv.size == 12)
+assert(decrypt(key, encryptedAndIv) == "This is a text.")
+
+try {
+    Key.fromBase64("MDEy")
+    throw RuntimeException("Should throw InvalidKeySizeException")
+} catch (e: InvalidKeyException.InvalidKeySizeException) {
+    // Expected
+}
+
+val tampered = encryptedAndIv.copy(encrypted = encryptedAndIv.encrypted.copyOf())
+tampered.encrypted[0] = (tampered.encrypted[0].toInt() xor 1).toByte()
+try {
+    decrypt(key, tampered)
+    throw RuntimeException("Should throw GenericDecryptionException")
+} catch (e: DecryptionException.GenericDecryptionException) {
+    // Expected
+}
@@ -0,0 +1,40 @@
+from simple_aes256_gcm import *
+
+key = Key.from_base64("MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=")
+assert key.to_base64() == "MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE="
+
+encrypted_and_iv = encrypt(key, "This is a text.")
+assert len(encrypted_and_iv.iv) == 12
+assert decrypt(key, encrypted_and_iv) == "This is a text."
+
+try:
+    Key.from_base64("MDEy")
+    raise AssertionError("Should raise InvalidKeySizeError")
+except InvalidKeyError.InvalidKeySizeError:
+    pass
+
+tampered = EncryptedAndIv(
+    encrypted=bytes([encrypted_and_iv.encrypted[0] ^ 1]) + encrypted_and_iv.encrypted[1:],
+    iv=encrypted_and_iv.iv,
+)
+try:
+    decrypt(key, tampered)
+    raise AssertionError("Should raise GenericDecryptionError")
+except DecryptionError.GenericDecryptionError:
+    pass
+
+token = encrypt_to_token(key, b"This is a text.")
+assert token.startswith("saes1:")
+assert decrypt_token(key, token) == b"This is a text."
+
+try:
+    decrypt_token(Key.generate(), token)
+    raise AssertionError("Should raise TokenDecryptionError")
+except TokenError.TokenDecryptionError as e:
+    assert isinstance(e.error, DecryptionError.GenericDecryptionError)
+
+try:
+    decrypt_token(key, "saes1:")
+    raise AssertionError("Should raise InvalidTokenError")
+except TokenError.InvalidTokenError:
+    pass
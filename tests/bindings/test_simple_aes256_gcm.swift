@@ -0,0 +1,24 @@
+import simple_aes256_gcm
+
+let key = try! Key.fromBase64(base64Key: "MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=")
+assert(key.toBase64() == "MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDE=")
+
+let encryptedAndIv = try! encrypt(key: key, plaintext: "This is a text.")
+assert(encryptedAndIv.iv.count == 12)
+assert(try! decrypt(key: key, encryptedAndIv: encryptedAndIv) == "This is a text.")
+
+do {
+    _ = try Key.fromBase64(base64Key: "MDEy")
+    fatalError("Should throw InvalidKeySizeError")
+} catch InvalidKeyError.InvalidKeySizeError {
+    // Expected
+}
+
+var tampered = encryptedAndIv
+tampered.encrypted[0] ^= 1
+do {
+    _ = try decrypt(key: key, encryptedAndIv: tampered)
+    fatalError("Should throw GenericDecryptionError")
+} catch DecryptionError.GenericDecryptionError {
+    // Expected
+}
@@ -0,0 +1,9 @@
+// Key must never implement Serialize, see src/export.rs.
+//
+// The expected compiler output names `Key` by its shortest unambiguous
+// path, which changes when uniffi brings another `Key` into the build.
+#[test]
+#[cfg(all(feature = "serde", not(feature = "uniffi")))]
+fn compile_fail() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,53 @@
+// Counts heap allocations made by the `small` functions on this thread.
+// A binary of its own: the counting allocator replaces the global one.
+// The `metrics` feature allocates its labels on every operation.
+#![cfg(not(feature = "metrics"))]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use simple_aes256_gcm::small::{decrypt_small, encrypt_small_with_iv, SMALL_MESSAGE_LEN};
+use simple_aes256_gcm::{Iv, Key};
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+fn round_trip(len: usize) {
+    let key = Key { u8_array: [7u8; 32] };
+    let plaintext = [0x5au8; SMALL_MESSAGE_LEN + 1];
+    let mut out = [0u8; SMALL_MESSAGE_LEN + 1];
+    let encrypted = encrypt_small_with_iv(&key, Iv { u8_array: [1u8; 12] }, &plaintext[..len]).unwrap();
+    assert_eq!(decrypt_small(&key, &encrypted, &mut out).unwrap(), &plaintext[..len]);
+}
+
+#[test]
+fn small_messages_do_not_allocate() {
+    // Once first, for any lazy initialization on the way.
+    round_trip(SMALL_MESSAGE_LEN);
+    assert_eq!(allocations(|| round_trip(0)), 0);
+    assert_eq!(allocations(|| round_trip(SMALL_MESSAGE_LEN)), 0);
+    assert!(allocations(|| round_trip(SMALL_MESSAGE_LEN + 1)) > 0)
+}
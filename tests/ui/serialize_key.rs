@@ -0,0 +1,10 @@
+use serde::Serialize;
+use simple_aes256_gcm::Key;
+
+#[derive(Serialize)]
+struct Settings {
+    name: String,
+    key: Key
+}
+
+fn main() {}
@@ -0,0 +1,16 @@
+#!/bin/sh
+# Builds for wasm32-wasip1 and runs the library's unit tests under wasmtime,
+# with a fresh host directory preopened as /tmp.
+#
+# Usage: tests/wasi/run.sh [cargo test arguments]...   (e.g. --features password)
+#
+# Needs the target and wasmtime:
+#   rustup target add wasm32-wasip1
+#   cargo install wasmtime-cli
+set -e
+
+cd "$(dirname "$0")/../.."
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+CARGO_TARGET_WASM32_WASIP1_RUNNER="wasmtime run --dir=$tmp::/tmp" \
+    cargo test --target wasm32-wasip1 --lib "$@"
@@ -0,0 +1,12 @@
+#!/bin/sh
+# Runs the wasm-bindgen tests (tests/webcrypto.rs) under Node's Web Crypto.
+#
+# Needs the wasm32-unknown-unknown target and a wasm-bindgen-test-runner
+# matching the wasm-bindgen version in Cargo.lock:
+#   rustup target add wasm32-unknown-unknown
+#   cargo install wasm-bindgen-cli --version <version>
+set -e
+
+cd "$(dirname "$0")/../.."
+CARGO_TARGET_WASM32_UNKNOWN_UNKNOWN_RUNNER=wasm-bindgen-test-runner \
+    cargo test --target wasm32-unknown-unknown --features json --test webcrypto
@@ -0,0 +1,42 @@
+//! Runs `webcrypto::DECRYPT_JS` on Web Crypto against ciphertexts from this
+//! crate. Only built for wasm32, see tests/wasm/run.sh.
+#![cfg(all(target_arch = "wasm32", feature = "json"))]
+
+use js_sys::{Function, Promise, Uint8Array};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use wasm_bindgen_test::wasm_bindgen_test;
+
+use simple_aes256_gcm::webcrypto::{to_webcrypto_parts, to_webcrypto_parts_with_aad, WebCryptoParts, DECRYPT_JS};
+use simple_aes256_gcm::{encrypt_bytes, encrypt_bytes_with_aad, Key};
+
+async fn decrypt_in_js(key: &Key, parts: &WebCryptoParts) -> Result<Vec<u8>, JsValue> {
+    let decrypt: Function = js_sys::eval(&format!("({})", DECRYPT_JS))?.dyn_into()?;
+    let promise: Promise = decrypt.call2(&JsValue::NULL, &JsValue::from(base64::encode(&key.u8_array)), &JsValue::from(parts.to_json()))?.dyn_into()?;
+    Ok(Uint8Array::new(&JsFuture::from(promise).await?).to_vec())
+}
+
+#[wasm_bindgen_test]
+async fn subtle_crypto_decrypts_a_crate_ciphertext() {
+    let key = Key::generate();
+    let encrypted_and_iv = encrypt_bytes(&key, b"This is a text.").unwrap();
+    assert_eq!(decrypt_in_js(&key, &to_webcrypto_parts(&encrypted_and_iv)).await.unwrap(), b"This is a text.")
+}
+
+#[wasm_bindgen_test]
+async fn subtle_crypto_checks_additional_data() {
+    let key = Key::generate();
+    let encrypted_and_iv = encrypt_bytes_with_aad(&key, b"This is a text.", b"user-42").unwrap();
+    let parts = to_webcrypto_parts_with_aad(&encrypted_and_iv, b"user-42");
+    assert_eq!(decrypt_in_js(&key, &parts).await.unwrap(), b"This is a text.");
+    let wrong = WebCryptoParts { additional_data: Some(b"user-43".to_vec()), ..parts.clone() };
+    assert!(decrypt_in_js(&key, &wrong).await.is_err());
+    let missing = WebCryptoParts { additional_data: None, ..parts };
+    assert!(decrypt_in_js(&key, &missing).await.is_err())
+}
+
+#[wasm_bindgen_test]
+async fn subtle_crypto_rejects_a_wrong_key() {
+    let encrypted_and_iv = encrypt_bytes(&Key::generate(), b"This is a text.").unwrap();
+    assert!(decrypt_in_js(&Key::generate(), &to_webcrypto_parts(&encrypted_and_iv)).await.is_err())
+}